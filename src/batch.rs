@@ -0,0 +1,169 @@
+use std::{collections::VecDeque, sync::mpsc, thread, time};
+use tracing::warn;
+
+// Milliseconds to wait for the last batch on the way out, so a slow sink can't hold us up.
+const CLOSE_GRACE_MS: u64 = 2000;
+
+pub trait Sink: Send + 'static {
+    // Where a `Writer` puts its batches, e.g. a database or a server.
+    type Item: Send + 'static;
+
+    // Write the batch, reporting any failure itself, and returning whether it's done with it. A
+    // batch it isn't done with is kept and tried again, with anything since, on the next flush.
+    fn write(&mut self, items: &[Self::Item]) -> bool;
+
+    // Housekeeping between flushes, e.g. pruning, also called once on starting.
+    fn upkeep(&mut self) {}
+}
+
+enum Message<T> {
+    Item(T),
+    // Flush what's left and stop, saying so once done.
+    Close(mpsc::Sender<()>),
+}
+
+pub struct Writer<T> {
+    // Batches items and writes them from a thread of its own every `interval`, so the poll loop
+    // never waits on the sink.
+    sender: mpsc::Sender<Message<T>>,
+    name: &'static str,
+}
+
+impl<T: Send + 'static> Writer<T> {
+    pub fn spawn<S: Sink<Item = T>>(
+        name: &'static str,
+        mut sink: S,
+        interval: time::Duration,
+        max_buffered: usize,
+    ) -> Writer<T> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = Buffer::new(max_buffered);
+            sink.upkeep();
+            let mut next_flush = time::Instant::now() + interval;
+            loop {
+                match receiver
+                    .recv_timeout(next_flush.saturating_duration_since(time::Instant::now()))
+                {
+                    Ok(Message::Item(item)) => buffer.push(item),
+                    Ok(Message::Close(done)) => {
+                        // Let go of the sink before saying so, e.g. closing a database.
+                        buffer.flush(&mut sink);
+                        drop(sink);
+                        let _ = done.send(());
+                        return;
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        buffer.flush(&mut sink);
+                        sink.upkeep();
+                        next_flush = time::Instant::now() + interval;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+
+        Writer { sender, name }
+    }
+
+    pub fn send(&self, item: T) {
+        if self.sender.send(Message::Item(item)).is_err() {
+            warn!("{} writer has stopped, dropping the poll.", self.name)
+        }
+    }
+
+    pub fn close(&self) {
+        // Give the last batch a chance to go out, without waiting long on it.
+        let (done, finished) = mpsc::channel();
+        if self.sender.send(Message::Close(done)).is_ok()
+            && finished
+                .recv_timeout(time::Duration::from_millis(CLOSE_GRACE_MS))
+                .is_err()
+        {
+            warn!("Gave up waiting for the last write to {}.", self.name)
+        }
+    }
+}
+
+struct Buffer<T> {
+    // The items waiting to go out, kept across failed writes until full, then dropping the oldest.
+    items: VecDeque<T>,
+    max: usize,
+}
+
+impl<T> Buffer<T> {
+    fn new(max: usize) -> Buffer<T> {
+        Buffer {
+            items: VecDeque::new(),
+            max,
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        if self.items.len() >= self.max {
+            self.items.pop_front();
+        }
+        self.items.push_back(item);
+    }
+
+    fn flush<S: Sink<Item = T>>(&mut self, sink: &mut S) {
+        if !self.items.is_empty() && sink.write(self.items.make_contiguous()) {
+            self.items.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Recording {
+        // Every batch written, and whether to fail them instead.
+        batches: Arc<Mutex<Vec<Vec<u32>>>>,
+        failing: Arc<Mutex<bool>>,
+    }
+
+    impl Sink for Recording {
+        type Item = u32;
+
+        fn write(&mut self, items: &[u32]) -> bool {
+            if *self.failing.lock().unwrap() {
+                return false;
+            }
+            self.batches.lock().unwrap().push(items.to_vec());
+            true
+        }
+    }
+
+    #[test]
+    fn buffer_keeps_failed_batches_and_drops_the_oldest() {
+        let mut sink = Recording::default();
+        let mut buffer = Buffer::new(3);
+
+        *sink.failing.lock().unwrap() = true;
+        for item in 1..=2 {
+            buffer.push(item);
+        }
+        buffer.flush(&mut sink);
+        for item in 3..=4 {
+            buffer.push(item);
+        }
+
+        *sink.failing.lock().unwrap() = false;
+        buffer.flush(&mut sink);
+        buffer.flush(&mut sink);
+        assert_eq!(*sink.batches.lock().unwrap(), vec![vec![2, 3, 4]]);
+    }
+
+    #[test]
+    fn close_writes_what_is_left() {
+        let sink = Recording::default();
+        let writer = Writer::spawn("test", sink.clone(), time::Duration::from_secs(60), 10);
+        writer.send(1);
+        writer.send(2);
+        writer.close();
+        assert_eq!(*sink.batches.lock().unwrap(), vec![vec![1, 2]]);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::notifier::{Event, Notifier, NotifyError, Severity, Worker};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{self, SystemTime};
+use tracing::{debug, error};
+
+const SEND_TIMEOUT: u64 = 10; // Seconds to wait on the webhook for each post.
+
+// The status readings shown alongside each alert, by their names in the status table.
+const STATUS_FIELDS: [&str; 6] = [
+    "Mode",
+    "Status",
+    "Battery",
+    "Runtime",
+    "Input voltage",
+    "Load",
+];
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatFormat {
+    // An incoming webhook posting attachments.
+    Slack,
+    // A channel webhook posting embeds.
+    Discord,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChatColors {
+    // Outline for the `colors` of the chat settings, as `#rrggbb` for each severity
+    #[serde(default = "default_info_color")]
+    pub info: String,
+    #[serde(default = "default_warning_color")]
+    pub warning: String,
+    #[serde(default = "default_critical_color")]
+    pub critical: String,
+}
+
+fn default_info_color() -> String {
+    "#2eb886".to_string()
+}
+
+fn default_warning_color() -> String {
+    "#daa038".to_string()
+}
+
+fn default_critical_color() -> String {
+    "#a30200".to_string()
+}
+
+impl Default for ChatColors {
+    fn default() -> Self {
+        ChatColors {
+            info: default_info_color(),
+            warning: default_warning_color(),
+            critical: default_critical_color(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChatSettings {
+    // Outline for the optional `[chat]` section of the mailer settings
+    //
+    // Parameters
+    // ----------
+    // - `url` is the Slack or Discord webhook to post alerts to
+    // - `format` of the payload, either `slack` or `discord`
+    // - `channel` overrides the webhook's channel, for Slack webhooks that allow it
+    // - `colors` mark each severity, defaulting to green, yellow and red
+    pub url: String,
+    pub format: ChatFormat,
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub colors: ChatColors,
+}
+
+impl ChatSettings {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, color) in [
+            ("info", &self.colors.info),
+            ("warning", &self.colors.warning),
+            ("critical", &self.colors.critical),
+        ] {
+            if parse_color(color).is_none() {
+                problems.push(format!(
+                    "chat colors.{} ({:?}) must be a hex color, e.g. \"#a30200\"",
+                    name, color
+                ));
+            }
+        }
+        if self.channel.is_some() && self.format == ChatFormat::Discord {
+            problems.push("chat channel is only supported by Slack webhooks".to_string());
+        }
+        problems
+    }
+
+    fn color(&self, severity: Severity) -> &str {
+        match severity {
+            Severity::Info => &self.colors.info,
+            Severity::Warning => &self.colors.warning,
+            Severity::Critical => &self.colors.critical,
+        }
+    }
+}
+
+fn parse_color(color: &str) -> Option<u32> {
+    // A `#rrggbb` color as the number Discord wants.
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+pub struct ChatNotifier {
+    // Posts alerts to Slack or Discord, colored by severity and with the key readings as fields.
+    worker: Worker<Value>,
+    settings: ChatSettings,
+    machine_id: String,
+    dry_run: bool,
+}
+
+impl ChatNotifier {
+    pub fn new(settings: ChatSettings, machine_id: String, dry_run: bool) -> ChatNotifier {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(time::Duration::from_secs(SEND_TIMEOUT))
+            .build();
+        let url = settings.url.clone();
+        let worker = Worker::spawn("the chat webhook", move |payload: Value| {
+            if let Err(e) = agent.post(&url).send_json(payload) {
+                error!("Failed to post to chat: {:?}", e)
+            }
+        });
+
+        ChatNotifier {
+            worker,
+            settings,
+            machine_id,
+            dry_run,
+        }
+    }
+
+    fn payload(&self, event: &Event) -> Value {
+        let subject = event.subject(self.dry_run);
+        let message = &event.message;
+        let fields: Vec<(&str, String)> = match &event.status {
+            Some(status) => status
+                .rows()
+                .into_iter()
+                .filter(|(name, _)| STATUS_FIELDS.contains(name))
+                .collect(),
+            None => Vec::new(),
+        };
+        let color = self.settings.color(event.severity);
+
+        match self.settings.format {
+            ChatFormat::Slack => {
+                let fields: Vec<Value> = fields
+                    .iter()
+                    .map(|(name, value)| json!({"title": name, "value": value, "short": true}))
+                    .collect();
+                let timestamp = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let mut payload = json!({
+                    "text": subject,
+                    "attachments": [{
+                        "color": color,
+                        "title": subject,
+                        "text": message,
+                        "fields": fields,
+                        "footer": self.machine_id,
+                        "ts": timestamp,
+                    }],
+                });
+                if let Some(channel) = &self.settings.channel {
+                    payload["channel"] = json!(channel);
+                }
+                payload
+            }
+            ChatFormat::Discord => {
+                let fields: Vec<Value> = fields
+                    .iter()
+                    .map(|(name, value)| json!({"name": name, "value": value, "inline": true}))
+                    .collect();
+                json!({
+                    "embeds": [{
+                        "title": subject,
+                        "description": message,
+                        "color": parse_color(color).unwrap_or_default(),
+                        "fields": fields,
+                        "footer": {"text": self.machine_id},
+                        "timestamp": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                    }],
+                })
+            }
+        }
+    }
+}
+
+impl Notifier for ChatNotifier {
+    fn name(&self) -> &str {
+        "chat"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        // Post a UPS alert to the chat webhook
+        if cfg!(debug_assertions) {
+            // In debug builds, don't spam anyone!
+            debug!("In debug build, not posting to chat.");
+            return Ok(());
+        }
+
+        self.worker.send(self.payload(event))
+    }
+
+    fn flush(&self, force: bool) {
+        self.worker.flush(force)
+    }
+}
@@ -0,0 +1,383 @@
+use crate::status;
+
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    thread, time,
+};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+};
+use tracing::warn;
+#[cfg(unix)]
+use tracing::{debug, info};
+
+#[cfg(unix)]
+const REPLY_TIMEOUT_SECONDS: u64 = 30; // Seconds `ups ctl` waits on the monitor, e.g. mid-poll.
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ControlSettings {
+    // Outline for the optional `[control]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // - `path` of the Unix socket the monitor takes commands on from `ups ctl`, empty to not
+    //   listen
+    // - `mode` of the socket, as octal permissions, e.g. "0660" to let its group in too
+    pub path: String,
+    pub mode: String,
+}
+
+impl Default for ControlSettings {
+    fn default() -> Self {
+        ControlSettings {
+            path: "/run/ups/control.sock".to_string(),
+            mode: "0600".to_string(),
+        }
+    }
+}
+
+impl ControlSettings {
+    pub fn permissions(&self) -> Result<u32, String> {
+        // The mode as a number, only allowing the permission bits.
+        u32::from_str_radix(&self.mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| {
+                format!(
+                    "control mode ({:?}) must be octal permissions, e.g. \"0660\"",
+                    self.mode
+                )
+            })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Command {
+    // The latest status, as of the last poll.
+    Status,
+    // Start a self-test, quick unless given minutes or until the battery's low.
+    Test {
+        minutes: Option<u8>,
+        #[serde(default)]
+        until_low: bool,
+    },
+    // Turn the beeper off or on, or toggle it.
+    Beep {
+        enabled: Option<bool>,
+    },
+    CancelShutdown,
+    // Poll in full now, rather than waiting out the delay.
+    PollNow,
+    // Re-read the config files, as on SIGHUP.
+    Reload,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Request {
+    // One line from a client, e.g. `{"command": "beep", "enabled": false, "unit": "rack-a"}`.
+    #[serde(flatten)]
+    pub command: Command,
+    // The unit by its label, defaulting to the first.
+    pub unit: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Reply {
+    // The line sent back, saying whether it worked and what happened, or the status asked for.
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<status::UPSStatus>,
+}
+
+impl Reply {
+    pub fn done(message: impl Into<String>) -> Reply {
+        Reply {
+            ok: true,
+            message: Some(message.into()),
+            status: None,
+        }
+    }
+
+    pub fn failed(message: impl Into<String>) -> Reply {
+        Reply {
+            ok: false,
+            message: Some(message.into()),
+            status: None,
+        }
+    }
+
+    pub fn status(status: &status::UPSStatus) -> Reply {
+        Reply {
+            ok: true,
+            message: None,
+            status: Some(status.clone()),
+        }
+    }
+}
+
+pub struct Pending {
+    // A request waiting on the poll loop, which owns the UPS, to act on it.
+    pub request: Request,
+    reply: mpsc::Sender<Reply>,
+}
+
+impl Pending {
+    pub fn reply(self, reply: Reply) {
+        // The client may have hung up by now, which is fine.
+        let _ = self.reply.send(reply);
+    }
+}
+
+pub struct Control {
+    // Requests from clients, handed over to the poll loop. The flag lets its wait end early.
+    requests: mpsc::Receiver<Pending>,
+    pending: Arc<AtomicBool>,
+    path: PathBuf,
+}
+
+impl Control {
+    pub fn pending(&self) -> bool {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn take(&self) -> Vec<Pending> {
+        // Clear the flag first, so a request arriving as we drain only wakes us again.
+        self.pending.store(false, Ordering::Relaxed);
+        self.requests.try_iter().collect()
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        // Tidy the socket away as we stop, though a stale one is replaced on start anyway.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+pub fn listen(settings: &ControlSettings) -> Option<Control> {
+    if settings.path.is_empty() {
+        return None;
+    }
+    let path = Path::new(&settings.path);
+    // Controlling the monitor matters less than monitoring, so carry on without it.
+    let listener = match bind(path, settings) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to open control socket at {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    info!("Taking commands on {}", path.display());
+
+    let (sender, requests) = mpsc::channel();
+    let pending = Arc::new(AtomicBool::new(false));
+    accept(listener, sender, Arc::clone(&pending));
+
+    Some(Control {
+        requests,
+        pending,
+        path: path.to_path_buf(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn listen(settings: &ControlSettings) -> Option<Control> {
+    if !settings.path.is_empty() {
+        warn!("The control socket needs a Unix platform, so isn't available.");
+    }
+    None
+}
+
+#[cfg(unix)]
+fn bind(path: &Path, settings: &ControlSettings) -> io::Result<UnixListener> {
+    // Replace a socket left behind by a monitor that didn't stop cleanly, but not a live one.
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "something other than a socket is in the way",
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another monitor is already listening",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let mode = settings
+        .permissions()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+#[cfg(unix)]
+fn accept(listener: UnixListener, sender: mpsc::Sender<Pending>, pending: Arc<AtomicBool>) {
+    // Give each client its own thread, so a slow one can't hold up the others.
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    let pending = Arc::clone(&pending);
+                    thread::spawn(move || {
+                        if let Err(e) = converse(stream, &sender, &pending) {
+                            debug!("Control client dropped: {}", e)
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept control client: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+fn converse(
+    stream: UnixStream,
+    sender: &mpsc::Sender<Pending>,
+    pending: &AtomicBool,
+) -> io::Result<()> {
+    // Answer each line with one of our own until the client hangs up.
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let (reply, answer) = mpsc::channel();
+                let sent = sender.send(Pending { request, reply });
+                pending.store(true, Ordering::Relaxed);
+                match sent.ok().and_then(|_| answer.recv().ok()) {
+                    Some(reply) => reply,
+                    None => Reply::failed("The monitor is stopping."),
+                }
+            }
+            Err(e) => Reply::failed(format!("Invalid request: {}", e)),
+        };
+        let mut line = serde_json::to_string(&reply)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn request(path: &str, request: &Request) -> io::Result<Reply> {
+    // Send one request to the running monitor and wait for its reply.
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(time::Duration::from_secs(REPLY_TIMEOUT_SECONDS)))?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+#[cfg(not(unix))]
+pub fn request(_path: &str, _request: &Request) -> io::Result<Reply> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the control socket needs a Unix platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_one_json_object() {
+        let request: Request =
+            serde_json::from_str(r#"{"command": "beep", "enabled": false, "unit": "rack-a"}"#)
+                .unwrap();
+        assert_eq!(
+            request,
+            Request {
+                command: Command::Beep {
+                    enabled: Some(false)
+                },
+                unit: Some("rack-a".to_string()),
+            }
+        );
+
+        let request: Request = serde_json::from_str(r#"{"command": "test"}"#).unwrap();
+        assert_eq!(
+            request.command,
+            Command::Test {
+                minutes: None,
+                until_low: false
+            }
+        );
+        assert_eq!(request.unit, None);
+        assert!(serde_json::from_str::<Request>(r#"{"command": "explode"}"#).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn requests_reach_the_poll_loop_and_back() {
+        let path = std::env::temp_dir().join(format!("ups-control-{}.sock", std::process::id()));
+        let settings = ControlSettings {
+            path: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let control = listen(&settings).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // A second monitor leaves the live socket alone.
+        assert!(listen(&settings).is_none());
+
+        let client = thread::spawn(move || {
+            request(
+                &settings.path,
+                &Request {
+                    command: Command::PollNow,
+                    unit: None,
+                },
+            )
+            .unwrap()
+        });
+        while !control.pending() {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let mut requests = control.take();
+        assert_eq!(requests.len(), 1);
+        let pending = requests.remove(0);
+        assert_eq!(pending.request.command, Command::PollNow);
+        pending.reply(Reply::done("Polling now."));
+        assert_eq!(client.join().unwrap(), Reply::done("Polling now."));
+
+        drop(control);
+        assert!(!path.exists());
+    }
+}
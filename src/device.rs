@@ -0,0 +1,270 @@
+use crate::health;
+use crate::protocol::TestKind;
+use crate::status;
+use crate::ups::{UPSError, UPS};
+
+use std::{sync::mpsc, thread, time};
+use tracing::warn;
+
+// Seconds to wait on the I/O thread beyond the longest the protocol allows itself for a poll.
+const REPLY_MARGIN_SECONDS: u64 = 5;
+
+type Job = Box<dyn FnOnce(&mut UPS) + Send>;
+
+// What a command returned, with the status and history as it left them.
+type Reply<T> = (T, status::UPSStatus, health::History);
+
+pub struct Device {
+    // A UPS owned by its own I/O thread, so a stalled read can't hold up the poll loop for
+    // longer than the timeout. Commands go in as jobs, and each reply brings back a copy of the
+    // status and history, which are kept here for the rest of the program to read.
+    jobs: mpsc::Sender<Job>,
+    pub status: status::UPSStatus,
+    pub health: health::History,
+    timeout: time::Duration,
+}
+
+impl Device {
+    pub fn spawn(
+        name: &str,
+        timeout: time::Duration,
+        open: impl FnOnce() -> Result<UPS, UPSError> + Send + 'static,
+    ) -> Result<Device, UPSError> {
+        // Open the UPS on the thread that will own it, handing back how that went.
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (opened, result) = mpsc::channel();
+        thread::Builder::new()
+            .name(format!("ups-io {}", name))
+            .spawn(move || {
+                let mut ups = match open() {
+                    Ok(ups) => ups,
+                    Err(e) => {
+                        let _ = opened.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = opened.send(Ok((ups.status.clone(), ups.health.clone())));
+
+                // Carry on until the device is dropped, taking the UPS with us.
+                for job in queue {
+                    job(&mut ups);
+                }
+            })?;
+
+        // Opening has its own retries and timeouts, so wait on it for as long as it takes.
+        let (status, health) = result.recv().map_err(|_| UPSError::Stopped)??;
+        Ok(Device {
+            jobs,
+            status,
+            health,
+            timeout: timeout + time::Duration::from_secs(REPLY_MARGIN_SECONDS),
+        })
+    }
+
+    fn call<T: Send + 'static>(
+        &self,
+        extra: time::Duration,
+        job: impl FnOnce(&mut UPS) -> T + Send + 'static,
+    ) -> Result<Reply<T>, UPSError> {
+        // Run a job on the I/O thread and wait for it. Giving up leaves the job queued, so the
+        // next one waits behind it, and its reply goes nowhere.
+        let (reply, answer) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |ups: &mut UPS| {
+                let result = job(ups);
+                let _ = reply.send((result, ups.status.clone(), ups.health.clone()));
+            }))
+            .map_err(|_| UPSError::Stopped)?;
+
+        let timeout = self.timeout + extra;
+        answer.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => UPSError::Unresponsive {
+                seconds: timeout.as_secs(),
+            },
+            mpsc::RecvTimeoutError::Disconnected => UPSError::Stopped,
+        })
+    }
+
+    fn run<T: Send + 'static>(
+        &mut self,
+        extra: time::Duration,
+        job: impl FnOnce(&mut UPS) -> Result<T, UPSError> + Send + 'static,
+    ) -> Result<T, UPSError> {
+        // As `call`, keeping the status and history the job left behind.
+        let (result, status, health) = self.call(extra, job)?;
+        self.status = status;
+        self.health = health;
+        result
+    }
+
+    pub fn connect(&mut self) -> Result<(), UPSError> {
+        self.run(time::Duration::ZERO, |ups| ups.connect())
+    }
+
+    pub fn disconnect(&mut self) {
+        if let Err(e) = self.run(time::Duration::ZERO, |ups| {
+            ups.disconnect();
+            Ok(())
+        }) {
+            warn!("Failed to release the UPS: {}", e.report())
+        }
+    }
+
+    pub fn get_ups_status(&mut self) -> Result<(), UPSError> {
+        self.run(time::Duration::ZERO, |ups| ups.get_ups_status())
+    }
+
+    pub fn get_ups_mode_quick(&mut self) -> Result<(), UPSError> {
+        self.run(time::Duration::ZERO, |ups| ups.get_ups_mode_quick())
+    }
+
+    pub fn health_report(&self) -> health::HealthReport {
+        health::HealthReport::new(&self.status, &self.health)
+    }
+
+    pub fn load_health(&mut self, path: &str, label: &str) {
+        // Pick up the battery's history where we left off.
+        let history = health::load(path, label);
+        if let Err(e) = self.run(time::Duration::ZERO, move |ups| {
+            ups.health = history;
+            Ok(())
+        }) {
+            warn!("Failed to restore the battery history: {}", e.report())
+        }
+    }
+
+    pub fn save_health(&mut self, path: &str, label: &str) {
+        // Save the history if anything's changed, from the I/O thread, which keeps track of that.
+        let (path, label) = (path.to_string(), label.to_string());
+        if let Err(e) = self.run(time::Duration::ZERO, move |ups| {
+            if ups.health.take_changed() {
+                health::save(&path, &label, &ups.health);
+            }
+            Ok(())
+        }) {
+            warn!("Failed to save the battery history: {}", e.report())
+        }
+    }
+
+    pub fn shutdown(&self, delay: f32, restart: i32) -> Result<(), UPSError> {
+        self.call(time::Duration::ZERO, move |ups| {
+            ups.shutdown(delay, restart)
+        })?
+        .0
+    }
+
+    pub fn describe_shutdown(&self, delay: f32, restart: i32) -> String {
+        self.call(time::Duration::ZERO, move |ups| {
+            ups.describe_shutdown(delay, restart)
+        })
+        .map(|(description, ..)| description)
+        .unwrap_or_else(|e| format!("shut down, though {}", e.report()))
+    }
+
+    pub fn start_test(&self, kind: TestKind) -> Result<(), UPSError> {
+        self.call(time::Duration::ZERO, move |ups| ups.start_test(kind))?
+            .0
+    }
+
+    pub fn cancel_shutdown(&mut self) -> Result<(), UPSError> {
+        self.run(time::Duration::ZERO, |ups| ups.cancel_shutdown())
+    }
+
+    pub fn toggle_beep(&mut self) -> Result<(), UPSError> {
+        self.run(time::Duration::ZERO, |ups| ups.toggle_beep())
+    }
+
+    pub fn set_beep(&mut self, enabled: bool) -> Result<(), UPSError> {
+        self.run(time::Duration::ZERO, move |ups| ups.set_beep(enabled))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::UpsProtocol;
+
+    struct Stalling {
+        // Answers twice, then takes longer than we're prepared to wait.
+        reads: u32,
+    }
+
+    impl UpsProtocol for Stalling {
+        fn connect(&mut self) -> Result<(), UPSError> {
+            Ok(())
+        }
+
+        fn disconnect(&mut self) {}
+
+        fn read_info(&mut self, _status: &mut status::UPSStatus) -> Result<(), UPSError> {
+            Ok(())
+        }
+
+        fn read_ratings(&mut self, _status: &mut status::UPSStatus) -> Result<(), UPSError> {
+            Ok(())
+        }
+
+        fn read_status(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+            self.reads += 1;
+            if self.reads > 2 {
+                thread::sleep(time::Duration::from_millis(500));
+            }
+            status.remaining_capacity = self.reads as u8;
+            Ok(())
+        }
+
+        fn shutdown(&self, _delay: f32, _restart: i32) -> Result<(), UPSError> {
+            Ok(())
+        }
+
+        fn cancel_shutdown(&self) -> Result<(), UPSError> {
+            Ok(())
+        }
+
+        fn start_test(&self, _kind: TestKind) -> Result<(), UPSError> {
+            Ok(())
+        }
+
+        fn cancel_test(&self) -> Result<(), UPSError> {
+            Ok(())
+        }
+
+        fn toggle_beep(&self) -> Result<(), UPSError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_stalled_read_times_out_and_a_later_one_catches_up() {
+        let mut device = Device::spawn("test", time::Duration::ZERO, || {
+            UPS::new(Box::new(Stalling { reads: 0 }))
+        })
+        .unwrap();
+        // Opening read the status once already.
+        assert_eq!(device.status.remaining_capacity, 1);
+        device.timeout = time::Duration::from_millis(100);
+
+        device.get_ups_status().unwrap();
+        assert_eq!(device.status.remaining_capacity, 2);
+
+        // The copy we have stays as it was while the read is stuck.
+        assert!(matches!(
+            device.get_ups_status(),
+            Err(UPSError::Unresponsive { .. })
+        ));
+        assert_eq!(device.status.remaining_capacity, 2);
+
+        // The next read waits behind it, then gets through once we allow the time.
+        device.timeout = time::Duration::from_secs(5);
+        device.get_ups_status().unwrap();
+        assert_eq!(device.status.remaining_capacity, 4);
+    }
+
+    #[test]
+    fn failing_to_open_is_returned() {
+        assert!(matches!(
+            Device::spawn("test", time::Duration::ZERO, || Err(UPSError::NoDevice)),
+            Err(UPSError::NoDevice)
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::status::{UPSStatus, UPSTestResults};
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, io, time::SystemTime};
+use tracing::warn;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SelfTestRecord {
+    // A finished self-test, with when we saw it finish, unknown if it had already finished
+    // before we started keeping track.
+    pub at: Option<String>,
+    pub result: UPSTestResults,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct History {
+    // What we've seen of the battery over time, kept in the health state file.
+    pub last_self_test: Option<SelfTestRecord>,
+    pub replace_battery_since: Option<String>,
+    // The result at the last poll, so a change shows a test has finished.
+    #[serde(skip)]
+    seen_result: Option<UPSTestResults>,
+    // Whether anything worth saving has changed since it was last taken.
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl History {
+    pub fn observe(&mut self, status: &UPSStatus, now: SystemTime) {
+        // Note a self-test finishing, and the battery first asking to be replaced.
+        let finished = !matches!(
+            status.test_result,
+            UPSTestResults::NoTest | UPSTestResults::InProgress
+        );
+        let was = self.seen_result.replace(status.test_result);
+        if finished {
+            match was {
+                Some(was) if was != status.test_result => {
+                    self.record_test(Some(timestamp(now)), status.test_result)
+                }
+                // Already finished when we first looked, so we can't say when.
+                None if self.last_self_test.is_none() => self.record_test(None, status.test_result),
+                _ => {}
+            }
+        }
+
+        match (&self.replace_battery_since, status.replace_battery) {
+            (None, true) => {
+                self.replace_battery_since = Some(timestamp(now));
+                self.changed = true;
+            }
+            // Cleared, so the battery's been replaced.
+            (Some(_), false) => {
+                self.replace_battery_since = None;
+                self.changed = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn record_test(&mut self, at: Option<String>, result: UPSTestResults) {
+        self.last_self_test = Some(SelfTestRecord { at, result });
+        self.changed = true;
+    }
+
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+}
+
+fn timestamp(at: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(at).to_string()
+}
+
+fn read(path: &str) -> BTreeMap<String, History> {
+    // Every unit's history, starting afresh if the file is missing or unreadable.
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+            warn!(
+                "Ignoring unreadable health state in {}, starting afresh: {}",
+                path, e
+            );
+            BTreeMap::new()
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            warn!(
+                "Failed to read health state from {}, starting afresh: {}",
+                path, e
+            );
+            BTreeMap::new()
+        }
+    }
+}
+
+pub fn load(path: &str, unit: &str) -> History {
+    read(path).remove(unit).unwrap_or_default()
+}
+
+pub fn save(path: &str, unit: &str, history: &History) {
+    // Rewrite the whole file, keeping other units' entries, through a temporary file so a crash
+    // part way never leaves it truncated.
+    let mut histories = read(path);
+    histories.insert(unit.to_string(), history.clone());
+    let temporary = format!("{}.tmp", path);
+    let result = serde_json::to_string_pretty(&histories)
+        .map_err(io::Error::from)
+        .and_then(|text| fs::write(&temporary, text))
+        .and_then(|_| fs::rename(&temporary, path));
+    if let Err(e) = result {
+        warn!("Failed to save health state to {}: {}", path, e)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    // The battery's health now, alongside what we've seen of it over time.
+    pub remaining_capacity: u8,
+    pub battery_voltage: f32,
+    pub rated_battery_voltage: f32,
+    pub last_self_test: Option<SelfTestRecord>,
+    pub replace_battery_since: Option<String>,
+}
+
+impl HealthReport {
+    pub fn new(status: &UPSStatus, history: &History) -> HealthReport {
+        HealthReport {
+            remaining_capacity: status.remaining_capacity,
+            battery_voltage: status.battery_voltage,
+            rated_battery_voltage: status.rated_battery_voltage,
+            last_self_test: history.last_self_test.clone(),
+            replace_battery_since: history.replace_battery_since.clone(),
+        }
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // e.g. "Battery health: 100% at 27.3 V (114% of the rated 24.0 V)".
+        write!(
+            f,
+            "Battery health: {}% at {:.1} V",
+            self.remaining_capacity, self.battery_voltage
+        )?;
+        if self.rated_battery_voltage > 0.0 {
+            write!(
+                f,
+                " ({:.0}% of the rated {:.1} V)",
+                self.battery_voltage / self.rated_battery_voltage * 100.0,
+                self.rated_battery_voltage
+            )?;
+        }
+        match &self.last_self_test {
+            Some(SelfTestRecord {
+                at: Some(at),
+                result,
+            }) => write!(f, "\nLast self-test: {:?} at {}", result, at)?,
+            Some(SelfTestRecord { at: None, result }) => {
+                write!(f, "\nLast self-test: {:?}, when unknown", result)?
+            }
+            None => write!(f, "\nLast self-test: none seen")?,
+        }
+        match &self.replace_battery_since {
+            Some(since) => write!(f, "\nReplace battery: since {}", since),
+            None => write!(f, "\nReplace battery: no"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn notes_tests_finishing_and_the_battery_wearing_out() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut history = History::default();
+        let mut status = UPSStatus {
+            test_result: UPSTestResults::Passed,
+            ..Default::default()
+        };
+
+        // A result from before we started has no date, and isn't recorded again.
+        history.observe(&status, start);
+        assert_eq!(
+            history.last_self_test,
+            Some(SelfTestRecord {
+                at: None,
+                result: UPSTestResults::Passed
+            })
+        );
+        assert!(history.take_changed());
+        history.observe(&status, start);
+        assert!(!history.take_changed());
+
+        status.test_result = UPSTestResults::InProgress;
+        history.observe(&status, start);
+        status.test_result = UPSTestResults::Warning;
+        status.replace_battery = true;
+        history.observe(&status, start + Duration::from_secs(60));
+        assert!(history.take_changed());
+        assert_eq!(
+            history.last_self_test,
+            Some(SelfTestRecord {
+                at: Some("2023-11-14T22:14:20Z".to_string()),
+                result: UPSTestResults::Warning
+            })
+        );
+        assert_eq!(
+            history.replace_battery_since.as_deref(),
+            Some("2023-11-14T22:14:20Z")
+        );
+
+        // Still flagged later on, so the date it first tripped stands.
+        history.observe(&status, start + Duration::from_secs(120));
+        assert_eq!(
+            history.replace_battery_since.as_deref(),
+            Some("2023-11-14T22:14:20Z")
+        );
+    }
+
+    #[test]
+    fn missing_or_corrupt_state_starts_afresh() {
+        let path = std::env::temp_dir().join(format!("ups-health-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        assert_eq!(load(path, "ups"), History::default());
+
+        fs::write(path, "{ not json").unwrap();
+        assert_eq!(load(path, "ups"), History::default());
+
+        let history = History {
+            replace_battery_since: Some("2023-11-14T22:14:20Z".to_string()),
+            ..Default::default()
+        };
+        save(path, "ups", &history);
+        save(path, "other", &History::default());
+        assert_eq!(load(path, "ups"), history);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,708 @@
+use crate::protocol::{TestKind, UpsProtocol};
+use crate::status;
+use crate::transport::{DeviceSelector, HidConnector};
+use crate::ups::UPSError;
+
+use hidapi::HidDevice;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+};
+
+// Usages from the USB HID Power Device class, as `page << 16 | id`.
+// Collections we look fields up under.
+const POWER_SUMMARY: u32 = 0x0084_0024;
+const INPUT: u32 = 0x0084_001A;
+const OUTPUT: u32 = 0x0084_001C;
+const BATTERY: u32 = 0x0084_0012;
+// Measurements and controls.
+const VOLTAGE: u32 = 0x0084_0030;
+const CURRENT: u32 = 0x0084_0031;
+const FREQUENCY: u32 = 0x0084_0032;
+const PERCENT_LOAD: u32 = 0x0084_0035;
+const CONFIG_VOLTAGE: u32 = 0x0084_0040;
+const CONFIG_CURRENT: u32 = 0x0084_0041;
+const CONFIG_FREQUENCY: u32 = 0x0084_0042;
+const DELAY_BEFORE_STARTUP: u32 = 0x0084_0056;
+const DELAY_BEFORE_SHUTDOWN: u32 = 0x0084_0057;
+const TEST: u32 = 0x0084_0058;
+const AUDIBLE_ALARM_CONTROL: u32 = 0x0084_005A;
+// Status flags.
+const INTERNAL_FAILURE: u32 = 0x0084_0062;
+const OVERLOAD: u32 = 0x0084_0065;
+const SHUTDOWN_IMMINENT: u32 = 0x0084_0069;
+const BOOST: u32 = 0x0084_006E;
+const BUCK: u32 = 0x0084_006F;
+const BELOW_REMAINING_CAPACITY_LIMIT: u32 = 0x0085_0042;
+const CHARGING: u32 = 0x0085_0044;
+const DISCHARGING: u32 = 0x0085_0045;
+const NEED_REPLACEMENT: u32 = 0x0085_004B;
+const REMAINING_CAPACITY: u32 = 0x0085_0066;
+const RUN_TIME_TO_EMPTY: u32 = 0x0085_0068;
+const AC_PRESENT: u32 = 0x0085_00D0;
+
+// `Test` values to write.
+const TEST_QUICK: i64 = 1;
+const TEST_DEEP: i64 = 2;
+const TEST_ABORT: i64 = 3;
+
+// `AudibleAlarmControl` values.
+const ALARM_DISABLED: i64 = 1;
+const ALARM_ENABLED: i64 = 2;
+
+// Feature reports are small, but leave room for chatty units.
+const MAX_REPORT_LENGTH: usize = 64;
+
+// The Unit items for volts, and for watts or VA, in SI linear units (g, cm, s and A).
+const UNIT_VOLT: u32 = 0x00F0_D121;
+const UNIT_WATT: u32 = 0x0000_D121;
+// Fields wider than this won't fit the `i64` we read them into, so they're skipped.
+const MAX_FIELD_BITS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Field {
+    // Where one value lives within the feature reports.
+    report_id: u8,
+    offset: usize,
+    size: usize,
+    signed: bool,
+    // The power of ten to scale by, once the unit's own scale is taken out.
+    exponent: i32,
+}
+
+#[derive(Debug, Default)]
+struct Fields {
+    // Each field with the usages of the collections enclosing it, outermost first.
+    fields: Vec<(Vec<u32>, u32, Field)>,
+}
+
+impl Fields {
+    fn find(&self, collection: Option<u32>, usage: u32) -> Option<Field> {
+        self.fields
+            .iter()
+            .find(|(path, u, _)| *u == usage && collection.iter().all(|c| path.contains(c)))
+            .map(|(_, _, field)| *field)
+    }
+}
+
+struct Reports<'a, F: FnMut(u8) -> Result<Vec<u8>, UPSError>> {
+    // The feature reports fetched for one read, as several fields share a report.
+    fields: &'a Fields,
+    reports: HashMap<u8, Vec<u8>>,
+    fetch: F,
+}
+
+impl<'a, F: FnMut(u8) -> Result<Vec<u8>, UPSError>> Reports<'a, F> {
+    fn new(fields: &'a Fields, fetch: F) -> Reports<'a, F> {
+        Reports {
+            fields,
+            reports: HashMap::new(),
+            fetch,
+        }
+    }
+
+    fn read(&mut self, collection: Option<u32>, usage: u32) -> Result<Option<f64>, UPSError> {
+        // Units only implement a subset of the class, so a missing field is not an error.
+        let field = match self.fields.find(collection, usage) {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+
+        // Only fetch each report once per read.
+        if let Entry::Vacant(entry) = self.reports.entry(field.report_id) {
+            entry.insert((self.fetch)(field.report_id)?);
+        }
+
+        // The report data follows the report id.
+        let raw = extract(
+            self.reports[&field.report_id].get(1..).unwrap_or(&[]),
+            &field,
+        );
+        Ok(Some(raw as f64 * 10f64.powi(field.exponent)))
+    }
+
+    fn flag(&mut self, usage: u32) -> Result<Option<bool>, UPSError> {
+        Ok(self.read(None, usage)?.map(|v| v != 0.0))
+    }
+
+    fn is_set(&mut self, usage: u32) -> Result<bool, UPSError> {
+        // For flags we can treat as clear when the unit lacks them.
+        Ok(self.flag(usage)?.unwrap_or(false))
+    }
+}
+
+pub struct HidPdcProtocol {
+    // The standard USB HID Power Device class, read and written through feature reports.
+    connector: HidConnector,
+    device: Option<HidDevice>,
+    fields: Option<Fields>,
+}
+
+impl HidPdcProtocol {
+    pub fn new(connector: HidConnector) -> HidPdcProtocol {
+        HidPdcProtocol {
+            connector,
+            device: None,
+            fields: None,
+        }
+    }
+
+    fn device(&self) -> Result<&HidDevice, UPSError> {
+        self.device.as_ref().ok_or(UPSError::NoDevice)
+    }
+
+    fn field(&self, collection: Option<u32>, usage: u32) -> Option<Field> {
+        self.fields.as_ref().and_then(|f| f.find(collection, usage))
+    }
+
+    fn get_report(&self, report_id: u8) -> Result<Vec<u8>, UPSError> {
+        let mut buf = [0; MAX_REPORT_LENGTH];
+        buf[0] = report_id;
+        let length = self.device()?.get_feature_report(&mut buf)?;
+
+        Ok(buf[..length].to_vec())
+    }
+
+    fn write(&self, collection: Option<u32>, usage: u32, value: i64) -> Result<(), UPSError> {
+        let field = self
+            .field(collection, usage)
+            .ok_or(UPSError::Unsupported(usage))?;
+
+        // Update just our field, leaving whatever else shares the report as it was.
+        let mut report = self.get_report(field.report_id)?;
+        if let Some(data) = report.get_mut(1..) {
+            insert(data, &field, value);
+        }
+        self.device()?.send_feature_report(&report)?;
+
+        Ok(())
+    }
+
+    fn read_descriptor(&self) -> Result<Vec<u8>, UPSError> {
+        // hidapi can't give us the report descriptor, but the kernel exposes it for each hidraw
+        // node. Read it by vid:pid (and serial, if set) so it works with either hidapi backend.
+        let selector: &DeviceSelector = self.connector.selector();
+        let hid_id = format!(
+            "HID_ID=0003:{:08X}:{:08X}",
+            selector.vendor_id, selector.product_id
+        );
+
+        for entry in fs::read_dir("/sys/class/hidraw")?.flatten() {
+            let device = entry.path().join("device");
+            let uevent = match fs::read_to_string(device.join("uevent")) {
+                Ok(uevent) => uevent,
+                Err(_) => continue,
+            };
+
+            let serial_matches = match &selector.serial_number {
+                Some(serial) => uevent.lines().any(|l| l == format!("HID_UNIQ={}", serial)),
+                None => true,
+            };
+            if uevent.lines().any(|l| l == hid_id) && serial_matches {
+                return Ok(fs::read(device.join("report_descriptor"))?);
+            }
+        }
+
+        Err(UPSError::Descriptor(format!(
+            "no hidraw node for {:04x}:{:04x}",
+            selector.vendor_id, selector.product_id
+        )))
+    }
+}
+
+impl UpsProtocol for HidPdcProtocol {
+    fn connect(&mut self) -> Result<(), UPSError> {
+        // The descriptor doesn't change, so only parse it on the first connect.
+        // This also has to happen before the libusb backend detaches the kernel driver.
+        if self.fields.is_none() {
+            self.fields = Some(parse_descriptor(&self.read_descriptor()?));
+        }
+
+        // Without a remaining capacity this isn't a power device we can monitor.
+        if self.field(None, REMAINING_CAPACITY).is_none() {
+            return Err(UPSError::ProtocolMismatch);
+        }
+
+        self.device = None;
+        self.device = Some(self.connector.open_device()?);
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        // Dropping the device releases the USB interface.
+        self.device = None;
+    }
+
+    fn read_info(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        // The class has no standard firmware version, so we only get the USB strings.
+        let device = self.device()?;
+        status.manufacturer = device.get_manufacturer_string()?;
+        status.model = device.get_product_string()?;
+
+        Ok(())
+    }
+
+    fn read_ratings(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        let fields = self.fields.as_ref().ok_or(UPSError::NoDevice)?;
+        let mut reports = Reports::new(fields, |id| self.get_report(id));
+
+        if let Some(v) = reports.read(Some(OUTPUT), CONFIG_VOLTAGE)? {
+            status.rated_output_voltage = v as f32;
+        }
+        if let Some(v) = reports.read(Some(OUTPUT), CONFIG_CURRENT)? {
+            status.rated_output_current = v as i32;
+        }
+        if let Some(v) = reports.read(Some(OUTPUT), CONFIG_FREQUENCY)? {
+            status.rated_output_frequency = v as f32;
+        }
+        if let Some(v) = reports.read(Some(BATTERY), CONFIG_VOLTAGE)? {
+            status.rated_battery_voltage = v as f32;
+        }
+
+        Ok(())
+    }
+
+    fn read_status(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        let fields = self.fields.as_ref().ok_or(UPSError::NoDevice)?;
+        read_status(&mut Reports::new(fields, |id| self.get_report(id)), status)
+    }
+
+    fn shutdown(&self, delay: f32, restart: i32) -> Result<(), UPSError> {
+        // The class counts in seconds, and a restart needs setting before the shutdown.
+        if restart > 0 {
+            self.write(None, DELAY_BEFORE_STARTUP, restart as i64 * 60)?;
+        }
+        self.write(None, DELAY_BEFORE_SHUTDOWN, (delay * 60.0) as i64)
+    }
+
+    fn describe_shutdown(&self, delay: f32, restart: i32) -> String {
+        let shutdown = format!("DelayBeforeShutdown={}s", (delay * 60.0) as i64);
+        if restart > 0 {
+            format!(
+                "set DelayBeforeStartup={}s, {}",
+                restart as i64 * 60,
+                shutdown
+            )
+        } else {
+            format!("set {}", shutdown)
+        }
+    }
+
+    fn cancel_shutdown(&self) -> Result<(), UPSError> {
+        // A negative delay cancels the shutdown.
+        self.write(None, DELAY_BEFORE_SHUTDOWN, -1)
+    }
+
+    fn start_test(&self, kind: TestKind) -> Result<(), UPSError> {
+        // The class only has a quick test and a deep test, which runs until the battery is low.
+        match kind {
+            TestKind::Quick => self.write(None, TEST, TEST_QUICK),
+            TestKind::UntilLow => self.write(None, TEST, TEST_DEEP),
+            TestKind::Minutes(_) => Err(UPSError::Unsupported(TEST)),
+        }
+    }
+
+    fn cancel_test(&self) -> Result<(), UPSError> {
+        self.write(None, TEST, TEST_ABORT)
+    }
+
+    fn toggle_beep(&self) -> Result<(), UPSError> {
+        let field = self
+            .field(None, AUDIBLE_ALARM_CONTROL)
+            .ok_or(UPSError::Unsupported(AUDIBLE_ALARM_CONTROL))?;
+        let report = self.get_report(field.report_id)?;
+        let value = if extract(report.get(1..).unwrap_or(&[]), &field) == ALARM_ENABLED {
+            ALARM_DISABLED
+        } else {
+            ALARM_ENABLED
+        };
+
+        self.write(None, AUDIBLE_ALARM_CONTROL, value)
+    }
+}
+
+fn read_status<F: FnMut(u8) -> Result<Vec<u8>, UPSError>>(
+    reports: &mut Reports<F>,
+    status: &mut status::UPSStatus,
+) -> Result<(), UPSError> {
+    if let Some(v) = reports.read(Some(INPUT), VOLTAGE)? {
+        status.input_voltage = v as f32;
+    }
+    if let Some(v) = reports.read(Some(INPUT), FREQUENCY)? {
+        status.input_frequency = v as f32;
+    }
+    if let Some(v) = reports.read(Some(OUTPUT), VOLTAGE)? {
+        status.output_voltage = v as f32;
+    }
+    if let Some(v) = reports.read(Some(OUTPUT), CURRENT)? {
+        status.output_current = v as f32;
+    }
+    if let Some(v) = reports.read(Some(OUTPUT), FREQUENCY)? {
+        status.output_frequency = v as f32;
+    }
+    if let Some(v) = reports.read(None, PERCENT_LOAD)? {
+        status.output_load = v as u8;
+    }
+    if let Some(v) = reports.read(Some(BATTERY), VOLTAGE)? {
+        status.battery_voltage = v as f32;
+    }
+    if let Some(v) = reports.read(Some(POWER_SUMMARY), REMAINING_CAPACITY)? {
+        status.remaining_capacity = v as u8;
+    }
+    if let Some(v) = reports.read(Some(POWER_SUMMARY), RUN_TIME_TO_EMPTY)? {
+        status.seconds_to_empty = v as i32;
+    }
+
+    // Flags are single bits. Without `ACPresent` we go by `Discharging`, and failing that assume
+    // we're on line power rather than reporting an outage that never ends.
+    status.utility_failed = match reports.flag(AC_PRESENT)? {
+        Some(present) => !present,
+        None => reports.is_set(DISCHARGING)?,
+    };
+    status.battery_low = reports.is_set(BELOW_REMAINING_CAPACITY_LIMIT)?;
+    status.charging = reports.is_set(CHARGING)?;
+    status.bypass_active = reports.is_set(BOOST)? || reports.is_set(BUCK)?;
+    status.ups_failed = reports.is_set(INTERNAL_FAILURE)?;
+    status.overloaded = reports.is_set(OVERLOAD)?;
+    status.replace_battery = reports.is_set(NEED_REPLACEMENT)?;
+    status.shutdown_active = reports.is_set(SHUTDOWN_IMMINENT)?;
+
+    // A pending shutdown also shows as a non-negative delay, for units without the flag.
+    if let Some(delay) = reports.read(None, DELAY_BEFORE_SHUTDOWN)? {
+        status.shutdown_active |= delay >= 0.0;
+    }
+
+    // The test result uses the same codes as the Megatec protocol.
+    status.test_result = match reports.read(None, TEST)?.map(|v| v as i64) {
+        Some(1) => status::UPSTestResults::Passed,
+        Some(2) => status::UPSTestResults::Warning,
+        Some(3) => status::UPSTestResults::Error,
+        Some(4) => status::UPSTestResults::Aborted,
+        Some(5) => status::UPSTestResults::InProgress,
+        _ => status::UPSTestResults::NoTest,
+    };
+    status.test_in_progress = status.test_result == status::UPSTestResults::InProgress;
+
+    if let Some(v) = reports.read(None, AUDIBLE_ALARM_CONTROL)? {
+        status.beeper_on = v as i64 == ALARM_ENABLED;
+    }
+
+    // There's no mode as such, so derive one from the flags.
+    status.fault = status.ups_failed;
+    status.ups_mode = if status.fault {
+        status::UPSModes::Fault
+    } else if status.test_in_progress {
+        status::UPSModes::SelfTest
+    } else if status.utility_failed {
+        status::UPSModes::Inverting
+    } else {
+        status::UPSModes::Line
+    };
+
+    Ok(())
+}
+
+fn extract(data: &[u8], field: &Field) -> i64 {
+    // Fields are packed little-endian at a bit offset, and short reports read as zero.
+    let mut raw: i64 = 0;
+    for bit in 0..field.size {
+        let position = field.offset + bit;
+        let byte = data.get(position / 8).copied().unwrap_or(0);
+        raw |= (((byte >> (position % 8)) & 1) as i64) << bit;
+    }
+
+    if field.signed && field.size > 0 && field.size < 64 && raw & (1 << (field.size - 1)) != 0 {
+        raw -= 1 << field.size;
+    }
+
+    raw
+}
+
+fn insert(data: &mut [u8], field: &Field, value: i64) {
+    for bit in 0..field.size {
+        let position = field.offset + bit;
+        if let Some(byte) = data.get_mut(position / 8) {
+            let mask = 1 << (position % 8);
+            if (value >> bit) & 1 == 1 {
+                *byte |= mask;
+            } else {
+                *byte &= !mask;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Globals {
+    // The global item state, which `Push`/`Pop` save and restore.
+    usage_page: u32,
+    logical_minimum: i64,
+    unit: u32,
+    unit_exponent: i32,
+    report_size: usize,
+    report_id: u8,
+    report_count: usize,
+}
+
+fn base_exponent(unit: u32) -> i32 {
+    // Units are in grams and centimetres, so volts, watts and VA come out 10^7 too big. The rest
+    // we read (percent, seconds, Hz, amps) need no scaling, as in NUT's `exponent()`.
+    match unit {
+        UNIT_VOLT | UNIT_WATT => 7,
+        _ => 0,
+    }
+}
+
+fn parse_descriptor(descriptor: &[u8]) -> Fields {
+    // Walk the report descriptor items, laying out every feature report field.
+    // Input and output reports are ignored, as all the class state is also in feature reports.
+    let mut fields = Fields::default();
+    let mut globals = Globals::default();
+    let mut stack: Vec<Globals> = Vec::new();
+    let mut usages: Vec<u32> = Vec::new();
+    let mut usage_minimum: Option<u32> = None;
+    let mut path: Vec<u32> = Vec::new();
+    let mut offsets: HashMap<u8, usize> = HashMap::new();
+
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+
+        // Long items are reserved and unused, so just skip over them.
+        if prefix == 0xFE {
+            let size = descriptor.get(i + 1).copied().unwrap_or(0) as usize;
+            i += 3 + size;
+            continue;
+        }
+
+        let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+        let data = match descriptor.get(i + 1..i + 1 + size) {
+            Some(data) => data,
+            None => break,
+        };
+        i += 1 + size;
+
+        let value = data
+            .iter()
+            .rev()
+            .fold(0u32, |acc, &b| (acc << 8) | b as u32);
+        let signed = match size {
+            1 => value as u8 as i8 as i64,
+            2 => value as u16 as i16 as i64,
+            4 => value as i32 as i64,
+            _ => 0,
+        };
+        let usage = if size == 4 {
+            value
+        } else {
+            (globals.usage_page << 16) | value
+        };
+
+        match ((prefix >> 2) & 0x03, prefix >> 4) {
+            // Main items.
+            (0, 0x0B) => {
+                // Feature, with a run of `report_count` fields sharing the last usage if short.
+                let offset = offsets.entry(globals.report_id).or_insert(0);
+                let skipped = value & 0x01 != 0 || globals.report_size > MAX_FIELD_BITS;
+                for n in 0..globals.report_count {
+                    if let (false, Some(&u)) = (skipped, usages.get(n).or(usages.last())) {
+                        fields.fields.push((
+                            path.clone(),
+                            u,
+                            Field {
+                                report_id: globals.report_id,
+                                offset: *offset,
+                                size: globals.report_size,
+                                signed: globals.logical_minimum < 0,
+                                exponent: globals.unit_exponent - base_exponent(globals.unit),
+                            },
+                        ));
+                    }
+                    *offset += globals.report_size;
+                }
+                usages.clear();
+            }
+            (0, 0x0A) => {
+                path.push(usages.first().copied().unwrap_or(0));
+                usages.clear();
+            }
+            (0, 0x0C) => {
+                path.pop();
+            }
+            (0, _) => usages.clear(),
+            // Global items.
+            (1, 0x00) => globals.usage_page = value,
+            (1, 0x01) => globals.logical_minimum = signed,
+            (1, 0x06) => globals.unit = value,
+            (1, 0x05) => {
+                // A 4-bit signed exponent.
+                globals.unit_exponent = if value & 0x08 != 0 {
+                    (value & 0x0F) as i32 - 16
+                } else {
+                    (value & 0x0F) as i32
+                }
+            }
+            (1, 0x07) => globals.report_size = value as usize,
+            (1, 0x08) => globals.report_id = value as u8,
+            (1, 0x09) => globals.report_count = value as usize,
+            (1, 0x0A) => stack.push(globals),
+            (1, 0x0B) => globals = stack.pop().unwrap_or_default(),
+            // Local items.
+            (2, 0x00) => usages.push(usage),
+            (2, 0x01) => usage_minimum = Some(usage),
+            (2, 0x02) => {
+                if let Some(minimum) = usage_minimum.take() {
+                    usages.extend(minimum..=usage);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An APC-style UPS with its power summary in report 1, input voltage in report 2 and the
+    // shutdown delay in report 3. It has no `ACPresent`, only `Charging`/`Discharging`, declared
+    // with a usage range under `Push`. Volts come with their Unit and its exponent of 7, as APC
+    // and CyberPower declare them.
+    const DESCRIPTOR: &[u8] = &[
+        0x05, 0x84, 0x09, 0x04, 0xA1, 0x01, // Power Device, UPS, Collection (Application)
+        0x09, 0x24, 0xA1, 0x00, // PowerSummary, Collection (Physical)
+        0x85, 0x01, 0x05, 0x85, 0x15, 0x00, 0x25, 0x64, // Report 1, Battery System, 0..100
+        0x75, 0x08, 0x95, 0x01, 0x09, 0x66, 0xB1, 0x02, // 8 bits, RemainingCapacity
+        0xA4, 0x75, 0x01, 0x25, 0x01, 0x95, 0x02, // Push, 2 x 1 bit, 0..1
+        0x19, 0x44, 0x29, 0x45, 0xB1, 0x02, // Charging..Discharging
+        0x95, 0x06, 0xB1, 0x01, 0xB4, // 6 bits padding, Pop
+        0xC0, // End Collection
+        0x05, 0x84, 0x09, 0x1A, 0xA1, 0x00, // Power Device, Input, Collection (Physical)
+        0x85, 0x02, 0x67, 0x21, 0xD1, 0xF0, 0x00, 0x55, 0x07, // Report 2, Unit (Volt), 10^7
+        0x15, 0x00, 0x27, 0xFF, 0xFF, 0x00, 0x00, // 0..65535
+        0x75, 0x10, 0x95, 0x01, 0x09, 0x30, 0xB1, 0x02, // 16 bits, Voltage
+        0xC0, // End Collection
+        0x85, 0x03, 0x66, 0x01, 0x10, 0x55, 0x00, // Report 3, Unit (Seconds), 10^0
+        0x15, 0xFF, 0x26, 0xFF, 0x7F, // -1..32767
+        0x75, 0x10, 0x95, 0x01, 0x09, 0x57, 0xB1, 0x02, // 16 bits, DelayBeforeShutdown
+        0xC0, // End Collection
+    ];
+
+    fn field(report_id: u8, offset: usize, size: usize, signed: bool, exponent: i32) -> Field {
+        Field {
+            report_id,
+            offset,
+            size,
+            signed,
+            exponent,
+        }
+    }
+
+    fn read(reports: &[Vec<u8>]) -> status::UPSStatus {
+        let fields = parse_descriptor(DESCRIPTOR);
+        let mut status = status::UPSStatus::default();
+        let mut reports = Reports::new(&fields, |id| {
+            reports
+                .iter()
+                .find(|r| r[0] == id)
+                .cloned()
+                .ok_or(UPSError::NoDevice)
+        });
+        read_status(&mut reports, &mut status).unwrap();
+        status
+    }
+
+    #[test]
+    fn parses_the_feature_layout() {
+        let fields = parse_descriptor(DESCRIPTOR);
+
+        assert_eq!(
+            fields.find(Some(POWER_SUMMARY), REMAINING_CAPACITY),
+            Some(field(1, 0, 8, false, 0))
+        );
+        // The range gives a usage per bit, and `Pop` puts the 8 bit size back for padding's sake.
+        assert_eq!(fields.find(None, CHARGING), Some(field(1, 8, 1, false, 0)));
+        assert_eq!(
+            fields.find(None, DISCHARGING),
+            Some(field(1, 9, 1, false, 0))
+        );
+        // Each report counts its offsets from zero, and the volt's own 10^7 is taken out.
+        assert_eq!(
+            fields.find(Some(INPUT), VOLTAGE),
+            Some(field(2, 0, 16, false, 0))
+        );
+        // A negative minimum makes it signed, and seconds need no scaling.
+        assert_eq!(
+            fields.find(None, DELAY_BEFORE_SHUTDOWN),
+            Some(field(3, 0, 16, true, 0))
+        );
+        assert_eq!(fields.find(Some(OUTPUT), VOLTAGE), None);
+        assert_eq!(fields.find(None, AC_PRESENT), None);
+        // The padding is constant, so it isn't a field.
+        assert_eq!(fields.fields.len(), 5);
+    }
+
+    #[test]
+    fn skips_fields_too_wide_to_read() {
+        // A 128 bit voltage, then an 8 bit current after it in the same report.
+        let fields = parse_descriptor(&[
+            0x05, 0x84, 0x85, 0x03, 0x95, 0x01, // Power Device, Report 3, 1 field
+            0x75, 0x80, 0x09, 0x30, 0xB1, 0x02, // 128 bits, Voltage
+            0x75, 0x08, 0x09, 0x31, 0xB1, 0x02, // 8 bits, Current
+        ]);
+
+        assert_eq!(fields.find(None, VOLTAGE), None);
+        assert_eq!(fields.find(None, CURRENT), Some(field(3, 128, 8, false, 0)));
+    }
+
+    #[test]
+    fn insert_and_extract_round_trip() {
+        let table = [
+            (field(0, 3, 10, true, 0), -5),
+            (field(0, 3, 10, true, 0), 511),
+            (field(0, 3, 10, false, 0), 1000),
+            (field(0, 8, 16, true, 0), -32768),
+            (field(0, 9, 1, false, 0), 1),
+        ];
+        for (field, value) in table {
+            let mut data = [0xA5; 4];
+            insert(&mut data, &field, value);
+            assert_eq!(extract(&data, &field), value, "{:?}", field);
+
+            // Everything outside the field is left alone.
+            let mut other = data;
+            insert(&mut other, &field, 0);
+            for bit in (0..32).filter(|b| !(field.offset..field.offset + field.size).contains(b)) {
+                assert_eq!(
+                    (other[bit / 8] >> (bit % 8)) & 1,
+                    (0xA5 >> (bit % 8)) & 1,
+                    "bit {} of {:?}",
+                    bit,
+                    field
+                );
+            }
+        }
+
+        // Short reports read as zero past their end.
+        assert_eq!(extract(&[0xFF], &field(0, 4, 8, false, 0)), 0x0F);
+    }
+
+    #[test]
+    fn reads_status_without_ac_present() {
+        let status = read(&[vec![1, 40, 0b10], vec![2, 0xE6, 0x00], vec![3, 0xFF, 0xFF]]);
+        assert_eq!(status.remaining_capacity, 40);
+        assert!((status.input_voltage - 230.0).abs() < 0.01);
+        assert!(!status.shutdown_active);
+        assert!(!status.charging);
+        assert!(status.utility_failed);
+        assert_eq!(status.ups_mode, status::UPSModes::Inverting);
+
+        let status = read(&[vec![1, 90, 0b01], vec![2, 0xE6, 0x00], vec![3, 0xFF, 0xFF]]);
+        assert!(status.charging);
+        assert!(!status.utility_failed);
+        assert_eq!(status.ups_mode, status::UPSModes::Line);
+    }
+}
@@ -0,0 +1,462 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "history")]
+use crate::{batch, status};
+#[cfg(feature = "history")]
+use rusqlite::{params, Connection, OpenFlags, Transaction};
+#[cfg(feature = "history")]
+use std::{fmt, fs, path::Path, time};
+#[cfg(feature = "history")]
+use tracing::{info, warn};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HistorySettings {
+    // Outline for the optional `[history]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // - `path` of the SQLite database to record each poll in, unset to not record (requires the
+    //   `history` feature)
+    // - `retention_days` to keep polls for, pruning older ones. 0 means forever
+    pub path: Option<String>,
+    pub retention_days: u32,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        HistorySettings {
+            path: None,
+            retention_days: 30,
+        }
+    }
+}
+
+// Seconds between writes, with the polls in between batched into one transaction.
+#[cfg(feature = "history")]
+const FLUSH_SECONDS: u64 = 10;
+
+// Seconds between pruning polls older than the retention.
+#[cfg(feature = "history")]
+const PRUNE_SECONDS: u64 = 3600;
+
+// Most polls to hold while the database can't be written, dropping the oldest beyond it.
+#[cfg(feature = "history")]
+const MAX_BUFFERED_POLLS: usize = 10000;
+
+// Each step of the schema in turn, with the database's `user_version` counting those applied.
+// Only ever add to the end, e.g. a column for a new field, filled from the `status` JSON.
+#[cfg(feature = "history")]
+const MIGRATIONS: &[&str] = &["CREATE TABLE polls (
+        time INTEGER NOT NULL,
+        utility_failed INTEGER NOT NULL,
+        input_voltage REAL NOT NULL,
+        output_load INTEGER NOT NULL,
+        remaining_capacity INTEGER NOT NULL,
+        battery_voltage REAL NOT NULL,
+        seconds_to_empty INTEGER NOT NULL,
+        status TEXT NOT NULL
+    );
+    CREATE INDEX polls_time ON polls (time);"];
+
+#[cfg(feature = "history")]
+fn open(path: &str) -> rusqlite::Result<Connection> {
+    // Open for writing, bringing the schema up to date. WAL lets `ups history` read alongside.
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            let _ = fs::create_dir_all(parent);
+        }
+    }
+    let mut connection = Connection::open(path)?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "NORMAL")?;
+    migrate(&mut connection)?;
+    Ok(connection)
+}
+
+#[cfg(feature = "history")]
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(rusqlite::Error::InvalidParameterName(format!(
+            "schema version {} is newer than this build knows ({})",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+
+    // All or nothing, so a failed step is simply tried again next time.
+    let transaction = connection.transaction()?;
+    for migration in &MIGRATIONS[version..] {
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    transaction.commit()
+}
+
+#[cfg(feature = "history")]
+fn seconds(at: time::SystemTime) -> i64 {
+    at.duration_since(time::SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[cfg(feature = "history")]
+fn timestamp(seconds: i64) -> String {
+    let at = time::SystemTime::UNIX_EPOCH + time::Duration::from_secs(seconds.max(0) as u64);
+    humantime::format_rfc3339_seconds(at).to_string()
+}
+
+#[cfg(feature = "history")]
+pub struct Writer {
+    // Batches each poll and writes them from a thread of its own, so the poll loop never waits
+    // on the disk.
+    writer: batch::Writer<(i64, status::UPSStatus)>,
+}
+
+#[cfg(feature = "history")]
+impl Writer {
+    pub fn new(settings: &HistorySettings) -> Option<Writer> {
+        let path = settings.path.as_ref()?;
+        // The history matters less than monitoring, so carry on without it.
+        let connection = match open(path) {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to open the history database {}: {}", path, e);
+                return None;
+            }
+        };
+        info!("Recording UPS status history in {}", path);
+
+        let database = Database {
+            connection,
+            path: path.clone(),
+            retention: settings.retention_days as i64 * 24 * 60 * 60,
+            next_prune: time::Instant::now(),
+            failing: false,
+        };
+        Some(Writer {
+            writer: batch::Writer::spawn(
+                "the history database",
+                database,
+                time::Duration::from_secs(FLUSH_SECONDS),
+                MAX_BUFFERED_POLLS,
+            ),
+        })
+    }
+
+    pub fn record(&self, status: &status::UPSStatus) {
+        let at = seconds(time::SystemTime::now());
+        self.writer.send((at, status.clone()));
+    }
+
+    pub fn close(&self) {
+        self.writer.close();
+    }
+}
+
+#[cfg(feature = "history")]
+struct Database {
+    // Where the polls go, pruning those past the retention every so often.
+    connection: Connection,
+    path: String,
+    retention: i64,
+    next_prune: time::Instant,
+    failing: bool,
+}
+
+#[cfg(feature = "history")]
+impl batch::Sink for Database {
+    type Item = (i64, status::UPSStatus);
+
+    fn write(&mut self, polls: &[(i64, status::UPSStatus)]) -> bool {
+        // Report a failure once until it recovers, keeping the polls to try again.
+        match self.insert(polls) {
+            Ok(()) => {
+                if self.failing {
+                    self.failing = false;
+                    info!("Recording UPS status history in {} again.", self.path)
+                }
+                true
+            }
+            Err(e) => {
+                if !self.failing {
+                    self.failing = true;
+                    warn!("Failed to write the history to {}: {}", self.path, e)
+                }
+                false
+            }
+        }
+    }
+
+    fn upkeep(&mut self) {
+        if self.retention == 0 || time::Instant::now() < self.next_prune {
+            return;
+        }
+        self.next_prune = time::Instant::now() + time::Duration::from_secs(PRUNE_SECONDS);
+        let cutoff = seconds(time::SystemTime::now()) - self.retention;
+        match self
+            .connection
+            .execute("DELETE FROM polls WHERE time < ?1", params![cutoff])
+        {
+            Ok(0) => {}
+            Ok(pruned) => info!("Pruned {} polls from the history.", pruned),
+            Err(e) => warn!("Failed to prune the history in {}: {}", self.path, e),
+        }
+    }
+}
+
+#[cfg(feature = "history")]
+impl Database {
+    fn insert(&mut self, polls: &[(i64, status::UPSStatus)]) -> rusqlite::Result<()> {
+        let transaction = self.connection.transaction()?;
+        insert_polls(&transaction, polls)?;
+        transaction.commit()
+    }
+}
+
+#[cfg(feature = "history")]
+fn insert_polls(
+    transaction: &Transaction,
+    polls: &[(i64, status::UPSStatus)],
+) -> rusqlite::Result<()> {
+    let mut statement = transaction.prepare_cached(
+        "INSERT INTO polls (time, utility_failed, input_voltage, output_load, remaining_capacity,
+            battery_voltage, seconds_to_empty, status) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    for (at, status) in polls {
+        statement.execute(params![
+            at,
+            status.utility_failed,
+            status.input_voltage,
+            status.output_load,
+            status.remaining_capacity,
+            status.battery_voltage,
+            status.seconds_to_empty,
+            serde_json::to_string(status).unwrap_or_default(),
+        ])?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "history")]
+struct Poll {
+    // The parts of a recorded poll the summary looks at.
+    time: i64,
+    utility_failed: bool,
+    input_voltage: f32,
+    remaining_capacity: u8,
+}
+
+#[cfg(feature = "history")]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Outage {
+    // A run of polls on battery, lasting until the next on line power, or still going.
+    pub start: String,
+    pub seconds: u64,
+    pub ongoing: bool,
+}
+
+#[cfg(feature = "history")]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Summary {
+    // What the recorded polls since `since` say about the power, with the input voltage range
+    // only taken on line power, and the lowest capacity only on battery.
+    pub since: String,
+    pub polls: usize,
+    pub min_input_voltage: Option<f32>,
+    pub max_input_voltage: Option<f32>,
+    pub outages: Vec<Outage>,
+    pub deepest_discharge: Option<u8>,
+    pub deepest_discharge_at: Option<String>,
+}
+
+#[cfg(feature = "history")]
+pub fn summary(path: &str, since: time::SystemTime) -> rusqlite::Result<Summary> {
+    // Read-only, so this never gets in the way of the monitor writing.
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let since = seconds(since);
+    let mut statement = connection.prepare(
+        "SELECT time, utility_failed, input_voltage, remaining_capacity FROM polls
+            WHERE time >= ?1 ORDER BY time",
+    )?;
+    let polls = statement
+        .query_map(params![since], |row| {
+            Ok(Poll {
+                time: row.get(0)?,
+                utility_failed: row.get(1)?,
+                input_voltage: row.get(2)?,
+                remaining_capacity: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(summarise(since, &polls))
+}
+
+#[cfg(feature = "history")]
+fn summarise(since: i64, polls: &[Poll]) -> Summary {
+    let mut summary = Summary {
+        since: timestamp(since),
+        polls: polls.len(),
+        min_input_voltage: None,
+        max_input_voltage: None,
+        outages: Vec::new(),
+        deepest_discharge: None,
+        deepest_discharge_at: None,
+    };
+
+    let mut outage_start = None;
+    for poll in polls {
+        if poll.utility_failed {
+            outage_start.get_or_insert(poll.time);
+            if summary
+                .deepest_discharge
+                .is_none_or(|deepest| poll.remaining_capacity < deepest)
+            {
+                summary.deepest_discharge = Some(poll.remaining_capacity);
+                summary.deepest_discharge_at = Some(timestamp(poll.time));
+            }
+            continue;
+        }
+
+        if let Some(start) = outage_start.take() {
+            summary.outages.push(Outage {
+                start: timestamp(start),
+                seconds: (poll.time - start).max(0) as u64,
+                ongoing: false,
+            });
+        }
+        let voltage = poll.input_voltage;
+        summary.min_input_voltage = Some(
+            summary
+                .min_input_voltage
+                .map_or(voltage, |v| v.min(voltage)),
+        );
+        summary.max_input_voltage = Some(
+            summary
+                .max_input_voltage
+                .map_or(voltage, |v| v.max(voltage)),
+        );
+    }
+
+    // Still on battery as of the last poll.
+    if let (Some(start), Some(last)) = (outage_start, polls.last()) {
+        summary.outages.push(Outage {
+            start: timestamp(start),
+            seconds: (last.time - start).max(0) as u64,
+            ongoing: true,
+        });
+    }
+    summary
+}
+
+#[cfg(feature = "history")]
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // e.g. "Input voltage: 221.0 V to 241.5 V on line power".
+        write!(f, "History since {}: {} polls", self.since, self.polls)?;
+        match (self.min_input_voltage, self.max_input_voltage) {
+            (Some(min), Some(max)) => write!(
+                f,
+                "\nInput voltage: {:.1} V to {:.1} V on line power",
+                min, max
+            )?,
+            _ => write!(f, "\nInput voltage: no polls on line power")?,
+        }
+        write!(f, "\nOutages: {}", self.outages.len())?;
+        for outage in &self.outages {
+            let duration = humantime::format_duration(time::Duration::from_secs(outage.seconds));
+            write!(f, "\n  {} for {}", outage.start, duration)?;
+            if outage.ongoing {
+                write!(f, ", ongoing")?;
+            }
+        }
+        match (self.deepest_discharge, &self.deepest_discharge_at) {
+            (Some(capacity), Some(at)) => write!(f, "\nDeepest discharge: {}% at {}", capacity, at),
+            _ => write!(f, "\nDeepest discharge: none"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "history"))]
+mod tests {
+    use super::*;
+
+    fn poll(time: i64, utility_failed: bool, input_voltage: f32, remaining_capacity: u8) -> Poll {
+        Poll {
+            time,
+            utility_failed,
+            input_voltage,
+            remaining_capacity,
+        }
+    }
+
+    #[test]
+    fn summarises_outages_and_the_deepest_discharge() {
+        let start = 1_700_000_000;
+        let polls = [
+            poll(start, false, 230.0, 100),
+            poll(start + 10, false, 221.5, 100),
+            poll(start + 20, true, 0.0, 98),
+            poll(start + 30, true, 0.0, 91),
+            poll(start + 200, false, 241.0, 92),
+            poll(start + 300, true, 0.0, 97),
+        ];
+        let summary = summarise(start, &polls);
+
+        assert_eq!(summary.polls, 6);
+        assert_eq!(summary.min_input_voltage, Some(221.5));
+        assert_eq!(summary.max_input_voltage, Some(241.0));
+        assert_eq!(
+            summary.outages,
+            vec![
+                Outage {
+                    start: "2023-11-14T22:13:40Z".to_string(),
+                    seconds: 180,
+                    ongoing: false,
+                },
+                Outage {
+                    start: "2023-11-14T22:18:20Z".to_string(),
+                    seconds: 0,
+                    ongoing: true,
+                },
+            ]
+        );
+        assert_eq!(summary.deepest_discharge, Some(91));
+        assert!(summary.to_string().contains("2023-11-14T22:13:40Z for 3m"));
+    }
+
+    #[test]
+    fn records_polls_and_reads_them_back() {
+        let path = std::env::temp_dir().join(format!("ups-history-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+
+        let settings = HistorySettings {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        let writer = Writer::new(&settings).unwrap();
+        let mut status = status::UPSStatus {
+            input_voltage: 230.0,
+            remaining_capacity: 100,
+            ..Default::default()
+        };
+        writer.record(&status);
+        status.utility_failed = true;
+        status.remaining_capacity = 80;
+        writer.record(&status);
+        writer.close();
+
+        let since = time::SystemTime::now() - time::Duration::from_secs(60);
+        let summary = summary(&path, since).unwrap();
+        assert_eq!(summary.polls, 2);
+        assert_eq!(summary.outages.len(), 1);
+        assert_eq!(summary.deepest_discharge, Some(80));
+
+        // Opening again finds the schema already up to date.
+        drop(open(&path).unwrap());
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::status;
+
+use serde_json::Value;
+use std::{
+    process::{Command, Stdio},
+    thread, time,
+};
+use tracing::{error, info, warn};
+
+// How often to check whether a hook has finished.
+const HOOK_CHECK_INTERVAL_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hook {
+    // The utility has failed, and we're on battery.
+    UtilityFailed,
+    // The utility is back.
+    UtilityRestored,
+    // The battery has run low.
+    LowBattery,
+    // We're about to shut down (or take the power action), before anything else happens.
+    Shutdown,
+    // We've lost communication with the UPS.
+    CommLost,
+}
+
+impl Hook {
+    pub const ALL: [Hook; 5] = [
+        Hook::UtilityFailed,
+        Hook::UtilityRestored,
+        Hook::LowBattery,
+        Hook::Shutdown,
+        Hook::CommLost,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        // As given to the hook in `UPS_EVENT`.
+        match self {
+            Hook::UtilityFailed => "utility_failed",
+            Hook::UtilityRestored => "utility_restored",
+            Hook::LowBattery => "low_battery",
+            Hook::Shutdown => "shutdown",
+            Hook::CommLost => "comm_lost",
+        }
+    }
+
+    pub fn setting(&self) -> &'static str {
+        // As set in the `[hooks]` section.
+        match self {
+            Hook::UtilityFailed => "on_utility_failed",
+            Hook::UtilityRestored => "on_utility_restored",
+            Hook::LowBattery => "on_low_battery",
+            Hook::Shutdown => "pre_shutdown",
+            Hook::CommLost => "on_comm_lost",
+        }
+    }
+}
+
+fn status_env(status: &status::UPSStatus) -> Vec<(String, String)> {
+    // Each status field as `UPS_<FIELD>`, e.g. `UPS_REMAINING_CAPACITY=85`, with unknowns empty.
+    let fields = match serde_json::to_value(status) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Default::default(),
+    };
+    fields
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::Null => String::new(),
+                Value::String(text) => text,
+                value => value.to_string(),
+            };
+            (format!("UPS_{}", name.to_uppercase()), value)
+        })
+        .collect()
+}
+
+pub fn run(
+    hook: Hook,
+    words: &[String],
+    status: &status::UPSStatus,
+    timeout: time::Duration,
+) -> Result<(), String> {
+    // Run the hook with the status in its environment, killing it if it outlives `timeout` so
+    // it can't hold up a shutdown. Failures are logged, and returned for those waiting on it.
+    let name = format!("{} hook", hook.setting());
+    info!("Running {}: {}", name, shell_words::join(words));
+    let mut child = match Command::new(&words[0])
+        .args(&words[1..])
+        .envs(status_env(status))
+        .env("UPS_EVENT", hook.name())
+        .stdin(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            let reason = format!("Failed to run {}: {}", name, e);
+            error!("{}", reason);
+            return Err(reason);
+        }
+    };
+
+    let started = time::Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(exit)) if exit.success() => {
+                info!("{} finished ({}).", name, exit);
+                return Ok(());
+            }
+            Ok(Some(exit)) => {
+                let reason = format!("{} failed ({}).", name, exit);
+                warn!("{}", reason);
+                return Err(reason);
+            }
+            Ok(None) if started.elapsed() >= timeout => {
+                let reason = format!(
+                    "{} still running after {}s, killing it.",
+                    name,
+                    timeout.as_secs()
+                );
+                warn!("{}", reason);
+                if let Err(e) = child.kill().and_then(|_| child.wait()) {
+                    error!("Failed to kill {}: {}", name, e);
+                }
+                return Err(reason);
+            }
+            Ok(None) => thread::sleep(time::Duration::from_millis(HOOK_CHECK_INTERVAL_MS)),
+            Err(e) => {
+                let reason = format!("Failed to wait for {}: {}", name, e);
+                error!("{}", reason);
+                return Err(reason);
+            }
+        }
+    }
+}
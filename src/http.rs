@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "http")]
+use crate::{notifier, status};
+#[cfg(feature = "http")]
+use serde_json::{json, Value};
+#[cfg(feature = "http")]
+use std::{
+    sync::{Arc, Mutex},
+    thread, time,
+};
+#[cfg(feature = "http")]
+use tracing::{info, warn};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct HttpSettings {
+    // Outline for the optional `[http]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // - `listen` address for the status page and `/api/status`, e.g. "0.0.0.0:8080", unset to not
+    //   serve (requires the `http` feature)
+    pub listen: Option<String>,
+}
+
+// Seconds between the status page refreshing itself.
+#[cfg(feature = "http")]
+const PAGE_REFRESH_SECONDS: u32 = 10;
+
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub struct Snapshot {
+    // The status as of the last poll, whether that poll failed so it's out of date, and the most
+    // recent failure to read the UPS.
+    pub status: status::UPSStatus,
+    pub stale: bool,
+    pub last_poll: Option<time::SystemTime>,
+    pub last_error: Option<(time::SystemTime, String)>,
+}
+
+#[cfg(feature = "http")]
+pub fn serve(settings: &HttpSettings, status: &status::UPSStatus) -> Option<Arc<Mutex<Snapshot>>> {
+    let address = settings.listen.as_ref()?;
+    // Monitoring matters more than the status page, so carry on without it.
+    let server = match tiny_http::Server::http(address) {
+        Ok(server) => server,
+        Err(e) => {
+            warn!("Failed to start status page on {}: {}", address, e);
+            return None;
+        }
+    };
+    info!("Serving the status page on http://{}/", address);
+
+    // The poll loop updates this snapshot, so requests never wait on the UPS itself, only for
+    // the moment it takes to copy.
+    let snapshot = Arc::new(Mutex::new(Snapshot {
+        status: status.clone(),
+        stale: false,
+        last_poll: None,
+        last_error: None,
+    }));
+    let server_snapshot = Arc::clone(&snapshot);
+    let started = time::Instant::now();
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let (body, content_type, code) = match (request.method(), request.url()) {
+                (tiny_http::Method::Get, "/") => {
+                    let snapshot = server_snapshot.lock().unwrap().clone();
+                    (page(&snapshot), "text/html; charset=utf-8", 200)
+                }
+                (tiny_http::Method::Get, "/api/status") => {
+                    let snapshot = server_snapshot.lock().unwrap().clone();
+                    (
+                        api_status(&snapshot, started.elapsed(), notifier::alerts_sent())
+                            .to_string(),
+                        "application/json",
+                        200,
+                    )
+                }
+                _ => ("Not found".to_string(), "text/plain", 404),
+            };
+            let response = tiny_http::Response::from_string(body)
+                .with_status_code(code)
+                .with_header(header("Content-Type", content_type))
+                .with_header(header("Cache-Control", "no-store"));
+
+            if let Err(e) = request.respond(response) {
+                warn!("Failed to respond to status page request: {:?}", e)
+            }
+        }
+    });
+
+    Some(snapshot)
+}
+
+#[cfg(feature = "http")]
+fn header(name: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+#[cfg(feature = "http")]
+fn timestamp(at: Option<time::SystemTime>) -> Option<String> {
+    at.map(|at| humantime::format_rfc3339_seconds(at).to_string())
+}
+
+#[cfg(feature = "http")]
+fn api_status(snapshot: &Snapshot, uptime: time::Duration, alerts_sent: u64) -> Value {
+    // The full status, alongside how the monitor itself is doing.
+    json!({
+        "status": snapshot.status,
+        "stale": snapshot.stale,
+        "last_poll": timestamp(snapshot.last_poll),
+        "uptime_seconds": uptime.as_secs(),
+        "alerts_sent": alerts_sent,
+        "last_error": snapshot.last_error.as_ref().map(|(_, error)| error),
+        "last_error_at": timestamp(snapshot.last_error.as_ref().map(|(at, _)| *at)),
+    })
+}
+
+#[cfg(feature = "http")]
+fn page(snapshot: &Snapshot) -> String {
+    // A plain page of the readings, refreshing itself, headed by whether we're on battery.
+    let status = &snapshot.status;
+    let (state, color) = if snapshot.stale {
+        ("Not responding", "#a30200")
+    } else if status.utility_failed {
+        ("On battery", "#daa038")
+    } else {
+        ("On line", "#2eb886")
+    };
+    let mut rows = String::new();
+    for (name, value) in status.rows() {
+        rows.push_str(&format!(
+            "<tr><th>{}</th><td>{}</td></tr>\n",
+            escape(name),
+            escape(&value)
+        ));
+    }
+    rows.push_str(&format!(
+        "<tr><th>Last poll</th><td>{}</td></tr>\n",
+        timestamp(snapshot.last_poll).unwrap_or_else(|| "not yet".to_string())
+    ));
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta http-equiv=\"refresh\" content=\"{refresh}\">
+<title>{title}</title>
+<style>body {{ font-family: sans-serif; }} th {{ text-align: left; padding-right: 1em; }}</style>
+</head>
+<body>
+<h1>{title}</h1>
+<h2 style=\"color: {color}\">{state}</h2>
+<table>
+{rows}</table>
+</body>
+</html>
+",
+        refresh = PAGE_REFRESH_SECONDS,
+        title = escape(&status.identity()),
+        color = color,
+        state = state,
+        rows = rows,
+    )
+}
+
+#[cfg(feature = "http")]
+fn escape(text: &str) -> String {
+    // Make text safe to put in the page, as the UPS reports its own model and firmware.
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(all(test, feature = "http"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_and_api_show_the_snapshot() {
+        let snapshot = Snapshot {
+            status: status::UPSStatus {
+                model: Some("<D2000>".to_string()),
+                utility_failed: true,
+                remaining_capacity: 80,
+                ..Default::default()
+            },
+            stale: false,
+            last_poll: Some(
+                time::SystemTime::UNIX_EPOCH + time::Duration::from_secs(1_700_000_000),
+            ),
+            last_error: None,
+        };
+
+        let page = page(&snapshot);
+        assert!(page.contains("On battery"));
+        assert!(page.contains("&lt;D2000&gt;"));
+        assert!(!page.contains("<D2000>"));
+        assert!(page.contains("2023-11-14T22:13:20Z"));
+
+        let api = api_status(&snapshot, time::Duration::from_secs(90), 3);
+        assert_eq!(api["status"]["remaining_capacity"], 80);
+        assert_eq!(api["uptime_seconds"], 90);
+        assert_eq!(api["alerts_sent"], 3);
+        assert_eq!(api["last_error"], Value::Null);
+    }
+}
@@ -0,0 +1,416 @@
+use crate::{batch, status};
+
+use serde::{Deserialize, Serialize};
+use std::time::{self, SystemTime};
+use tracing::{error, info, warn};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InfluxSettings {
+    // Outline for the optional `[influx]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // - `url` of the InfluxDB server, unset to not write
+    // - `org` and `bucket` to write to on InfluxDB 2, or `database` on InfluxDB 1
+    // - `token` to authenticate with, if the server needs one
+    // - `flush_seconds` between writes, with the polls in between batched together
+    pub url: Option<String>,
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub database: Option<String>,
+    pub token: Option<String>,
+    pub flush_seconds: u64,
+}
+
+impl Default for InfluxSettings {
+    fn default() -> Self {
+        InfluxSettings {
+            url: None,
+            org: None,
+            bucket: None,
+            database: None,
+            token: None,
+            flush_seconds: 10,
+        }
+    }
+}
+
+impl InfluxSettings {
+    pub fn write_url(&self) -> Result<Option<String>, String> {
+        // Where to POST the points, or none if writing is off.
+        let url = match &self.url {
+            Some(url) => url.trim_end_matches('/'),
+            None => return Ok(None),
+        };
+        if self.flush_seconds == 0 {
+            return Err("influx flush_seconds must be at least 1".to_string());
+        }
+        match (&self.org, &self.bucket, &self.database) {
+            (Some(org), Some(bucket), None) => Ok(Some(format!(
+                "{}/api/v2/write?org={}&bucket={}&precision=s",
+                url,
+                encode(org),
+                encode(bucket)
+            ))),
+            (None, None, Some(database)) => Ok(Some(format!(
+                "{}/write?db={}&precision=s",
+                url,
+                encode(database)
+            ))),
+            _ => Err("influx needs either org and bucket, or database".to_string()),
+        }
+    }
+}
+
+// Most points to hold while the server is unreachable, dropping the oldest beyond it.
+const MAX_BUFFERED_POINTS: usize = 10000;
+
+// Seconds to wait on the server for each write.
+const WRITE_TIMEOUT: u64 = 10;
+
+pub struct Writer {
+    // Batches each poll and writes them from a thread of its own, so the poll loop never waits.
+    writer: batch::Writer<String>,
+    host: String,
+}
+
+impl Writer {
+    pub fn new(settings: &InfluxSettings, machine_id: &str) -> Option<Writer> {
+        // The settings were validated on load, so an error here can't happen.
+        let url = settings.write_url().ok()??;
+        info!("Writing UPS status to InfluxDB at {}", url);
+
+        let server = Server {
+            agent: ureq::AgentBuilder::new()
+                .timeout(time::Duration::from_secs(WRITE_TIMEOUT))
+                .build(),
+            url,
+            authorization: settings
+                .token
+                .as_ref()
+                .map(|token| format!("Token {}", token)),
+            failures: 0,
+        };
+        Some(Writer {
+            writer: batch::Writer::spawn(
+                "InfluxDB",
+                server,
+                time::Duration::from_secs(settings.flush_seconds),
+                MAX_BUFFERED_POINTS,
+            ),
+            host: machine_id.to_string(),
+        })
+    }
+
+    pub fn record(&self, status: &status::UPSStatus) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.writer.send(line(&self.host, status, timestamp));
+    }
+
+    pub fn close(&self) {
+        self.writer.close();
+    }
+}
+
+struct Server {
+    // Where the points go, counting failed writes until one gets through.
+    agent: ureq::Agent,
+    url: String,
+    authorization: Option<String>,
+    failures: u64,
+}
+
+impl batch::Sink for Server {
+    type Item = String;
+
+    fn write(&mut self, lines: &[String]) -> bool {
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "text/plain; charset=utf-8");
+        if let Some(authorization) = &self.authorization {
+            request = request.set("Authorization", authorization);
+        }
+        match request.send_string(&lines.join("\n")) {
+            Ok(_) => {
+                if self.failures > 0 {
+                    info!(
+                        "Writing to InfluxDB again, after {} failed writes.",
+                        self.failures
+                    );
+                    self.failures = 0;
+                }
+                true
+            }
+            Err(ureq::Error::Status(code @ 400..=499, response)) => {
+                // The server will never take this batch, so drop it rather than let it hold up
+                // every later write.
+                error!(
+                    "InfluxDB refused {} points ({}), dropping them: {}",
+                    lines.len(),
+                    code,
+                    response.into_string().unwrap_or_default()
+                );
+                true
+            }
+            Err(e) => {
+                // Keep the points for the next flush, as the server may just be restarting.
+                self.failures += 1;
+                warn!(
+                    "Failed to write {} points to InfluxDB ({} failed writes): {}",
+                    lines.len(),
+                    self.failures,
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+enum Field {
+    Float(f32),
+    Integer(i64),
+    Boolean(bool),
+    Text(String),
+}
+
+fn line(host: &str, status: &status::UPSStatus, timestamp: u64) -> String {
+    // One poll in line protocol, e.g. `ups,host=rack input_voltage=230,output_load=12i 1700000000`.
+    // The identity and ratings don't change between polls, so they're left out.
+    let mut fields = vec![
+        ("input_voltage", Field::Float(status.input_voltage)),
+        ("input_frequency", Field::Float(status.input_frequency)),
+        (
+            "input_fault_voltage",
+            Field::Float(status.input_fault_voltage),
+        ),
+        ("output_voltage", Field::Float(status.output_voltage)),
+        ("output_current", Field::Float(status.output_current)),
+        ("output_frequency", Field::Float(status.output_frequency)),
+        ("output_load", Field::Integer(status.output_load as i64)),
+        ("battery_voltage", Field::Float(status.battery_voltage)),
+        (
+            "remaining_capacity",
+            Field::Integer(status.remaining_capacity as i64),
+        ),
+        (
+            "seconds_to_empty",
+            Field::Integer(status.seconds_to_empty as i64),
+        ),
+        ("utility_failed", Field::Boolean(status.utility_failed)),
+        ("battery_low", Field::Boolean(status.battery_low)),
+        ("charging", Field::Boolean(status.charging)),
+        ("bypass_active", Field::Boolean(status.bypass_active)),
+        ("fault", Field::Boolean(status.fault || status.ups_failed)),
+        ("overloaded", Field::Boolean(status.overloaded)),
+        ("replace_battery", Field::Boolean(status.replace_battery)),
+        ("test_in_progress", Field::Boolean(status.test_in_progress)),
+        ("shutdown_active", Field::Boolean(status.shutdown_active)),
+        ("ups_mode", Field::Text(status::name(status.ups_mode))),
+        ("test_result", Field::Text(status::name(status.test_result))),
+    ];
+    if let Some(count) = status.battery_count {
+        fields.push(("battery_count", Field::Integer(count as i64)));
+    }
+    if let Some(voltage) = status.battery_group_voltage {
+        fields.push(("battery_group_voltage", Field::Float(voltage)));
+    }
+
+    // Line protocol has no NaN or infinity, so leave out any reading that comes to one.
+    let fields: Vec<String> = fields
+        .into_iter()
+        .filter(|(_, value)| !matches!(value, Field::Float(value) if !value.is_finite()))
+        .map(|(key, value)| {
+            let value = match value {
+                Field::Float(value) => value.to_string(),
+                Field::Integer(value) => format!("{}i", value),
+                Field::Boolean(value) => value.to_string(),
+                Field::Text(value) => {
+                    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+                }
+            };
+            format!("{}={}", key, value)
+        })
+        .collect();
+    format!(
+        "ups,host={} {} {}",
+        escape_tag(host),
+        fields.join(","),
+        timestamp
+    )
+}
+
+fn escape_tag(value: &str) -> String {
+    // Tag values can't hold bare commas, spaces or equals signs.
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if matches!(c, ',' | ' ' | '=' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn encode(value: &str) -> String {
+    // Percent-encode a query parameter, keeping the unreserved characters.
+    value
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    fn on_battery() -> status::UPSStatus {
+        status::UPSStatus {
+            input_voltage: 0.0,
+            output_voltage: 230.5,
+            output_load: 12,
+            remaining_capacity: 85,
+            seconds_to_empty: 600,
+            utility_failed: true,
+            ups_mode: status::UPSModes::Inverting,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn write_url_picks_the_api_version() {
+        let mut settings = InfluxSettings {
+            url: Some("http://influx:8086/".to_string()),
+            org: Some("home lab".to_string()),
+            bucket: Some("ups".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.write_url(),
+            Ok(Some(
+                "http://influx:8086/api/v2/write?org=home%20lab&bucket=ups&precision=s".to_string()
+            ))
+        );
+
+        settings.database = Some("ups".to_string());
+        assert!(settings.write_url().is_err());
+
+        settings.org = None;
+        settings.bucket = None;
+        assert_eq!(
+            settings.write_url(),
+            Ok(Some(
+                "http://influx:8086/write?db=ups&precision=s".to_string()
+            ))
+        );
+
+        assert_eq!(InfluxSettings::default().write_url(), Ok(None));
+    }
+
+    #[test]
+    fn batches_points_in_line_protocol() {
+        // A one-shot HTTP server, capturing the request the writer makes.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = Vec::new();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                head.push(header.trim_end().to_string());
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+
+        let writer = Writer::new(
+            &InfluxSettings {
+                url: Some(format!("http://127.0.0.1:{}", port)),
+                org: Some("home".to_string()),
+                bucket: Some("ups".to_string()),
+                token: Some("secret".to_string()),
+                flush_seconds: 3600,
+                ..Default::default()
+            },
+            "rack a",
+        )
+        .unwrap();
+        writer.record(&status::UPSStatus::default());
+        writer.record(&on_battery());
+        writer.close();
+
+        let (head, body) = server.join().unwrap();
+        assert_eq!(
+            head[0],
+            "POST /api/v2/write?org=home&bucket=ups&precision=s HTTP/1.1"
+        );
+        assert!(head.iter().any(|h| h == "Authorization: Token secret"));
+
+        let lines: Vec<&str> = body.split('\n').collect();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert!(line.starts_with("ups,host=rack\\ a input_voltage="));
+        }
+        let mut parts = lines[1].split(' ').skip(2);
+        let fields = parts.next().unwrap();
+        assert!(parts.next().unwrap().parse::<u64>().is_ok());
+        for field in [
+            "input_voltage=0",
+            "output_voltage=230.5",
+            "output_load=12i",
+            "remaining_capacity=85i",
+            "seconds_to_empty=600i",
+            "utility_failed=true",
+            "charging=false",
+            "ups_mode=\"inverting\"",
+        ] {
+            assert!(fields.split(',').any(|f| f == field), "missing {}", field);
+        }
+        assert!(!fields.contains("battery_count"));
+    }
+
+    #[test]
+    fn non_finite_readings_are_left_out() {
+        let status = status::UPSStatus {
+            input_voltage: f32::NAN,
+            output_voltage: f32::INFINITY,
+            battery_voltage: 27.0,
+            ..Default::default()
+        };
+        let line = line("rack", &status, 1700000000);
+
+        assert!(!line.contains("input_voltage"));
+        assert!(!line.contains("output_voltage"));
+        assert!(line.contains("battery_voltage=27"));
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time,
+};
+
+// Bumped for each generator, so several made in the same instant still differ.
+static GENERATORS: AtomicU64 = AtomicU64::new(0);
+
+pub struct Jitter {
+    // Picks random delays up to `max`, to spread polls across machines that would otherwise all
+    // poll (and alert) in step. Not for anything needing real randomness.
+    max: time::Duration,
+    state: u64,
+}
+
+impl Jitter {
+    pub fn new(max: time::Duration) -> Jitter {
+        // Seed from the clock, our pid and the count so far, so each process takes its own path.
+        let nanos = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let seed =
+            nanos ^ (u64::from(process::id()) << 32) ^ GENERATORS.fetch_add(1, Ordering::Relaxed);
+        Jitter::seeded(max, seed)
+    }
+
+    fn seeded(max: time::Duration, seed: u64) -> Jitter {
+        Jitter { max, state: seed }
+    }
+
+    pub fn next(&mut self) -> time::Duration {
+        // A splitmix64 step, scaled into `0..=max`.
+        if self.max.is_zero() {
+            return time::Duration::ZERO;
+        }
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        self.max.mul_f64(z as f64 / u64::MAX as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_within_the_max_and_varies() {
+        let max = time::Duration::from_secs(5);
+        let mut jitter = Jitter::seeded(max, 42);
+        let delays: Vec<time::Duration> = (0..100).map(|_| jitter.next()).collect();
+
+        assert!(delays.iter().all(|delay| *delay <= max));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+        assert_eq!(
+            Jitter::seeded(time::Duration::ZERO, 42).next(),
+            time::Duration::ZERO
+        );
+    }
+}
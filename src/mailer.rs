@@ -1,48 +1,268 @@
-use hostname;
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
+use crate::{
+    chat,
+    notifier::{Event, Notifier, NotifyError, Severity},
+    ntfy, webhook,
+};
+
+use lettre::transport::smtp::{self, authentication::Credentials};
+use lettre::{message::Mailbox, Message, SmtpTransport, Transport};
 use serde::{Deserialize, Serialize};
+use std::{thread, time};
+use tracing::{debug, error, warn};
+
+// Attempts to send an email when the relay reports a transient (4xx) error.
+const SEND_ATTEMPTS: u32 = 3;
+const SEND_RETRY_DELAY: u64 = 1; // Seconds before the first retry, doubling after each.
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailerTls {
+    // Plain SMTP, usually on port 25.
+    None,
+    // Upgrade to TLS with STARTTLS, usually on port 587.
+    Starttls,
+    // Connect over TLS, usually on port 465.
+    Implicit,
+}
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MailerSettings {
     // Outline for the expected mailer settings config object
     //
     // Parameters
     // ----------
     // - `user`, `pass` outline connection to the smtp relay given in `relay`
+    // - `port` overrides the usual port for the `tls` mode, one of "none", "starttls" or
+    //   "implicit"
+    // - `timeout_seconds` limits how long we wait on the relay
     // - `from` refers to the sender address
-    // - `to` can contain multiple recipients
+    // - `to` can contain multiple recipients, either all getting every alert, or a table of lists
+    //   by severity
     // - `machine_id` is an optional identifier for the machine
+    // - `webhook` optionally configures a webhook to notify alongside email
+    // - `notifiers` lists any more notifiers, each a table with its `type`
+    // - `chat` optionally configures a Slack or Discord webhook to post alerts to
+    // - `max_emails_per_minute` limits how many emails are sent (0 for no limit), with the rest
+    //   batched into a digest sent `digest_window_seconds` after the first held back
+    // - `include_raw_status` appends every field of the latest status to each email, as the
+    //   program sees it, beneath the readable report
+    //
+    // Email is skipped if `relay` is left empty, e.g. to only use the webhooks.
+    #[serde(default)]
     pub user: String,
+    #[serde(default)]
     pub pass: String,
+    #[serde(default)]
     pub relay: String,
+    pub port: Option<u16>,
+    #[serde(default = "default_tls")]
+    pub tls: MailerTls,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default)]
     pub from: String,
-    pub to: Vec<String>,
+    #[serde(default)]
+    pub to: Recipients,
     pub machine_id: Option<String>,
+    #[serde(default = "default_max_emails_per_minute")]
+    pub max_emails_per_minute: usize,
+    #[serde(default = "default_digest_window_seconds")]
+    pub digest_window_seconds: u64,
+    #[serde(default)]
+    pub include_raw_status: bool,
+    pub webhook: Option<webhook::WebhookSettings>,
+    pub chat: Option<chat::ChatSettings>,
+    #[serde(default)]
+    pub notifiers: Vec<NotifierSettings>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotifierSettings {
+    // An entry in `[[notifiers]]`, by its `type`.
+    Webhook(webhook::WebhookSettings),
+    Ntfy(ntfy::NtfySettings),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Recipients {
+    // Everyone gets every alert.
+    All(Vec<String>),
+    // Each alert goes to the list for its severity.
+    BySeverity(SeverityRecipients),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SeverityRecipients {
+    // Outline for `to` as a table, routing alerts by severity
+    //
+    // Parameters
+    // ----------
+    // - `info`, `warning` and `critical` list who gets alerts of that severity
+    // - `default` gets alerts of any severity without its own list
+    #[serde(default)]
+    pub default: Vec<String>,
+    pub info: Option<Vec<String>>,
+    pub warning: Option<Vec<String>>,
+    pub critical: Option<Vec<String>>,
+}
+
+impl Default for Recipients {
+    fn default() -> Self {
+        Recipients::All(Vec::new())
+    }
+}
+
+impl Recipients {
+    pub fn for_severity(&self, severity: Severity) -> &[String] {
+        match self {
+            Recipients::All(to) => to,
+            Recipients::BySeverity(by_severity) => {
+                let list = match severity {
+                    Severity::Info => &by_severity.info,
+                    Severity::Warning => &by_severity.warning,
+                    Severity::Critical => &by_severity.critical,
+                };
+                list.as_ref().unwrap_or(&by_severity.default)
+            }
+        }
+    }
+
+    fn addresses(&self) -> Vec<&String> {
+        // Every address given, for checking.
+        match self {
+            Recipients::All(to) => to.iter().collect(),
+            Recipients::BySeverity(by_severity) => [
+                Some(&by_severity.default),
+                by_severity.info.as_ref(),
+                by_severity.warning.as_ref(),
+                by_severity.critical.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect(),
+        }
+    }
+}
+
+impl MailerSettings {
+    pub fn webhooks(&self) -> impl Iterator<Item = &webhook::WebhookSettings> {
+        // The `[webhook]` section, then any in `[[notifiers]]`.
+        self.webhook
+            .iter()
+            .chain(self.notifiers.iter().filter_map(|notifier| match notifier {
+                NotifierSettings::Webhook(webhook) => Some(webhook),
+                _ => None,
+            }))
+    }
+
+    pub fn ntfys(&self) -> impl Iterator<Item = &ntfy::NtfySettings> {
+        self.notifiers.iter().filter_map(|notifier| match notifier {
+            NotifierSettings::Ntfy(ntfy) => Some(ntfy),
+            _ => None,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        // Check the addresses up front, rather than finding out when the first alert fails to send,
+        // listing every problem.
+        // An empty relay is fine, as it just skips email.
+        let mut problems = Vec::new();
+        if !self.relay.is_empty() {
+            if let Err(e) = self.from.parse::<Mailbox>() {
+                problems.push(format!("from address {:?} is invalid: {}", self.from, e));
+            }
+            // An explicitly empty list by severity is fine, as it just skips those alerts.
+            if self.to.addresses().is_empty() {
+                problems.push("to needs at least one recipient".to_string());
+            } else if let Recipients::BySeverity(by_severity) = &self.to {
+                for (severity, list) in [
+                    ("info", &by_severity.info),
+                    ("warning", &by_severity.warning),
+                    ("critical", &by_severity.critical),
+                ] {
+                    if list.is_none() && by_severity.default.is_empty() {
+                        problems.push(format!(
+                            "to has no {} recipients, and no default to fall back on",
+                            severity
+                        ));
+                    }
+                }
+            }
+            for to in self.to.addresses() {
+                if let Err(e) = to.parse::<Mailbox>() {
+                    problems.push(format!("to address {:?} is invalid: {}", to, e));
+                }
+            }
+        }
+        if let Some(chat) = &self.chat {
+            problems.extend(chat.validate());
+        }
+        for webhook in self.webhooks() {
+            problems.extend(webhook.validate());
+        }
+        for ntfy in self.ntfys() {
+            problems.extend(ntfy.validate());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+fn default_tls() -> MailerTls {
+    MailerTls::Implicit
+}
+
+fn default_timeout_seconds() -> u64 {
+    60
+}
+
+fn default_max_emails_per_minute() -> usize {
+    5
+}
+
+fn default_digest_window_seconds() -> u64 {
+    300
 }
 
 pub struct Mailer {
     from: String,
-    vec_to: Vec<String>,
+    to: Recipients,
     machine_id: String,
+    dry_run: bool,
+    include_raw_status: bool,
     transport: SmtpTransport,
 }
 
 impl Mailer {
-    pub fn new(settings: MailerSettings) -> Mailer {
-        let relay = SmtpTransport::relay(&settings.relay).unwrap();
+    pub fn new(
+        settings: MailerSettings,
+        machine_id: String,
+        dry_run: bool,
+    ) -> Result<Mailer, smtp::Error> {
+        let mut relay = match settings.tls {
+            MailerTls::None => SmtpTransport::builder_dangerous(&settings.relay),
+            MailerTls::Starttls => SmtpTransport::starttls_relay(&settings.relay)?,
+            MailerTls::Implicit => SmtpTransport::relay(&settings.relay)?,
+        }
+        .timeout(Some(time::Duration::from_secs(settings.timeout_seconds)));
+        if let Some(port) = settings.port {
+            relay = relay.port(port);
+        }
 
         // Construct a new mailer instance - used to send UPS alerts over SMTP.
-        return Mailer {
+        Ok(Mailer {
             from: settings.from,
-            vec_to: settings.to,
-            // Specify a fallback for `machine_id`, being simply the machine hostname.
-            machine_id: settings.machine_id.unwrap_or(
-                hostname::get()
-                    .expect("Failed to retrieve hostname")
-                    .into_string()
-                    .expect("Failed to convert hostname to string"),
-            ),
+            to: settings.to,
+            machine_id,
+            dry_run,
+            include_raw_status: settings.include_raw_status,
             // The actual `SmtpTransport::relay` instance, which internally includes the credentials
             // from the above config.
             transport: if settings.user.is_empty() {
@@ -52,32 +272,137 @@ impl Mailer {
                     .credentials(Credentials::new(settings.user, settings.pass))
                     .build()
             },
-        };
+        })
+    }
+
+    pub fn test_connection(&self) -> Result<bool, smtp::Error> {
+        // Connect and log in as we would to send, without sending anything.
+        self.transport.test_connection()
+    }
+
+    fn body(&self, event: &Event) -> String {
+        // The alert as given, with the raw status beneath it if asked for.
+        match &event.status {
+            Some(status) if self.include_raw_status => {
+                format!("{}\n\nRaw status:\n{:#?}", event.message, status)
+            }
+            _ => event.message.clone(),
+        }
+    }
+
+    fn send_with_retry(&self, email: &Message) -> Result<(), smtp::Error> {
+        // Retry transient failures with backoff, e.g. a relay that's briefly too busy.
+        let mut retry_delay = SEND_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match self.transport.send(email) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.is_transient() && attempt < SEND_ATTEMPTS => {
+                    warn!(
+                        "Failed to send email (attempt {}/{}), retrying in {}s: {:?}",
+                        attempt, SEND_ATTEMPTS, retry_delay, e
+                    );
+                    thread::sleep(time::Duration::from_secs(retry_delay));
+                    retry_delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Notifier for Mailer {
+    fn name(&self) -> &str {
+        "email"
     }
 
-    pub fn send(&self, subject: &str, message: &str) {
-        // Send a UPS alert email
-        println!("{}", subject);
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        // Send a UPS alert email, to whoever gets alerts of its severity.
+        let recipients = self.to.for_severity(event.severity);
+        if recipients.is_empty() {
+            debug!(
+                "No recipients for {:?} alerts, not emailing.",
+                event.severity
+            );
+            return Ok(());
+        }
         if cfg!(debug_assertions) {
             // In debug builds, don't spam anyone!
-            println!("In debug build, not sending emails.");
-        } else {
-            // For production, construct a message from the configured email.
-            let mut builder = Message::builder().from(self.from.parse().unwrap());
-            // Loop recipients and add them to the mail builder.
-            for to in &self.vec_to {
-                builder = builder.to(to.parse().unwrap())
-            }
-            // Finally, set the subject and content, including the `machine_id`.
-            let email = builder
-                .subject(format!("{}: {}", self.machine_id, subject))
-                .body(message.to_string())
-                .unwrap();
-
-            // Attempt to send it, print an error if it fails
-            if let Err(e) = self.transport.send(&email) {
-                eprintln!("Failed to send email: {:?}", e)
+            debug!("In debug build, not sending emails.");
+            return Ok(());
+        }
+
+        // For production, construct a message from the configured email.
+        let from: Mailbox = self
+            .from
+            .parse()
+            .map_err(|e| NotifyError::Invalid(format!("from address {:?}: {}", self.from, e)))?;
+        let mut builder = Message::builder().from(from);
+        // Loop recipients and add them to the mail builder, skipping any we can't parse so the
+        // rest still hear about it.
+        for to in recipients {
+            match to.parse() {
+                Ok(to) => builder = builder.to(to),
+                Err(e) => error!("Invalid recipient address {}: {:?}", to, e),
             }
         }
+        // Finally, set the subject and content, including the `machine_id`.
+        let email = builder
+            .subject(format!(
+                "{}: {}",
+                self.machine_id,
+                event.subject(self.dry_run)
+            ))
+            .body(self.body(event))
+            .map_err(|e| NotifyError::Invalid(e.to_string()))?;
+
+        Ok(self.send_with_retry(&email)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{notifier::EventKind, status};
+
+    #[test]
+    fn raw_status_only_when_asked_for() {
+        let settings: MailerSettings = serde_json::from_str(
+            r#"{"relay": "localhost", "tls": "none", "from": "ups@example.com", "to": ["ops@example.com"]}"#,
+        )
+        .unwrap();
+        let mut event = Event {
+            kind: EventKind::BatteryLow,
+            severity: Severity::Warning,
+            subject: "Battery low capacity.".to_string(),
+            message: "Battery: 87%".to_string(),
+            status: None,
+        };
+        let status = status::UPSStatus {
+            remaining_capacity: 87,
+            ..Default::default()
+        };
+
+        let mailer = Mailer::new(settings.clone(), "rack".to_string(), false).unwrap();
+        event.status = Some(status.clone());
+        assert_eq!(mailer.body(&event), "Battery: 87%");
+        event.status = None;
+
+        let mailer = Mailer::new(
+            MailerSettings {
+                include_raw_status: true,
+                ..settings
+            },
+            "rack".to_string(),
+            false,
+        )
+        .unwrap();
+        // Nothing to add until there's been a poll.
+        assert_eq!(mailer.body(&event), "Battery: 87%");
+        event.status = Some(status);
+        let body = mailer.body(&event);
+        assert!(body.starts_with("Battery: 87%\n\nRaw status:\nUPSStatus {"));
+        assert!(body.contains("remaining_capacity: 87,"));
     }
 }
@@ -1,56 +1,535 @@
+mod batch;
+mod chat;
+mod control;
+mod device;
+mod health;
+mod hid_pdc;
+mod history;
+mod hook;
+mod http;
+mod influx;
+mod jitter;
 mod mailer;
+mod megatec;
+mod metrics;
+mod monitor;
+mod mqtt;
+mod notifier;
+mod ntfy;
+mod nut_server;
+mod protocol;
+mod simulate;
 mod status;
+mod status_log;
+mod stop;
+mod transport;
 mod ups;
+mod watchdog;
+mod webhook;
 
 use std::{
-    path::PathBuf,
+    fmt,
+    path::{Path, PathBuf},
     process::{exit, Command},
     thread, time,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use figment::{
     providers::{Format, Serialized, Toml},
     Figment,
 };
 use hidapi::HidApi;
-use serde::{Deserialize, Serialize};
+use hook::Hook;
+use monitor::{Action, Monitor};
+use notifier::{EventKind, MultiNotifier, Notifier, Severity};
+use protocol::{TestKind, UpsProtocol};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
 // The following define polling behaviour and shutdown behaviour.
 const POLL_DELAY: u64 = 10; // Seconds to wait between polls.
+const POLL_JITTER_SECONDS: u64 = 0; // Most seconds to randomly add to each poll delay on line power, to spread out polls across machines.
+const QUICK_POLL_DELAY: u64 = 0; // Seconds between quick mode checks, in between polls on line power. 0 means never.
 const UTILITY_FAILED_POLL_DELAY: u64 = 1; // Seconds to wait between polls while utility is failed.
 const COMMUNICATION_FAILED_POLL_DELAY: u64 = 2; //Seconds to wait between polls if communication failed.
+const COMM_FAILURE_RETRIES: u32 = 5; // Rounds of retries after communication fails, before shutting down.
+const MAX_COMM_FAILURE_DELAY: u64 = 60; // The most we'll back off to between those rounds.
+const UTILITY_FAILED_DEBOUNCE_COUNT: u32 = 1; // Consecutive reads needed to believe a utility failure or restoration.
 const SECONDS_TO_SHUTDOWN: i32 = 30; // Seconds to wait before shutting down.
-const BATTERY_LOW_THRESHOLD: u8 = 50; // Threshold capacity for a low battery.
+const BATTERY_ALERT_THRESHOLD: u8 = 50; // Threshold capacity to alert on a low battery.
+const BATTERY_ALERT_HYSTERESIS: u8 = 5; // Capacity above the threshold needed to clear the alert.
+const BATTERY_SHUTDOWN_THRESHOLD: u8 = 20; // Capacity to shut down below when not charging. 0 means never.
+const OUTPUT_LOAD_WARN_THRESHOLD: u8 = 0; // Load to warn above, ahead of the UPS tripping on overload. 0 means never.
+const OUTPUT_LOAD_HYSTERESIS: u8 = 5; // Load below the threshold needed to clear the warning.
+const BATTERY_VOLTAGE_DEVIATION_PERCENT: f32 = 20.0; // Battery group voltage off nominal to warn on. 0 means never.
+const REPEAT_INTERVAL_MINUTES: u64 = 0; // Minutes between reminders for an ongoing condition. 0 means never.
+const MIN_RUNTIME_SECONDS: i32 = 0; // Runtime left on battery that triggers a shutdown. 0 means never.
 const MINUTES_TO_SHUTDOWN: f32 = 2.0; // Time to wait for PC to shutdown before UPS shuts down.
-const MINUTES_TO_RESTART: i32 = 0; // Time after shutdown before restart. 0 means no restart.
+const MINUTES_TO_RESTART: i32 = 0; // Time after shutdown before restart, up to 9999. 0 means no timed restart.
+const HOOK_TIMEOUT_SECONDS: u64 = 30; // Seconds to let an event hook run before killing it.
+const SELF_TEST_TIMEOUT: u64 = 60; // Seconds to wait for a self-test to complete.
+const SELF_TEST_INTERVAL_DAYS: u64 = 0; // Days between automatic self-tests. 0 means never.
+const HEARTBEAT_INTERVAL_HOURS: u64 = 0; // Hours between heartbeat alerts. 0 means never.
+const SILENCE_BEEPER_ON_START: bool = false; // Whether to turn the UPS beeper off at startup.
+const WATCHDOG_TIMEOUT_SECONDS: u64 = 120; // Seconds without a poll before alerting. 0 means never.
+const WATCHDOG_ABORT: bool = false; // Whether to abort once stalled, for the service manager to restart.
+const CONNECT_ATTEMPTS: u32 = 5; // Attempts to connect to the UPS before giving up. 0 means forever.
+const CONNECT_RETRY_DELAY: u64 = 2; // Seconds before the first connection retry, doubling after each.
+const READ_TIMEOUT_MS: i32 = 500; // Milliseconds to wait for each read from a megatec UPS.
+const READ_RETRIES: usize = 3; // Attempts at a megatec command that gets no reply.
+const READ_MAX_LOOP: usize = 20; // Most reads to make for one megatec reply.
+const RECONNECT_DELAY_MS: u64 = 200; // Milliseconds before reconnecting to a megatec UPS gone quiet, doubling after each.
+const MAX_RECONNECT_SECONDS: u64 = 10; // Seconds to keep reconnecting to a megatec UPS after `read_retries`, before giving up.
+const MAX_READ_TIMEOUT_MS: u64 = 10000; // The most `read_timeout_ms` may be.
+const MAX_READ_RETRIES: u64 = 10; // The most `read_retries` may be.
+const MAX_READ_MAX_LOOP: u64 = 100; // The most `read_max_loop` may be.
+const MAX_RECONNECT_DELAY_MS: u64 = 5000; // The most `reconnect_delay_ms` may be.
+const MAX_MAX_RECONNECT_SECONDS: u64 = 300; // The most `max_reconnect_seconds` may be.
+const PROTOCOL: &str = "megatec"; // What the UPS speaks, either "megatec" or "hid-pdc".
+const DEVICE: &str = "hid"; // How to reach the UPS, either "hid" or "serial:<port>".
+const CRITICAL: bool = true; // Whether this unit failing counts towards shutting down, with several units.
+const VENDOR_ID: u16 = 0x0665; // USB vendor id of the UPS.
+const PRODUCT_ID: u16 = 0x5161; // USB product id of the UPS.
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 struct UpsSettings {
     // Configuration for the actual UPS communication, with the above definitions.
     poll_delay: u64,
+    quick_poll_delay: u64,
+    poll_jitter_seconds: u64,
     utility_failed_poll_delay: u64,
     communication_failed_poll_delay: u64,
+    comm_failure_retries: u32,
+    utility_failed_debounce_count: u32,
     seconds_to_shutdown: i32,
-    battery_low_threshold: u8,
+    battery_alert_threshold: u8,
+    battery_alert_hysteresis: u8,
+    battery_shutdown_threshold: u8,
+    battery_voltage_deviation_percent: f32,
+    output_load_warn_threshold: u8,
+    output_load_hysteresis: u8,
+    repeat_interval_minutes: u64,
+    min_runtime_seconds: i32,
     minutes_to_shutdown: f32,
     minutes_to_restart: i32,
+    power_action: PowerAction,
+    shutdown_method: ShutdownMethod,
+    shutdown_command: Option<ShutdownCommand>,
+    on_utility_failed: Option<ShutdownCommand>,
+    on_utility_restored: Option<ShutdownCommand>,
+    on_shutdown: Option<ShutdownCommand>,
+    hook_timeout_seconds: u64,
+    self_test_timeout: u64,
+    self_test_interval_days: u64,
+    health_state_path: Option<String>,
+    heartbeat_interval_hours: u64,
+    silence_beeper_on_start: bool,
+    watchdog_timeout_seconds: u64,
+    watchdog_abort: bool,
+    connect_attempts: u32,
+    connect_retry_delay: u64,
+    read_timeout_ms: i32,
+    read_retries: usize,
+    read_max_loop: usize,
+    reconnect_delay_ms: u64,
+    max_reconnect_seconds: u64,
+    protocol: String,
+    protocol_override: Option<String>,
+    device: String,
+    #[serde(
+        serialize_with = "serialize_usb_id",
+        deserialize_with = "deserialize_usb_id"
+    )]
+    vendor_id: u16,
+    #[serde(
+        serialize_with = "serialize_usb_id",
+        deserialize_with = "deserialize_usb_id"
+    )]
+    product_id: u16,
+    serial_number: Option<String>,
+    usb_path: Option<String>,
+    label: Option<String>,
+    critical: bool,
+    shutdown_policy: ShutdownPolicy,
+    units: Vec<figment::value::Dict>,
+    metrics: metrics::MetricsSettings,
+    mqtt: mqtt::MqttSettings,
+    influx: influx::InfluxSettings,
+    nut_server: nut_server::NutServerSettings,
+    http: http::HttpSettings,
+    control: control::ControlSettings,
+    status_log: status_log::StatusLogSettings,
+    history: history::HistorySettings,
+    events: notifier::EventSettings,
+    hooks: HookSettings,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ShutdownPolicy {
+    // With several units, shut down once any critical unit fails.
+    Any,
+    // With several units, shut down only once every critical unit has failed.
+    All,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PowerAction {
+    // Shut the machine down, and have the UPS cut power after it.
+    Shutdown,
+    // Hibernate the machine, then carry on monitoring once it resumes.
+    Hibernate,
+    // Suspend the machine, then carry on monitoring once it resumes.
+    Suspend,
+    // Run `shutdown_command`, then carry on monitoring.
+    Custom,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ShutdownMethod {
+    // Run `shutdown_command`, or the default for the OS.
+    Command,
+    // Ask logind to power off over D-Bus, falling back to the command if that fails.
+    Logind,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+enum ShutdownCommand {
+    // Either a command line, split like a shell would, or the program and its arguments.
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl ShutdownCommand {
+    fn words(&self) -> Result<Vec<String>, String> {
+        let words = match self {
+            ShutdownCommand::Line(line) => shell_words::split(line)
+                .map_err(|e| format!("Failed to parse shutdown command {:?}: {}", line, e))?,
+            ShutdownCommand::Args(args) => args.clone(),
+        };
+        if words.is_empty() {
+            return Err("Shutdown command is empty.".to_string());
+        }
+
+        Ok(words)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+struct HookSettings {
+    // Outline for the optional `[hooks]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // Each is a command to run on its event, like `shutdown_command`. `pre_shutdown` runs before
+    // shutting down and is waited on, the rest run in the background. The older top-level
+    // `on_utility_failed`, `on_utility_restored` and `on_shutdown` are used for any left unset.
+    on_utility_failed: Option<ShutdownCommand>,
+    on_utility_restored: Option<ShutdownCommand>,
+    on_low_battery: Option<ShutdownCommand>,
+    pre_shutdown: Option<ShutdownCommand>,
+    on_comm_lost: Option<ShutdownCommand>,
+}
+
+impl fmt::Display for ShutdownCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShutdownCommand::Line(line) => write!(f, "{}", line),
+            ShutdownCommand::Args(args) => write!(f, "{}", shell_words::join(args)),
+        }
+    }
 }
 
 impl Default for UpsSettings {
     fn default() -> Self {
         UpsSettings {
             poll_delay: POLL_DELAY,
+            quick_poll_delay: QUICK_POLL_DELAY,
+            poll_jitter_seconds: POLL_JITTER_SECONDS,
             utility_failed_poll_delay: UTILITY_FAILED_POLL_DELAY,
             communication_failed_poll_delay: COMMUNICATION_FAILED_POLL_DELAY,
+            comm_failure_retries: COMM_FAILURE_RETRIES,
+            utility_failed_debounce_count: UTILITY_FAILED_DEBOUNCE_COUNT,
             seconds_to_shutdown: SECONDS_TO_SHUTDOWN,
-            battery_low_threshold: BATTERY_LOW_THRESHOLD,
+            battery_alert_threshold: BATTERY_ALERT_THRESHOLD,
+            battery_alert_hysteresis: BATTERY_ALERT_HYSTERESIS,
+            battery_shutdown_threshold: BATTERY_SHUTDOWN_THRESHOLD,
+            battery_voltage_deviation_percent: BATTERY_VOLTAGE_DEVIATION_PERCENT,
+            output_load_warn_threshold: OUTPUT_LOAD_WARN_THRESHOLD,
+            output_load_hysteresis: OUTPUT_LOAD_HYSTERESIS,
+            repeat_interval_minutes: REPEAT_INTERVAL_MINUTES,
+            min_runtime_seconds: MIN_RUNTIME_SECONDS,
             minutes_to_shutdown: MINUTES_TO_SHUTDOWN,
             minutes_to_restart: MINUTES_TO_RESTART,
+            power_action: PowerAction::Shutdown,
+            shutdown_method: ShutdownMethod::Command,
+            shutdown_command: None,
+            on_utility_failed: None,
+            on_utility_restored: None,
+            on_shutdown: None,
+            hook_timeout_seconds: HOOK_TIMEOUT_SECONDS,
+            self_test_timeout: SELF_TEST_TIMEOUT,
+            self_test_interval_days: SELF_TEST_INTERVAL_DAYS,
+            health_state_path: None,
+            heartbeat_interval_hours: HEARTBEAT_INTERVAL_HOURS,
+            silence_beeper_on_start: SILENCE_BEEPER_ON_START,
+            watchdog_timeout_seconds: WATCHDOG_TIMEOUT_SECONDS,
+            watchdog_abort: WATCHDOG_ABORT,
+            connect_attempts: CONNECT_ATTEMPTS,
+            connect_retry_delay: CONNECT_RETRY_DELAY,
+            read_timeout_ms: READ_TIMEOUT_MS,
+            read_retries: READ_RETRIES,
+            read_max_loop: READ_MAX_LOOP,
+            reconnect_delay_ms: RECONNECT_DELAY_MS,
+            max_reconnect_seconds: MAX_RECONNECT_SECONDS,
+            protocol: PROTOCOL.to_string(),
+            protocol_override: None,
+            device: DEVICE.to_string(),
+            vendor_id: VENDOR_ID,
+            product_id: PRODUCT_ID,
+            serial_number: None,
+            usb_path: None,
+            label: None,
+            critical: CRITICAL,
+            shutdown_policy: ShutdownPolicy::Any,
+            units: Vec::new(),
+            metrics: metrics::MetricsSettings::default(),
+            mqtt: mqtt::MqttSettings::default(),
+            influx: influx::InfluxSettings::default(),
+            nut_server: nut_server::NutServerSettings::default(),
+            http: http::HttpSettings::default(),
+            control: control::ControlSettings::default(),
+            status_log: status_log::StatusLogSettings::default(),
+            history: history::HistorySettings::default(),
+            events: notifier::EventSettings::default(),
+            hooks: HookSettings::default(),
         }
     }
 }
 
+impl UpsSettings {
+    fn validate(&self) -> Result<(), Vec<String>> {
+        // Catch settings that can't work together, before we rely on them, listing every problem.
+        let mut problems = Vec::new();
+        for (name, threshold) in [
+            ("battery_alert_threshold", self.battery_alert_threshold),
+            (
+                "battery_shutdown_threshold",
+                self.battery_shutdown_threshold,
+            ),
+            (
+                "output_load_warn_threshold",
+                self.output_load_warn_threshold,
+            ),
+        ] {
+            if threshold > 100 {
+                problems.push(format!(
+                    "{} ({}) must be a percentage, 0-100",
+                    name, threshold
+                ));
+            }
+        }
+
+        if self.battery_shutdown_threshold > 0
+            && self.battery_shutdown_threshold >= self.battery_alert_threshold
+        {
+            problems.push(format!(
+                "battery_shutdown_threshold ({}) must be below battery_alert_threshold ({})",
+                self.battery_shutdown_threshold, self.battery_alert_threshold
+            ));
+        }
+
+        if self.output_load_warn_threshold > 0
+            && self.output_load_hysteresis >= self.output_load_warn_threshold
+        {
+            problems.push(format!(
+                "output_load_hysteresis ({}) must be below output_load_warn_threshold ({})",
+                self.output_load_hysteresis, self.output_load_warn_threshold
+            ));
+        }
+
+        if self.quick_poll_delay > 0 && self.quick_poll_delay >= self.poll_delay {
+            problems.push(format!(
+                "quick_poll_delay ({}) must be below poll_delay ({}), or 0 to turn it off",
+                self.quick_poll_delay, self.poll_delay
+            ));
+        }
+
+        // The UPS can only count the delays in its own units, so keep to what it can take.
+        if !(0.2..=megatec::MAX_SHUTDOWN_MINUTES).contains(&self.minutes_to_shutdown) {
+            problems.push(format!(
+                "minutes_to_shutdown ({}) must be between 0.2 and {}",
+                self.minutes_to_shutdown,
+                megatec::MAX_SHUTDOWN_MINUTES
+            ));
+        }
+        if !(0..=megatec::MAX_RESTART_MINUTES).contains(&self.minutes_to_restart) {
+            problems.push(format!(
+                "minutes_to_restart ({}) must be between 0 (no timed restart) and {}",
+                self.minutes_to_restart,
+                megatec::MAX_RESTART_MINUTES
+            ));
+        }
+
+        if self.utility_failed_debounce_count == 0 {
+            problems.push("utility_failed_debounce_count must be at least 1".to_string());
+        }
+
+        if self.power_action == PowerAction::Custom && self.shutdown_command.is_none() {
+            problems.push("power_action \"custom\" needs a shutdown_command".to_string());
+        }
+
+        for hook in Hook::ALL {
+            if let Some(Err(reason)) = self.hook_command(hook).map(ShutdownCommand::words) {
+                problems.push(format!("{}: {}", hook.setting(), reason));
+            }
+        }
+
+        if self.read_timeout_ms <= 0 || self.read_retries == 0 || self.read_max_loop == 0 {
+            problems.push(format!(
+                "read_timeout_ms ({}), read_retries ({}) and read_max_loop ({}) must all be positive",
+                self.read_timeout_ms, self.read_retries, self.read_max_loop
+            ));
+        }
+        for (name, value, max) in [
+            (
+                "read_timeout_ms",
+                self.read_timeout_ms as u64,
+                MAX_READ_TIMEOUT_MS,
+            ),
+            ("read_retries", self.read_retries as u64, MAX_READ_RETRIES),
+            (
+                "read_max_loop",
+                self.read_max_loop as u64,
+                MAX_READ_MAX_LOOP,
+            ),
+            (
+                "reconnect_delay_ms",
+                self.reconnect_delay_ms,
+                MAX_RECONNECT_DELAY_MS,
+            ),
+            (
+                "max_reconnect_seconds",
+                self.max_reconnect_seconds,
+                MAX_MAX_RECONNECT_SECONDS,
+            ),
+        ] {
+            if value > max {
+                problems.push(format!("{} ({}) must be at most {}", name, value, max));
+            }
+        }
+        // A poll that's still retrying must not look like a stalled monitor.
+        let reply_timeout = self.reply_timeout().as_secs();
+        if self.watchdog_timeout_seconds > 0 && reply_timeout >= self.watchdog_timeout_seconds {
+            problems.push(format!(
+                "read_timeout_ms, read_retries, reconnect_delay_ms and max_reconnect_seconds allow \
+                 a poll to take {}s, which must be under watchdog_timeout_seconds ({})",
+                reply_timeout, self.watchdog_timeout_seconds
+            ));
+        }
+
+        let serial_port = self.device.strip_prefix("serial:");
+        if self.device != "hid" && serial_port.is_none_or(str::is_empty) {
+            problems.push(format!(
+                "device ({:?}) must be \"hid\" or \"serial:<port>\"",
+                self.device
+            ));
+        }
+        match self.protocol.as_str() {
+            "megatec" => {}
+            // The power device class is only defined over USB.
+            "hid-pdc" if serial_port.is_some() => problems.push(format!(
+                "protocol \"hid-pdc\" needs device \"hid\", not {:?}",
+                self.device
+            )),
+            "hid-pdc" => {}
+            protocol => problems.push(format!(
+                "protocol ({:?}) must be \"megatec\" or \"hid-pdc\"",
+                protocol
+            )),
+        }
+
+        if let Some(Err(problem)) = self
+            .protocol_override
+            .as_deref()
+            .map(megatec::ProtocolOverride::parse)
+        {
+            problems.push(problem);
+        }
+
+        if let Err(problem) = self.influx.write_url() {
+            problems.push(problem);
+        }
+
+        if let Err(problem) = self.control.permissions() {
+            problems.push(problem);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn hook_command(&self, hook: Hook) -> Option<&ShutdownCommand> {
+        match hook {
+            Hook::UtilityFailed => self.hooks.on_utility_failed.as_ref(),
+            Hook::UtilityRestored => self.hooks.on_utility_restored.as_ref(),
+            Hook::LowBattery => self.hooks.on_low_battery.as_ref(),
+            Hook::Shutdown => self.hooks.pre_shutdown.as_ref(),
+            Hook::CommLost => self.hooks.on_comm_lost.as_ref(),
+        }
+        .or(match hook {
+            Hook::UtilityFailed => self.on_utility_failed.as_ref(),
+            Hook::UtilityRestored => self.on_utility_restored.as_ref(),
+            Hook::Shutdown => self.on_shutdown.as_ref(),
+            _ => None,
+        })
+    }
+
+    fn megatec_protocol_override(&self) -> Option<megatec::ProtocolOverride> {
+        // Already checked by validate, so anything unparseable is simply ignored.
+        self.protocol_override
+            .as_deref()
+            .and_then(|value| megatec::ProtocolOverride::parse(value).ok())
+    }
+
+    fn reply_timeout(&self) -> time::Duration {
+        // How long a poll may take before we count the UPS as unresponsive. Only megatec units
+        // retry and reconnect within a poll, though its timing bounds the others well enough.
+        self.megatec_timing().poll_timeout()
+    }
+
+    fn megatec_timing(&self) -> megatec::Timing {
+        megatec::Timing {
+            timeout_ms: self.read_timeout_ms,
+            retries: self.read_retries,
+            max_reads: self.read_max_loop,
+            reconnect_delay_ms: self.reconnect_delay_ms,
+            max_reconnect_ms: self.max_reconnect_seconds * 1000,
+        }
+    }
+}
+
+// USB ids are written as hex strings in the config, e.g. "0665" or "0x0665", matching `lsusb`.
+fn serialize_usb_id<S: Serializer>(id: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:#06x}", id))
+}
+
+fn deserialize_usb_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let id = String::deserialize(deserializer)?;
+    let digits = id.trim().trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16)
+        .map_err(|e| de::Error::custom(format!("invalid USB id {:?}: {}", id, e)))
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
@@ -73,228 +552,2097 @@ struct Cli {
         value_name = "FILE"
     )]
     ups_settings_path: PathBuf,
+
+    /// Log level or filter, e.g. `debug` or `ups=trace` [default: `RUST_LOG`, else info]
+    #[clap(long, value_name = "LEVEL")]
+    log_level: Option<String>,
+
+    /// Log as readable lines, or as one JSON object per line, e.g. for Loki
+    #[clap(long, value_enum, default_value = "human", value_name = "FORMAT")]
+    log_format: LogFormat,
+
+    /// Replay UPS states from a TOML file instead of talking to a UPS, without shutting down
+    #[clap(long, value_parser, value_name = "FILE")]
+    simulate: Option<PathBuf>,
+
+    /// Only log alerts, ignoring the mailer settings
+    #[clap(long)]
+    no_mail: bool,
+
+    /// Alert as usual, but only log the shutdown it would do and carry on monitoring
+    #[clap(long)]
+    dry_run: bool,
+
+    /// List attached HID devices, marking any that match the configured UPS, and exit
+    #[clap(long)]
+    list_devices: bool,
+
+    /// Check both config files, the SMTP relay and the UPS connection, then exit
+    #[clap(long)]
+    config_check: bool,
+
+    #[clap(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum LogFormat {
+    Human,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Start a UPS self-test and exit
+    Test {
+        /// Wait for the self-test to finish, then print and send the result
+        #[clap(long)]
+        wait: bool,
+        /// Test the battery for this many minutes, rather than the quick test
+        #[clap(
+            long,
+            value_name = "N",
+            value_parser = clap::value_parser!(u8).range(1..=99),
+            conflicts_with = "until-low"
+        )]
+        minutes: Option<u8>,
+        /// Test the battery until it's low, rather than the quick test
+        #[clap(long)]
+        until_low: bool,
+    },
+    /// Cancel a running UPS self-test and exit
+    CancelTest,
+    /// Toggle the UPS beeper, or turn it off or on, and exit
+    Beep {
+        /// Turn the beeper off, e.g. for planned maintenance
+        #[clap(long, conflicts_with = "unmute")]
+        mute: bool,
+        /// Turn the beeper back on
+        #[clap(long)]
+        unmute: bool,
+    },
+    /// Print the UPS status once and exit, without alerting or shutting down
+    Status {
+        /// Print the full status as JSON, for scripts
+        #[clap(long)]
+        json: bool,
+        /// Print just bit N of the raw QS status bits, as 1 or 0, e.g. for vendor-specific bits
+        #[clap(long, value_name = "N", conflicts_with = "json")]
+        bit: Option<usize>,
+    },
+    /// Send a raw Megatec command and print the response
+    Raw {
+        /// The command to send, e.g. `QS`
+        command: String,
+        /// Stop reading after this many bytes, rather than at the terminator
+        #[clap(long, value_name = "N")]
+        expect_length: Option<usize>,
+        /// Send the command this many times
+        #[clap(long, value_name = "N", default_value_t = 1)]
+        repeat: usize,
+    },
+    /// Summarise the recorded status history, from the `[history]` database
+    History {
+        /// How far back to look, e.g. `24h` or `7days`
+        #[clap(long, value_name = "DURATION", default_value = "24h", value_parser = humantime::parse_duration)]
+        since: time::Duration,
+        /// Print the summary as JSON, for scripts
+        #[clap(long)]
+        json: bool,
+    },
+    /// Query or command the running monitor over its control socket
+    Ctl {
+        /// Act on the unit with this label, rather than the first
+        #[clap(long, value_name = "LABEL")]
+        unit: Option<String>,
+        #[clap(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Print the status as of the monitor's last poll
+    Status {
+        /// Print the full status as JSON, for scripts
+        #[clap(long)]
+        json: bool,
+    },
+    /// Start a UPS self-test
+    Test {
+        /// Test the battery for this many minutes, rather than the quick test
+        #[clap(
+            long,
+            value_name = "N",
+            value_parser = clap::value_parser!(u8).range(1..=99),
+            conflicts_with = "until-low"
+        )]
+        minutes: Option<u8>,
+        /// Test the battery until it's low, rather than the quick test
+        #[clap(long)]
+        until_low: bool,
+    },
+    /// Toggle the UPS beeper, or turn it off or on
+    Beep {
+        /// Turn the beeper off
+        #[clap(long, conflicts_with = "unmute")]
+        mute: bool,
+        /// Turn the beeper back on
+        #[clap(long)]
+        unmute: bool,
+    },
+    /// Cancel a shutdown the UPS has pending
+    CancelShutdown,
+    /// Poll the UPS in full now, rather than waiting out the delay
+    PollNow,
+    /// Re-read the config files, as on SIGHUP
+    Reload,
+}
+
+fn build_notifiers(
+    mailer_settings: &mailer::MailerSettings,
+    machine_id: &str,
+    dry_run: bool,
+) -> MultiNotifier {
+    // Initialise the notifiers - email unless the relay is left empty, and the optional webhooks
+    // and pushes. Each reports its own failures, so one going down never holds up the rest.
+    let mut notifiers = MultiNotifier::default();
+    if let Some(chat_settings) = &mailer_settings.chat {
+        notifiers.push(Box::new(chat::ChatNotifier::new(
+            chat_settings.clone(),
+            machine_id.to_string(),
+            dry_run,
+        )));
+    }
+    for webhook_settings in mailer_settings.webhooks() {
+        notifiers.push(Box::new(webhook::WebhookNotifier::new(
+            webhook_settings.clone(),
+            machine_id.to_string(),
+            dry_run,
+        )));
+    }
+    for ntfy_settings in mailer_settings.ntfys() {
+        notifiers.push(Box::new(ntfy::NtfyNotifier::new(
+            ntfy_settings.clone(),
+            machine_id.to_string(),
+            dry_run,
+        )));
+    }
+    if !mailer_settings.relay.is_empty() {
+        let max_per_minute = mailer_settings.max_emails_per_minute;
+        let digest_window = time::Duration::from_secs(mailer_settings.digest_window_seconds);
+        // A bad relay shouldn't stop us monitoring, so carry on with any other notifiers.
+        match mailer::Mailer::new(mailer_settings.clone(), machine_id.to_string(), dry_run) {
+            Ok(mailer) => notifiers.push(Box::new(notifier::RateLimited::new(
+                mailer,
+                max_per_minute,
+                digest_window,
+            ))),
+            Err(e) => error!("Failed to set up email relay, not sending emails: {}", e),
+        }
+    }
+
+    // With nowhere else to send alerts, at least log them in full.
+    if notifiers.is_empty() {
+        notifiers.push(Box::new(notifier::ConsoleNotifier {
+            machine_id: machine_id.to_string(),
+        }));
+    }
+
+    notifiers
+}
+
+fn notify(
+    notifiers: &MultiNotifier,
+    events: &notifier::EventSettings,
+    event: EventKind,
+    severity: Severity,
+    subject: &str,
+    message: &str,
+) {
+    // Send an alert if its event is enabled, otherwise just log it.
+    if events.enabled(event) {
+        notifiers.send(event, severity, subject, message);
+    } else {
+        info!(event = ?event, severity = ?severity, "{}", subject);
+    }
 }
 
 // Helpers to shut down specific OS candidates
-fn linux_shutdown() {
-    Command::new("/bin/sudo")
-        .arg("/sbin/halt")
-        .output()
-        .unwrap();
-}
-
-fn windows_shutdown() {
-    Command::new("C:\\Windows\\System32\\shutdown.exe")
-        .arg("/s")
-        .arg("/f")
-        .arg("/t")
-        .arg("0")
-        .output()
-        .unwrap();
-}
-
-fn shutdown(ups: &ups::UPS, minutes_to_shutdown: f32, minutes_to_restart: i32) {
-    if cfg!(debug_assertions) {
-        // Don't actually shut down in debug builds.
-        println!("In debug build, not shutting down.")
+fn run_shutdown_command(program: &str, args: &[&str]) -> Result<(), String> {
+    // Report why a command failed, as a silent failure here leaves the machine running.
+    let output = Command::new(program).args(args).output().map_err(|e| {
+        let reason = format!(
+            "Failed to run shutdown command {} {:?}: {}",
+            program, args, e
+        );
+        error!("{}", reason);
+        reason
+    })?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stdout.trim().is_empty() {
+        info!("Shutdown command output: {}", stdout.trim());
+    }
+    if output.status.success() {
+        if !stderr.trim().is_empty() {
+            warn!("Shutdown command errors: {}", stderr.trim());
+        }
+        return Ok(());
+    }
+
+    let reason = format!(
+        "Shutdown command {} {:?} failed ({}): {}",
+        program,
+        args,
+        output.status,
+        stderr.trim()
+    );
+    error!("{}", reason);
+    Err(reason)
+}
+
+fn linux_shutdown() -> Result<(), String> {
+    // Prefer systemd, falling back to halt where it isn't available or allowed.
+    run_shutdown_command("systemctl", &["poweroff"]).or_else(|systemctl| {
+        run_shutdown_command("/bin/sudo", &["/sbin/halt"])
+            .map_err(|halt| format!("{}\n{}", systemctl, halt))
+    })
+}
+
+fn macos_shutdown() -> Result<(), String> {
+    // macOS has no systemd, and its halt skips the usual shutdown, so use shutdown itself. This
+    // needs root, or `shutdown_command` can point at something else, e.g. via osascript.
+    run_shutdown_command("/sbin/shutdown", &["-h", "now"])
+}
+
+fn windows_shutdown() -> Result<(), String> {
+    run_shutdown_command(
+        "C:\\Windows\\System32\\shutdown.exe",
+        &["/s", "/f", "/t", "0"],
+    )
+}
+
+#[cfg(feature = "logind")]
+fn logind_shutdown(method: &str) -> Result<(), String> {
+    // Power off (or sleep) through logind, which polkit can allow for an unprivileged user.
+    let connection = zbus::blocking::Connection::system()
+        .map_err(|e| format!("Failed to connect to the system bus: {}", e))?;
+    connection
+        .call_method(
+            Some("org.freedesktop.login1"),
+            "/org/freedesktop/login1",
+            Some("org.freedesktop.login1.Manager"),
+            method,
+            &(false,),
+        )
+        .map(|_| ())
+        .map_err(|e| format!("logind refused to {}: {}", method, e))
+}
+
+#[cfg(not(feature = "logind"))]
+fn logind_shutdown(_method: &str) -> Result<(), String> {
+    Err("Shutting down through logind needs the `logind` feature.".to_string())
+}
+
+fn command_shutdown(ups_settings: &UpsSettings) -> Result<(), String> {
+    if let Some(command) = &ups_settings.shutdown_command {
+        custom_shutdown(command)
+    } else if cfg!(target_os = "macos") {
+        macos_shutdown()
+    } else if cfg!(unix) {
+        linux_shutdown()
+    } else if cfg!(windows) {
+        windows_shutdown()
     } else {
-        if let Ok(_) = ups.shutdown(minutes_to_shutdown, minutes_to_restart) {
-            // Inform the UPS to shut down after we have
-            println!("Set UPS to shutdown in {}M.", minutes_to_shutdown)
-        } else {
-            eprintln!("Failed to set UPS to shutdown in {}M.", minutes_to_shutdown)
+        Err("No default shutdown command for this OS, set `shutdown_command`.".to_string())
+    }
+}
+
+fn sleep_machine(ups_settings: &UpsSettings) -> Result<(), String> {
+    // Hibernate or suspend, falling back to the OS's command if logind fails.
+    let (method, linux, windows): (&str, [&str; 2], [&str; 3]) = match ups_settings.power_action {
+        PowerAction::Hibernate => (
+            "Hibernate",
+            ["systemctl", "hibernate"],
+            ["C:\\Windows\\System32\\shutdown.exe", "/h", "/f"],
+        ),
+        PowerAction::Suspend => (
+            "Suspend",
+            ["systemctl", "suspend"],
+            [
+                "C:\\Windows\\System32\\rundll32.exe",
+                "powrprof.dll,SetSuspendState",
+                "0,1,0",
+            ],
+        ),
+        PowerAction::Custom => {
+            return custom_shutdown(
+                ups_settings
+                    .shutdown_command
+                    .as_ref()
+                    .ok_or("No shutdown_command for the custom power action.")?,
+            )
         }
+        PowerAction::Shutdown => unreachable!("Shutdowns don't sleep"),
+    };
 
-        // Now shut down the system
-        println!("Shutting down.");
-        if cfg!(unix) {
-            linux_shutdown()
+    let command = || {
+        if cfg!(target_os = "macos") {
+            // macOS picks between sleep and hibernation itself, per `pmset hibernatemode`.
+            run_shutdown_command("/usr/bin/pmset", &["sleepnow"])
+        } else if cfg!(unix) {
+            run_shutdown_command(linux[0], &linux[1..])
         } else if cfg!(windows) {
-            windows_shutdown()
+            run_shutdown_command(windows[0], &windows[1..])
+        } else {
+            Err(format!("No {} command for this OS.", method.to_lowercase()))
         }
+    };
+    match ups_settings.shutdown_method {
+        ShutdownMethod::Command => command(),
+        ShutdownMethod::Logind => logind_shutdown(method).or_else(|reason| {
+            error!(
+                "{} Falling back to the {} command.",
+                reason,
+                method.to_lowercase()
+            );
+            command().map_err(|fallback| format!("{}\n{}", reason, fallback))
+        }),
     }
-
-    // Friendly exit for Rust's sake, but we'd never actually get here in production...
-    exit(0)
 }
 
-fn main() {
-    // Use the cli to make config paths configurable
-    let cli = Cli::parse();
+fn custom_shutdown(command: &ShutdownCommand) -> Result<(), String> {
+    let words = command.words().map_err(|reason| {
+        error!("{}", reason);
+        reason
+    })?;
+    let args: Vec<&str> = words[1..].iter().map(String::as_str).collect();
+    run_shutdown_command(&words[0], &args)
+}
 
-    // Load in the optional ups config, merging with defaults.
-    let ups_settings: UpsSettings = Figment::from(Serialized::defaults(UpsSettings::default()))
-        .merge(Toml::file(cli.ups_settings_path))
-        .extract()
-        .expect("Failed to read ups config");
+fn run_hook(
+    settings: &UpsSettings,
+    hook: Hook,
+    status: &status::UPSStatus,
+    wait: bool,
+) -> Result<(), String> {
+    // Hooks for passing events run in the background, so a slow one doesn't hold up polling.
+    // Validation already caught commands that don't parse.
+    let words = match settings.hook_command(hook).map(ShutdownCommand::words) {
+        Some(Ok(words)) => words,
+        _ => return Ok(()),
+    };
+    let timeout = time::Duration::from_secs(settings.hook_timeout_seconds);
+    if wait {
+        hook::run(hook, &words, status, timeout)
+    } else {
+        let status = status.clone();
+        thread::spawn(move || hook::run(hook, &words, &status, timeout));
+        Ok(())
+    }
+}
 
-    // Load in the mailer config - this one is mandatory.
-    let mailer_settings: mailer::MailerSettings = Figment::new()
-        .merge(Toml::file(cli.mailer_settings_path))
-        .extract()
-        .expect("Failed to read smtp config");
+fn shutdown(units: &[Unit], ups_settings: &UpsSettings, notifiers: &MultiNotifier, dry_run: bool) {
+    // Get any held back alerts out while we still can.
+    for unit in units {
+        unit.notifiers.flush(true);
+    }
+    notifiers.flush(true);
 
-    if cfg!(debug_assertions) {
-        // Print our config in debug mode.
-        println!("{:#?}", ups_settings);
-        println!("{:#?}", mailer_settings);
+    // Give the user's hook its chance to tidy up first, e.g. stopping a database cleanly.
+    // Its failure is worth knowing about, but mustn't stop the shutdown.
+    if let Some(command) = ups_settings.hook_command(Hook::Shutdown) {
+        if dry_run {
+            warn!("Dry run, not running the pre_shutdown hook: {}", command);
+        } else if let Err(reason) =
+            run_hook(ups_settings, Hook::Shutdown, &units[0].ups.status, true)
+        {
+            notifiers.send(
+                EventKind::HookFailed,
+                Severity::Critical,
+                "Shutdown hook failed - shutting down anyway.",
+                &format!("{}\n\n{}", reason, units[0].ups.status),
+            );
+            notifiers.flush(true);
+        }
     }
 
-    // Initialise the mailer.
-    let mailer = mailer::Mailer::new(mailer_settings);
+    // Anything short of a full shutdown leaves the UPS on, and returns to carry on monitoring.
+    if ups_settings.power_action != PowerAction::Shutdown {
+        if dry_run {
+            warn!(
+                "Dry run, not acting - would {} the machine, leaving the UPS on.",
+                format!("{:?}", ups_settings.power_action).to_lowercase()
+            );
+            return;
+        }
 
-    // Initialise the UPS connection.
-    let api: HidApi = HidApi::new().expect("Failed to initialise HIDAPI");
-    let mut ups = ups::UPS::new(api);
+        info!("Power action: {:?}.", ups_settings.power_action);
+        if let Err(reason) = sleep_machine(ups_settings) {
+            notify(
+                notifiers,
+                &ups_settings.events,
+                EventKind::ShutdownFailed,
+                Severity::Critical,
+                "Power action failed - machine still running.",
+                &reason,
+            );
+            notifiers.flush(true);
+        }
+        return;
+    }
 
-    println!("UPS monitor running and connected!");
+    let minutes_to_shutdown = ups_settings.minutes_to_shutdown;
+    if dry_run {
+        // Don't actually shut down in dry runs, debug builds or simulations - say what we'd do, and
+        // return to carry on monitoring.
+        for unit in units {
+            warn!(
+                "Dry run, not shutting down UPS {} - would {}.",
+                unit.label,
+                unit.ups.describe_shutdown(
+                    unit.settings.minutes_to_shutdown,
+                    unit.settings.minutes_to_restart
+                )
+            );
+        }
+        let mut command = match &ups_settings.shutdown_command {
+            Some(command) => command.to_string(),
+            None if cfg!(windows) => "shutdown.exe /s /f /t 0".to_string(),
+            None if cfg!(target_os = "macos") => "/sbin/shutdown -h now".to_string(),
+            None => "systemctl poweroff, falling back to sudo /sbin/halt".to_string(),
+        };
+        if ups_settings.shutdown_method == ShutdownMethod::Logind {
+            command = format!("logind PowerOff, falling back to {}", command);
+        }
+        warn!(
+            "Dry run, not shutting down the machine - would run {}.",
+            command
+        );
+        return;
+    } else {
+        // Inform every UPS to shut down after we have, so they all come back together.
+        for unit in units {
+            let minutes = unit.settings.minutes_to_shutdown;
+            let restart = unit.settings.minutes_to_restart;
+            match unit.ups.shutdown(minutes, restart) {
+                Ok(()) => info!(
+                    unit = %unit.label,
+                    minutes,
+                    restart,
+                    "Set UPS {} to shutdown in {}M.",
+                    unit.label,
+                    minutes
+                ),
+                Err(e) => error!(
+                    unit = %unit.label,
+                    minutes,
+                    restart,
+                    error = %e.report(),
+                    "Failed to set UPS {} to shutdown in {}M.",
+                    unit.label,
+                    minutes
+                ),
+            }
+        }
 
-    // And now enter the endless checking loop...
-    let mut sent_utility_failed: bool = false;
-    let mut seconds_until_shutdown: i32 = ups_settings.seconds_to_shutdown;
-    let mut poll_delay: u64;
-    loop {
-        if let Err(e) = ups.get_ups_status() {
-            mailer.send(
+        // Now shut down the system
+        info!(
+            method = ?ups_settings.shutdown_method,
+            remaining_capacity = units[0].ups.status.remaining_capacity,
+            seconds_to_empty = units[0].ups.status.seconds_to_empty,
+            "Shutting down."
+        );
+        let result = match ups_settings.shutdown_method {
+            ShutdownMethod::Command => command_shutdown(ups_settings),
+            ShutdownMethod::Logind => logind_shutdown("PowerOff").or_else(|reason| {
+                error!("{} Falling back to the shutdown command.", reason);
+                command_shutdown(ups_settings)
+                    .map_err(|fallback| format!("{}\n{}", reason, fallback))
+            }),
+        };
+
+        // A last gasp, as otherwise nobody knows the machine is about to lose power uncleanly.
+        if let Err(reason) = result {
+            notify(
+                notifiers,
+                &ups_settings.events,
+                EventKind::ShutdownFailed,
+                Severity::Critical,
+                "Shutdown command failed - machine still running.",
                 &format!(
-                    "UPS communication failed - retrying in {}.",
-                    ups_settings.communication_failed_poll_delay
+                    "{}\n\nThe UPS will still cut power in {}M.",
+                    reason, minutes_to_shutdown
                 ),
-                &format!("{:#?}\n{:#?}", e, ups.status).to_string(),
             );
+            notifiers.flush(true);
+        }
+    }
 
-            thread::sleep(time::Duration::from_secs(
-                ups_settings.communication_failed_poll_delay,
-            ));
+    // Friendly exit for Rust's sake, but we'd never actually get here in production...
+    exit(0)
+}
 
-            if let Err(e) = ups.connect() {
-                mailer.send(
-                    "UPS reconnect failed - shutting down.",
-                    &format!("{:#?}\n{:#?}", e, ups.status).to_string(),
-                );
+// Seconds the wall clock can run ahead of the monotonic clock before we take it as having slept.
+const RESUME_GAP_SECONDS: u64 = 30;
+
+// The most we'll back off to between connection attempts.
+const MAX_CONNECT_RETRY_DELAY: u64 = 60;
+
+// Exit codes for `ups status`, when the UPS can't be opened or doesn't answer properly.
+const EXIT_OPEN_FAILED: i32 = 2;
+const EXIT_READ_FAILED: i32 = 3;
+
+fn comm_failure_delay(first_delay: u64, round: u32) -> u64 {
+    // Seconds to wait after a failed round of communication retries, doubling each round.
+    first_delay
+        .saturating_mul(1 << (round.saturating_sub(1)).min(16))
+        .min(MAX_COMM_FAILURE_DELAY.max(first_delay))
+}
 
-                shutdown(
-                    &ups,
-                    ups_settings.minutes_to_shutdown,
-                    ups_settings.minutes_to_restart,
+fn retry_connect<T>(
+    ups_settings: &UpsSettings,
+    attempts: u32,
+    notifiers: Option<&MultiNotifier>,
+    mut connect: impl FnMut() -> Result<T, ups::UPSError>,
+) -> Result<T, ups::UPSError> {
+    // Retry with backoff, as at boot we may start before the USB device has been enumerated.
+    // Zero attempts means we keep trying forever.
+    let mut retry_delay = ups_settings.connect_retry_delay;
+    let mut attempt = 1;
+    loop {
+        match connect() {
+            Ok(connected) => return Ok(connected),
+            // Waiting won't fix the settings.
+            Err(e @ ups::UPSError::Settings(_)) => return Err(e),
+            Err(e) if attempts == 0 || attempt < attempts => {
+                let limit = if attempts == 0 {
+                    "unlimited".to_string()
+                } else {
+                    attempts.to_string()
+                };
+                warn!(
+                    "Failed to connect to UPS (attempt {}/{}), retrying in {}s: {}",
+                    attempt,
+                    limit,
+                    retry_delay,
+                    e.report()
                 );
+
+                // Only email once, rather than for every attempt.
+                if let (1, Some(notifiers)) = (attempt, notifiers) {
+                    notifiers.send(
+                        EventKind::NotFound,
+                        Severity::Warning,
+                        "UPS not found - waiting for it.",
+                        &e.report(),
+                    );
+                }
+
+                thread::sleep(time::Duration::from_secs(retry_delay));
+                retry_delay = (retry_delay * 2).min(MAX_CONNECT_RETRY_DELAY);
+                attempt += 1;
             }
+            Err(e) => return Err(e),
+        }
+    }
+}
 
-            if let Err(e) = ups.get_ups_status() {
-                mailer.send(
-                    "UPS communication failed - shutting down.",
-                    &format!("{:#?}\n{:#?}", e, ups.status).to_string(),
-                );
+fn connect_ups(
+    ups_settings: &UpsSettings,
+    notifiers: Option<&MultiNotifier>,
+) -> Result<ups::UPS, ups::UPSError> {
+    retry_connect(
+        ups_settings,
+        ups_settings.connect_attempts,
+        notifiers,
+        || open_protocol(ups_settings).and_then(ups::UPS::new),
+    )
+}
 
-                shutdown(
-                    &ups,
-                    ups_settings.minutes_to_shutdown,
-                    ups_settings.minutes_to_restart,
-                );
+fn connected<T>(result: Result<T, ups::UPSError>) -> T {
+    // Give up with the reason rather than a panic, once the connection attempts are spent.
+    result.unwrap_or_else(|e| {
+        error!("Failed to connect to UPS: {}", e.report());
+        exit(1)
+    })
+}
+
+fn connect_device(
+    ups_settings: &UpsSettings,
+    notifiers: Option<&MultiNotifier>,
+) -> Result<device::Device, ups::UPSError> {
+    // As `connect_ups`, handing the UPS to its own I/O thread for the monitor.
+    let name = ups_settings.label.as_deref().unwrap_or("ups");
+    retry_connect(
+        ups_settings,
+        ups_settings.connect_attempts,
+        notifiers,
+        || {
+            let settings = ups_settings.clone();
+            device::Device::spawn(name, ups_settings.reply_timeout(), move || {
+                open_protocol(&settings).and_then(ups::UPS::new)
+            })
+        },
+    )
+}
+
+fn open_protocol(
+    ups_settings: &UpsSettings,
+) -> Result<Box<dyn protocol::UpsProtocol>, ups::UPSError> {
+    match ups_settings.protocol.as_str() {
+        "megatec" => Ok(Box::new(megatec::MegatecProtocol::new(
+            open_connector(ups_settings)?,
+            ups_settings.megatec_timing(),
+            ups_settings.megatec_protocol_override(),
+        ))),
+        // The power device class is only defined over USB.
+        "hid-pdc" if ups_settings.device == "hid" => Ok(Box::new(hid_pdc::HidPdcProtocol::new(
+            open_hid_connector(ups_settings)?,
+        ))),
+        _ => Err(ups::UPSError::Settings(format!(
+            "unsupported protocol {:?} on device {:?}, expected \"megatec\", or \"hid-pdc\" with \"hid\"",
+            ups_settings.protocol, ups_settings.device
+        ))),
+    }
+}
+
+fn open_hid_connector(
+    ups_settings: &UpsSettings,
+) -> Result<transport::HidConnector, ups::UPSError> {
+    let selector = transport::DeviceSelector {
+        vendor_id: ups_settings.vendor_id,
+        product_id: ups_settings.product_id,
+        serial_number: ups_settings.serial_number.clone(),
+        usb_path: ups_settings.usb_path.clone(),
+    };
+
+    // A fresh `HidApi` each attempt, so we pick up newly enumerated devices.
+    let api = HidApi::new()?;
+    Ok(transport::HidConnector::new(api, selector))
+}
+
+fn open_connector(
+    ups_settings: &UpsSettings,
+) -> Result<Box<dyn transport::Connector>, ups::UPSError> {
+    if ups_settings.device == "hid" {
+        Ok(Box::new(open_hid_connector(ups_settings)?))
+    } else if let Some(port) = ups_settings.device.strip_prefix("serial:") {
+        Ok(Box::new(transport::SerialConnector::new(port.to_string())))
+    } else {
+        Err(ups::UPSError::Settings(format!(
+            "unknown device {:?}, expected \"hid\" or \"serial:<port>\"",
+            ups_settings.device
+        )))
+    }
+}
+
+fn list_devices(ups_settings: &UpsSettings) -> Result<(), ups::UPSError> {
+    let api = HidApi::new()?;
+
+    println!(
+        "  {:<4} {:<4} {:<24} {:<24} {:<16} Path",
+        "VID", "PID", "Manufacturer", "Product", "Serial"
+    );
+    for device in api.device_list() {
+        // Mark devices matching the configured vid:pid, so it's obvious which one we'd use.
+        let marker = if device.vendor_id() == ups_settings.vendor_id
+            && device.product_id() == ups_settings.product_id
+        {
+            "*"
+        } else {
+            " "
+        };
+
+        println!(
+            "{} {:04x} {:04x} {:<24} {:<24} {:<16} {}",
+            marker,
+            device.vendor_id(),
+            device.product_id(),
+            device.manufacturer_string().unwrap_or("-"),
+            device.product_string().unwrap_or("-"),
+            device.serial_number().unwrap_or("-"),
+            device.path().to_string_lossy()
+        );
+    }
+
+    Ok(())
+}
+
+fn test_kind(minutes: Option<u8>, until_low: bool) -> TestKind {
+    match (minutes, until_low) {
+        (Some(minutes), _) => TestKind::Minutes(minutes),
+        (None, true) => TestKind::UntilLow,
+        (None, false) => TestKind::Quick,
+    }
+}
+
+fn beep_enabled(mute: bool, unmute: bool) -> Option<bool> {
+    // Where to set the beeper, or None to toggle it.
+    match (mute, unmute) {
+        (true, _) => Some(false),
+        (_, true) => Some(true),
+        _ => None,
+    }
+}
+
+fn run_test(cli: &Cli, ups_settings: &UpsSettings, kind: TestKind, wait: bool) {
+    let mut ups = connected(connect_ups(ups_settings, None));
+
+    if !wait {
+        if let Err(e) = ups.start_test(kind) {
+            error!("Failed to start self-test: {}", e.report());
+            exit(1)
+        }
+        println!("Started UPS self-test.");
+        exit(0)
+    }
+
+    // Mail the outcome as the scheduled test does, checking the config before spending a test.
+    let mailer_settings = load_mailer_settings(&cli.mailer_settings_path, cli.no_mail)
+        .unwrap_or_else(|problems| invalid_config(&cli.mailer_settings_path, problems));
+    let machine_id = notifier::machine_id(mailer_settings.machine_id.clone());
+    let notifiers = build_notifiers(&mailer_settings, &machine_id, cli.dry_run);
+
+    println!("Running UPS self-test.");
+    let result = ups.run_test(
+        kind,
+        time::Duration::from_secs(ups_settings.self_test_timeout),
+    );
+    notifiers.update(&ups.status);
+    let (severity, subject, message) = match &result {
+        Ok(status::UPSTestResults::InProgress) => (
+            Severity::Warning,
+            "Self-test did not complete - treating as aborted.".to_string(),
+            ups.status.to_string(),
+        ),
+        // Only a passing test is nothing to worry about.
+        Ok(result) => (
+            if *result == status::UPSTestResults::Passed {
+                Severity::Info
             } else {
-                mailer.send(
-                    &format!("UPS communication restored.",),
-                    &format!("{:#?}", ups.status).to_string(),
+                Severity::Warning
+            },
+            format!("Self-test result: {:?}.", result),
+            ups.status.to_string(),
+        ),
+        Err(e) => (
+            Severity::Warning,
+            "Self-test failed to run.".to_string(),
+            format!("{}\n\n{}", e.report(), ups.status),
+        ),
+    };
+    notify(
+        &notifiers,
+        &ups_settings.events,
+        EventKind::SelfTest,
+        severity,
+        &subject,
+        &message,
+    );
+    notifiers.flush(true);
+
+    match result {
+        Ok(status::UPSTestResults::InProgress) => {
+            error!(
+                "Self-test did not complete within {}s of the expected time - treating as aborted.",
+                ups_settings.self_test_timeout
+            );
+            exit(1)
+        }
+        Ok(result) => {
+            println!("Self-test result: {:?}", result);
+            exit(0)
+        }
+        Err(e) => {
+            error!("Self-test failed: {}", e.report());
+            exit(1)
+        }
+    }
+}
+
+fn cancel_test(ups_settings: &UpsSettings) {
+    let ups = connected(connect_ups(ups_settings, None));
+
+    if let Err(e) = ups.cancel_test() {
+        error!("Failed to cancel self-test: {}", e.report());
+        exit(1)
+    }
+
+    println!("Cancelled UPS self-test.");
+    exit(0)
+}
+
+fn print_status(ups_settings: &UpsSettings, json: bool, bit: Option<usize>) {
+    // Connect just the once, as this is meant for scripts polling us, and connecting reads the
+    // ratings and status for us.
+    let mut ups = match open_protocol(ups_settings).and_then(ups::UPS::new) {
+        Ok(ups) => ups,
+        Err(e) => {
+            error!("Failed to read UPS status: {}", e.report());
+            // Tell scripts whether the UPS wasn't there at all or just didn't answer properly.
+            exit(if e.is_open_failure() {
+                EXIT_OPEN_FAILED
+            } else {
+                EXIT_READ_FAILED
+            })
+        }
+    };
+
+    // Only read the history, leaving the monitor to keep it.
+    if let Some(path) = &ups_settings.health_state_path {
+        let label = ups_settings
+            .label
+            .clone()
+            .unwrap_or_else(|| ups.status.identity());
+        ups.health = health::load(path, &label);
+        ups.health.observe(&ups.status, time::SystemTime::now());
+    }
+
+    if let Some(index) = bit {
+        match ups.status.status_bit(index) {
+            Some(set) => println!("{}", set as u8),
+            None => {
+                error!(
+                    "UPS sent {} status bits ({:?}), so there's no bit {}.",
+                    ups.status.raw_status_flags.len(),
+                    ups.status.raw_status_flags,
+                    index
                 );
+                exit(EXIT_READ_FAILED)
             }
         }
+    } else if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&ups.status).expect("Failed to serialize UPS status")
+        );
+    } else {
+        println!("{}\n\n{}", ups.status.table(), ups.health_report());
+    }
+    exit(0)
+}
 
-        if cfg!(debug_assertions) {
-            println!("{:#?}", ups.status);
-        }
+fn beep(ups_settings: &UpsSettings, enabled: Option<bool>) {
+    let mut ups = connected(connect_ups(ups_settings, None));
 
-        if ups.status.utility_failed {
-            poll_delay = ups_settings.utility_failed_poll_delay;
-            seconds_until_shutdown -= poll_delay as i32;
+    // Toggle or set it, then read the status back so we can report where it ended up.
+    let result = match enabled {
+        Some(enabled) => ups.set_beep(enabled),
+        None => ups.toggle_beep().and_then(|_| ups.get_ups_status()),
+    };
+    if let Err(e) = result {
+        error!("Failed to set beeper: {}", e.report());
+        exit(1)
+    }
+
+    println!(
+        "Beeper is now {}.",
+        if ups.status.beeper_on { "on" } else { "off" }
+    );
+    exit(0)
+}
 
-            if !sent_utility_failed {
-                mailer.send("Utility failed.", &format!("{:#?}", ups.status).to_string());
-                sent_utility_failed = true;
+fn raw_command(ups_settings: &UpsSettings, command: &str, length: Option<usize>, repeat: usize) {
+    // Skip the `UPS` wrapper, as it polls the UPS itself and we want exactly what we send.
+    let mut protocol = megatec::MegatecProtocol::new(
+        connected(open_connector(ups_settings)),
+        ups_settings.megatec_timing(),
+        ups_settings.megatec_protocol_override(),
+    );
+    if let Err(e) = protocol.connect() {
+        error!("Failed to connect to UPS: {}", e.report());
+        exit(1)
+    }
+
+    let mut failed = false;
+    for _ in 0..repeat {
+        match protocol.raw_command(command, length) {
+            Ok(res) => {
+                let hex: Vec<String> = res.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{}", hex.join(" "));
+                println!("{}", String::from_utf8_lossy(&res));
             }
-            if seconds_until_shutdown <= 0 {
-                mailer.send(
-                    "Utility failed - shutting down.",
-                    &format!(
-                        "UPS has {}s remaining, will shutdown in {}min.\n{:#?}",
-                        ups.status.seconds_to_empty, ups_settings.minutes_to_shutdown, ups.status
-                    )
-                    .to_string(),
-                );
+            Err(e) => {
+                error!("No response to {:?}: {}", command, e.report());
+                failed = true;
+            }
+        }
+    }
 
-                shutdown(
-                    &ups,
-                    ups_settings.minutes_to_shutdown,
-                    ups_settings.minutes_to_restart,
-                );
-            } else {
-                eprintln!("Utility failed - shutdown in {}s.", seconds_until_shutdown)
+    exit(if failed { 1 } else { 0 })
+}
+
+fn print_history(ups_settings: &UpsSettings, since: time::Duration, json: bool) {
+    let path = match &ups_settings.history.path {
+        Some(path) => path,
+        None => {
+            error!("No history is recorded - set `path` under `[history]` to start.");
+            exit(1)
+        }
+    };
+
+    #[cfg(feature = "history")]
+    {
+        let summary = match history::summary(path, time::SystemTime::now() - since) {
+            Ok(summary) => summary,
+            Err(e) => {
+                error!("Failed to read the history from {}: {}", path, e);
+                exit(EXIT_OPEN_FAILED)
             }
+        };
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&summary).expect("Failed to serialize history")
+            );
         } else {
-            poll_delay = ups_settings.poll_delay;
-            seconds_until_shutdown = ups_settings.seconds_to_shutdown;
+            println!("{}", summary);
+        }
+        exit(0)
+    }
 
-            if sent_utility_failed {
-                mailer.send(
-                    "Utility restored.",
-                    &format!("{:#?}", ups.status).to_string(),
-                );
-                sent_utility_failed = false;
+    #[cfg(not(feature = "history"))]
+    {
+        let _ = (since, json);
+        error!(
+            "Can't read the history in {} - this build lacks the `history` feature.",
+            path
+        );
+        exit(1)
+    }
+}
+
+fn ctl(ups_settings: &UpsSettings, unit: &Option<String>, command: &CtlCommand) {
+    // Hand the command to the running monitor, which holds the UPS, and print what it says.
+    let request = control::Request {
+        command: match *command {
+            CtlCommand::Status { .. } => control::Command::Status,
+            CtlCommand::Test { minutes, until_low } => {
+                control::Command::Test { minutes, until_low }
             }
+            CtlCommand::Beep { mute, unmute } => control::Command::Beep {
+                enabled: beep_enabled(mute, unmute),
+            },
+            CtlCommand::CancelShutdown => control::Command::CancelShutdown,
+            CtlCommand::PollNow => control::Command::PollNow,
+            CtlCommand::Reload => control::Command::Reload,
+        },
+        unit: unit.clone(),
+    };
+    let path = &ups_settings.control.path;
+    let reply = match control::request(path, &request) {
+        Ok(reply) => reply,
+        Err(e) => {
+            error!("Failed to reach the monitor on {}: {}", path, e);
+            exit(EXIT_OPEN_FAILED)
         }
+    };
+
+    if !reply.ok {
+        error!("{}", reply.message.unwrap_or_default());
+        exit(1)
+    }
+    match (reply.status, command) {
+        (Some(status), CtlCommand::Status { json: true }) => println!(
+            "{}",
+            serde_json::to_string_pretty(&status).expect("Failed to serialize UPS status")
+        ),
+        (Some(status), _) => println!("{}", status.table()),
+        (None, _) => println!("{}", reply.message.unwrap_or_default()),
+    }
+    exit(0)
+}
 
-        if ups.status.fault {
-            mailer.send(
-                "Fault detected - shutting down.",
-                &format!("{:#?}", ups.status).to_string(),
+fn config_check(cli: &Cli) -> ! {
+    // Try everything the monitor relies on, without alerting or touching the UPS beyond a status
+    // read, and print how each went.
+    let mut failed = 0;
+    let mut report = |check: &str, result: Result<String, String>| {
+        let (outcome, detail) = match result {
+            Ok(detail) => ("PASS", detail),
+            Err(detail) => {
+                failed += 1;
+                ("FAIL", detail)
+            }
+        };
+        print_check(outcome, check, &detail);
+    };
+
+    let ups_path = &cli.ups_settings_path;
+    let units = load_ups_settings(ups_path);
+    report(
+        "UPS settings",
+        match &units {
+            Ok((_, units)) if !ups_path.exists() => Ok(format!(
+                "{} doesn't exist, so using the defaults for {} unit(s)",
+                ups_path.display(),
+                units.len()
+            )),
+            Ok((_, units)) => Ok(format!("{}, {} unit(s)", ups_path.display(), units.len())),
+            Err(problems) => Err(describe_problems(ups_path, problems)),
+        },
+    );
+
+    let mailer_path = &cli.mailer_settings_path;
+    let mailer_settings = load_mailer_settings(mailer_path, cli.no_mail);
+    match &mailer_settings {
+        _ if cli.no_mail => print_check("SKIP", "Mailer settings", "ignored with --no-mail"),
+        Ok(_) if !mailer_path.exists() => report(
+            "Mailer settings",
+            Ok(format!(
+                "{} doesn't exist, so alerts will only be logged",
+                mailer_path.display()
+            )),
+        ),
+        Ok(_) => report("Mailer settings", Ok(mailer_path.display().to_string())),
+        Err(problems) => report(
+            "Mailer settings",
+            Err(describe_problems(mailer_path, problems)),
+        ),
+    }
+
+    match mailer_settings {
+        Ok(settings) if !settings.relay.is_empty() => {
+            let relay = settings.relay.clone();
+            let authenticating = !settings.user.is_empty();
+            report(
+                "SMTP relay",
+                mailer::Mailer::new(settings, String::new(), false)
+                    .and_then(|mailer| mailer.test_connection())
+                    .map_err(|e| format!("{}: {}", relay, e))
+                    .and_then(|connected| match (connected, authenticating) {
+                        (true, true) => Ok(format!("{}, connected and logged in", relay)),
+                        (true, false) => Ok(format!("{}, connected", relay)),
+                        (false, _) => Err(format!("{} isn't responding", relay)),
+                    }),
+            )
+        }
+        Ok(_) => print_check("SKIP", "SMTP relay", "no relay set"),
+        Err(_) => print_check(
+            "SKIP",
+            "SMTP relay",
+            "the mailer settings need fixing first",
+        ),
+    }
+
+    // The UPS itself, unless simulating, one attempt each so a missing one shows straight away.
+    if let Ok((ups_settings, units)) = &units {
+        let held = Path::new(&ups_settings.control.path).exists();
+        for settings in units {
+            let check = match &settings.label {
+                Some(label) => format!("UPS {}", label),
+                None => "UPS".to_string(),
+            };
+            if cli.simulate.is_some() {
+                print_check("SKIP", &check, "simulating");
+                continue;
+            }
+            report(
+                &check,
+                open_protocol(settings)
+                    .and_then(ups::UPS::new)
+                    .map(|mut ups| {
+                        ups.disconnect();
+                        format!("connected to {}", ups.status.identity())
+                    })
+                    .map_err(|e| {
+                        if held {
+                            format!(
+                                "{} (the monitor looks to be running, and holds the UPS)",
+                                e.report()
+                            )
+                        } else {
+                            e.report()
+                        }
+                    }),
             );
+        }
+    }
+
+    if failed == 0 {
+        println!("All checks passed.");
+        exit(0)
+    }
+    println!("{} check(s) failed.", failed);
+    exit(1)
+}
 
-            shutdown(
-                &ups,
-                ups_settings.minutes_to_shutdown,
-                ups_settings.minutes_to_restart,
+fn print_check(outcome: &str, check: &str, detail: &str) {
+    // e.g. "[PASS] SMTP relay: smtp.example.com, connected", lining up any further lines.
+    println!(
+        "[{}] {}: {}",
+        outcome,
+        check,
+        detail.replace('\n', "\n       ")
+    );
+}
+
+fn load_ups_settings(path: &Path) -> Result<(UpsSettings, Vec<UpsSettings>), Vec<String>> {
+    // The top-level settings and each unit's, merged with the defaults and validated.
+    let figment =
+        Figment::from(Serialized::defaults(UpsSettings::default())).merge(Toml::file(path));
+    let ups_settings: UpsSettings = figment.extract().map_err(figment_problems)?;
+    let unit_settings = load_units(&figment, &ups_settings)?;
+    let mut problems = Vec::new();
+    for settings in &unit_settings {
+        if let Err(unit_problems) = settings.validate() {
+            // Say which unit each problem is in, once there's more than one.
+            problems.extend(
+                unit_problems
+                    .into_iter()
+                    .map(|problem| match &settings.label {
+                        Some(label) if unit_settings.len() > 1 => format!("{}: {}", label, problem),
+                        _ => problem,
+                    }),
             );
         }
+    }
+    if unit_settings.iter().all(|settings| !settings.critical) {
+        problems.push("at least one unit must be critical".to_string());
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    Ok((ups_settings, unit_settings))
+}
 
-        if ups.status.overloaded {
-            mailer.send(
-                "UPS overloaded - shutting down.",
-                &format!("{:#?}", ups.status).to_string(),
+fn load_mailer_settings(path: &Path, no_mail: bool) -> Result<mailer::MailerSettings, Vec<String>> {
+    // The notification settings, empty when told to only log alerts.
+    let figment = if no_mail {
+        Figment::new()
+    } else {
+        Figment::new().merge(Toml::file(path))
+    };
+    let mailer_settings: mailer::MailerSettings = figment.extract().map_err(figment_problems)?;
+    mailer_settings.validate()?;
+    Ok(mailer_settings)
+}
+
+fn load_units(
+    figment: &Figment,
+    ups_settings: &UpsSettings,
+) -> Result<Vec<UpsSettings>, Vec<String>> {
+    // Each `[[units]]` table overrides the top-level settings for one UPS. Without any, the
+    // top-level settings describe the only UPS.
+    if ups_settings.units.is_empty() {
+        return Ok(vec![figment.extract().map_err(figment_problems)?]);
+    }
+
+    ups_settings
+        .units
+        .iter()
+        .enumerate()
+        .map(|(i, unit)| {
+            let mut settings: UpsSettings = figment
+                .clone()
+                .merge(Serialized::defaults(unit))
+                .extract()
+                .map_err(figment_problems)?;
+            settings.units.clear();
+            settings
+                .label
+                .get_or_insert_with(|| format!("ups{}", i + 1));
+            Ok(settings)
+        })
+        .collect()
+}
+
+fn invalid_config(path: &Path, problems: Vec<String>) -> ! {
+    error!("{}", describe_problems(path, &problems));
+    exit(1)
+}
+
+fn describe_problems(path: &Path, problems: &[String]) -> String {
+    // List everything wrong at once, rather than making the user fix one problem per run.
+    format!(
+        "Invalid config in {}:\n  - {}",
+        path.display(),
+        problems.join("\n  - ")
+    )
+}
+
+fn reload_settings(
+    cli: &Cli,
+    unit_count: usize,
+) -> Result<(UpsSettings, Vec<UpsSettings>, mailer::MailerSettings), String> {
+    // Read both config files again as at startup, for a reload. Adding or removing units means
+    // connecting afresh, so that needs a restart.
+    let (ups_settings, unit_settings) = load_ups_settings(&cli.ups_settings_path)
+        .map_err(|problems| describe_problems(&cli.ups_settings_path, &problems))?;
+    if cli.simulate.is_none() && unit_settings.len() != unit_count {
+        return Err(describe_problems(
+            &cli.ups_settings_path,
+            &[format!(
+                "the number of units changed from {} to {}, which needs a restart",
+                unit_count,
+                unit_settings.len()
+            )],
+        ));
+    }
+    let mailer_settings = load_mailer_settings(&cli.mailer_settings_path, cli.no_mail)
+        .map_err(|problems| describe_problems(&cli.mailer_settings_path, &problems))?;
+    Ok((ups_settings, unit_settings, mailer_settings))
+}
+
+fn figment_problems(e: figment::Error) -> Vec<String> {
+    // Figment collects every field it couldn't read, each naming the field and where it's from.
+    e.into_iter().map(|e| e.to_string()).collect()
+}
+
+fn reload_units(
+    cli: &Cli,
+    units: &mut [Unit],
+    notifiers: &mut MultiNotifier,
+    machine_id: &mut String,
+) -> Result<(ShutdownPolicy, time::Duration), String> {
+    // Re-read the settings and swap them in, returning the new shutdown policy and heartbeat
+    // interval. If they don't validate, keep the current ones and say so.
+    let (ups_settings, unit_settings, mailer_settings) = match reload_settings(cli, units.len()) {
+        Ok(settings) => settings,
+        Err(problems) => {
+            warn!("Keeping the current settings. {}", problems);
+            notifiers.send(
+                EventKind::ReloadFailed,
+                Severity::Warning,
+                "Failed to reload the UPS monitor settings.",
+                &format!("Keeping the current settings.\n\n{}", problems),
             );
+            return Err(problems);
+        }
+    };
+    let shutdown_policy = ups_settings.shutdown_policy;
+    let heartbeat_interval =
+        time::Duration::from_secs(ups_settings.heartbeat_interval_hours * 60 * 60);
+    // A simulation runs on the top-level settings, as at startup.
+    let unit_settings = if cli.simulate.is_some() {
+        vec![ups_settings]
+    } else {
+        unit_settings
+    };
+
+    *machine_id = notifier::machine_id(mailer_settings.machine_id.clone());
+    notifiers.flush(true);
+    *notifiers = build_notifiers(&mailer_settings, machine_id, cli.dry_run);
+    notifiers.update(&units[0].ups.status);
+    let labelled = units.len() > 1;
+    for (unit, settings) in units.iter_mut().zip(unit_settings) {
+        unit.reload(
+            settings,
+            &mailer_settings,
+            machine_id,
+            labelled,
+            cli.dry_run,
+        );
+    }
+    info!("Reloaded the settings.");
+    Ok((shutdown_policy, heartbeat_interval))
+}
+
+fn control_reply(request: &control::Request, units: &mut [Unit]) -> control::Reply {
+    // Act on a request from `ups ctl`, for the unit it names or else the first.
+    let unit = match &request.unit {
+        Some(label) => match units.iter_mut().find(|unit| &unit.label == label) {
+            Some(unit) => unit,
+            None => return control::Reply::failed(format!("No unit is labelled {:?}.", label)),
+        },
+        None => &mut units[0],
+    };
+
+    let result = match request.command {
+        control::Command::Status => return control::Reply::status(&unit.ups.status),
+        control::Command::Test { minutes, until_low } => unit
+            .ups
+            .start_test(test_kind(minutes, until_low))
+            .map(|_| "Started UPS self-test.".to_string()),
+        control::Command::Beep { enabled } => match enabled {
+            Some(enabled) => unit.ups.set_beep(enabled),
+            None => unit
+                .ups
+                .toggle_beep()
+                .and_then(|_| unit.ups.get_ups_status()),
+        }
+        .map(|_| {
+            format!(
+                "Beeper is now {}.",
+                if unit.ups.status.beeper_on {
+                    "on"
+                } else {
+                    "off"
+                }
+            )
+        }),
+        control::Command::CancelShutdown => unit
+            .ups
+            .cancel_shutdown()
+            .map(|_| "Cancelled the pending UPS shutdown.".to_string()),
+        control::Command::PollNow => {
+            unit.next_poll = time::Instant::now();
+            unit.next_full_poll = None;
+            Ok("Polling now.".to_string())
+        }
+        // Reloads cover every unit, so the poll loop sees to them.
+        control::Command::Reload => unreachable!("Reloads are handled by the poll loop"),
+    };
+    match result {
+        Ok(message) => {
+            info!("{} (via ups ctl)", message);
+            control::Reply::done(message)
+        }
+        Err(e) => control::Reply::failed(e.report()),
+    }
+}
+
+fn unit_notifiers(
+    label: &str,
+    mailer_settings: &mailer::MailerSettings,
+    machine_id: &str,
+    labelled: bool,
+    dry_run: bool,
+) -> MultiNotifier {
+    // Label alerts with the unit they came from, once there's more than one.
+    if labelled {
+        build_notifiers(
+            mailer_settings,
+            &format!("{} ({})", machine_id, label),
+            dry_run,
+        )
+    } else {
+        build_notifiers(mailer_settings, machine_id, dry_run)
+    }
+}
+
+struct Unit {
+    // One monitored UPS, with its own settings, state, and labelled notifiers.
+    label: String,
+    settings: UpsSettings,
+    ups: device::Device,
+    monitor: Monitor,
+    notifiers: MultiNotifier,
+    next_poll: time::Instant,
+    // When the next full poll is due, while quick checks fill the time until then.
+    next_full_poll: Option<time::Instant>,
+    // Whether this unit's last poll was a full one, rather than a quick check finding no change.
+    polled_in_full: bool,
+    // Whether this unit's last poll called for a shutdown.
+    failed: bool,
+    // Whether this unit's last poll couldn't read it, even after reconnecting.
+    comm_failed: bool,
+    // Failed reads in a row, counting down the `comm_failure_retries` budget.
+    comm_failures: u32,
+    // When we last failed to read this unit, and why, for the status page.
+    last_error: Option<(time::SystemTime, String)>,
+    // Spreads out polls on line power, per `poll_jitter_seconds`.
+    jitter: jitter::Jitter,
+}
+
+impl Unit {
+    fn new(
+        settings: UpsSettings,
+        ups: device::Device,
+        mailer_settings: &mailer::MailerSettings,
+        machine_id: &str,
+        labelled: bool,
+        dry_run: bool,
+    ) -> Unit {
+        let label = settings
+            .label
+            .clone()
+            .unwrap_or_else(|| ups.status.identity());
+        let notifiers = unit_notifiers(&label, mailer_settings, machine_id, labelled, dry_run);
+        notifiers.update(&ups.status);
+
+        let mut ups = ups;
+        if let Some(path) = &settings.health_state_path {
+            ups.load_health(path, &label);
+        }
+
+        Unit {
+            label,
+            monitor: Monitor::new(&settings),
+            jitter: jitter::Jitter::new(time::Duration::from_secs(settings.poll_jitter_seconds)),
+            settings,
+            ups,
+            notifiers,
+            next_poll: time::Instant::now(),
+            next_full_poll: None,
+            polled_in_full: false,
+            failed: false,
+            comm_failed: false,
+            comm_failures: 0,
+            last_error: None,
+        }
+    }
+
+    fn reload(
+        &mut self,
+        settings: UpsSettings,
+        mailer_settings: &mailer::MailerSettings,
+        machine_id: &str,
+        labelled: bool,
+        dry_run: bool,
+    ) {
+        // Swap in new settings, keeping the connection and the monitor's latches, so nothing
+        // already alerted on is sent again. Queued alerts go out through the old notifiers first.
+        self.notifiers.flush(true);
+        self.notifiers =
+            unit_notifiers(&self.label, mailer_settings, machine_id, labelled, dry_run);
+        self.notifiers.update(&self.ups.status);
+        self.jitter = jitter::Jitter::new(time::Duration::from_secs(settings.poll_jitter_seconds));
+        self.settings = settings;
+    }
+
+    fn quick_check(&mut self, poll_delay_scale: f64) -> bool {
+        // Check just the utility, mode and fault between full polls, returning whether they're
+        // unchanged. Any change, or failure to read them, calls for a full poll straight away.
+        let status = &self.ups.status;
+        let before = (status.utility_failed, status.ups_mode, status.fault);
+        if let Err(e) = self.ups.get_ups_mode_quick() {
+            debug!("Quick check failed, polling in full: {}", e.report());
+            return false;
+        }
+        let status = &self.ups.status;
+        if (status.utility_failed, status.ups_mode, status.fault) != before {
+            debug!("UPS mode or utility changed, polling in full.");
+            return false;
+        }
 
-            shutdown(
-                &ups,
-                ups_settings.minutes_to_shutdown,
-                ups_settings.minutes_to_restart,
+        let next_check = time::Instant::now()
+            + time::Duration::from_secs(self.settings.quick_poll_delay).mul_f64(poll_delay_scale);
+        self.next_poll = match self.next_full_poll {
+            Some(due) => next_check.min(due),
+            None => next_check,
+        };
+        true
+    }
+
+    fn poll(&mut self, poll_delay_scale: f64) -> bool {
+        // Poll the UPS once and act on it, returning whether it calls for a shutdown.
+        // Once failed, we keep polling in case it recovers, but don't repeat the alerts.
+        if let Some(due) = self.next_full_poll {
+            if time::Instant::now() < due && self.quick_check(poll_delay_scale) {
+                self.polled_in_full = false;
+                return self.failed;
+            }
+        }
+        self.next_full_poll = None;
+        self.polled_in_full = true;
+
+        let settings = &self.settings;
+        let ups = &mut self.ups;
+        let notifiers = &self.notifiers;
+        let quiet = self.failed;
+        self.next_poll = time::Instant::now()
+            + time::Duration::from_secs(settings.utility_failed_poll_delay)
+                .mul_f64(poll_delay_scale);
+
+        if let Err(e) = ups.get_ups_status() {
+            warn!(
+                unit = %self.label,
+                error = %e.report(),
+                "Failed to read UPS {}.",
+                self.label
             );
+            self.comm_failures += 1;
+            self.last_error = Some((time::SystemTime::now(), e.report()));
+            // Alert once as it starts failing, however many rounds it takes to recover.
+            if self.comm_failures == 1 && !quiet {
+                let _ = run_hook(settings, Hook::CommLost, &ups.status, false);
+                notify(
+                    notifiers,
+                    &settings.events,
+                    EventKind::CommFailed,
+                    Severity::Warning,
+                    "UPS communication degraded - retrying.",
+                    &format!("{}\n\n{}", e.report(), ups.status),
+                );
+            }
+
+            // Reconnect in case the handle went stale, e.g. after a driver reset.
+            if let Err(e) = ups.connect().and_then(|_| ups.get_ups_status()) {
+                self.comm_failed = true;
+
+                // Back off between rounds until the budget's spent, but don't wait around if we
+                // were already on battery, as we can't tell how long it has left.
+                let round = self.comm_failures;
+                if round <= settings.comm_failure_retries && !ups.status.utility_failed {
+                    let delay = comm_failure_delay(settings.communication_failed_poll_delay, round);
+                    warn!(
+                        unit = %self.label,
+                        error = %e.report(),
+                        "Failed to reconnect to UPS {} (round {}/{}), retrying in {}s.",
+                        self.label,
+                        round,
+                        settings.comm_failure_retries,
+                        delay
+                    );
+                    self.next_poll = time::Instant::now()
+                        + time::Duration::from_secs(delay).mul_f64(poll_delay_scale);
+                    return self.failed;
+                }
+
+                error!(
+                    unit = %self.label,
+                    error = %e.report(),
+                    utility_failed = ups.status.utility_failed,
+                    remaining_capacity = ups.status.remaining_capacity,
+                    "Failed to reconnect to UPS {}.",
+                    self.label
+                );
+                if !quiet {
+                    notify(
+                        notifiers,
+                        &settings.events,
+                        EventKind::Shutdown,
+                        Severity::Critical,
+                        "UPS communication failed - shutting down.",
+                        &format!("{}\n\n{}", e.report(), ups.status),
+                    );
+                }
+                return true;
+            }
         }
 
-        if ups.status.replace_battery {
-            mailer.send(
-                "Battery needs replacement - shutting down.",
-                &format!("{:#?}", ups.status).to_string(),
+        if self.comm_failures > 0 {
+            self.comm_failures = 0;
+            notify(
+                notifiers,
+                &settings.events,
+                EventKind::CommRestored,
+                Severity::Info,
+                "UPS communication restored.",
+                &ups.status.to_string(),
             );
+        }
+        self.comm_failed = false;
+        debug!(
+            unit = %self.label,
+            mode = ?ups.status.ups_mode,
+            utility_failed = ups.status.utility_failed,
+            battery_low = ups.status.battery_low,
+            charging = ups.status.charging,
+            remaining_capacity = ups.status.remaining_capacity,
+            seconds_to_empty = ups.status.seconds_to_empty,
+            input_voltage = ups.status.input_voltage,
+            output_load = ups.status.output_load,
+            battery_voltage = ups.status.battery_voltage,
+            "Polled UPS {}.",
+            self.label
+        );
+        notifiers.update(&ups.status);
+
+        if let Some(path) = &settings.health_state_path {
+            ups.save_health(path, &self.label);
+        }
+
+        let actions = self.monitor.evaluate(&ups.status, settings);
+        let failed = actions.contains(&Action::Shutdown);
+        for action in actions {
+            match action {
+                Action::SendAlert(_) if quiet && failed => {}
+                Action::SendAlert(alert) => {
+                    notifiers.send(alert.event, alert.severity, &alert.subject, &alert.message)
+                }
+                Action::Shutdown => {}
+                Action::CancelShutdown => {
+                    let result = ups.cancel_shutdown();
+                    if let Some(alert) = self.monitor.cancel_finished(result, &ups.status, settings)
+                    {
+                        notifiers.send(alert.event, alert.severity, &alert.subject, &alert.message)
+                    }
+                }
+                Action::RunHook(hook) => {
+                    let _ = run_hook(settings, hook, &ups.status, false);
+                }
+                Action::SelfTest => {
+                    let result = ups.start_test(TestKind::Quick);
+                    if let Some(alert) =
+                        self.monitor
+                            .self_test_started(result, &ups.status, settings)
+                    {
+                        notifiers.send(alert.event, alert.severity, &alert.subject, &alert.message)
+                    }
+                }
+                Action::Wait(delay) => {
+                    // Only jitter the usual delay, not the countdown on battery, which needs to
+                    // keep time.
+                    let delay = if !ups.status.utility_failed
+                        && delay == time::Duration::from_secs(settings.poll_delay)
+                    {
+                        delay + self.jitter.next()
+                    } else {
+                        delay
+                    };
+                    self.next_poll = time::Instant::now() + delay.mul_f64(poll_delay_scale);
+
+                    // Fill a long wait with quick checks, so we still react within a second or so.
+                    let quick = time::Duration::from_secs(settings.quick_poll_delay);
+                    if settings.quick_poll_delay > 0 && delay > quick {
+                        self.next_full_poll = Some(self.next_poll);
+                        self.next_poll = time::Instant::now() + quick.mul_f64(poll_delay_scale);
+                    }
+                }
+            }
+        }
+
+        failed
+    }
+}
+
+fn should_shut_down(policy: ShutdownPolicy, units: &[Unit]) -> bool {
+    // Only critical units count, and there's always at least one.
+    let mut critical = units.iter().filter(|unit| unit.settings.critical);
+    match policy {
+        ShutdownPolicy::Any => critical.any(|unit| unit.failed),
+        ShutdownPolicy::All => critical.all(|unit| unit.failed),
+    }
+}
+
+fn main() {
+    // Use the cli to make config paths configurable
+    let cli = Cli::parse();
+
+    // An explicit `--log-level` wins over `RUST_LOG`.
+    let filter = match &cli.log_level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    // Log to stderr, keeping stdout for what the subcommands print, e.g. JSON for scripts.
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match cli.log_format {
+        LogFormat::Human => subscriber.init(),
+        // Fields alongside the message at the top level, so they're easy to query on.
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+
+    if cli.config_check {
+        config_check(&cli);
+    }
+
+    // Load in the optional ups config, merging with defaults.
+    let (ups_settings, unit_settings) = load_ups_settings(&cli.ups_settings_path)
+        .unwrap_or_else(|problems| invalid_config(&cli.ups_settings_path, problems));
+
+    // Handle one-off commands before we bother with notifications.
+    if cli.list_devices {
+        if let Err(e) = list_devices(&ups_settings) {
+            error!("Failed to list HID devices: {}", e.report());
+            exit(1)
+        }
+        exit(0)
+    }
+    match cli.command {
+        Some(Commands::Test {
+            wait,
+            minutes,
+            until_low,
+        }) => run_test(&cli, &ups_settings, test_kind(minutes, until_low), wait),
+        Some(Commands::CancelTest) => cancel_test(&ups_settings),
+        Some(Commands::Beep { mute, unmute }) => beep(&ups_settings, beep_enabled(mute, unmute)),
+        Some(Commands::Status { json, bit }) => print_status(&ups_settings, json, bit),
+        Some(Commands::Raw {
+            ref command,
+            expect_length,
+            repeat,
+        }) => raw_command(&ups_settings, command, expect_length, repeat),
+        Some(Commands::History { since, json }) => print_history(&ups_settings, since, json),
+        Some(Commands::Ctl {
+            ref unit,
+            ref command,
+        }) => ctl(&ups_settings, unit, command),
+        None => {}
+    }
+
+    // Load in the mailer config, unless told to only log alerts. Without one, alerts are logged too.
+    let mailer_settings = load_mailer_settings(&cli.mailer_settings_path, cli.no_mail)
+        .unwrap_or_else(|problems| invalid_config(&cli.mailer_settings_path, problems));
+
+    // Simulations and debug builds never shut down either, but only an explicit dry run says so in
+    // its alerts.
+    let dry_run = cfg!(debug_assertions) || cli.dry_run || cli.simulate.is_some();
+    let mut machine_id = notifier::machine_id(mailer_settings.machine_id.clone());
+    let mut notifiers = build_notifiers(&mailer_settings, &machine_id, cli.dry_run);
+    if mailer_settings.relay.is_empty()
+        && mailer_settings.notifiers.is_empty()
+        && mailer_settings.webhook.is_none()
+        && mailer_settings.chat.is_none()
+    {
+        warn!("No email relay, webhook, chat or notifiers configured - alerts will only be logged.")
+    }
+
+    // Initialise the UPS connections, or the simulated one.
+    let mut poll_delay_scale = 1.0;
+    let mut units: Vec<Unit> = match &cli.simulate {
+        Some(path) => {
+            let simulation = simulate::Simulation::load(path);
+            poll_delay_scale = simulation.poll_delay_scale;
+            let ups = connected(device::Device::spawn(
+                "simulated",
+                ups_settings.reply_timeout(),
+                move || ups::UPS::new(Box::new(simulate::SimulatedProtocol::new(simulation))),
+            ));
+            vec![Unit::new(
+                ups_settings,
+                ups,
+                &mailer_settings,
+                &machine_id,
+                false,
+                cli.dry_run,
+            )]
+        }
+        None => {
+            let labelled = unit_settings.len() > 1;
+            unit_settings
+                .into_iter()
+                .map(|settings| {
+                    let ups = connected(connect_device(&settings, Some(&notifiers)));
+                    Unit::new(
+                        settings,
+                        ups,
+                        &mailer_settings,
+                        &machine_id,
+                        labelled,
+                        cli.dry_run,
+                    )
+                })
+                .collect()
+        }
+    };
+
+    for unit in &mut units {
+        if unit.settings.silence_beeper_on_start {
+            if let Err(e) = unit.ups.set_beep(false) {
+                error!("Failed to silence beeper on {}: {}", unit.label, e.report())
+            }
+        }
+    }
+
+    // The integrations below report the first unit.
+    let ups_settings = &units[0].settings;
+
+    // Start the metrics endpoint, if enabled.
+    #[cfg(feature = "metrics")]
+    let metrics_snapshot = metrics::serve(&ups_settings.metrics, &units[0].ups.status);
+    #[cfg(not(feature = "metrics"))]
+    if ups_settings.metrics.enabled {
+        warn!("Metrics are enabled but this build lacks the `metrics` feature.")
+    }
+
+    // Connect the MQTT publisher, if enabled.
+    #[cfg(feature = "mqtt")]
+    let mqtt_publisher =
+        mqtt::Publisher::connect(&ups_settings.mqtt, &machine_id, &units[0].ups.status);
+    #[cfg(not(feature = "mqtt"))]
+    if ups_settings.mqtt.enabled {
+        warn!("MQTT is enabled but this build lacks the `mqtt` feature.")
+    }
+
+    // Serve the status to NUT clients, if configured.
+    #[cfg(feature = "nut")]
+    let nut_snapshot = nut_server::serve(&ups_settings.nut_server, &units[0].ups.status);
+    #[cfg(not(feature = "nut"))]
+    if ups_settings.nut_server.listen.is_some() {
+        warn!("The NUT server is configured but this build lacks the `nut` feature.")
+    }
+
+    // Serve the status page, if configured.
+    #[cfg(feature = "http")]
+    let http_snapshot = http::serve(&ups_settings.http, &units[0].ups.status);
+    #[cfg(not(feature = "http"))]
+    if ups_settings.http.listen.is_some() {
+        warn!("The status page is configured but this build lacks the `http` feature.")
+    }
+
+    // Take commands from `ups ctl`, if configured.
+    let control = control::listen(&ups_settings.control);
+
+    // Write each poll to InfluxDB, if configured.
+    let influx_writer = influx::Writer::new(&ups_settings.influx, &machine_id);
+
+    #[cfg(not(feature = "logind"))]
+    if ups_settings.shutdown_method == ShutdownMethod::Logind {
+        warn!("Shutdown through logind is set but this build lacks the `logind` feature, so the shutdown command will be used.")
+    }
+
+    // Log each poll to a file, if configured.
+    let mut status_log = status_log::StatusLog::new(&ups_settings.status_log);
+
+    // And record it in the history database, if configured.
+    #[cfg(feature = "history")]
+    let history_writer = history::Writer::new(&ups_settings.history);
+    #[cfg(not(feature = "history"))]
+    if ups_settings.history.path.is_some() {
+        warn!("The history database is configured but this build lacks the `history` feature.")
+    }
+
+    // Flag a stop on SIGTERM/SIGINT, so we can finish the current poll and exit cleanly.
+    let stop = stop::Stop::on_signals();
+    // And re-read the settings on SIGHUP.
+    let reload = stop::Reload::on_signal();
+
+    for unit in &units {
+        match &unit.settings.label {
+            Some(label) => info!(
+                "UPS monitor running and connected to {} ({})!",
+                unit.ups.status.identity(),
+                label
+            ),
+            None => info!(
+                "UPS monitor running and connected to {}!",
+                unit.ups.status.identity()
+            ),
+        }
+    }
+
+    // And now enter the endless checking loop...
+    let watchdog = (ups_settings.watchdog_timeout_seconds > 0).then(|| {
+        watchdog::Watchdog::spawn(
+            time::Duration::from_secs(ups_settings.watchdog_timeout_seconds),
+            ups_settings.watchdog_abort,
+            build_notifiers(&mailer_settings, &machine_id, cli.dry_run),
+        )
+    });
+    let mut shutdown_policy = ups_settings.shutdown_policy;
+    let started = time::Instant::now();
+    let mut heartbeat_interval =
+        time::Duration::from_secs(ups_settings.heartbeat_interval_hours * 60 * 60);
+    let mut last_heartbeat = started;
+    let mut last_instant = time::Instant::now();
+    let mut last_wall = time::SystemTime::now();
+    loop {
+        if stop.is_set() {
+            break;
+        }
+        if let Some(watchdog) = &watchdog {
+            watchdog.pet();
+        }
+
+        // Poll the units round-robin, each when its own delay is up.
+        let index = (0..units.len())
+            .min_by_key(|&i| units[i].next_poll)
+            .expect("No UPS units");
+        // Wake early on a stop, rather than leave the service manager waiting out the delay, or
+        // to answer `ups ctl`. Waiting on a poll isn't a stall, however long the delay or backoff,
+        // so keep petting the watchdog meanwhile.
+        if stop.wait(
+            units[index]
+                .next_poll
+                .saturating_duration_since(time::Instant::now()),
+            || {
+                if let Some(watchdog) = &watchdog {
+                    watchdog.pet();
+                }
+                control.as_ref().is_some_and(control::Control::pending)
+            },
+        ) {
+            break;
+        }
+
+        // Answer requests from `ups ctl`, leaving reloads to go with any from SIGHUP.
+        let mut reloads = Vec::new();
+        if let Some(control) = &control {
+            for pending in control.take() {
+                if pending.request.command == control::Command::Reload {
+                    reloads.push(pending);
+                } else {
+                    let reply = control_reply(&pending.request, &mut units);
+                    pending.reply(reply);
+                }
+            }
+        }
+
+        // Swap in edited settings, keeping the current ones if the new ones don't validate.
+        if reload.take() || !reloads.is_empty() {
+            let result = reload_units(&cli, &mut units, &mut notifiers, &mut machine_id);
+            if let Ok((policy, interval)) = result {
+                shutdown_policy = policy;
+                heartbeat_interval = interval;
+            }
+            for pending in reloads {
+                pending.reply(match &result {
+                    Ok(_) => control::Reply::done("Reloaded the settings."),
+                    Err(problems) => control::Reply::failed(problems.clone()),
+                });
+            }
+        }
 
-            shutdown(
-                &ups,
-                ups_settings.minutes_to_shutdown,
-                ups_settings.minutes_to_restart,
+        // Woken early to answer, so go back to waiting unless a poll is now due.
+        if units[index].next_poll > time::Instant::now() {
+            continue;
+        }
+        notifiers.flush(false);
+
+        // The monotonic clock stops while the machine sleeps but the wall clock doesn't, so a
+        // gap between them means we've just resumed, likely with a stale USB handle.
+        let asleep = last_wall
+            .elapsed()
+            .unwrap_or_default()
+            .saturating_sub(last_instant.elapsed());
+        if asleep > time::Duration::from_secs(RESUME_GAP_SECONDS) {
+            info!(
+                "Resumed after ~{}s asleep - reconnecting to the UPS.",
+                asleep.as_secs()
             );
+            for unit in &mut units {
+                unit.ups.disconnect();
+                let ups = &mut unit.ups;
+                // Only try once, as waiting here would hold up the other units and any stop. A
+                // UPS still missing is left to the poll's communication failure rounds.
+                if let Err(e) = retry_connect(&unit.settings, 1, None, || {
+                    ups.connect()?;
+                    ups.get_ups_status()
+                }) {
+                    warn!(
+                        "Failed to reconnect to UPS {} after resuming: {}",
+                        unit.label,
+                        e.report()
+                    )
+                }
+            }
         }
+        last_instant = time::Instant::now();
+        last_wall = time::SystemTime::now();
 
-        if ups.status.remaining_capacity < ups_settings.battery_low_threshold {
-            if ups.status.charging {
-                mailer.send(
-                    "Battery low capacity.",
-                    &format!("{:#?}", ups.status).to_string(),
-                );
-            } else {
-                mailer.send(
-                    "Battery low capacity and not charging - shutting down.",
-                    &format!("{:#?}", ups.status).to_string(),
+        let unit = &mut units[index];
+        unit.notifiers.flush(false);
+
+        let failed = unit.poll(poll_delay_scale);
+        if failed && !unit.failed {
+            warn!("UPS {} wants a shutdown.", unit.label);
+        } else if !failed && unit.failed {
+            info!("UPS {} no longer wants a shutdown.", unit.label);
+        }
+        unit.failed = failed;
+
+        // A quick check only gets this far having found nothing new, so skip the integrations.
+        if index == 0 && units[0].polled_in_full {
+            notifiers.update(&units[0].ups.status);
+
+            if let Some(log) = &mut status_log {
+                log.record(&units[0].ups.status);
+            }
+
+            // Skip polls we couldn't read, rather than repeat a stale status into the history.
+            if let Some(writer) = &influx_writer {
+                if !units[0].comm_failed {
+                    writer.record(&units[0].ups.status);
+                }
+            }
+            #[cfg(feature = "history")]
+            if let Some(writer) = &history_writer {
+                if !units[0].comm_failed {
+                    writer.record(&units[0].ups.status);
+                }
+            }
+
+            #[cfg(feature = "nut")]
+            if let Some(snapshot) = &nut_snapshot {
+                let mut snapshot = snapshot.lock().unwrap();
+                snapshot.status = units[0].ups.status.clone();
+                snapshot.stale = units[0].comm_failed;
+            }
+
+            #[cfg(feature = "http")]
+            if let Some(snapshot) = &http_snapshot {
+                let mut snapshot = snapshot.lock().unwrap();
+                snapshot.status = units[0].ups.status.clone();
+                snapshot.stale = units[0].comm_failed;
+                snapshot.last_poll = Some(time::SystemTime::now());
+                snapshot.last_error = units[0].last_error.clone();
+            }
+
+            #[cfg(feature = "metrics")]
+            if let Some(snapshot) = &metrics_snapshot {
+                *snapshot.lock().unwrap() = units[0].ups.status.clone();
+            }
+
+            #[cfg(feature = "mqtt")]
+            if let Some(publisher) = &mqtt_publisher {
+                publisher.publish(&units[0].ups.status, !units[0].comm_failed);
+            }
+        }
+
+        // Check in now and then, so a missing heartbeat says the monitor has died.
+        if !heartbeat_interval.is_zero() && last_heartbeat.elapsed() >= heartbeat_interval {
+            last_heartbeat = time::Instant::now();
+            let uptime = time::Duration::from_secs(started.elapsed().as_secs());
+            for unit in &units {
+                unit.notifiers.send(
+                    EventKind::Heartbeat,
+                    Severity::Info,
+                    "UPS monitor heartbeat.",
+                    &format!(
+                        "Monitor up for {}.\n\n{}\n\n{}",
+                        humantime::format_duration(uptime),
+                        unit.ups.health_report(),
+                        unit.ups.status
+                    ),
                 );
             }
         }
 
-        thread::sleep(time::Duration::from_secs(poll_delay));
+        if should_shut_down(shutdown_policy, &units) {
+            shutdown(&units, &units[0].settings, &notifiers, dry_run);
+
+            // A simulation ends at the shutdown it was replaying towards.
+            if cli.simulate.is_some() && units[0].settings.power_action == PowerAction::Shutdown {
+                break;
+            }
+
+            // Only a dry run, or a hibernate, suspend or custom action gets here. Start each unit
+            // afresh, so the countdown restarts if we're still on battery once the machine is back.
+            for unit in &mut units {
+                unit.monitor = Monitor::new(&unit.settings);
+                unit.failed = false;
+            }
+        }
+    }
+
+    for unit in &mut units {
+        // A shutdown left pending would cut the power with nobody watching, so call it off.
+        if unit.ups.status.shutdown_active {
+            match unit.ups.cancel_shutdown() {
+                Ok(()) => info!("Cancelled the pending shutdown of UPS {}.", unit.label),
+                Err(e) => notify(
+                    &unit.notifiers,
+                    &unit.settings.events,
+                    EventKind::CancelShutdown,
+                    Severity::Critical,
+                    "Failed to cancel the pending UPS shutdown while stopping.",
+                    &format!("{}\n\n{}", e.report(), unit.ups.status),
+                ),
+            }
+        }
+
+        notify(
+            &unit.notifiers,
+            &unit.settings.events,
+            EventKind::Stopping,
+            Severity::Info,
+            "UPS monitor stopping.",
+            &unit.ups.status.to_string(),
+        );
+        unit.notifiers.flush(true);
+
+        // Release the USB interface before we exit.
+        unit.ups.disconnect();
+    }
+
+    #[cfg(feature = "mqtt")]
+    if let Some(publisher) = &mqtt_publisher {
+        publisher.close();
+    }
+
+    if let Some(writer) = &influx_writer {
+        writer.close();
+    }
+
+    #[cfg(feature = "history")]
+    if let Some(writer) = &history_writer {
+        writer.close();
     }
 }
@@ -0,0 +1,1547 @@
+use crate::protocol::{TestKind, UpsProtocol};
+use crate::status;
+use crate::transport::{Connector, Transport};
+use crate::ups::UPSError;
+
+use std::{
+    num::ParseIntError,
+    str::{self, FromStr},
+    thread, time,
+};
+use tracing::{trace, warn};
+
+// The UPS uses ASCII characters for communication.
+const TERMINATOR: u8 = 13; // Carriage return
+const SEPARATOR: u8 = 32; // Space
+const RESPONSE_PREFIXES: [u8; 2] = [b'#', b'(']; // Leading a response, before its fields.
+const PROTOCOL_ID: u8 = 72; // 'H'
+const STATUS_PREFIX: u8 = 40; // '(', starting QS and QI responses
+const INFO_PREFIX: u8 = 35; // '#', starting F and I responses
+const F_FIELD_COUNT: usize = 4; // Ratings in an F response, though some units differ.
+
+// Messages received are at most 8 values.
+// Longer messages are hence split with the above terminator.
+const MAX_DATA_LENGTH: usize = 8;
+
+const DRAIN_TIMEOUT: i32 = 10; // Milliseconds to wait for stale data after a response.
+const MAX_RECONNECT_DELAY_MS: u64 = 5000; // The most we'll back off to between reconnects.
+const COMMANDS_PER_POLL: u32 = 3; // QS, QI and QBV, each of which may need retrying.
+pub const MIN_SHUTDOWN_TENTHS: i32 = 2; // The shortest shutdown delay the UPS takes, in tenths of a minute.
+pub const MAX_SHUTDOWN_MINUTES: f32 = 10.0; // The longest shutdown delay the UPS takes.
+pub const MAX_RESTART_MINUTES: i32 = 9999; // The longest restart delay the UPS takes.
+
+pub struct Timing {
+    // How patiently we read replies, as slow units and bridges need longer.
+    pub timeout_ms: i32,         // Milliseconds to wait for each read.
+    pub retries: usize,          // Attempts at a command that gets no reply, reconnecting between.
+    pub max_reads: usize,        // An arbitrary max number of messages to try receive per reply.
+    pub reconnect_delay_ms: u64, // Milliseconds before the first reconnect, doubling after each.
+    pub max_reconnect_ms: u64,   // Milliseconds to keep reconnecting for, after `retries`.
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            timeout_ms: 500,
+            retries: 3,
+            max_reads: 20,
+            reconnect_delay_ms: 200,
+            max_reconnect_ms: 10000,
+        }
+    }
+}
+
+impl Timing {
+    pub fn poll_timeout(&self) -> time::Duration {
+        // The longest a status poll can take to give up on a unit gone quiet, so anything longer
+        // is a read that's stuck. Each attempt clears stale data then waits on the reply, and
+        // attempts carry on with backoff between them until the reconnect budget is spent.
+        let attempt = 2 * self.timeout_ms.max(0) as u64;
+        let mut backoff = 0;
+        let mut delay = self.reconnect_delay_ms;
+        for _ in 1..self.retries {
+            backoff += delay;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY_MS);
+        }
+        let command = (self.retries as u64 + 1) * attempt + backoff + self.max_reconnect_ms;
+        time::Duration::from_millis(command) * COMMANDS_PER_POLL
+    }
+}
+
+// The protocol IDs known to answer `M` while speaking the same dialect, beyond our own - `V` is
+// the Voltronic QS firmware. Others can be let through with `protocol_override` until they're
+// confirmed.
+const COMPATIBLE_PROTOCOL_IDS: &[u8] = b"V";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolOverride {
+    // Accept this protocol ID as well, for firmware answering `M` with something else.
+    Id(u8),
+    // Accept any protocol ID, skipping the check.
+    Any,
+}
+
+impl ProtocolOverride {
+    pub fn parse(value: &str) -> Result<ProtocolOverride, String> {
+        // Either a single character, as `M` answers, or "any".
+        match value.as_bytes() {
+            b"any" => Ok(ProtocolOverride::Any),
+            [id] if id.is_ascii_graphic() => Ok(ProtocolOverride::Id(*id)),
+            _ => Err(format!(
+                "protocol_override ({:?}) must be a single character, e.g. \"V\", or \"any\"",
+                value
+            )),
+        }
+    }
+
+    fn accepts(&self, id: u8) -> bool {
+        match self {
+            ProtocolOverride::Id(accepted) => *accepted == id,
+            ProtocolOverride::Any => true,
+        }
+    }
+}
+
+pub struct MegatecProtocol {
+    // The Megatec/Q1 ASCII protocol, spoken over any byte transport.
+    connector: Box<dyn Connector>,
+    device: Option<Box<dyn Transport>>,
+    timing: Timing,
+    protocol_override: Option<ProtocolOverride>,
+}
+
+impl MegatecProtocol {
+    pub fn new(
+        connector: Box<dyn Connector>,
+        timing: Timing,
+        protocol_override: Option<ProtocolOverride>,
+    ) -> MegatecProtocol {
+        MegatecProtocol {
+            connector,
+            device: None,
+            protocol_override,
+            timing,
+        }
+    }
+
+    fn send_command(&self, cmd: &str) -> Result<(), UPSError> {
+        if let Some(device) = &self.device {
+            // We first read a few times to make sure there's no partial messages waiting.
+            for i in 0..self.timing.max_reads {
+                trace!("CLEAR LOOP {}", i);
+                // Read one message.
+                let bytes_read =
+                    device.read_timeout(&mut [0; MAX_DATA_LENGTH], self.timing.timeout_ms)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                if i + 1 == self.timing.max_reads {
+                    warn!("Appears messages may still be waiting on device - may crash.")
+                }
+            }
+
+            trace!("=====================");
+            for chunk in cmd.as_bytes().chunks(MAX_DATA_LENGTH) {
+                // Each chunk is zero-padded to `MAX_DATA_LENGTH`.
+                let mut message: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
+
+                // Now we convert our command to bytes
+                message[..chunk.len()].copy_from_slice(chunk);
+
+                trace!("SEND {:?} {}", message, String::from_utf8_lossy(&message));
+
+                // And send it off to the UPS.
+                device.write(&message)?;
+            }
+
+            trace!(
+                "SEND {:?} {}",
+                [TERMINATOR],
+                String::from_utf8_lossy(&[TERMINATOR])
+            );
+            device.write(&[TERMINATOR])?;
+            Ok(())
+        } else {
+            Err(UPSError::NoDevice)
+        }
+    }
+
+    pub fn raw_command(&self, cmd: &str, length: Option<usize>) -> Result<Vec<u8>, UPSError> {
+        // Send a command and read the reply once, without retries, for debugging.
+        let mut res: Vec<u8> = Vec::new();
+        self.send_command(cmd)?;
+        self.get_response(cmd, &mut res, length)?;
+
+        Ok(res)
+    }
+
+    fn get_response(
+        &self,
+        cmd: &str,
+        res: &mut Vec<u8>,
+        length: Option<usize>,
+    ) -> Result<(), UPSError> {
+        if let Some(device) = &self.device {
+            // We at most `max_reads` times (till we read a terminator).
+            for i in 0..self.timing.max_reads {
+                trace!("READ LOOP {}", i);
+
+                // Temporary array for data.
+                let mut data: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
+
+                // Read one message.
+                let bytes_read = device.read_timeout(&mut data, self.timing.timeout_ms)?;
+                if bytes_read == 0 {
+                    return Err(UPSError::EmptyResponse);
+                }
+
+                trace!("READ {:?} {}", data, String::from_utf8_lossy(&data));
+
+                // Short reports are zero-padded, so drop the padding before looking at them.
+                let end = data[..bytes_read]
+                    .iter()
+                    .rposition(|&c| c != 0)
+                    .map_or(0, |i| i + 1);
+
+                // Add character by character to the output, and return on the terminator.
+                // Alternately return when message is the right length.
+                for &c in &data[..end] {
+                    if c == TERMINATOR {
+                        self.drain(device.as_ref())?;
+                        return Ok(());
+                    }
+                    if c == 0 {
+                        return Err(UPSError::Malformed {
+                            command: cmd.to_string(),
+                            raw: String::from_utf8_lossy(&data[..end]).to_string(),
+                            reason: "NUL byte mid-message".to_string(),
+                        });
+                    }
+                    res.push(c);
+                    if let Some(l) = length {
+                        if res.len() == l {
+                            self.drain(device.as_ref())?;
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+
+        Err(UPSError::NoDevice)
+    }
+
+    fn drain(&self, device: &dyn Transport) -> Result<(), UPSError> {
+        // Discard anything queued after the response, so it can't be read as the next one.
+        let mut data: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
+        for _ in 0..self.timing.max_reads {
+            let bytes_read = device.read_timeout(&mut data, DRAIN_TIMEOUT)?;
+            if bytes_read == 0 {
+                break;
+            }
+            trace!("DRAIN {:?} {}", data, String::from_utf8_lossy(&data));
+        }
+
+        Ok(())
+    }
+
+    fn send_and_receive(
+        &mut self,
+        cmd: &str,
+        data: &mut Vec<u8>,
+        length: Option<usize>,
+    ) -> Result<(), UPSError> {
+        // Send and receive from the UPS, reconnecting and retrying if it goes quiet or drops off.
+        // We back off between attempts, making at least `retries` of them and then carrying on
+        // until `max_reconnect_ms` is spent, so a brief USB renegotiation isn't a lost UPS.
+        let started = time::Instant::now();
+        let budget = time::Duration::from_millis(self.timing.max_reconnect_ms);
+        let mut delay = time::Duration::from_millis(self.timing.reconnect_delay_ms);
+        let mut attempt = 1;
+        loop {
+            // Drop anything from a failed attempt, so it doesn't prefix the retry.
+            data.clear();
+            let e = match self
+                .send_command(cmd)
+                .and_then(|_| self.get_response(cmd, data, length))
+            {
+                Ok(_) => return Ok(()),
+                Err(e) if e.is_link_failure() => e,
+                Err(e) => return Err(e),
+            };
+            if attempt >= self.timing.retries && started.elapsed() + delay > budget {
+                return Err(e);
+            }
+
+            warn!(
+                "{} for {:?}, reconnecting in {}ms (attempt {}).",
+                e,
+                cmd,
+                delay.as_millis(),
+                attempt
+            );
+            thread::sleep(delay);
+            delay = (delay * 2).min(time::Duration::from_millis(MAX_RECONNECT_DELAY_MS));
+            attempt += 1;
+
+            // The device may still be coming back, so a failed reconnect is just another attempt.
+            if let Err(e) = self.connect() {
+                if !e.is_link_failure() {
+                    return Err(e);
+                }
+                warn!("Failed to reconnect to the UPS: {}", e);
+            }
+        }
+    }
+
+    fn send_and_expect(
+        &mut self,
+        cmd: &str,
+        prefix: u8,
+        length: Option<usize>,
+    ) -> Result<Vec<u8>, UPSError> {
+        // Set up an array for our data, then send and receive from the UPS.
+        let mut data: Vec<u8> = Vec::new();
+        self.send_and_receive(cmd, &mut data, length)?;
+
+        // A UPS that doesn't accept the command replies with something else entirely, e.g. the
+        // command echoed back, so check for the prefix before parsing.
+        match data.first() {
+            Some(&c) if c == prefix => Ok(data),
+            Some(_) => Err(UPSError::Rejected { reply: data }),
+            None => Err(UPSError::EmptyResponse),
+        }
+    }
+
+    fn read_and_parse<T>(
+        &mut self,
+        cmd: &str,
+        prefix: u8,
+        length: Option<usize>,
+        parse: fn(&[Vec<u8>]) -> Result<T, UPSError>,
+    ) -> Result<T, UPSError> {
+        // Read and parse a response, noting the command in any error.
+        let data = self.read_response(cmd, prefix, length)?;
+        parse_response(cmd, &data, parse)
+    }
+
+    fn read_response(
+        &mut self,
+        cmd: &str,
+        prefix: u8,
+        length: Option<usize>,
+    ) -> Result<Vec<u8>, UPSError> {
+        self.send_and_expect(cmd, prefix, length)
+            .map_err(|e| e.during(cmd))
+    }
+}
+
+fn parse_response<T>(
+    cmd: &str,
+    data: &[u8],
+    parse: fn(&[Vec<u8>]) -> Result<T, UPSError>,
+) -> Result<T, UPSError> {
+    // Parse a response, noting the command and the raw response in any error.
+    parse(&split_response(data, true)).map_err(|e| UPSError::Malformed {
+        command: cmd.to_string(),
+        raw: String::from_utf8_lossy(data).to_string(),
+        reason: match e {
+            UPSError::Malformed { reason, .. } => reason,
+            e => e.report(),
+        },
+    })
+}
+
+pub fn parse_status(qs: &str, qi: &str, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+    // Parse the raw `QS` and `QI` responses into the status, without touching the UPS, so
+    // responses captured from a unit can be checked against what we make of them. Each may keep
+    // its leading '(' and trailing carriage return, e.g. "(230.0 230.0 230.0 012 50.0 27.3 --.-
+    // 10000001\r". Both are parsed before either is applied, so on an error the status is left as
+    // it was, rather than half-updated.
+    let qs = parse_response("QS", qs.trim_end().as_bytes(), parse_qs)?;
+    let qi = parse_response("QI", qi.trim_end().as_bytes(), parse_qi)?;
+    qs.apply(status);
+    qi.apply(status);
+    Ok(())
+}
+
+impl UpsProtocol for MegatecProtocol {
+    fn connect(&mut self) -> Result<(), UPSError> {
+        if self.device.is_some() {
+            self.device = None;
+        }
+
+        self.device = Some(self.connector.open()?);
+
+        // Check the protocol is right.
+        self.send_command("M")?;
+        let mut res: Vec<u8> = Vec::new();
+        self.get_response("M", &mut res, None)?;
+
+        // Firmware that answers differently may still speak our dialect, so only refuse IDs we
+        // neither know nor have been told to accept.
+        let overridden = |got| self.protocol_override.is_some_and(|o| o.accepts(got));
+        match res.first() {
+            Some(&PROTOCOL_ID) => {}
+            Some(&got) if COMPATIBLE_PROTOCOL_IDS.contains(&got) => {
+                warn!(
+                    "UPS reported protocol {:?}, continuing as it's known to be compatible.",
+                    got as char
+                )
+            }
+            Some(&got) if overridden(got) => {
+                warn!(
+                    "UPS reported protocol {:?}, continuing as protocol_override allows.",
+                    got as char
+                )
+            }
+            Some(&got) => return Err(UPSError::WrongProtocol { got }),
+            None => {
+                return Err(UPSError::Malformed {
+                    command: "M".to_string(),
+                    raw: String::new(),
+                    reason: "empty protocol ID".to_string(),
+                })
+            }
+        }
+
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        // Dropping the device releases the USB interface or serial port.
+        self.device = None;
+    }
+
+    fn read_info(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        let mut res: Vec<u8> = Vec::new();
+        self.send_and_receive("I", &mut res, None)
+            .map_err(|e| e.during("I"))?;
+
+        // Units that don't support `I` echo the command back rather than answering with a '#'.
+        if res.first() != Some(&INFO_PREFIX) {
+            return Ok(());
+        }
+
+        // The fields are fixed width (company 15, model 10, version 10) and may contain spaces,
+        // so we slice them out rather than splitting at `SEPARATOR`.
+        let text = str::from_utf8(&res[1..])?;
+        let field = |start: usize, length: usize| {
+            text.get(start..(start + length).min(text.len()))
+                .map(|f| f.trim().to_string())
+                .filter(|f| !f.is_empty())
+        };
+        status.manufacturer = field(0, 15);
+        status.model = field(16, 10);
+        status.firmware_version = field(27, 10);
+
+        Ok(())
+    }
+
+    fn read_ratings(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        self.read_and_parse("F", INFO_PREFIX, None, parse_f)?
+            .apply(status);
+
+        Ok(())
+    }
+
+    fn read_status(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        // Read both before parsing either, so a failed poll doesn't leave a half-updated status.
+        let qs = self.read_response("QS", STATUS_PREFIX, None)?;
+        let qi = self.read_response("QI", STATUS_PREFIX, Some(48))?;
+        parse_status(
+            &String::from_utf8_lossy(&qs),
+            &String::from_utf8_lossy(&qi),
+            status,
+        )
+    }
+
+    fn read_mode(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        // QS alone carries the utility and fault flags. It has no mode, so only move the last
+        // one between line and battery (or to fault) as the flags say, until the next full read.
+        let qs = self.read_and_parse("QS", STATUS_PREFIX, None, parse_qs)?;
+        status.utility_failed = qs.utility_failed;
+        status.ups_failed = qs.ups_failed;
+        status.ups_mode = match status.ups_mode {
+            _ if qs.ups_failed => status::UPSModes::Fault,
+            status::UPSModes::Line if qs.utility_failed => status::UPSModes::Inverting,
+            status::UPSModes::Inverting if !qs.utility_failed => status::UPSModes::Line,
+            mode => mode,
+        };
+        status.fault = qs.ups_failed || status.ups_mode == status::UPSModes::Fault;
+
+        Ok(())
+    }
+
+    fn read_battery_detail(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        self.read_and_parse("QBV", STATUS_PREFIX, None, parse_qbv)?
+            .apply(status);
+
+        Ok(())
+    }
+
+    fn shutdown(&self, delay: f32, restart: i32) -> Result<(), UPSError> {
+        self.send_command(shutdown_command(delay, restart).as_str())?;
+        Ok(())
+    }
+
+    fn describe_shutdown(&self, delay: f32, restart: i32) -> String {
+        format!("send {}", shutdown_command(delay, restart))
+    }
+
+    fn start_test(&self, kind: TestKind) -> Result<(), UPSError> {
+        // Timed tests only take two digits of minutes.
+        let cmd = match kind {
+            TestKind::Quick => "T".to_string(),
+            TestKind::Minutes(minutes) => format!("T{:02}", minutes.clamp(1, 99)),
+            TestKind::UntilLow => "TL".to_string(),
+        };
+        self.send_command(&cmd)?;
+        Ok(())
+    }
+
+    fn cancel_test(&self) -> Result<(), UPSError> {
+        self.send_command("CT")?;
+        Ok(())
+    }
+
+    fn cancel_shutdown(&self) -> Result<(), UPSError> {
+        self.send_command("C")?;
+        Ok(())
+    }
+
+    fn toggle_beep(&self) -> Result<(), UPSError> {
+        self.send_command("Q")?;
+        Ok(())
+    }
+}
+
+fn shutdown_command(delay: f32, restart: i32) -> String {
+    // Build `S<n>R<m>` as the spec gives it. The delay `n` is `.2` to `.9` minutes in tenths, or
+    // `01` to `10` in whole minutes, so we round to the nearest the UPS can take. The restart `m`
+    // is `0001` to `9999` minutes, with no way to say never.
+    // A restart of 0 means no restart, so we leave `R` off entirely, as some units take `R0000`
+    // to mean restart immediately. Without it, there's no timed restart, though units may still
+    // power back on by themselves once the utility returns.
+    let tenths = (delay * 10.0).round() as i32;
+    let delay = if tenths < 10 {
+        format!(".{}", tenths.max(MIN_SHUTDOWN_TENTHS))
+    } else {
+        format!(
+            "{:02}",
+            (tenths as f32 / 10.0).round().min(MAX_SHUTDOWN_MINUTES)
+        )
+    };
+    if restart > 0 {
+        format!("S{}R{:04}", delay, restart.min(MAX_RESTART_MINUTES))
+    } else {
+        format!("S{}", delay)
+    }
+}
+
+pub fn split_response(raw: &[u8], strip_prefix: bool) -> Vec<Vec<u8>> {
+    // Split a response into its fields at `SEPARATOR`, first dropping the leading '#' or '(' if
+    // asked, but only if it's really there. An empty response has no fields, while separators
+    // at the end leave empty fields, as they do anywhere else.
+    let fields = match raw.split_first() {
+        Some((first, rest)) if strip_prefix && RESPONSE_PREFIXES.contains(first) => rest,
+        Some(_) => raw,
+        None => return Vec::new(),
+    };
+    fields
+        .split(|&c| c == SEPARATOR)
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+fn parse_float(raw: &[u8]) -> Result<f32, UPSError> {
+    // Parse a numeric field, keeping its text for the error.
+    let text = str::from_utf8(raw)?;
+    text.parse().map_err(|error| UPSError::ParseFloat {
+        text: text.to_string(),
+        error,
+    })
+}
+
+fn parse_int<T: FromStr<Err = ParseIntError>>(raw: &[u8]) -> Result<T, UPSError> {
+    let text = str::from_utf8(raw)?;
+    text.parse().map_err(|error| UPSError::ParseInt {
+        text: text.to_string(),
+        error,
+    })
+}
+
+fn get_field<'a>(
+    cmd: &str,
+    res: &'a [Vec<u8>],
+    index: usize,
+    name: &str,
+) -> Result<&'a [u8], UPSError> {
+    // Fetch one field of a split response, naming it if the response was cut short.
+    res.get(index)
+        .map(|f| f.as_slice())
+        .ok_or_else(|| UPSError::Malformed {
+            command: cmd.to_string(),
+            raw: String::from_utf8_lossy(&res.join(&SEPARATOR)).to_string(),
+            reason: format!("missing {}", name),
+        })
+}
+
+fn get_flag(cmd: &str, bits: &[u8], index: usize, name: &str) -> Result<u8, UPSError> {
+    // Fetch one character of a status bit string, naming it if the string was cut short.
+    bits.get(index).copied().ok_or_else(|| UPSError::Malformed {
+        command: cmd.to_string(),
+        raw: String::from_utf8_lossy(bits).to_string(),
+        reason: format!("status bits are missing {}", name),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+struct FFields {
+    // The ratings from `F`, each unset if the unit left it off.
+    rated_output_voltage: Option<f32>,
+    rated_output_current: Option<i32>,
+    rated_battery_voltage: Option<f32>,
+    rated_output_frequency: Option<f32>,
+}
+
+impl FFields {
+    fn apply(&self, status: &mut status::UPSStatus) {
+        if let Some(voltage) = self.rated_output_voltage {
+            status.rated_output_voltage = voltage;
+        }
+        if let Some(current) = self.rated_output_current {
+            status.rated_output_current = current;
+        }
+        if let Some(voltage) = self.rated_battery_voltage {
+            status.rated_battery_voltage = voltage;
+        }
+        if let Some(frequency) = self.rated_output_frequency {
+            status.rated_output_frequency = frequency;
+        }
+    }
+}
+
+fn parse_f(res: &[Vec<u8>]) -> Result<FFields, UPSError> {
+    // Parse whichever ratings are there, in order, as some units leave one off and others add
+    // their own after the usual ones. Any left off keep their defaults.
+    if res.len() != F_FIELD_COUNT {
+        warn!(
+            "Expected {} ratings from F but got {}, reading what's there: {:?}",
+            F_FIELD_COUNT,
+            res.len(),
+            String::from_utf8_lossy(&res.join(&SEPARATOR))
+        );
+    }
+    let field = |index: usize| res.get(index).map(Vec::as_slice);
+    Ok(FFields {
+        rated_output_voltage: field(0).map(parse_float).transpose()?,
+        rated_output_current: field(1).map(parse_int).transpose()?,
+        rated_battery_voltage: field(2).map(parse_float).transpose()?,
+        rated_output_frequency: field(3).map(parse_float).transpose()?,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+struct QbvFields {
+    // The battery detail from `QBV`, e.g. "(026.5 02 01 068 255".
+    battery_group_voltage: f32,
+    battery_count: u8,
+}
+
+impl QbvFields {
+    fn apply(&self, status: &mut status::UPSStatus) {
+        status.battery_group_voltage = Some(self.battery_group_voltage);
+        status.battery_count = Some(self.battery_count);
+    }
+}
+
+fn parse_qbv(res: &[Vec<u8>]) -> Result<QbvFields, UPSError> {
+    let field = |index, name| get_field("QBV", res, index, name);
+    Ok(QbvFields {
+        battery_group_voltage: parse_float(field(0, "battery group voltage")?)?,
+        battery_count: parse_int(field(1, "battery count")?)?,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+struct QsFields {
+    // The measurements and status bits from `QS`.
+    input_voltage: f32,
+    input_fault_voltage: f32,
+    output_voltage: f32,
+    output_load: u8,
+    output_frequency: f32,
+    battery_voltage: f32,
+    utility_failed: bool,
+    battery_low: bool,
+    bypass_active: bool,
+    ups_failed: bool,
+    standby_type: bool,
+    test_in_progress: bool,
+    shutdown_active: bool,
+    beeper_on: bool,
+    raw_flags: String,
+}
+
+impl QsFields {
+    fn apply(&self, status: &mut status::UPSStatus) {
+        status.input_voltage = self.input_voltage;
+        status.input_fault_voltage = self.input_fault_voltage;
+        status.output_voltage = self.output_voltage;
+        status.output_load = self.output_load;
+        status.output_frequency = self.output_frequency;
+        status.battery_voltage = self.battery_voltage;
+        status.utility_failed = self.utility_failed;
+        status.battery_low = self.battery_low;
+        status.bypass_active = self.bypass_active;
+        status.ups_failed = self.ups_failed;
+        status.standby_type = self.standby_type;
+        status.test_in_progress = self.test_in_progress;
+        status.shutdown_active = self.shutdown_active;
+        status.beeper_on = self.beeper_on;
+        status.raw_status_flags = self.raw_flags.clone();
+
+        // QI may also flag a fault through the mode, but that's applied after this.
+        status.fault = self.ups_failed;
+    }
+}
+
+fn parse_qs(res: &[Vec<u8>]) -> Result<QsFields, UPSError> {
+    let field = |index, name| get_field("QS", res, index, name);
+
+    // The status bits are, in order: utility failed, battery low, bypass/boost active,
+    // UPS failed, standby (vs line-interactive) type, test in progress, shutdown active,
+    // and beeper on.
+    let bits = field(7, "status bits")?;
+    let flag = |index, name| get_flag("QS", bits, index, name);
+    Ok(QsFields {
+        input_voltage: parse_float(field(0, "input voltage")?)?,
+        input_fault_voltage: parse_float(field(1, "input fault voltage")?)?,
+        output_voltage: parse_float(field(2, "output voltage")?)?,
+        output_load: parse_int(field(3, "output load")?)?,
+        output_frequency: parse_float(field(4, "output frequency")?)?,
+        battery_voltage: parse_float(field(5, "battery voltage")?)?,
+        utility_failed: flag(0, "utility failed")? == b'1',
+        battery_low: flag(1, "battery low")? == b'1',
+        bypass_active: flag(2, "bypass active")? == b'1',
+        ups_failed: flag(3, "UPS failed")? == b'1',
+        standby_type: flag(4, "standby type")? == b'1',
+        test_in_progress: flag(5, "test in progress")? == b'1',
+        shutdown_active: flag(6, "shutdown active")? == b'1',
+        beeper_on: flag(7, "beeper on")? == b'1',
+        raw_flags: String::from_utf8_lossy(bits).to_string(),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+struct QiFields {
+    // The battery state, self-test result and mode from `QI`.
+    remaining_capacity: u8,
+    seconds_to_empty: i32,
+    input_frequency: f32,
+    output_current: f32,
+    test_result: status::UPSTestResults,
+    overloaded: bool,
+    replace_battery: bool,
+    charging: bool,
+    ups_mode: status::UPSModes,
+    raw_flags: String,
+}
+
+impl QiFields {
+    fn apply(&self, status: &mut status::UPSStatus) {
+        status.remaining_capacity = self.remaining_capacity;
+        status.seconds_to_empty = self.seconds_to_empty;
+        status.input_frequency = self.input_frequency;
+        status.output_current = self.output_current;
+        status.test_result = self.test_result;
+        status.overloaded = self.overloaded;
+        status.replace_battery = self.replace_battery;
+        status.charging = self.charging;
+        status.ups_mode = self.ups_mode;
+        status.raw_info_flags = self.raw_flags.clone();
+        if self.ups_mode == status::UPSModes::Fault {
+            status.fault = true;
+        }
+    }
+}
+
+fn parse_qi(res: &[Vec<u8>]) -> Result<QiFields, UPSError> {
+    let field = |index, name| get_field("QI", res, index, name);
+    let bits = field(7, "status bits")?;
+    let flag = |index, name| get_flag("QI", bits, index, name);
+    Ok(QiFields {
+        remaining_capacity: parse_int(field(0, "remaining capacity")?)?,
+        seconds_to_empty: parse_int(field(1, "seconds to empty")?)?,
+        input_frequency: parse_float(field(2, "input frequency")?)?,
+        output_current: parse_float(field(3, "output current")?)?,
+        test_result: match flag(7, "test result")? {
+            b'1' => status::UPSTestResults::Passed,
+            b'2' => status::UPSTestResults::Warning,
+            b'3' => status::UPSTestResults::Error,
+            b'4' => status::UPSTestResults::Aborted,
+            b'5' => status::UPSTestResults::InProgress,
+            _ => status::UPSTestResults::NoTest,
+        },
+        overloaded: flag(8, "overloaded")? == b'1',
+        replace_battery: flag(9, "replace battery")? == b'1',
+        charging: flag(10, "charging")? == b'1',
+        ups_mode: match flag(12, "mode")? {
+            b'1' => status::UPSModes::Standby,
+            b'2' => status::UPSModes::Line,
+            b'3' => status::UPSModes::Inverting,
+            b'4' => status::UPSModes::SelfTest,
+            b'5' => status::UPSModes::Fault,
+            _ => status::UPSModes::Idle,
+        },
+        raw_flags: String::from_utf8_lossy(bits).to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::{MockConnector, MockState};
+    use crate::ups::UPS;
+
+    use proptest::prelude::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    const QS: &[u8] = b"(230.0 230.0 230.0 012 50.0 27.3 --.- 10000001\r";
+    const QI: &[u8] = b"(100 01200 50.0 001.2 000 000 000 0000000000102\r";
+
+    fn quick_timing() -> Timing {
+        // Reconnect without the real delays, so silent units don't slow the tests down.
+        Timing {
+            reconnect_delay_ms: 1,
+            max_reconnect_ms: 0,
+            ..Default::default()
+        }
+    }
+
+    fn connect(state: &Rc<RefCell<MockState>>) -> MegatecProtocol {
+        let mut protocol = MegatecProtocol::new(
+            Box::new(MockConnector(Rc::clone(state))),
+            quick_timing(),
+            None,
+        );
+        protocol.connect().unwrap();
+        protocol
+    }
+
+    fn split(raw: &[u8]) -> Vec<Vec<u8>> {
+        split_response(raw, true)
+    }
+
+    #[test]
+    fn poll_timeout_covers_giving_up_on_a_silent_unit() {
+        // Three commands, each with 4 attempts of 2 reads, 600ms of backoff and the 10s budget.
+        assert_eq!(
+            Timing::default().poll_timeout(),
+            time::Duration::from_millis(3 * (4 * 1000 + 600 + 10000))
+        );
+        // Backoff is capped, however many retries.
+        let timing = Timing {
+            retries: 10,
+            max_reconnect_ms: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            timing.poll_timeout(),
+            time::Duration::from_millis(3 * (11 * 1000 + 200 + 400 + 800 + 1600 + 3200 + 4 * 5000))
+        );
+    }
+
+    #[test]
+    fn split_response_strips_only_a_real_prefix() {
+        let fields = |fields: &[&str]| -> Vec<Vec<u8>> {
+            fields
+                .iter()
+                .map(|field| field.as_bytes().to_vec())
+                .collect()
+        };
+
+        assert_eq!(split(b"(230.0 50.0"), fields(&["230.0", "50.0"]));
+        assert_eq!(split(b"#230.0 004"), fields(&["230.0", "004"]));
+        assert_eq!(
+            split_response(b"(230.0 50.0", false),
+            fields(&["(230.0", "50.0"])
+        );
+        assert_eq!(split(b"230.0 50.0"), fields(&["230.0", "50.0"]));
+        assert_eq!(split(b""), fields(&[]));
+        assert_eq!(split(b"("), fields(&[""]));
+        assert_eq!(split(b"(230.0 50.0 "), fields(&["230.0", "50.0", ""]));
+    }
+
+    #[test]
+    fn qs_ups_failed_bit_sets_fault() {
+        let mut status = status::UPSStatus::default();
+        parse_qs(&split(b"(230.0 230.0 230.0 012 50.0 27.3 --.- 00010001"))
+            .unwrap()
+            .apply(&mut status);
+
+        assert!(status.ups_failed);
+        assert!(status.fault);
+        assert!(status.beeper_on);
+        assert!(!status.utility_failed);
+        assert_eq!(status.output_load, 12);
+
+        // Every bit stays readable as sent, decoded or not.
+        assert_eq!(status.raw_status_flags, "00010001");
+        assert_eq!(status.status_bit(3), Some(true));
+        assert_eq!(status.status_bit(4), Some(false));
+        assert_eq!(status.status_bit(8), None);
+    }
+
+    #[test]
+    fn qi_fault_mode_sets_fault() {
+        let mut status = status::UPSStatus::default();
+        parse_qs(&split(b"(230.0 230.0 230.0 012 50.0 27.3 --.- 00000001"))
+            .unwrap()
+            .apply(&mut status);
+        assert!(!status.fault);
+
+        parse_qi(&split(b"(100 01200 50.0 001.2 000 000 000 0000000000105"))
+            .unwrap()
+            .apply(&mut status);
+
+        assert_eq!(status.ups_mode, status::UPSModes::Fault);
+        assert!(status.fault);
+    }
+
+    #[test]
+    fn parse_captured_responses() {
+        assert_eq!(
+            parse_f(&split(b"#230.0 004 24.00 50.0")).unwrap(),
+            FFields {
+                rated_output_voltage: Some(230.0),
+                rated_output_current: Some(4),
+                rated_battery_voltage: Some(24.0),
+                rated_output_frequency: Some(50.0),
+            }
+        );
+        assert_eq!(
+            parse_qs(&split(b"(208.4 140.0 208.4 034 59.9 2.05 35.0 00110000")).unwrap(),
+            QsFields {
+                input_voltage: 208.4,
+                input_fault_voltage: 140.0,
+                output_voltage: 208.4,
+                output_load: 34,
+                output_frequency: 59.9,
+                battery_voltage: 2.05,
+                utility_failed: false,
+                battery_low: false,
+                bypass_active: true,
+                ups_failed: true,
+                standby_type: false,
+                test_in_progress: false,
+                shutdown_active: false,
+                beeper_on: false,
+                raw_flags: "00110000".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_qbv(&split(b"(026.5 02 01 068 255")).unwrap(),
+            QbvFields {
+                battery_group_voltage: 26.5,
+                battery_count: 2,
+            }
+        );
+        assert_eq!(
+            parse_qi(&split(b"(100 01200 50.0 001.2 000 000 000 0000000400103")).unwrap(),
+            QiFields {
+                remaining_capacity: 100,
+                seconds_to_empty: 1200,
+                input_frequency: 50.0,
+                output_current: 1.2,
+                test_result: status::UPSTestResults::Aborted,
+                overloaded: false,
+                replace_battery: false,
+                charging: true,
+                ups_mode: status::UPSModes::Inverting,
+                raw_flags: "0000000400103".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_too_few_fields() {
+        assert!(matches!(
+            parse_qs(&split(b"(230.0 230.0 230.0 012 50.0 27.3")),
+            Err(UPSError::Malformed { .. })
+        ));
+        assert!(matches!(
+            parse_qi(&split(b"(100 01200 50.0 001.2")),
+            Err(UPSError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_more_or_fewer_ratings() {
+        // Missing ratings keep their defaults, and extra ones are ignored.
+        for (raw, frequency) in [
+            (&b"#230.0 004 24.00"[..], 0.0),
+            (b"#230.0 004 24.00 50.0", 50.0),
+            (b"#230.0 004 24.00 50.0 048", 50.0),
+        ] {
+            let mut status = status::UPSStatus::default();
+            parse_f(&split(raw)).unwrap().apply(&mut status);
+            assert_eq!(status.rated_output_voltage, 230.0);
+            assert_eq!(status.rated_output_current, 4);
+            assert_eq!(status.rated_battery_voltage, 24.0);
+            assert_eq!(status.rated_output_frequency, frequency);
+        }
+    }
+
+    #[test]
+    fn parse_non_numeric_values() {
+        assert!(matches!(
+            parse_f(&split(b"#230.0 4A 24.00 50.0")),
+            Err(UPSError::ParseInt { text, .. }) if text == "4A"
+        ));
+        assert!(matches!(
+            parse_qs(&split(b"(230.0 ---.- 230.0 012 50.0 27.3 --.- 00000001")),
+            Err(UPSError::ParseFloat { text, .. }) if text == "---.-"
+        ));
+    }
+
+    #[test]
+    fn parse_captured_status_lines() {
+        let mut status = status::UPSStatus::default();
+        parse_status(
+            str::from_utf8(QS).unwrap(),
+            str::from_utf8(QI).unwrap(),
+            &mut status,
+        )
+        .unwrap();
+        assert_eq!(status.input_voltage, 230.0);
+        assert_eq!(status.output_load, 12);
+        assert!(status.utility_failed);
+        assert_eq!(status.remaining_capacity, 100);
+        assert_eq!(status.seconds_to_empty, 1200);
+        assert_eq!(status.ups_mode, status::UPSModes::Line);
+
+        // Without the prefix is fine too, but a bad QI leaves the status as QS and QI had it.
+        let before = status.clone();
+        assert!(matches!(
+            parse_status(
+                "208.4 140.0 208.4 034 59.9 2.05 35.0 00110000",
+                "(100 01200",
+                &mut status
+            ),
+            Err(UPSError::Malformed { command, .. }) if command == "QI"
+        ));
+        assert_eq!(status, before);
+    }
+
+    #[test]
+    fn parse_short_status_bits() {
+        assert!(matches!(
+            parse_qs(&split(b"(230.0 230.0 230.0 012 50.0 27.3 --.- 0000000")),
+            Err(UPSError::Malformed { .. })
+        ));
+        assert!(matches!(
+            parse_qi(&split(b"(100 01200 50.0 001.2 000 000 000 000000000010")),
+            Err(UPSError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn status_round_trip() {
+        let state = MockState::new();
+        state.borrow_mut().respond("QS", &[Some(QS)]);
+        state.borrow_mut().respond("QI", &[Some(QI)]);
+
+        let mut status = status::UPSStatus::default();
+        connect(&state).read_status(&mut status).unwrap();
+
+        assert_eq!(state.borrow().commands, ["M", "QS", "QI"]);
+        assert!(status.utility_failed);
+        assert!(status.beeper_on);
+        assert_eq!(status.battery_voltage, 27.3);
+        assert_eq!(status.remaining_capacity, 100);
+        assert_eq!(status.seconds_to_empty, 1200);
+        assert_eq!(status.ups_mode, status::UPSModes::Line);
+    }
+
+    #[test]
+    fn shutdown_command_follows_the_spec() {
+        for (delay, restart, expected) in [
+            (0.2, 0, "S.2"),
+            (0.5, 0, "S.5"),
+            (1.0, 0, "S01"),
+            (10.0, 0, "S10"),
+            (0.2, 1, "S.2R0001"),
+            (0.5, 1, "S.5R0001"),
+            (1.0, 1, "S01R0001"),
+            (10.0, 1, "S10R0001"),
+            (0.2, 9999, "S.2R9999"),
+            (0.5, 9999, "S.5R9999"),
+            (1.0, 9999, "S01R9999"),
+            (10.0, 9999, "S10R9999"),
+            // Anything else rounds to the nearest the UPS can take.
+            (0.25, 0, "S.3"),
+            (0.1, 0, "S.2"),
+            (0.96, 0, "S01"),
+            (2.4, 30, "S02R0030"),
+            (15.0, 12000, "S10R9999"),
+        ] {
+            assert_eq!(
+                shutdown_command(delay, restart),
+                expected,
+                "{}M, restart {}M",
+                delay,
+                restart
+            );
+        }
+    }
+
+    #[test]
+    fn shutdown_sends_the_exact_bytes() {
+        let state = MockState::new();
+        let protocol = connect(&state);
+        protocol.shutdown(0.5, 0).unwrap();
+        protocol.shutdown(2.0, 1).unwrap();
+
+        // The mock assembles each command from every byte written up to the terminator.
+        assert_eq!(state.borrow().commands, ["M", "S.5", "S02R0001"]);
+    }
+
+    #[test]
+    fn mode_read_only_sends_qs() {
+        let state = MockState::new();
+        state.borrow_mut().respond("QS", &[Some(QS)]);
+
+        let mut status = status::UPSStatus {
+            ups_mode: status::UPSModes::Line,
+            remaining_capacity: 50,
+            ..Default::default()
+        };
+        connect(&state).read_mode(&mut status).unwrap();
+
+        assert_eq!(state.borrow().commands, ["M", "QS"]);
+        assert!(status.utility_failed);
+        assert!(!status.fault);
+        assert_eq!(status.ups_mode, status::UPSModes::Inverting);
+        // Everything else waits for the next full read.
+        assert_eq!(status.remaining_capacity, 50);
+        assert!(!status.beeper_on);
+    }
+
+    #[test]
+    fn response_split_across_short_reports() {
+        // Some units hand back fewer than 8 bytes per report, which mustn't be padded out.
+        let state = MockState::new();
+        state.borrow_mut().chunk = 3;
+        state
+            .borrow_mut()
+            .respond("F", &[Some(b"#220.0 003 12.00 50.0\r")]);
+
+        let mut status = status::UPSStatus::default();
+        connect(&state).read_ratings(&mut status).unwrap();
+
+        assert_eq!(status.rated_output_voltage, 220.0);
+        assert_eq!(status.rated_output_current, 3);
+        assert_eq!(status.rated_battery_voltage, 12.0);
+        assert_eq!(status.rated_output_frequency, 50.0);
+    }
+
+    #[test]
+    fn timeout_then_success() {
+        let state = MockState::new();
+        state.borrow_mut().respond("QS", &[None, Some(QS)]);
+        state.borrow_mut().respond("QI", &[Some(QI)]);
+
+        let mut status = status::UPSStatus::default();
+        connect(&state).read_status(&mut status).unwrap();
+
+        // The silent attempt reconnects (checking the protocol again) before retrying.
+        assert_eq!(state.borrow().commands, ["M", "QS", "M", "QS", "QI"]);
+        assert!(status.utility_failed);
+    }
+
+    #[test]
+    fn reconnects_until_the_budget_is_spent() {
+        // Silent for longer than the retries, but back within the budget.
+        let state = MockState::new();
+        state
+            .borrow_mut()
+            .respond("QS", &[None, None, None, None, Some(QS)]);
+        state.borrow_mut().respond("QI", &[Some(QI)]);
+
+        let mut protocol = MegatecProtocol::new(
+            Box::new(MockConnector(Rc::clone(&state))),
+            Timing {
+                max_reconnect_ms: 1000,
+                ..quick_timing()
+            },
+            None,
+        );
+        protocol.connect().unwrap();
+        let mut status = status::UPSStatus::default();
+        protocol.read_status(&mut status).unwrap();
+        assert_eq!(
+            state
+                .borrow()
+                .commands
+                .iter()
+                .filter(|c| *c == "QS")
+                .count(),
+            5
+        );
+
+        // Without any budget, it gives up after the retries.
+        let state = MockState::new();
+        state
+            .borrow_mut()
+            .respond("QS", &[None, None, None, None, Some(QS)]);
+        let result = connect(&state).read_status(&mut status);
+        assert!(matches!(result, Err(UPSError::Command { error, .. })
+            if matches!(*error, UPSError::EmptyResponse)));
+        assert_eq!(
+            state
+                .borrow()
+                .commands
+                .iter()
+                .filter(|c| *c == "QS")
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn missing_terminator_is_an_error() {
+        let state = MockState::new();
+        state
+            .borrow_mut()
+            .respond("QS", &[Some(&QS[..QS.len() - 1])]);
+
+        let mut status = status::UPSStatus::default();
+        let result = connect(&state).read_status(&mut status);
+
+        assert!(matches!(result, Err(UPSError::Command { command, error })
+            if command == "QS" && matches!(*error, UPSError::EmptyResponse)));
+    }
+
+    #[test]
+    fn wrong_protocol_is_an_error() {
+        let state = MockState::new();
+        state.borrow_mut().respond("M", &[Some(b"P\r")]);
+
+        let mut protocol = MegatecProtocol::new(
+            Box::new(MockConnector(Rc::clone(&state))),
+            quick_timing(),
+            None,
+        );
+        let result = protocol.connect();
+
+        assert!(matches!(result, Err(UPSError::WrongProtocol { got: b'P' })));
+    }
+
+    #[test]
+    fn compatible_firmware_connects_without_override() {
+        let state = MockState::new();
+        state.borrow_mut().respond("M", &[Some(b"V\r")]);
+        let mut ups = MegatecProtocol::new(
+            Box::new(MockConnector(Rc::clone(&state))),
+            quick_timing(),
+            None,
+        );
+
+        assert!(ups.connect().is_ok());
+    }
+
+    #[test]
+    fn protocol_override_accepts_other_firmware() {
+        let state = MockState::new();
+        state.borrow_mut().respond("M", &[Some(b"X\r")]);
+        let open = |protocol_override| {
+            MegatecProtocol::new(
+                Box::new(MockConnector(Rc::clone(&state))),
+                quick_timing(),
+                protocol_override,
+            )
+            .connect()
+        };
+
+        assert!(open(Some(ProtocolOverride::Id(b'X'))).is_ok());
+        assert!(open(Some(ProtocolOverride::Any)).is_ok());
+        assert!(matches!(
+            open(Some(ProtocolOverride::Id(b'P'))),
+            Err(UPSError::WrongProtocol { got: b'X' })
+        ));
+        assert!(matches!(
+            open(None),
+            Err(UPSError::WrongProtocol { got: b'X' })
+        ));
+
+        assert_eq!(ProtocolOverride::parse("V"), Ok(ProtocolOverride::Id(b'V')));
+        assert_eq!(ProtocolOverride::parse("any"), Ok(ProtocolOverride::Any));
+        assert!(ProtocolOverride::parse("VH").is_err());
+        assert!(ProtocolOverride::parse("").is_err());
+    }
+
+    #[test]
+    fn padded_reports_are_reassembled() {
+        // Responses either side of the report size, and one spanning several reports.
+        for length in [7, 8, 9, 47] {
+            let mut response = vec![b'('];
+            response.extend((b'0'..=b'9').cycle().take(length - 2));
+            response.push(TERMINATOR);
+
+            let state = MockState::new();
+            state.borrow_mut().pad = true;
+            state.borrow_mut().respond("F", &[Some(&response)]);
+
+            let res = connect(&state).raw_command("F", None).unwrap();
+            assert_eq!(res, &response[..length - 1], "length {}", length);
+        }
+    }
+
+    #[test]
+    fn stale_response_is_drained() {
+        let state = MockState::new();
+        state.borrow_mut().respond("F", &[Some(b"#1\r#stale\r")]);
+        state.borrow_mut().respond("I", &[Some(b"#2\r")]);
+
+        let protocol = connect(&state);
+        assert_eq!(protocol.raw_command("F", None).unwrap(), b"#1");
+        assert_eq!(protocol.raw_command("I", None).unwrap(), b"#2");
+    }
+
+    #[test]
+    fn nul_mid_message_is_malformed() {
+        let state = MockState::new();
+        state.borrow_mut().respond("F", &[Some(b"#1\x002\r")]);
+
+        let result = connect(&state).raw_command("F", None);
+
+        assert!(matches!(result, Err(UPSError::Malformed { .. })));
+    }
+
+    #[test]
+    fn wrong_prefix_is_rejected() {
+        let state = MockState::new();
+        state.borrow_mut().respond("QS", &[Some(b"QS\r")]);
+
+        let mut status = status::UPSStatus::default();
+        let result = connect(&state).read_status(&mut status);
+
+        assert!(matches!(result, Err(UPSError::Command { error, .. })
+            if matches!(&*error, UPSError::Rejected { reply } if reply == b"QS")));
+    }
+
+    #[test]
+    fn empty_response_is_an_error() {
+        let state = MockState::new();
+        state.borrow_mut().respond("F", &[Some(b"\r")]);
+
+        let mut status = status::UPSStatus::default();
+        let result = connect(&state).read_ratings(&mut status);
+
+        assert!(matches!(result, Err(UPSError::Command { error, .. })
+            if matches!(*error, UPSError::EmptyResponse)));
+    }
+
+    #[test]
+    fn captured_responses_through_ups() {
+        // Responses recorded from a line-interactive unit, replayed as padded 8-byte HID reports.
+        let state = MockState::new();
+        {
+            let mut state = state.borrow_mut();
+            state.pad = true;
+            state.respond("I", &[Some(b"#ACME            UPS2000    V1.0      \r")]);
+            state.respond("F", &[Some(b"#230.0 004 24.00 50.0\r")]);
+            state.respond(
+                "QS",
+                &[Some(b"(236.5 140.0 236.5 018 50.1 27.0 --.- 00001001\r")],
+            );
+            state.respond(
+                "QI",
+                &[Some(b"(085 00720 50.1 000.8 000 000 000 0000000101102\r")],
+            );
+            state.respond("QBV", &[Some(b"(026.5 02 01 085 012\r")]);
+        }
+
+        let protocol = MegatecProtocol::new(
+            Box::new(MockConnector(Rc::clone(&state))),
+            quick_timing(),
+            None,
+        );
+        let status = UPS::new(Box::new(protocol)).unwrap().status;
+
+        assert_eq!(state.borrow().commands, ["M", "I", "F", "QS", "QI", "QBV"]);
+        assert_eq!(status.identity(), "ACME UPS2000 (firmware V1.0)");
+
+        assert_eq!(status.rated_output_voltage, 230.0);
+        assert_eq!(status.rated_output_current, 4);
+        assert_eq!(status.rated_battery_voltage, 24.0);
+        assert_eq!(status.rated_output_frequency, 50.0);
+
+        assert_eq!(status.input_voltage, 236.5);
+        assert_eq!(status.input_fault_voltage, 140.0);
+        assert_eq!(status.output_voltage, 236.5);
+        assert_eq!(status.output_load, 18);
+        assert_eq!(status.output_frequency, 50.1);
+        assert_eq!(status.battery_voltage, 27.0);
+
+        // Only the standby type and beeper bits are set in QS.
+        assert!(!status.utility_failed);
+        assert!(!status.battery_low);
+        assert!(!status.bypass_active);
+        assert!(!status.ups_failed);
+        assert!(status.standby_type);
+        assert!(!status.test_in_progress);
+        assert!(!status.shutdown_active);
+        assert!(status.beeper_on);
+
+        assert_eq!(status.remaining_capacity, 85);
+        assert_eq!(status.seconds_to_empty, 720);
+        assert_eq!(status.input_frequency, 50.1);
+        assert_eq!(status.output_current, 0.8);
+
+        // QI's flags give a passed test, replace battery and charging, in line mode.
+        assert_eq!(status.test_result, status::UPSTestResults::Passed);
+        assert!(!status.overloaded);
+        assert!(status.replace_battery);
+        assert!(status.charging);
+        assert_eq!(status.ups_mode, status::UPSModes::Line);
+        assert!(!status.fault);
+
+        assert_eq!(status.battery_group_voltage, Some(26.5));
+        assert_eq!(status.battery_count, Some(2));
+    }
+
+    #[test]
+    fn set_beep_only_toggles_when_needed() {
+        // The beeper starts on, then reads off once toggled.
+        let state = MockState::new();
+        {
+            let mut state = state.borrow_mut();
+            state.respond("F", &[Some(b"#230.0 004 24.00 50.0\r")]);
+            state.respond(
+                "QS",
+                &[
+                    Some(b"(236.5 140.0 236.5 018 50.1 27.0 --.- 00001001\r"),
+                    Some(b"(236.5 140.0 236.5 018 50.1 27.0 --.- 00001001\r"),
+                    Some(b"(236.5 140.0 236.5 018 50.1 27.0 --.- 00001000\r"),
+                ],
+            );
+            state.respond(
+                "QI",
+                &[Some(b"(085 00720 50.1 000.8 000 000 000 0000000100102\r")],
+            );
+            state.respond("QBV", &[Some(b"QBV\r")]);
+        }
+
+        let protocol = MegatecProtocol::new(
+            Box::new(MockConnector(Rc::clone(&state))),
+            quick_timing(),
+            None,
+        );
+        let mut ups = UPS::new(Box::new(protocol)).unwrap();
+        assert!(ups.status.beeper_on);
+
+        ups.set_beep(false).unwrap();
+        assert!(!ups.status.beeper_on);
+        ups.set_beep(false).unwrap();
+        assert!(!ups.status.beeper_on);
+
+        let toggles = state.borrow().commands.iter().filter(|c| *c == "Q").count();
+        assert_eq!(toggles, 1);
+    }
+
+    #[test]
+    fn refused_battery_detail_is_not_asked_again() {
+        // Units without QBV echo it back, which leaves the detail unset rather than failing.
+        let state = MockState::new();
+        {
+            let mut state = state.borrow_mut();
+            state.respond("F", &[Some(b"#230.0 004 24.00 50.0\r")]);
+            state.respond(
+                "QS",
+                &[Some(b"(236.5 140.0 236.5 018 50.1 27.0 --.- 00001001\r")],
+            );
+            state.respond(
+                "QI",
+                &[Some(b"(085 00720 50.1 000.8 000 000 000 0000000100102\r")],
+            );
+            state.respond("QBV", &[Some(b"QBV\r")]);
+        }
+
+        let protocol = MegatecProtocol::new(
+            Box::new(MockConnector(Rc::clone(&state))),
+            quick_timing(),
+            None,
+        );
+        let mut ups = UPS::new(Box::new(protocol)).unwrap();
+        ups.get_ups_status().unwrap();
+
+        assert_eq!(ups.status.battery_group_voltage, None);
+        assert_eq!(ups.status.battery_count, None);
+        let commands = &state.borrow().commands;
+        assert_eq!(commands.iter().filter(|c| *c == "QBV").count(), 1);
+        assert_eq!(commands.iter().filter(|c| *c == "QS").count(), 2);
+    }
+
+    #[test]
+    fn truncated_status_names_the_missing_field() {
+        let state = MockState::new();
+        state
+            .borrow_mut()
+            .respond("QS", &[Some(b"(230.0 230.0 230.0 012 50.0\r")]);
+        state.borrow_mut().respond("QI", &[Some(QI)]);
+
+        let mut status = status::UPSStatus::default();
+        let result = connect(&state).read_status(&mut status);
+
+        assert!(
+            matches!(result, Err(UPSError::Malformed { command, raw, reason })
+            if command == "QS" && raw == "(230.0 230.0 230.0 012 50.0" && reason == "missing status bits")
+        );
+    }
+
+    proptest! {
+        // Whatever the UPS sends, parsing should give a value or an error, never a panic.
+
+        #[test]
+        fn arbitrary_bytes_never_panic(data in prop::collection::vec(any::<u8>(), 0..64)) {
+            let fields = split(&data);
+            let _ = parse_f(&fields);
+            let _ = parse_qs(&fields);
+            let _ = parse_qi(&fields);
+        }
+
+        #[test]
+        fn response_shaped_bytes_never_panic(
+            prefix in prop::sample::select(vec![b'(', b'#', b'H']),
+            fields in prop::collection::vec("[0-9.\\-]{0,14}", 0..10),
+        ) {
+            let mut data = vec![prefix];
+            data.extend_from_slice(fields.join(" ").as_bytes());
+            let fields = split(&data);
+            let _ = parse_f(&fields);
+            let _ = parse_qs(&fields);
+            let _ = parse_qi(&fields);
+        }
+
+        #[test]
+        fn arbitrary_replies_never_panic(reply in prop::collection::vec(any::<u8>(), 0..48)) {
+            // Always terminate the reply, or each case would sit through the retries.
+            let mut reply = reply;
+            reply.push(TERMINATOR);
+
+            let state = MockState::new();
+            for cmd in ["I", "F", "QS", "QI"] {
+                state.borrow_mut().respond(cmd, &[Some(&reply)]);
+            }
+
+            let mut protocol = connect(&state);
+            let mut status = status::UPSStatus::default();
+            let _ = protocol.read_info(&mut status);
+            let _ = protocol.read_ratings(&mut status);
+            let _ = protocol.read_status(&mut status);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "metrics")]
+use crate::status;
+#[cfg(feature = "metrics")]
+use std::{
+    fmt::{Display, Write},
+    sync::{Arc, Mutex},
+    thread,
+};
+#[cfg(feature = "metrics")]
+use tracing::{info, warn};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MetricsSettings {
+    // Outline for the optional `[metrics]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // - `enabled` turns on the Prometheus `/metrics` endpoint (requires the `metrics` feature)
+    // - `address` and `port` give where the endpoint listens
+    pub enabled: bool,
+    pub address: String,
+    pub port: u16,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings {
+            enabled: false,
+            address: "127.0.0.1".to_string(),
+            port: 9105,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub fn serve(
+    settings: &MetricsSettings,
+    status: &status::UPSStatus,
+) -> Option<Arc<Mutex<status::UPSStatus>>> {
+    if !settings.enabled {
+        return None;
+    }
+
+    let address = format!("{}:{}", settings.address, settings.port);
+    // Monitoring matters more than the metrics, so carry on without them.
+    let server = match tiny_http::Server::http(&address) {
+        Ok(server) => server,
+        Err(e) => {
+            warn!("Failed to start metrics server on {}: {}", address, e);
+            return None;
+        }
+    };
+    info!("Serving metrics on http://{}/metrics", address);
+
+    // The poll loop updates this snapshot, so scrapes never wait on the UPS itself.
+    let snapshot = Arc::new(Mutex::new(status.clone()));
+    let server_snapshot = Arc::clone(&snapshot);
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                let status = server_snapshot.lock().unwrap().clone();
+                tiny_http::Response::from_string(render(&status)).with_header(
+                    tiny_http::Header::from_bytes(
+                        &b"Content-Type"[..],
+                        &b"text/plain; version=0.0.4"[..],
+                    )
+                    .unwrap(),
+                )
+            } else {
+                tiny_http::Response::from_string("Not found").with_status_code(404)
+            };
+
+            if let Err(e) = request.respond(response) {
+                warn!("Failed to respond to metrics request: {:?}", e)
+            }
+        }
+    });
+
+    Some(snapshot)
+}
+
+#[cfg(feature = "metrics")]
+fn render(status: &status::UPSStatus) -> String {
+    // Render the status in the Prometheus text exposition format.
+    let gauges: [(&str, &str, &dyn Display); 26] = [
+        (
+            "input_voltage",
+            "Input voltage in volts.",
+            &status.input_voltage,
+        ),
+        (
+            "input_frequency",
+            "Input frequency in hertz.",
+            &status.input_frequency,
+        ),
+        (
+            "input_fault_voltage",
+            "Input fault voltage in volts.",
+            &status.input_fault_voltage,
+        ),
+        (
+            "output_voltage",
+            "Output voltage in volts.",
+            &status.output_voltage,
+        ),
+        (
+            "output_current",
+            "Output current in amps.",
+            &status.output_current,
+        ),
+        (
+            "output_frequency",
+            "Output frequency in hertz.",
+            &status.output_frequency,
+        ),
+        (
+            "output_load",
+            "Output load in percent.",
+            &status.output_load,
+        ),
+        (
+            "rated_output_voltage",
+            "Rated output voltage in volts.",
+            &status.rated_output_voltage,
+        ),
+        (
+            "rated_output_current",
+            "Rated output current in amps.",
+            &status.rated_output_current,
+        ),
+        (
+            "rated_output_frequency",
+            "Rated output frequency in hertz.",
+            &status.rated_output_frequency,
+        ),
+        (
+            "battery_voltage",
+            "Battery voltage in volts.",
+            &status.battery_voltage,
+        ),
+        (
+            "remaining_capacity",
+            "Remaining battery capacity in percent.",
+            &status.remaining_capacity,
+        ),
+        (
+            "seconds_to_empty",
+            "Estimated battery runtime in seconds.",
+            &status.seconds_to_empty,
+        ),
+        (
+            "rated_battery_voltage",
+            "Rated battery voltage in volts.",
+            &status.rated_battery_voltage,
+        ),
+        // Flags are exposed as 0/1 gauges.
+        (
+            "utility_failed",
+            "Whether utility power has failed.",
+            &(status.utility_failed as u8),
+        ),
+        (
+            "battery_low",
+            "Whether the UPS reports a low battery.",
+            &(status.battery_low as u8),
+        ),
+        (
+            "charging",
+            "Whether the battery is charging.",
+            &(status.charging as u8),
+        ),
+        (
+            "bypass_active",
+            "Whether bypass/boost is active.",
+            &(status.bypass_active as u8),
+        ),
+        (
+            "ups_failed",
+            "Whether the UPS reports it has failed.",
+            &(status.ups_failed as u8),
+        ),
+        (
+            "standby_type",
+            "Whether the UPS is a standby (rather than line-interactive) type.",
+            &(status.standby_type as u8),
+        ),
+        (
+            "test_in_progress",
+            "Whether a self-test is in progress.",
+            &(status.test_in_progress as u8),
+        ),
+        (
+            "shutdown_active",
+            "Whether a UPS shutdown is pending.",
+            &(status.shutdown_active as u8),
+        ),
+        (
+            "beeper_on",
+            "Whether the UPS beeper is enabled.",
+            &(status.beeper_on as u8),
+        ),
+        (
+            "fault",
+            "Whether the UPS reports a fault.",
+            &(status.fault as u8),
+        ),
+        (
+            "overloaded",
+            "Whether the UPS is overloaded.",
+            &(status.overloaded as u8),
+        ),
+        (
+            "replace_battery",
+            "Whether the battery needs replacing.",
+            &(status.replace_battery as u8),
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, help, value) in gauges {
+        writeln!(out, "# HELP ups_{} {}", name, help).unwrap();
+        writeln!(out, "# TYPE ups_{} gauge", name).unwrap();
+        writeln!(out, "ups_{} {}", name, value).unwrap();
+    }
+
+    // The identity is exposed as an info-style gauge, so dashboards can tell units apart.
+    writeln!(out, "# HELP ups_info UPS manufacturer, model and firmware.").unwrap();
+    writeln!(out, "# TYPE ups_info gauge").unwrap();
+    writeln!(
+        out,
+        "ups_info{{manufacturer=\"{}\",model=\"{}\",firmware_version=\"{}\"}} 1",
+        label(status.manufacturer.as_deref().unwrap_or("")),
+        label(status.model.as_deref().unwrap_or("")),
+        label(status.firmware_version.as_deref().unwrap_or(""))
+    )
+    .unwrap();
+
+    // Enums are exposed as info-style gauges, labelled with the current value by the names the
+    // other outputs use.
+    writeln!(out, "# HELP ups_test_result Result of the last self-test.").unwrap();
+    writeln!(out, "# TYPE ups_test_result gauge").unwrap();
+    writeln!(
+        out,
+        "ups_test_result{{result=\"{}\"}} 1",
+        status::name(status.test_result)
+    )
+    .unwrap();
+    writeln!(out, "# HELP ups_mode Current UPS operating mode.").unwrap();
+    writeln!(out, "# TYPE ups_mode gauge").unwrap();
+    writeln!(
+        out,
+        "ups_mode{{mode=\"{}\"}} 1",
+        status::name(status.ups_mode)
+    )
+    .unwrap();
+
+    out
+}
+
+#[cfg(feature = "metrics")]
+fn label(value: &str) -> String {
+    // Label values are quoted, so backslashes, quotes and newlines need escaping.
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_snake_case_names_and_escaped_labels() {
+        let status = status::UPSStatus {
+            manufacturer: Some("ACME \"Power\"".to_string()),
+            model: Some("UPS\\2000".to_string()),
+            firmware_version: Some("V1.0\nbeta".to_string()),
+            input_voltage: 230.5,
+            remaining_capacity: 85,
+            utility_failed: true,
+            ups_mode: status::UPSModes::Inverting,
+            test_result: status::UPSTestResults::InProgress,
+            ..Default::default()
+        };
+        let rendered = render(&status);
+        let lines: Vec<&str> = rendered.lines().collect();
+
+        for expected in [
+            "# HELP ups_input_voltage Input voltage in volts.",
+            "# TYPE ups_input_voltage gauge",
+            "ups_input_voltage 230.5",
+            "ups_remaining_capacity 85",
+            "ups_utility_failed 1",
+            "ups_charging 0",
+            r#"ups_info{manufacturer="ACME \"Power\"",model="UPS\\2000",firmware_version="V1.0\nbeta"} 1"#,
+            r#"ups_test_result{result="in_progress"} 1"#,
+            r#"ups_mode{mode="inverting"} 1"#,
+        ] {
+            assert!(lines.contains(&expected), "missing {}", expected);
+        }
+        // Three lines for each of the gauges, the identity and the two enums.
+        assert_eq!(lines.len(), 3 * 29);
+    }
+}
@@ -0,0 +1,1041 @@
+use crate::hook::Hook;
+use crate::notifier::{EventKind, EventSettings, Severity};
+use crate::protocol::TestKind;
+use crate::status::{UPSModes, UPSStatus, UPSTestResults};
+use crate::ups::UPSError;
+use crate::UpsSettings;
+
+use std::time;
+use tracing::{info, warn};
+
+// Runtime estimates beyond a day are treated as bogus, like the zero from units that can't tell.
+const MAX_PLAUSIBLE_RUNTIME_SECONDS: i32 = 24 * 60 * 60;
+
+#[derive(Debug, PartialEq)]
+pub struct Alert {
+    pub event: EventKind,
+    pub severity: Severity,
+    pub subject: String,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    // Send an alert through the notifiers.
+    SendAlert(Alert),
+    // Shut down the UPS and this machine. Nothing follows it, as we don't come back.
+    Shutdown,
+    // Run the user's hook for an event, if they've set one.
+    RunHook(Hook),
+    // Call off a pending UPS shutdown, reporting the result to `Monitor::cancel_finished`.
+    CancelShutdown,
+    // Run the scheduled self-test.
+    SelfTest,
+    // Sleep until the next poll.
+    Wait(time::Duration),
+}
+
+#[derive(Default)]
+struct Latch {
+    // Tracks an alert for a persistent condition, so it's sent once when the condition starts
+    // (plus any reminders) and once when it clears, rather than every poll.
+    sent_at: Option<time::Instant>,
+}
+
+impl Latch {
+    fn is_set(&self) -> bool {
+        self.sent_at.is_some()
+    }
+
+    fn set(&mut self) {
+        self.sent_at = Some(time::Instant::now());
+    }
+
+    fn clear(&mut self) {
+        self.sent_at = None;
+    }
+
+    fn reminder_due(&self, repeat_interval_minutes: u64) -> bool {
+        // Zero means never remind.
+        match self.sent_at {
+            Some(at) if repeat_interval_minutes > 0 => {
+                at.elapsed() >= time::Duration::from_secs(repeat_interval_minutes * 60)
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct Monitor {
+    // Decides what to do after each poll, keeping the state between polls but none of the IO.
+    utility_failed: Latch,
+    battery_low: Latch,
+    battery_voltage: Latch,
+    load_high: Latch,
+    // Consecutive reads disagreeing with `utility_failed`, to debounce a noisy line.
+    utility_changed_reads: u32,
+    last_mode: Option<UPSModes>,
+    sent_cancel_failed: bool,
+    seconds_until_shutdown: i32,
+    last_self_test: time::Instant,
+    // When the scheduled self-test we're waiting on the result of started.
+    self_test_started: Option<time::Instant>,
+}
+
+fn alert(
+    events: &EventSettings,
+    event: EventKind,
+    severity: Severity,
+    subject: &str,
+    message: String,
+) -> Option<Alert> {
+    // Disabled events are still logged, just not sent.
+    if !events.enabled(event) {
+        info!("{}", subject);
+        return None;
+    }
+
+    Some(Alert {
+        event,
+        severity,
+        subject: subject.to_string(),
+        message,
+    })
+}
+
+fn push_alert(
+    actions: &mut Vec<Action>,
+    events: &EventSettings,
+    event: EventKind,
+    severity: Severity,
+    subject: &str,
+    message: String,
+) {
+    actions.extend(alert(events, event, severity, subject, message).map(Action::SendAlert));
+}
+
+impl Monitor {
+    pub fn new(settings: &UpsSettings) -> Monitor {
+        Monitor {
+            utility_failed: Latch::default(),
+            battery_low: Latch::default(),
+            battery_voltage: Latch::default(),
+            load_high: Latch::default(),
+            utility_changed_reads: 0,
+            last_mode: None,
+            sent_cancel_failed: false,
+            seconds_until_shutdown: settings.seconds_to_shutdown,
+            last_self_test: time::Instant::now(),
+            self_test_started: None,
+        }
+    }
+
+    pub fn evaluate(&mut self, status: &UPSStatus, settings: &UpsSettings) -> Vec<Action> {
+        let mut actions: Vec<Action> = Vec::new();
+        let events = &settings.events;
+
+        // Alert as the UPS switches to its inverter or faults, as some units change mode a beat
+        // before setting the utility failed flag. Every change is logged.
+        if let Some(last_mode) = self.last_mode.filter(|mode| *mode != status.ups_mode) {
+            info!(
+                "UPS mode changed from {:?} to {:?}.",
+                last_mode, status.ups_mode
+            );
+            let severity = match status.ups_mode {
+                UPSModes::Inverting => Some(Severity::Warning),
+                UPSModes::Fault => Some(Severity::Critical),
+                _ => None,
+            };
+            if let Some(severity) = severity {
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::ModeChanged,
+                    severity,
+                    &format!("UPS mode changed to {:?}.", status.ups_mode),
+                    format!(
+                        "UPS mode changed from {:?} to {:?}.\n\n{}",
+                        last_mode, status.ups_mode, status
+                    ),
+                );
+            }
+        }
+        self.last_mode = Some(status.ups_mode);
+
+        // Only believe the utility has failed or come back once enough reads in a row say so, so a
+        // single noisy read neither starts the countdown nor calls it off.
+        if status.utility_failed == self.utility_failed.is_set() {
+            self.utility_changed_reads = 0;
+        } else {
+            self.utility_changed_reads += 1;
+        }
+        let utility_failed = if self.utility_changed_reads >= settings.utility_failed_debounce_count
+        {
+            self.utility_changed_reads = 0;
+            status.utility_failed
+        } else {
+            self.utility_failed.is_set()
+        };
+
+        let poll_delay = if utility_failed {
+            self.seconds_until_shutdown -= settings.utility_failed_poll_delay as i32;
+
+            if !self.utility_failed.is_set() {
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::UtilityFailed,
+                    Severity::Warning,
+                    "Utility failed.",
+                    status.to_string(),
+                );
+                actions.push(Action::RunHook(Hook::UtilityFailed));
+                self.utility_failed.set();
+            } else if self
+                .utility_failed
+                .reminder_due(settings.repeat_interval_minutes)
+            {
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::UtilityFailed,
+                    Severity::Warning,
+                    "Utility still failed.",
+                    status.to_string(),
+                );
+                self.utility_failed.set();
+            }
+            // Whichever comes first of the countdown and the UPS's own runtime estimate triggers
+            // the shutdown. Units that can't estimate runtime report zero, so ignore that.
+            let runtime_plausible = status.seconds_to_empty > 0
+                && status.seconds_to_empty <= MAX_PLAUSIBLE_RUNTIME_SECONDS;
+            let runtime_low = settings.min_runtime_seconds > 0
+                && runtime_plausible
+                && status.seconds_to_empty < settings.min_runtime_seconds;
+            if runtime_low {
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::Shutdown,
+                    Severity::Critical,
+                    "Battery runtime low - shutting down.",
+                    format!(
+                        "Triggered by the runtime rule: UPS has {}s remaining (minimum {}s), will \
+                         shutdown in {}min.\n\n{}",
+                        status.seconds_to_empty,
+                        settings.min_runtime_seconds,
+                        settings.minutes_to_shutdown,
+                        status
+                    ),
+                );
+                actions.push(Action::Shutdown);
+                return actions;
+            } else if self.seconds_until_shutdown <= 0 {
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::Shutdown,
+                    Severity::Critical,
+                    "Utility failed - shutting down.",
+                    format!(
+                        "Triggered by the countdown: on battery for {}s, UPS has {}s remaining, \
+                         will shutdown in {}min.\n\n{}",
+                        settings.seconds_to_shutdown,
+                        status.seconds_to_empty,
+                        settings.minutes_to_shutdown,
+                        status
+                    ),
+                );
+                actions.push(Action::Shutdown);
+                return actions;
+            } else {
+                warn!(
+                    "Utility failed - shutdown in {}s.",
+                    self.seconds_until_shutdown
+                )
+            }
+
+            settings.utility_failed_poll_delay
+        } else {
+            self.seconds_until_shutdown = settings.seconds_to_shutdown;
+
+            if self.utility_failed.is_set() {
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::UtilityRestored,
+                    Severity::Info,
+                    "Utility restored.",
+                    status.to_string(),
+                );
+                actions.push(Action::RunHook(Hook::UtilityRestored));
+                self.utility_failed.clear();
+            }
+
+            // Utility is back but the UPS still has a shutdown armed, so call it off before it
+            // cuts output on a machine that's still running.
+            if status.shutdown_active && !status.utility_failed {
+                actions.push(Action::CancelShutdown);
+            }
+
+            // Poll quickly while a possible failure is being confirmed.
+            if status.utility_failed {
+                settings.utility_failed_poll_delay
+            } else {
+                settings.poll_delay
+            }
+        };
+
+        if status.fault {
+            // Say which indicator tripped, as they can disagree between units.
+            let source = match (status.ups_failed, status.ups_mode == UPSModes::Fault) {
+                (true, true) => "status bit and mode",
+                (true, false) => "status bit",
+                _ => "mode",
+            };
+            push_alert(
+                &mut actions,
+                events,
+                EventKind::Fault,
+                Severity::Critical,
+                &format!("Fault detected ({}) - shutting down.", source),
+                status.to_string(),
+            );
+            actions.push(Action::Shutdown);
+            return actions;
+        }
+
+        if status.overloaded {
+            push_alert(
+                &mut actions,
+                events,
+                EventKind::Overload,
+                Severity::Critical,
+                "UPS overloaded - shutting down.",
+                status.to_string(),
+            );
+            actions.push(Action::Shutdown);
+            return actions;
+        }
+
+        // Warn while there's still time to shed load, before the UPS trips on overload.
+        if settings.output_load_warn_threshold > 0 {
+            if status.output_load > settings.output_load_warn_threshold && !self.load_high.is_set()
+            {
+                self.load_high.set();
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::LoadHigh,
+                    Severity::Warning,
+                    "UPS load high.",
+                    format!(
+                        "Output load at {}% (warning above {}%).\n\n{}",
+                        status.output_load, settings.output_load_warn_threshold, status
+                    ),
+                );
+            } else if self.load_high.is_set()
+                && status.output_load
+                    <= settings
+                        .output_load_warn_threshold
+                        .saturating_sub(settings.output_load_hysteresis)
+            {
+                // Only clear once comfortably below, so a load hovering at the threshold doesn't
+                // flap.
+                self.load_high.clear();
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::LoadNormal,
+                    Severity::Info,
+                    "UPS load back to normal.",
+                    status.to_string(),
+                );
+            }
+        }
+
+        if status.replace_battery {
+            push_alert(
+                &mut actions,
+                events,
+                EventKind::ReplaceBattery,
+                Severity::Critical,
+                "Battery needs replacement - shutting down.",
+                status.to_string(),
+            );
+            actions.push(Action::Shutdown);
+            return actions;
+        }
+
+        // Prefer the UPS's own battery low bit, falling back to our threshold for units that never
+        // set it. Only shut down when it isn't coming back, as while charging we're fine.
+        let below_threshold = settings.battery_shutdown_threshold > 0
+            && status.remaining_capacity < settings.battery_shutdown_threshold;
+        if (status.battery_low || below_threshold) && !status.charging {
+            let subject = if status.battery_low {
+                "UPS reports battery low and not charging - shutting down."
+            } else {
+                "Battery below shutdown threshold and not charging - shutting down."
+            };
+            push_alert(
+                &mut actions,
+                events,
+                EventKind::Shutdown,
+                Severity::Critical,
+                subject,
+                format!(
+                    "UPS has {}% remaining (minimum {}%).\n\n{}",
+                    status.remaining_capacity, settings.battery_shutdown_threshold, status
+                ),
+            );
+            actions.push(Action::Shutdown);
+            return actions;
+        }
+
+        // Prefer the UPS's own battery low bit, but fall back to our threshold for units that
+        // never set it.
+        let battery_low =
+            status.battery_low || status.remaining_capacity < settings.battery_alert_threshold;
+        if battery_low && !self.battery_low.is_set() {
+            self.battery_low.set();
+            push_alert(
+                &mut actions,
+                events,
+                EventKind::BatteryLow,
+                Severity::Warning,
+                "Battery low capacity.",
+                status.to_string(),
+            );
+            actions.push(Action::RunHook(Hook::LowBattery));
+        } else if battery_low
+            && self
+                .battery_low
+                .reminder_due(settings.repeat_interval_minutes)
+        {
+            self.battery_low.set();
+            push_alert(
+                &mut actions,
+                events,
+                EventKind::BatteryLow,
+                Severity::Warning,
+                "Battery still low capacity.",
+                status.to_string(),
+            );
+        } else if self.battery_low.is_set()
+            && !status.battery_low
+            && status.remaining_capacity
+                >= settings
+                    .battery_alert_threshold
+                    .saturating_add(settings.battery_alert_hysteresis)
+        {
+            // Only clear once we're comfortably above the threshold, so we don't flap on it.
+            push_alert(
+                &mut actions,
+                events,
+                EventKind::BatteryRecovered,
+                Severity::Info,
+                "Battery capacity recovered.",
+                status.to_string(),
+            );
+            self.battery_low.clear();
+        }
+
+        // An aging pack drifts from nominal well before the UPS asks for a replacement.
+        if let Some(voltage) = status.battery_group_voltage {
+            let deviation = if status.rated_battery_voltage > 0.0 {
+                (voltage - status.rated_battery_voltage).abs() / status.rated_battery_voltage
+                    * 100.0
+            } else {
+                0.0
+            };
+            let off_nominal = settings.battery_voltage_deviation_percent > 0.0
+                && deviation > settings.battery_voltage_deviation_percent;
+            if off_nominal && !self.battery_voltage.is_set() {
+                self.battery_voltage.set();
+                warn!(
+                    "Battery group at {:.1} V, {:.0}% off its nominal {:.1} V.",
+                    voltage, deviation, status.rated_battery_voltage
+                );
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::BatteryVoltage,
+                    Severity::Warning,
+                    "Battery voltage off nominal.",
+                    format!(
+                        "Battery group at {:.1} V, {:.0}% off its nominal {:.1} V (limit {}%).\n\n{}",
+                        voltage,
+                        deviation,
+                        status.rated_battery_voltage,
+                        settings.battery_voltage_deviation_percent,
+                        status
+                    ),
+                );
+            } else if !off_nominal && self.battery_voltage.is_set() {
+                self.battery_voltage.clear();
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::BatteryVoltage,
+                    Severity::Info,
+                    "Battery voltage back to nominal.",
+                    status.to_string(),
+                );
+            }
+        }
+
+        // Report the scheduled self-test once it's had time to run and the UPS is done with it,
+        // or give up on it after `self_test_timeout` beyond that.
+        if let Some(started) = self.self_test_started {
+            let expected = TestKind::Quick.duration();
+            let timeout = time::Duration::from_secs(settings.self_test_timeout);
+            let elapsed = started.elapsed();
+            if elapsed >= expected && status.test_result != UPSTestResults::InProgress {
+                self.self_test_started = None;
+                // Only a passing test is nothing to worry about.
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::SelfTest,
+                    if status.test_result == UPSTestResults::Passed {
+                        Severity::Info
+                    } else {
+                        Severity::Warning
+                    },
+                    &format!("Self-test result: {:?}.", status.test_result),
+                    status.to_string(),
+                );
+            } else if elapsed >= expected + timeout {
+                self.self_test_started = None;
+                push_alert(
+                    &mut actions,
+                    events,
+                    EventKind::SelfTest,
+                    Severity::Warning,
+                    "Self-test did not complete - treating as aborted.",
+                    status.to_string(),
+                );
+            }
+        }
+
+        // Run the scheduled self-test, but never while we're running off the battery.
+        if settings.self_test_interval_days > 0
+            && self.self_test_started.is_none()
+            && self.last_self_test.elapsed()
+                >= time::Duration::from_secs(settings.self_test_interval_days * 24 * 60 * 60)
+            && !status.utility_failed
+        {
+            actions.push(Action::SelfTest);
+            self.last_self_test = time::Instant::now();
+        }
+
+        actions.push(Action::Wait(time::Duration::from_secs(poll_delay)));
+        actions
+    }
+
+    pub fn self_test_started(
+        &mut self,
+        result: Result<(), UPSError>,
+        status: &UPSStatus,
+        settings: &UpsSettings,
+    ) -> Option<Alert> {
+        // Later polls pick up the result, so the loop never waits on the test.
+        match result {
+            Ok(()) => {
+                self.self_test_started = Some(time::Instant::now());
+                None
+            }
+            Err(e) => alert(
+                &settings.events,
+                EventKind::SelfTest,
+                Severity::Warning,
+                "Self-test failed to run.",
+                format!("{}\n\n{}", e.report(), status),
+            ),
+        }
+    }
+
+    pub fn cancel_finished(
+        &mut self,
+        result: Result<(), UPSError>,
+        status: &UPSStatus,
+        settings: &UpsSettings,
+    ) -> Option<Alert> {
+        // We keep retrying each poll, but only alert on the first failure.
+        match result {
+            Err(_) if self.sent_cancel_failed => None,
+            Err(e) => {
+                self.sent_cancel_failed = true;
+                alert(
+                    &settings.events,
+                    EventKind::CancelShutdown,
+                    Severity::Critical,
+                    "Failed to cancel pending UPS shutdown.",
+                    format!("{}\n\n{}", e.report(), status),
+                )
+            }
+            Ok(_) => {
+                self.sent_cancel_failed = false;
+                alert(
+                    &settings.events,
+                    EventKind::CancelShutdown,
+                    Severity::Info,
+                    "Pending UPS shutdown cancelled.",
+                    status.to_string(),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::megatec::{MegatecProtocol, Timing};
+    use crate::transport::mock::{MockConnector, MockState};
+    use crate::ups::UPS;
+    use std::rc::Rc;
+
+    // A name, how to set the status up, and the alert we expect.
+    type Scenario = (&'static str, fn(&mut UPSStatus), &'static str);
+
+    fn on_line() -> UPSStatus {
+        UPSStatus {
+            remaining_capacity: 100,
+            charging: true,
+            ..Default::default()
+        }
+    }
+
+    fn on_battery() -> UPSStatus {
+        let mut status = on_line();
+        status.utility_failed = true;
+        status.charging = false;
+        status
+    }
+
+    fn subjects(actions: &[Action]) -> Vec<&str> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::SendAlert(alert) => Some(alert.subject.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn countdown_shuts_down_after_seconds_to_shutdown() {
+        let settings = UpsSettings::default();
+        let mut monitor = Monitor::new(&settings);
+
+        let polls = settings.seconds_to_shutdown / settings.utility_failed_poll_delay as i32;
+        for poll in 1..polls {
+            let actions = monitor.evaluate(&on_battery(), &settings);
+            assert!(!actions.contains(&Action::Shutdown), "poll {}", poll);
+            if poll == 1 {
+                assert_eq!(subjects(&actions), ["Utility failed."]);
+            } else {
+                assert!(subjects(&actions).is_empty());
+            }
+        }
+
+        let actions = monitor.evaluate(&on_battery(), &settings);
+        assert_eq!(subjects(&actions), ["Utility failed - shutting down."]);
+        assert_eq!(actions.last(), Some(&Action::Shutdown));
+    }
+
+    #[test]
+    fn restore_resets_the_countdown() {
+        let settings = UpsSettings::default();
+        let mut monitor = Monitor::new(&settings);
+
+        for _ in 1..settings.seconds_to_shutdown {
+            monitor.evaluate(&on_battery(), &settings);
+        }
+        let actions = monitor.evaluate(&on_line(), &settings);
+        assert_eq!(subjects(&actions), ["Utility restored."]);
+        assert!(actions.contains(&Action::RunHook(Hook::UtilityRestored)));
+
+        let actions = monitor.evaluate(&on_battery(), &settings);
+        assert!(!actions.contains(&Action::Shutdown));
+        assert!(actions.contains(&Action::RunHook(Hook::UtilityFailed)));
+    }
+
+    #[test]
+    fn utility_changes_are_debounced() {
+        let settings = UpsSettings {
+            utility_failed_debounce_count: 3,
+            ..Default::default()
+        };
+        let mut monitor = Monitor::new(&settings);
+
+        // A couple of noisy reads don't count, and polling speeds up to confirm them.
+        for _ in 0..2 {
+            let actions = monitor.evaluate(&on_battery(), &settings);
+            assert!(subjects(&actions).is_empty());
+            assert_eq!(
+                actions.last(),
+                Some(&Action::Wait(time::Duration::from_secs(
+                    settings.utility_failed_poll_delay
+                )))
+            );
+        }
+        monitor.evaluate(&on_line(), &settings);
+        for _ in 0..2 {
+            assert!(subjects(&monitor.evaluate(&on_battery(), &settings)).is_empty());
+        }
+
+        // The third in a row does.
+        let actions = monitor.evaluate(&on_battery(), &settings);
+        assert_eq!(subjects(&actions), ["Utility failed."]);
+
+        // Restoring needs the same run of good reads.
+        for _ in 0..2 {
+            assert!(subjects(&monitor.evaluate(&on_line(), &settings)).is_empty());
+        }
+        let actions = monitor.evaluate(&on_line(), &settings);
+        assert_eq!(subjects(&actions), ["Utility restored."]);
+    }
+
+    #[test]
+    fn mode_changes_alert_on_the_edge() {
+        let settings = UpsSettings::default();
+        let mut monitor = Monitor::new(&settings);
+        let in_mode = |mode| UPSStatus {
+            ups_mode: mode,
+            ..on_line()
+        };
+
+        // Nothing to compare the first poll with.
+        assert!(subjects(&monitor.evaluate(&in_mode(UPSModes::Inverting), &settings)).is_empty());
+
+        monitor.evaluate(&in_mode(UPSModes::Line), &settings);
+        let actions = monitor.evaluate(&in_mode(UPSModes::Inverting), &settings);
+        assert_eq!(subjects(&actions), ["UPS mode changed to Inverting."]);
+        assert!(subjects(&monitor.evaluate(&in_mode(UPSModes::Inverting), &settings)).is_empty());
+
+        // Only the inverter and faults are worth an alert.
+        assert!(subjects(&monitor.evaluate(&in_mode(UPSModes::Line), &settings)).is_empty());
+    }
+
+    #[test]
+    fn shutdown_scenarios() {
+        let table: Vec<Scenario> = vec![
+            (
+                "runtime low",
+                |s| {
+                    s.utility_failed = true;
+                    s.seconds_to_empty = 60;
+                },
+                "Battery runtime low - shutting down.",
+            ),
+            (
+                "fault",
+                |s| {
+                    s.fault = true;
+                    s.ups_failed = true;
+                },
+                "Fault detected (status bit) - shutting down.",
+            ),
+            (
+                "overloaded",
+                |s| s.overloaded = true,
+                "UPS overloaded - shutting down.",
+            ),
+            (
+                "replace battery",
+                |s| s.replace_battery = true,
+                "Battery needs replacement - shutting down.",
+            ),
+        ];
+
+        let settings = UpsSettings {
+            min_runtime_seconds: 300,
+            ..UpsSettings::default()
+        };
+        for (name, setup, subject) in table {
+            let mut status = on_line();
+            setup(&mut status);
+
+            let actions = Monitor::new(&settings).evaluate(&status, &settings);
+            assert_eq!(subjects(&actions).last(), Some(&subject), "{}", name);
+            assert_eq!(actions.last(), Some(&Action::Shutdown), "{}", name);
+        }
+    }
+
+    #[test]
+    fn bogus_runtime_is_ignored() {
+        let settings = UpsSettings {
+            min_runtime_seconds: 300,
+            ..UpsSettings::default()
+        };
+        for seconds_to_empty in [0, MAX_PLAUSIBLE_RUNTIME_SECONDS + 1] {
+            let mut status = on_battery();
+            status.seconds_to_empty = seconds_to_empty;
+
+            let actions = Monitor::new(&settings).evaluate(&status, &settings);
+            assert!(
+                !actions.contains(&Action::Shutdown),
+                "{}s",
+                seconds_to_empty
+            );
+        }
+    }
+
+    #[test]
+    fn low_battery_shuts_down_only_when_not_charging() {
+        let settings = UpsSettings::default();
+        let mut status = on_line();
+        status.remaining_capacity = settings.battery_shutdown_threshold - 1;
+
+        let actions = Monitor::new(&settings).evaluate(&status, &settings);
+        assert!(!actions.contains(&Action::Shutdown));
+
+        status.charging = false;
+        let actions = Monitor::new(&settings).evaluate(&status, &settings);
+        assert_eq!(
+            subjects(&actions),
+            ["Battery below shutdown threshold and not charging - shutting down."]
+        );
+        assert_eq!(actions.last(), Some(&Action::Shutdown));
+    }
+
+    #[test]
+    fn load_warning_clears_with_hysteresis() {
+        let settings = UpsSettings {
+            output_load_warn_threshold: 80,
+            ..Default::default()
+        };
+        let mut monitor = Monitor::new(&settings);
+        let mut status = on_line();
+
+        let table = [
+            (80, vec![]),
+            (81, vec!["UPS load high."]),
+            (95, vec![]),
+            (76, vec![]),
+            (75, vec!["UPS load back to normal."]),
+            (50, vec![]),
+        ];
+        for (load, expected) in table {
+            status.output_load = load;
+            let actions = monitor.evaluate(&status, &settings);
+            assert_eq!(subjects(&actions), expected, "load {}", load);
+            assert!(!actions.contains(&Action::Shutdown));
+        }
+    }
+
+    #[test]
+    fn battery_low_clears_with_hysteresis() {
+        let settings = UpsSettings::default();
+        let mut monitor = Monitor::new(&settings);
+        let mut status = on_line();
+
+        let table = [
+            (
+                settings.battery_alert_threshold - 1,
+                vec!["Battery low capacity."],
+            ),
+            (settings.battery_alert_threshold - 2, vec![]),
+            (settings.battery_alert_threshold, vec![]),
+            (
+                settings.battery_alert_threshold + settings.battery_alert_hysteresis,
+                vec!["Battery capacity recovered."],
+            ),
+        ];
+        for (capacity, expected) in table {
+            status.remaining_capacity = capacity;
+            let actions = monitor.evaluate(&status, &settings);
+            assert_eq!(subjects(&actions), expected, "capacity {}", capacity);
+            assert_eq!(
+                actions.contains(&Action::RunHook(Hook::LowBattery)),
+                capacity == settings.battery_alert_threshold - 1
+            );
+        }
+    }
+
+    #[test]
+    fn pending_shutdown_is_cancelled_once_restored() {
+        let settings = UpsSettings::default();
+        let mut monitor = Monitor::new(&settings);
+        let mut status = on_line();
+        status.shutdown_active = true;
+
+        let actions = monitor.evaluate(&status, &settings);
+        assert!(actions.contains(&Action::CancelShutdown));
+
+        // Only the first failure alerts, until a cancel succeeds.
+        assert!(monitor
+            .cancel_finished(Err(UPSError::CancelIgnored), &status, &settings)
+            .is_some());
+        assert!(monitor
+            .cancel_finished(Err(UPSError::CancelIgnored), &status, &settings)
+            .is_none());
+        assert!(monitor
+            .cancel_finished(Ok(()), &status, &settings)
+            .is_some());
+    }
+
+    #[test]
+    fn stuck_conditions_alert_once() {
+        // A long outage at low capacity, without reminders, alerts once each way.
+        let settings = UpsSettings {
+            seconds_to_shutdown: 3600,
+            ..Default::default()
+        };
+        let mut monitor = Monitor::new(&settings);
+        let mut status = on_battery();
+        status.remaining_capacity = settings.battery_alert_threshold - 1;
+
+        let actions = monitor.evaluate(&status, &settings);
+        assert_eq!(
+            subjects(&actions),
+            ["Utility failed.", "Battery low capacity."]
+        );
+        for _ in 0..20 {
+            assert!(subjects(&monitor.evaluate(&status, &settings)).is_empty());
+        }
+
+        let mut status = on_line();
+        assert_eq!(
+            subjects(&monitor.evaluate(&status, &settings)),
+            ["Utility restored.", "Battery capacity recovered."]
+        );
+        status.remaining_capacity = 100;
+        assert!(subjects(&monitor.evaluate(&status, &settings)).is_empty());
+    }
+
+    #[test]
+    fn stuck_conditions_remind_after_interval() {
+        let settings = UpsSettings {
+            seconds_to_shutdown: 3600,
+            repeat_interval_minutes: 30,
+            ..Default::default()
+        };
+        let mut monitor = Monitor::new(&settings);
+        let mut status = on_battery();
+        status.remaining_capacity = settings.battery_alert_threshold - 1;
+
+        assert_eq!(subjects(&monitor.evaluate(&status, &settings)).len(), 2);
+        assert!(subjects(&monitor.evaluate(&status, &settings)).is_empty());
+
+        // Backdate the alerts past the interval, rather than waiting it out.
+        let earlier = time::Instant::now().checked_sub(time::Duration::from_secs(31 * 60));
+        monitor.utility_failed.sent_at = earlier;
+        monitor.battery_low.sent_at = earlier;
+        assert_eq!(
+            subjects(&monitor.evaluate(&status, &settings)),
+            ["Utility still failed.", "Battery still low capacity."]
+        );
+        assert!(subjects(&monitor.evaluate(&status, &settings)).is_empty());
+    }
+
+    #[test]
+    fn battery_voltage_off_nominal_alerts_once() {
+        let settings = UpsSettings::default();
+        let mut monitor = Monitor::new(&settings);
+        let mut status = on_line();
+        status.rated_battery_voltage = 24.0;
+
+        let table = [
+            (None, vec![]),
+            (Some(27.0), vec![]),
+            (Some(30.0), vec!["Battery voltage off nominal."]),
+            (Some(31.0), vec![]),
+            (Some(26.0), vec!["Battery voltage back to nominal."]),
+        ];
+        for (voltage, expected) in table {
+            status.battery_group_voltage = voltage;
+            let actions = monitor.evaluate(&status, &settings);
+            assert_eq!(subjects(&actions), expected, "at {:?} V", voltage);
+        }
+    }
+
+    #[test]
+    fn low_battery_from_device_shuts_down() {
+        // Regression: the low-and-not-charging path used to alert "shutting down" without
+        // shutting down. Drive it from device replies, so the parsed flags are covered too.
+        let state = MockState::new();
+        {
+            let mut state = state.borrow_mut();
+            state.respond("I", &[Some(b"#ACME            UPS2000    V1.0      \r")]);
+            state.respond("F", &[Some(b"#230.0 004 24.00 50.0\r")]);
+            state.respond(
+                "QS",
+                &[Some(b"(236.5 140.0 236.5 018 50.1 23.0 --.- 00001001\r")],
+            );
+            state.respond(
+                "QI",
+                &[Some(b"(015 00300 50.1 000.8 000 000 000 0000000100002\r")],
+            );
+        }
+
+        let protocol = MegatecProtocol::new(
+            Box::new(MockConnector(Rc::clone(&state))),
+            // No reconnect budget, as the unanswered info queries would otherwise spend it.
+            Timing {
+                max_reconnect_ms: 0,
+                ..Default::default()
+            },
+            None,
+        );
+        let status = UPS::new(Box::new(protocol)).unwrap().status;
+        assert_eq!(status.remaining_capacity, 15);
+        assert!(!status.charging);
+
+        let settings = UpsSettings::default();
+        let actions = Monitor::new(&settings).evaluate(&status, &settings);
+        assert_eq!(
+            subjects(&actions),
+            ["Battery below shutdown threshold and not charging - shutting down."]
+        );
+        assert_eq!(actions.last(), Some(&Action::Shutdown));
+    }
+
+    #[test]
+    fn battery_low_bit_shuts_down_when_not_charging() {
+        // The UPS flagging its battery low at 40%, well above our own threshold, on battery.
+        let mut status = on_battery();
+        status.battery_low = true;
+        status.remaining_capacity = 40;
+
+        let settings = UpsSettings::default();
+        let actions = Monitor::new(&settings).evaluate(&status, &settings);
+        assert!(subjects(&actions)
+            .contains(&"UPS reports battery low and not charging - shutting down."));
+        assert!(actions.contains(&Action::Shutdown));
+    }
+
+    #[test]
+    fn self_test_result_is_picked_up_by_later_polls() {
+        let settings = UpsSettings::default();
+        let mut monitor = Monitor::new(&settings);
+        let mut status = on_line();
+        status.test_result = UPSTestResults::Passed;
+
+        assert_eq!(monitor.self_test_started(Ok(()), &status, &settings), None);
+        // The previous result doesn't count until the test has had time to run.
+        assert!(subjects(&monitor.evaluate(&status, &settings)).is_empty());
+
+        let ago = |seconds| time::Instant::now().checked_sub(time::Duration::from_secs(seconds));
+        monitor.self_test_started = ago(TestKind::Quick.duration().as_secs());
+        status.test_result = UPSTestResults::InProgress;
+        assert!(subjects(&monitor.evaluate(&status, &settings)).is_empty());
+
+        status.test_result = UPSTestResults::Warning;
+        let actions = monitor.evaluate(&status, &settings);
+        assert_eq!(subjects(&actions), ["Self-test result: Warning."]);
+        assert!(subjects(&monitor.evaluate(&status, &settings)).is_empty());
+
+        // One still running past the timeout is given up on.
+        monitor.self_test_started =
+            ago(TestKind::Quick.duration().as_secs() + settings.self_test_timeout);
+        status.test_result = UPSTestResults::InProgress;
+        assert_eq!(
+            subjects(&monitor.evaluate(&status, &settings)),
+            ["Self-test did not complete - treating as aborted."]
+        );
+        assert_eq!(monitor.self_test_started, None);
+    }
+}
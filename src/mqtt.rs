@@ -0,0 +1,370 @@
+#[cfg(feature = "mqtt")]
+use crate::status;
+
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "mqtt")]
+use serde_json::json;
+#[cfg(feature = "mqtt")]
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+#[cfg(feature = "mqtt")]
+use tracing::{info, warn};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MqttSettings {
+    // Outline for the optional `[mqtt]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // - `enabled` turns on publishing the status each poll (requires the `mqtt` feature)
+    // - `host` and `port` give the broker, with optional `username` and `password`
+    // - `tls` connects over TLS (requires the `mqtt-tls` feature), trusting the system's roots or
+    //   just `ca_file` if given
+    // - `base_topic` is where the status (`<base_topic>/state`), its key values
+    //   (`<base_topic>/<field>`) and availability (`<base_topic>/availability`) are published
+    // - `homeassistant_discovery` announces the sensors to Home Assistant under
+    //   `discovery_prefix`
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: bool,
+    pub ca_file: Option<String>,
+    pub base_topic: String,
+    pub homeassistant_discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            username: None,
+            password: None,
+            tls: false,
+            ca_file: None,
+            base_topic: "ups".to_string(),
+            homeassistant_discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+// Seconds to wait before reconnecting to the broker after an error.
+#[cfg(feature = "mqtt")]
+const RECONNECT_DELAY: u64 = 5;
+
+// Milliseconds to give the connection to send our goodbye before we exit.
+#[cfg(feature = "mqtt")]
+const CLOSE_GRACE_MS: u64 = 500;
+
+// The sensors announced to Home Assistant: the status field, name, unit and device class.
+#[cfg(feature = "mqtt")]
+const SENSORS: [(&str, &str, Option<&str>, Option<&str>); 9] = [
+    ("input_voltage", "Input voltage", Some("V"), Some("voltage")),
+    (
+        "input_frequency",
+        "Input frequency",
+        Some("Hz"),
+        Some("frequency"),
+    ),
+    (
+        "output_voltage",
+        "Output voltage",
+        Some("V"),
+        Some("voltage"),
+    ),
+    (
+        "output_current",
+        "Output current",
+        Some("A"),
+        Some("current"),
+    ),
+    ("output_load", "Output load", Some("%"), None),
+    (
+        "battery_voltage",
+        "Battery voltage",
+        Some("V"),
+        Some("voltage"),
+    ),
+    ("remaining_capacity", "Battery", Some("%"), Some("battery")),
+    ("seconds_to_empty", "Runtime", Some("s"), Some("duration")),
+    ("ups_mode", "Mode", None, None),
+];
+
+// The binary sensors announced to Home Assistant: the status flag, name and device class.
+#[cfg(feature = "mqtt")]
+const BINARY_SENSORS: [(&str, &str, Option<&str>); 6] = [
+    ("utility_failed", "Utility failed", Some("problem")),
+    ("battery_low", "Battery low", Some("battery")),
+    ("charging", "Charging", Some("battery_charging")),
+    ("fault", "Fault", Some("problem")),
+    ("overloaded", "Overloaded", Some("problem")),
+    ("replace_battery", "Replace battery", Some("problem")),
+];
+
+#[cfg(feature = "mqtt")]
+pub struct Publisher {
+    client: rumqttc::Client,
+    base_topic: String,
+    availability_topic: String,
+    // Whether we can read the UPS, shared with the connection so it announces the right thing.
+    available: Arc<AtomicBool>,
+}
+
+#[cfg(feature = "mqtt")]
+impl Publisher {
+    pub fn connect(
+        settings: &MqttSettings,
+        machine_id: &str,
+        status: &status::UPSStatus,
+    ) -> Option<Publisher> {
+        if !settings.enabled {
+            return None;
+        }
+
+        // Topics and ids can't contain spaces or wildcards, so keep the id to the safe characters.
+        let node_id: String = machine_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let state_topic = format!("{}/state", settings.base_topic);
+        let availability_topic = format!("{}/availability", settings.base_topic);
+
+        // The broker marks us offline itself if we drop off without saying so.
+        let mut options =
+            rumqttc::MqttOptions::new(format!("ups-{}", node_id), &settings.host, settings.port);
+        options.set_last_will(rumqttc::LastWill::new(
+            &availability_topic,
+            "offline",
+            rumqttc::QoS::AtLeastOnce,
+            true,
+        ));
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+            options.set_credentials(username, password);
+        }
+        if settings.tls {
+            // Never fall back to plain text, as that would give away the credentials.
+            #[cfg(feature = "mqtt-tls")]
+            options.set_transport(match &settings.ca_file {
+                Some(path) => match std::fs::read(path) {
+                    Ok(ca) => rumqttc::Transport::tls(ca, None, None),
+                    Err(e) => {
+                        warn!(
+                            "Failed to read MQTT CA file {}, not publishing: {}",
+                            path, e
+                        );
+                        return None;
+                    }
+                },
+                None => rumqttc::Transport::tls_with_default_config(),
+            });
+            #[cfg(not(feature = "mqtt-tls"))]
+            {
+                warn!("MQTT TLS is enabled but this build lacks the `mqtt-tls` feature, not publishing.");
+                return None;
+            }
+        }
+
+        let discovery = if settings.homeassistant_discovery {
+            discovery(
+                settings,
+                &node_id,
+                &state_topic,
+                &availability_topic,
+                status,
+            )
+        } else {
+            Vec::new()
+        };
+        let (client, mut connection) = rumqttc::Client::new(options, 64);
+        let announcer = client.clone();
+        let available = Arc::new(AtomicBool::new(true));
+        let announce_available = Arc::clone(&available);
+        let announce_topic = availability_topic.clone();
+        info!(
+            "Publishing UPS status to mqtt://{}:{}/{}",
+            settings.host, settings.port, state_topic
+        );
+
+        // Drive the connection, announcing ourselves each time we (re)connect.
+        thread::spawn(move || loop {
+            for event in connection.iter() {
+                match event {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        let availability = if announce_available.load(Ordering::Relaxed) {
+                            "online"
+                        } else {
+                            "offline"
+                        };
+                        let messages = discovery
+                            .iter()
+                            .map(|(topic, payload)| (topic.as_str(), payload.clone()))
+                            .chain([(announce_topic.as_str(), availability.to_string())]);
+                        for (topic, payload) in messages {
+                            if let Err(e) = announcer.try_publish(
+                                topic,
+                                rumqttc::QoS::AtLeastOnce,
+                                true,
+                                payload,
+                            ) {
+                                warn!("Failed to announce to MQTT: {}", e)
+                            }
+                        }
+                    }
+                    // We only disconnect on the way out, so stop driving the connection.
+                    Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => return,
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("MQTT connection failed: {}", e);
+                        break;
+                    }
+                }
+            }
+            thread::sleep(time::Duration::from_secs(RECONNECT_DELAY));
+        });
+
+        Some(Publisher {
+            client,
+            base_topic: settings.base_topic.clone(),
+            availability_topic,
+            available,
+        })
+    }
+
+    fn try_publish(&self, topic: &str, qos: rumqttc::QoS, payload: String) {
+        // Never wait on the broker, as that would hold up the poll loop.
+        if let Err(e) = self.client.try_publish(topic, qos, true, payload) {
+            warn!("Failed to publish to MQTT {}: {}", topic, e)
+        }
+    }
+
+    pub fn publish(&self, status: &status::UPSStatus, available: bool) {
+        // Mark the sensors unavailable while we can't read the UPS, rather than repeating a stale
+        // status, and only say so when it changes as the broker retains it.
+        if self.available.swap(available, Ordering::Relaxed) != available {
+            let availability = if available { "online" } else { "offline" };
+            self.try_publish(
+                &self.availability_topic,
+                rumqttc::QoS::AtLeastOnce,
+                availability.to_string(),
+            );
+        }
+        if !available {
+            return;
+        }
+
+        let fields = match serde_json::to_value(status) {
+            Ok(fields) => fields,
+            Err(e) => {
+                warn!("Failed to serialize UPS status: {}", e);
+                return;
+            }
+        };
+        self.try_publish(
+            &format!("{}/state", self.base_topic),
+            rumqttc::QoS::AtMostOnce,
+            fields.to_string(),
+        );
+        // The key values on their own too, for anything that can't pick apart the JSON.
+        for (key, _, _, _) in SENSORS {
+            let value = match &fields[key] {
+                serde_json::Value::String(text) => text.clone(),
+                value => value.to_string(),
+            };
+            self.try_publish(
+                &format!("{}/{}", self.base_topic, key),
+                rumqttc::QoS::AtMostOnce,
+                value,
+            );
+        }
+    }
+
+    pub fn close(&self) {
+        // Say we're going rather than leave it to the last will, which only fires after the
+        // broker's keep alive runs out.
+        self.try_publish(
+            &self.availability_topic,
+            rumqttc::QoS::AtLeastOnce,
+            "offline".to_string(),
+        );
+        if let Err(e) = self.client.try_disconnect() {
+            warn!("Failed to disconnect from MQTT: {}", e)
+        }
+        thread::sleep(time::Duration::from_millis(CLOSE_GRACE_MS));
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn discovery(
+    settings: &MqttSettings,
+    node_id: &str,
+    state_topic: &str,
+    availability_topic: &str,
+    status: &status::UPSStatus,
+) -> Vec<(String, String)> {
+    // Home Assistant discovery configs, so the sensors register themselves as one device.
+    let device = json!({
+        "identifiers": [format!("ups_{}", node_id)],
+        "name": format!("UPS ({})", node_id),
+        "manufacturer": status.manufacturer,
+        "model": status.model,
+        "sw_version": status.firmware_version,
+    });
+
+    let mut configs: Vec<(String, String)> = Vec::new();
+    for (key, name, unit, class) in SENSORS {
+        let mut config = json!({
+            "name": name,
+            "unique_id": format!("ups_{}_{}", node_id, key),
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} }}}}", key),
+            "availability_topic": availability_topic,
+            "device": device,
+        });
+        if let Some(unit) = unit {
+            config["unit_of_measurement"] = json!(unit);
+            config["state_class"] = json!("measurement");
+        }
+        if let Some(class) = class {
+            config["device_class"] = json!(class);
+        }
+        configs.push((
+            format!(
+                "{}/sensor/ups_{}/{}/config",
+                settings.discovery_prefix, node_id, key
+            ),
+            config.to_string(),
+        ));
+    }
+    for (key, name, class) in BINARY_SENSORS {
+        let mut config = json!({
+            "name": name,
+            "unique_id": format!("ups_{}_{}", node_id, key),
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ 'ON' if value_json.{} else 'OFF' }}}}", key),
+            "availability_topic": availability_topic,
+            "device": device,
+        });
+        if let Some(class) = class {
+            config["device_class"] = json!(class);
+        }
+        configs.push((
+            format!(
+                "{}/binary_sensor/ups_{}/{}/config",
+                settings.discovery_prefix, node_id, key
+            ),
+            config.to_string(),
+        ));
+    }
+
+    configs
+}
@@ -0,0 +1,614 @@
+use crate::status;
+
+use lettre::transport::smtp;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread, time,
+};
+use tracing::{error, info, warn};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EventSettings {
+    // Outline for the optional `[events]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // Each flag enables alerts for one kind of event, all defaulting to on. Disabled events are
+    // still logged.
+    pub utility_failed: bool,
+    pub utility_restored: bool,
+    pub fault: bool,
+    pub mode_changed: bool,
+    pub overload: bool,
+    pub replace_battery: bool,
+    pub battery_low: bool,
+    pub load_high: bool,
+    pub comm_failed: bool,
+    pub comm_restored: bool,
+    pub shutdown: bool,
+    pub stopping: bool,
+}
+
+impl EventSettings {
+    pub fn enabled(&self, event: EventKind) -> bool {
+        // Whether alerts for the event are sent. Events without a flag always are.
+        match event {
+            EventKind::UtilityFailed => self.utility_failed,
+            EventKind::UtilityRestored => self.utility_restored,
+            EventKind::Fault => self.fault,
+            EventKind::ModeChanged => self.mode_changed,
+            EventKind::Overload => self.overload,
+            EventKind::ReplaceBattery | EventKind::BatteryVoltage => self.replace_battery,
+            EventKind::BatteryLow | EventKind::BatteryRecovered => self.battery_low,
+            EventKind::LoadHigh | EventKind::LoadNormal => self.load_high,
+            EventKind::CommFailed => self.comm_failed,
+            EventKind::CommRestored => self.comm_restored,
+            EventKind::Shutdown | EventKind::ShutdownFailed | EventKind::CancelShutdown => {
+                self.shutdown
+            }
+            EventKind::Stopping => self.stopping,
+            _ => true,
+        }
+    }
+}
+
+impl Default for EventSettings {
+    fn default() -> Self {
+        EventSettings {
+            utility_failed: true,
+            utility_restored: true,
+            fault: true,
+            mode_changed: true,
+            overload: true,
+            replace_battery: true,
+            battery_low: true,
+            load_high: true,
+            comm_failed: true,
+            comm_restored: true,
+            shutdown: true,
+            stopping: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    // What an alert is about, so machines reading it needn't parse the subject.
+    UtilityFailed,
+    UtilityRestored,
+    ModeChanged,
+    Fault,
+    Overload,
+    ReplaceBattery,
+    BatteryVoltage,
+    BatteryLow,
+    BatteryRecovered,
+    // The load crossing `output_load_warn_threshold`, ahead of an overload, and dropping back.
+    LoadHigh,
+    LoadNormal,
+    CommFailed,
+    CommRestored,
+    NotFound,
+    Shutdown,
+    ShutdownFailed,
+    CancelShutdown,
+    // A hook command failed or timed out.
+    HookFailed,
+    SelfTest,
+    Stopping,
+    // Edited settings didn't validate on a reload, so the old ones were kept.
+    ReloadFailed,
+    // The monitor checking in, so its absence stands out.
+    Heartbeat,
+    Stalled,
+    Unstalled,
+    // Several alerts batched together by the rate limit.
+    Digest,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    // Something recovered or finished, worth knowing but nothing to do.
+    Info,
+    // Something needs watching, e.g. an outage or a low battery.
+    Warning,
+    // The machine is going down or the UPS can't protect it.
+    Critical,
+}
+
+// Milliseconds to wait for a backend's queued alerts before we exit, so it can't hold up a shutdown.
+const FLUSH_TIMEOUT_MS: u64 = 2000;
+
+// Alerts raised since we started, across every unit, e.g. for the status page.
+static ALERTS_SENT: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "http")]
+pub fn alerts_sent() -> u64 {
+    ALERTS_SENT.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    // One alert, with the status as it stood when raised, for backends that show it.
+    pub kind: EventKind,
+    pub severity: Severity,
+    pub subject: String,
+    pub message: String,
+    pub status: Option<status::UPSStatus>,
+}
+
+impl Event {
+    pub fn subject(&self, dry_run: bool) -> String {
+        // Mark dry runs, so nobody mistakes them for the real thing.
+        if dry_run {
+            format!("[DRY RUN] {}", self.subject)
+        } else {
+            self.subject.clone()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NotifyError {
+    // The backend's worker thread has gone, so the alert can't be queued.
+    Stopped,
+    // The alert couldn't be put together, e.g. an invalid email address.
+    Invalid(String),
+    Smtp(smtp::Error),
+}
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NotifyError::Stopped => write!(f, "Delivery thread has stopped"),
+            NotifyError::Invalid(reason) => write!(f, "Invalid alert: {}", reason),
+            NotifyError::Smtp(_) => write!(f, "Failed to send email"),
+        }
+    }
+}
+impl Error for NotifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NotifyError::Smtp(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<smtp::Error> for NotifyError {
+    fn from(e: smtp::Error) -> Self {
+        NotifyError::Smtp(e)
+    }
+}
+
+pub trait Notifier: Send {
+    // What the backend is, e.g. "email", for reporting its failures.
+    fn name(&self) -> &str;
+
+    // Send a UPS alert, with its severity deciding who it goes to. Backends that deliver from a
+    // thread of their own only fail here if they can't queue it, reporting later failures there.
+    fn notify(&self, event: &Event) -> Result<(), NotifyError>;
+
+    // Send anything held back, called each poll. `force` sends it regardless of any limits,
+    // e.g. before we exit.
+    fn flush(&self, _force: bool) {}
+}
+
+enum Call<T> {
+    // A payload to deliver.
+    Send(T),
+    // Say so once everything queued before it is done.
+    Flush(mpsc::Sender<()>),
+}
+
+pub struct Worker<T> {
+    // Delivers a backend's payloads from a thread of its own, so a slow or unreachable endpoint
+    // never holds up the poll loop. The sender reports its own failures.
+    calls: mpsc::Sender<Call<T>>,
+    name: &'static str,
+}
+
+impl<T: Send + 'static> Worker<T> {
+    pub fn spawn(name: &'static str, mut send: impl FnMut(T) + Send + 'static) -> Worker<T> {
+        let (calls, queue) = mpsc::channel();
+        thread::spawn(move || {
+            for call in queue {
+                match call {
+                    Call::Send(payload) => send(payload),
+                    Call::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        Worker { calls, name }
+    }
+
+    pub fn send(&self, payload: T) -> Result<(), NotifyError> {
+        self.calls
+            .send(Call::Send(payload))
+            .map_err(|_| NotifyError::Stopped)
+    }
+
+    pub fn flush(&self, force: bool) {
+        // Before we exit, give the queued payloads a moment to go out, but not long enough to
+        // hold up a shutdown.
+        if !force {
+            return;
+        }
+        let (done, finished) = mpsc::channel();
+        if self.calls.send(Call::Flush(done)).is_ok()
+            && finished
+                .recv_timeout(time::Duration::from_millis(FLUSH_TIMEOUT_MS))
+                .is_err()
+        {
+            warn!("Gave up waiting for {}.", self.name)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MultiNotifier {
+    // Every configured backend, so one failing never stops the others, with the latest status to
+    // attach to each alert.
+    notifiers: Vec<Box<dyn Notifier>>,
+    status: RefCell<Option<status::UPSStatus>>,
+}
+
+impl MultiNotifier {
+    pub fn push(&mut self, notifier: Box<dyn Notifier>) {
+        self.notifiers.push(notifier);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifiers.is_empty()
+    }
+
+    pub fn update(&self, status: &status::UPSStatus) {
+        // Keep up with the latest status, called each poll.
+        *self.status.borrow_mut() = Some(status.clone());
+    }
+
+    pub fn send(&self, kind: EventKind, severity: Severity, subject: &str, message: &str) {
+        // Raise an alert with the latest status. Failures are logged by `notify`, and there's
+        // nothing more to do about them here.
+        let _ = self.notify(&Event {
+            kind,
+            severity,
+            subject: subject.to_string(),
+            message: message.to_string(),
+            status: self.status.borrow().clone(),
+        });
+    }
+}
+
+impl Notifier for MultiNotifier {
+    fn name(&self) -> &str {
+        "notifiers"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        // Fan the alert out to every backend, logging each failure, and returning the first.
+        info!(event = ?event.kind, severity = ?event.severity, "{}", event.subject);
+        ALERTS_SENT.fetch_add(1, Ordering::Relaxed);
+        let mut result = Ok(());
+        for notifier in &self.notifiers {
+            if let Err(e) = notifier.notify(event) {
+                error!(
+                    "Failed to send alert by {}: {}",
+                    notifier.name(),
+                    report(&e)
+                );
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    fn flush(&self, force: bool) {
+        for notifier in &self.notifiers {
+            notifier.flush(force);
+        }
+    }
+}
+
+fn report(error: &dyn Error) -> String {
+    // The error and everything behind it, e.g. "A: B: C".
+    let mut report = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        report.push_str(&format!(": {}", error));
+        source = error.source();
+    }
+    report
+}
+
+pub struct ConsoleNotifier {
+    // Logs the whole alert, for running without any email relay or webhook, e.g. on a bench.
+    pub machine_id: String,
+}
+
+impl Notifier for ConsoleNotifier {
+    fn name(&self) -> &str {
+        "console"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        warn!("{}: {}\n{}", self.machine_id, event.subject, event.message);
+        Ok(())
+    }
+}
+
+pub struct RateLimited<N: Notifier> {
+    // Wraps a notifier to send at most `max_per_minute` alerts, batching the rest into a digest
+    // sent once the first batched alert is `digest_window` old.
+    inner: N,
+    max_per_minute: usize,
+    digest_window: time::Duration,
+    sent: RefCell<VecDeque<time::Instant>>,
+    pending: RefCell<Vec<Event>>,
+    pending_since: RefCell<Option<time::Instant>>,
+}
+
+impl<N: Notifier> RateLimited<N> {
+    pub fn new(inner: N, max_per_minute: usize, digest_window: time::Duration) -> RateLimited<N> {
+        RateLimited {
+            inner,
+            max_per_minute,
+            digest_window,
+            sent: RefCell::new(VecDeque::new()),
+            pending: RefCell::new(Vec::new()),
+            pending_since: RefCell::new(None),
+        }
+    }
+
+    fn has_capacity(&self) -> bool {
+        // Forget sends from over a minute ago, then check we've room for another.
+        let mut sent = self.sent.borrow_mut();
+        while let Some(at) = sent.front() {
+            if at.elapsed() < time::Duration::from_secs(60) {
+                break;
+            }
+            sent.pop_front();
+        }
+
+        self.max_per_minute == 0 || sent.len() < self.max_per_minute
+    }
+
+    fn send_now(&self, event: &Event) -> Result<(), NotifyError> {
+        self.sent.borrow_mut().push_back(time::Instant::now());
+        self.inner.notify(event)
+    }
+}
+
+impl<N: Notifier> Notifier for RateLimited<N> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        // Once batching, keep batching, so the digest keeps alerts in order.
+        if self.pending.borrow().is_empty() && self.has_capacity() {
+            return self.send_now(event);
+        }
+
+        info!("Rate limited, adding to the next digest: {}", event.subject);
+        self.pending.borrow_mut().push(event.clone());
+        self.pending_since
+            .borrow_mut()
+            .get_or_insert_with(time::Instant::now);
+        Ok(())
+    }
+
+    fn flush(&self, force: bool) {
+        let due = match *self.pending_since.borrow() {
+            Some(since) => force || since.elapsed() >= self.digest_window,
+            None => false,
+        };
+        if !due || !(force || self.has_capacity()) {
+            return;
+        }
+
+        let pending: Vec<Event> = self.pending.borrow_mut().drain(..).collect();
+        *self.pending_since.borrow_mut() = None;
+
+        // The digest is as severe as the most severe alert in it, and carries the latest status.
+        let mut digest = String::new();
+        let mut severity = Severity::Info;
+        for event in &pending {
+            severity = severity.max(event.severity);
+            digest.push_str(&format!("{}\n{}\n\n", event.subject, event.message));
+        }
+        let digest = Event {
+            kind: EventKind::Digest,
+            severity,
+            subject: format!("{} batched UPS alerts.", pending.len()),
+            message: digest,
+            status: pending.last().and_then(|event| event.status.clone()),
+        };
+        if let Err(e) = self.send_now(&digest) {
+            error!("Failed to send digest by {}: {}", self.name(), report(&e));
+        }
+    }
+}
+
+pub fn machine_id(configured: Option<String>) -> String {
+    // Specify a fallback for `machine_id`, being simply the machine hostname.
+    configured.unwrap_or_else(|| {
+        hostname::get()
+            .expect("Failed to retrieve hostname")
+            .into_string()
+            .expect("Failed to convert hostname to string")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Recording {
+        // Every alert sent, shared so the test can look after handing it over, failing each one
+        // if asked to.
+        sent: Arc<Mutex<Vec<Event>>>,
+        failing: bool,
+    }
+
+    impl Notifier for Recording {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+            self.sent.lock().unwrap().push(event.clone());
+            if self.failing {
+                return Err(NotifyError::Invalid("refused".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    fn sent(recording: &Recording) -> Vec<(EventKind, Severity, String)> {
+        recording
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| (e.kind, e.severity, e.subject.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn fans_out_to_every_notifier_with_the_latest_status() {
+        let (first, second) = (Recording::default(), Recording::default());
+        let mut notifiers = MultiNotifier::default();
+        notifiers.push(Box::new(first.clone()));
+        notifiers.push(Box::new(second.clone()));
+
+        notifiers.update(&status::UPSStatus {
+            remaining_capacity: 42,
+            ..Default::default()
+        });
+        notifiers.send(
+            EventKind::UtilityFailed,
+            Severity::Warning,
+            "Utility failed.",
+            "",
+        );
+
+        for recording in [first, second] {
+            assert_eq!(
+                sent(&recording),
+                vec![(
+                    EventKind::UtilityFailed,
+                    Severity::Warning,
+                    "Utility failed.".to_string()
+                )]
+            );
+            let status = recording.sent.lock().unwrap()[0].status.clone();
+            assert_eq!(status.map(|s| s.remaining_capacity), Some(42));
+        }
+    }
+
+    #[test]
+    fn a_failing_notifier_doesnt_stop_the_rest() {
+        let failing = Recording {
+            failing: true,
+            ..Default::default()
+        };
+        let working = Recording::default();
+        let mut notifiers = MultiNotifier::default();
+        notifiers.push(Box::new(failing.clone()));
+        notifiers.push(Box::new(working.clone()));
+
+        let event = Event {
+            kind: EventKind::Shutdown,
+            severity: Severity::Critical,
+            subject: "Shutting down.".to_string(),
+            message: String::new(),
+            status: None,
+        };
+        assert!(matches!(
+            notifiers.notify(&event),
+            Err(NotifyError::Invalid(_))
+        ));
+        assert_eq!(*failing.sent.lock().unwrap(), vec![event.clone()]);
+        assert_eq!(*working.sent.lock().unwrap(), vec![event]);
+    }
+
+    #[test]
+    fn rate_limit_batches_into_a_digest() {
+        let recording = Recording::default();
+        let limited = RateLimited::new(recording.clone(), 1, time::Duration::from_secs(60));
+        let event = |kind, severity, subject: &str, capacity| Event {
+            kind,
+            severity,
+            subject: subject.to_string(),
+            message: String::new(),
+            status: Some(status::UPSStatus {
+                remaining_capacity: capacity,
+                ..Default::default()
+            }),
+        };
+
+        for event in [
+            event(
+                EventKind::UtilityFailed,
+                Severity::Warning,
+                "Utility failed.",
+                90,
+            ),
+            event(EventKind::BatteryLow, Severity::Warning, "Battery low.", 20),
+            event(
+                EventKind::Shutdown,
+                Severity::Critical,
+                "Shutting down.",
+                10,
+            ),
+        ] {
+            limited.notify(&event).unwrap();
+        }
+        assert_eq!(recording.sent.lock().unwrap().len(), 1);
+
+        // Still within the window, so only a forced flush sends the digest.
+        limited.flush(false);
+        assert_eq!(recording.sent.lock().unwrap().len(), 1);
+        limited.flush(true);
+        assert_eq!(
+            sent(&recording)[1],
+            (
+                EventKind::Digest,
+                Severity::Critical,
+                "2 batched UPS alerts.".to_string()
+            )
+        );
+        let status = recording.sent.lock().unwrap()[1].status.clone();
+        assert_eq!(status.map(|s| s.remaining_capacity), Some(10));
+    }
+
+    #[test]
+    fn worker_flush_waits_for_queued_payloads() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&delivered);
+        let worker = Worker::spawn("test", move |payload: u32| {
+            thread::sleep(time::Duration::from_millis(20));
+            log.lock().unwrap().push(payload);
+        });
+        for payload in 0..3 {
+            worker.send(payload).unwrap();
+        }
+
+        worker.flush(true);
+        assert_eq!(*delivered.lock().unwrap(), vec![0, 1, 2]);
+    }
+}
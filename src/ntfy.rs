@@ -0,0 +1,272 @@
+use crate::notifier::{Event, EventKind, Notifier, NotifyError, Severity, Worker};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time;
+use tracing::{debug, error};
+
+const SEND_TIMEOUT: u64 = 10; // Seconds to wait on the server for each publish.
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NtfyPriority {
+    Min,
+    Low,
+    Default,
+    High,
+    Urgent,
+}
+
+impl NtfyPriority {
+    fn level(&self) -> u8 {
+        // As ntfy numbers them, 1 (min) to 5 (urgent).
+        match self {
+            NtfyPriority::Min => 1,
+            NtfyPriority::Low => 2,
+            NtfyPriority::Default => 3,
+            NtfyPriority::High => 4,
+            NtfyPriority::Urgent => 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NtfyPriorities {
+    // Outline for the `priorities` of the ntfy settings, the priority for each severity
+    #[serde(default = "default_info_priority")]
+    pub info: NtfyPriority,
+    #[serde(default = "default_warning_priority")]
+    pub warning: NtfyPriority,
+    #[serde(default = "default_critical_priority")]
+    pub critical: NtfyPriority,
+}
+
+fn default_info_priority() -> NtfyPriority {
+    NtfyPriority::Default
+}
+
+fn default_warning_priority() -> NtfyPriority {
+    NtfyPriority::High
+}
+
+fn default_critical_priority() -> NtfyPriority {
+    NtfyPriority::Urgent
+}
+
+impl Default for NtfyPriorities {
+    fn default() -> Self {
+        NtfyPriorities {
+            info: default_info_priority(),
+            warning: default_warning_priority(),
+            critical: default_critical_priority(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NtfySettings {
+    // Outline for an entry in `[[notifiers]]` with `type = "ntfy"`
+    //
+    // Parameters
+    // ----------
+    // - `server` is the ntfy server, e.g. your own, defaulting to ntfy.sh
+    // - `topic` to publish alerts to
+    // - `token` optionally authenticates, for protected topics
+    // - `priorities` sets the push priority for each severity
+    // - `urgent_events` always go out as urgent, whatever their severity
+    #[serde(default = "default_server")]
+    pub server: String,
+    pub topic: String,
+    pub token: Option<String>,
+    #[serde(default)]
+    pub priorities: NtfyPriorities,
+    #[serde(default = "default_urgent_events")]
+    pub urgent_events: Vec<EventKind>,
+}
+
+fn default_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+fn default_urgent_events() -> Vec<EventKind> {
+    vec![
+        EventKind::UtilityFailed,
+        EventKind::Shutdown,
+        EventKind::ShutdownFailed,
+    ]
+}
+
+impl NtfySettings {
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.server.starts_with("http://") && !self.server.starts_with("https://") {
+            problems.push(format!(
+                "ntfy server ({:?}) must be an http:// or https:// URL",
+                self.server
+            ));
+        }
+        if self.topic.is_empty() || self.topic.contains('/') {
+            problems.push(format!(
+                "ntfy topic ({:?}) must be a name, without any '/'",
+                self.topic
+            ));
+        }
+        problems
+    }
+
+    fn priority(&self, event: EventKind, severity: Severity) -> NtfyPriority {
+        if self.urgent_events.contains(&event) {
+            return NtfyPriority::Urgent;
+        }
+        match severity {
+            Severity::Info => self.priorities.info,
+            Severity::Warning => self.priorities.warning,
+            Severity::Critical => self.priorities.critical,
+        }
+    }
+}
+
+fn tags(event: EventKind, severity: Severity) -> Vec<&'static str> {
+    // Emoji shortcodes ntfy shows before the title, the severity then what it's about.
+    let mut tags = vec![match severity {
+        Severity::Info => "white_check_mark",
+        Severity::Warning => "warning",
+        Severity::Critical => "rotating_light",
+    }];
+    match event {
+        EventKind::UtilityFailed | EventKind::UtilityRestored => tags.push("electric_plug"),
+        EventKind::BatteryLow
+        | EventKind::BatteryRecovered
+        | EventKind::BatteryVoltage
+        | EventKind::ReplaceBattery => tags.push("battery"),
+        EventKind::Shutdown | EventKind::ShutdownFailed | EventKind::CancelShutdown => {
+            tags.push("zap")
+        }
+        _ => {}
+    }
+    tags
+}
+
+pub struct NtfyNotifier {
+    // Publishes alerts to an ntfy topic as push notifications.
+    worker: Worker<Value>,
+    settings: NtfySettings,
+    machine_id: String,
+    dry_run: bool,
+}
+
+impl NtfyNotifier {
+    pub fn new(settings: NtfySettings, machine_id: String, dry_run: bool) -> NtfyNotifier {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(time::Duration::from_secs(SEND_TIMEOUT))
+            .build();
+        let server = settings.server.clone();
+        let authorization = settings
+            .token
+            .as_ref()
+            .map(|token| format!("Bearer {}", token));
+        let worker = Worker::spawn("ntfy", move |payload: Value| {
+            let mut request = agent.post(&server);
+            if let Some(authorization) = &authorization {
+                request = request.set("Authorization", authorization);
+            }
+            if let Err(e) = request.send_json(payload) {
+                error!("Failed to publish to ntfy: {:?}", e)
+            }
+        });
+
+        NtfyNotifier {
+            worker,
+            settings,
+            machine_id,
+            dry_run,
+        }
+    }
+
+    fn payload(&self, event: &Event) -> Value {
+        // Published as JSON to the server root, so the title needn't fit in a header.
+        json!({
+            "topic": self.settings.topic,
+            "title": format!("{}: {}", self.machine_id, event.subject(self.dry_run)),
+            "message": event.message,
+            "priority": self.settings.priority(event.kind, event.severity).level(),
+            "tags": tags(event.kind, event.severity),
+        })
+    }
+}
+
+impl Notifier for NtfyNotifier {
+    fn name(&self) -> &str {
+        "ntfy"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        // Send a UPS alert to the ntfy topic
+        if cfg!(debug_assertions) {
+            // In debug builds, don't spam anyone!
+            debug!("In debug build, not publishing to ntfy.");
+            return Ok(());
+        }
+
+        self.worker.send(self.payload(event))
+    }
+
+    fn flush(&self, force: bool) {
+        self.worker.flush(force)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: EventKind, severity: Severity, subject: &str) -> Event {
+        Event {
+            kind,
+            severity,
+            subject: subject.to_string(),
+            message: String::new(),
+            status: None,
+        }
+    }
+
+    #[test]
+    fn priorities_follow_severity_unless_urgent() {
+        let notifier = NtfyNotifier::new(
+            NtfySettings {
+                server: default_server(),
+                topic: "ups".to_string(),
+                token: None,
+                priorities: NtfyPriorities::default(),
+                urgent_events: default_urgent_events(),
+            },
+            "rack".to_string(),
+            false,
+        );
+
+        let failed = notifier.payload(&event(
+            EventKind::UtilityFailed,
+            Severity::Warning,
+            "Utility failed",
+        ));
+        assert_eq!(failed["priority"], 5);
+        assert_eq!(failed["tags"], json!(["warning", "electric_plug"]));
+        assert_eq!(failed["title"], "rack: Utility failed");
+
+        let restored = notifier.payload(&event(
+            EventKind::UtilityRestored,
+            Severity::Info,
+            "Utility restored",
+        ));
+        assert_eq!(restored["priority"], 3);
+        assert_eq!(
+            restored["tags"],
+            json!(["white_check_mark", "electric_plug"])
+        );
+
+        assert_eq!(
+            notifier.payload(&event(EventKind::BatteryLow, Severity::Warning, ""))["priority"],
+            4
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "nut")]
+use crate::status;
+#[cfg(feature = "nut")]
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+#[cfg(feature = "nut")]
+use tracing::{debug, info, warn};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NutServerSettings {
+    // Outline for the optional `[nut_server]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // - `listen` address for NUT clients (upsmon, upsc) to connect to, e.g. "0.0.0.0:3493",
+    //   unset to not serve (requires the `nut` feature)
+    // - `name` the UPS is known by to clients, as in `<name>@<host>`
+    pub listen: Option<String>,
+    pub name: String,
+}
+
+impl Default for NutServerSettings {
+    fn default() -> Self {
+        NutServerSettings {
+            listen: None,
+            name: "ups".to_string(),
+        }
+    }
+}
+
+// The protocol version we answer `NETVER` with.
+#[cfg(feature = "nut")]
+const NETWORK_VERSION: &str = "1.3";
+
+#[cfg(feature = "nut")]
+pub struct Snapshot {
+    // The status as of the last poll, and whether that poll failed so it's out of date.
+    pub status: status::UPSStatus,
+    pub stale: bool,
+}
+
+#[cfg(feature = "nut")]
+pub fn serve(
+    settings: &NutServerSettings,
+    status: &status::UPSStatus,
+) -> Option<Arc<Mutex<Snapshot>>> {
+    let address = settings.listen.as_ref()?;
+    // Monitoring matters more than serving NUT, so carry on without it.
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to start NUT server on {}: {}", address, e);
+            return None;
+        }
+    };
+    info!("Serving NUT on {} as {}", address, settings.name);
+
+    // The poll loop updates this snapshot, so clients never wait on the UPS itself.
+    let snapshot = Arc::new(Mutex::new(Snapshot {
+        status: status.clone(),
+        stale: false,
+    }));
+    accept(listener, settings.name.clone(), Arc::clone(&snapshot));
+
+    Some(snapshot)
+}
+
+#[cfg(feature = "nut")]
+fn accept(listener: TcpListener, name: String, snapshot: Arc<Mutex<Snapshot>>) {
+    // Give each client its own thread, as upsmon holds its connection open.
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let name = name.clone();
+                    let snapshot = Arc::clone(&snapshot);
+                    thread::spawn(move || {
+                        let peer = stream
+                            .peer_addr()
+                            .map(|peer| peer.to_string())
+                            .unwrap_or_default();
+                        debug!("NUT client {} connected.", peer);
+                        if let Err(e) = converse(stream, &name, &snapshot) {
+                            debug!("NUT client {} dropped: {}", peer, e)
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept NUT client: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(feature = "nut")]
+fn converse(stream: TcpStream, name: &str, snapshot: &Mutex<Snapshot>) -> io::Result<()> {
+    // Answer each line until the client logs out or hangs up.
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let words = match shell_words::split(&line) {
+            Ok(words) if !words.is_empty() => words,
+            Ok(_) => continue,
+            Err(_) => {
+                writer.write_all(b"ERR INVALID-ARGUMENT\n")?;
+                continue;
+            }
+        };
+        let (reply, done) = respond(&words, name, &snapshot.lock().unwrap());
+        writer.write_all(reply.as_bytes())?;
+        if done {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "nut")]
+fn respond(words: &[String], name: &str, snapshot: &Snapshot) -> (String, bool) {
+    // The reply to one command, and whether to hang up after it.
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let reply = match words.as_slice() {
+        ["LIST", "UPS"] => format!(
+            "BEGIN LIST UPS\nUPS {} \"{}\"\nEND LIST UPS\n",
+            name,
+            quote(&snapshot.status.identity())
+        ),
+        ["LIST", "VAR", ups] if *ups == name => {
+            if snapshot.stale {
+                return ("ERR DATA-STALE\n".to_string(), false);
+            }
+            let mut reply = format!("BEGIN LIST VAR {}\n", name);
+            for (var, value) in variables(&snapshot.status) {
+                reply.push_str(&format!("VAR {} {} \"{}\"\n", name, var, quote(&value)));
+            }
+            reply.push_str(&format!("END LIST VAR {}\n", name));
+            reply
+        }
+        ["GET", "VAR", ups, var] if *ups == name => {
+            if snapshot.stale {
+                return ("ERR DATA-STALE\n".to_string(), false);
+            }
+            match variables(&snapshot.status)
+                .into_iter()
+                .find(|(known, _)| known == var)
+            {
+                Some((_, value)) => format!("VAR {} {} \"{}\"\n", name, var, quote(&value)),
+                None => "ERR VAR-NOT-SUPPORTED\n".to_string(),
+            }
+        }
+        ["GET", "UPSDESC", ups] if *ups == name => format!(
+            "UPSDESC {} \"{}\"\n",
+            name,
+            quote(&snapshot.status.identity())
+        ),
+        ["GET", "NUMLOGINS", ups] if *ups == name => format!("NUMLOGINS {} 0\n", name),
+        // Nothing is writable or commandable, so these lists are always empty.
+        ["LIST", list @ ("RW" | "CMD"), ups] if *ups == name => {
+            format!("BEGIN LIST {} {}\nEND LIST {} {}\n", list, name, list, name)
+        }
+        // We don't check credentials, as the status is all clients can get from us.
+        ["USERNAME", _] | ["PASSWORD", _] => "OK\n".to_string(),
+        ["LOGIN", ups] if *ups == name => "OK\n".to_string(),
+        ["LIST", "VAR" | "RW" | "CMD", _]
+        | ["GET", "VAR", _, _]
+        | ["GET", "UPSDESC" | "NUMLOGINS", _]
+        | ["LOGIN", _] => "ERR UNKNOWN-UPS\n".to_string(),
+        ["LOGOUT"] => return ("OK Goodbye\n".to_string(), true),
+        ["VER"] => format!("ups {}\n", env!("CARGO_PKG_VERSION")),
+        ["NETVER"] => format!("{}\n", NETWORK_VERSION),
+        ["STARTTLS"] => "ERR FEATURE-NOT-CONFIGURED\n".to_string(),
+        // Clients can only watch, not command the UPS or force a shutdown.
+        ["INSTCMD", ..] | ["SET", ..] | ["FSD", ..] => "ERR ACCESS-DENIED\n".to_string(),
+        _ => "ERR UNKNOWN-COMMAND\n".to_string(),
+    };
+    (reply, false)
+}
+
+#[cfg(feature = "nut")]
+fn variables(status: &status::UPSStatus) -> Vec<(&'static str, String)> {
+    // The status under the standard NUT variable names, leaving out what we don't know.
+    let mut variables = vec![
+        ("ups.status", ups_status(status)),
+        ("battery.charge", status.remaining_capacity.to_string()),
+        ("battery.voltage", format!("{:.1}", status.battery_voltage)),
+        (
+            "battery.voltage.nominal",
+            format!("{:.1}", status.rated_battery_voltage),
+        ),
+        ("input.voltage", format!("{:.1}", status.input_voltage)),
+        ("input.frequency", format!("{:.1}", status.input_frequency)),
+        ("output.voltage", format!("{:.1}", status.output_voltage)),
+        (
+            "output.voltage.nominal",
+            format!("{:.1}", status.rated_output_voltage),
+        ),
+        ("output.current", format!("{:.1}", status.output_current)),
+        (
+            "output.frequency",
+            format!("{:.1}", status.output_frequency),
+        ),
+        ("ups.load", status.output_load.to_string()),
+        (
+            "ups.beeper.status",
+            if status.beeper_on {
+                "enabled"
+            } else {
+                "disabled"
+            }
+            .to_string(),
+        ),
+    ];
+    if status.seconds_to_empty > 0 {
+        variables.push(("battery.runtime", status.seconds_to_empty.to_string()));
+    }
+    for (var, value) in [
+        ("ups.mfr", &status.manufacturer),
+        ("ups.model", &status.model),
+        ("ups.firmware", &status.firmware_version),
+    ] {
+        if let Some(value) = value {
+            variables.push((var, value.clone()));
+        }
+    }
+    variables
+}
+
+#[cfg(feature = "nut")]
+fn ups_status(status: &status::UPSStatus) -> String {
+    // The `ups.status` flags upsmon acts on, e.g. "OB LB".
+    let flags: Vec<&str> = [
+        (!status.utility_failed, "OL"),
+        (status.utility_failed, "OB"),
+        (status.battery_low, "LB"),
+        (status.charging, "CHRG"),
+        (status.replace_battery, "RB"),
+        (status.overloaded, "OVER"),
+        (status.bypass_active, "BYPASS"),
+        (status.fault || status.ups_failed, "ALARM"),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, flag)| *flag)
+    .collect();
+    flags.join(" ")
+}
+
+#[cfg(feature = "nut")]
+fn quote(value: &str) -> String {
+    // Escape a value for inside double quotes.
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(all(test, feature = "nut"))]
+mod tests {
+    use super::*;
+
+    fn snapshot(status: status::UPSStatus) -> Snapshot {
+        Snapshot {
+            status,
+            stale: false,
+        }
+    }
+
+    fn ask(command: &str, snapshot: &Snapshot) -> String {
+        respond(&shell_words::split(command).unwrap(), "ups", snapshot).0
+    }
+
+    #[test]
+    fn status_flags_follow_the_ups() {
+        let mut status = status::UPSStatus {
+            charging: true,
+            ..Default::default()
+        };
+        assert_eq!(ups_status(&status), "OL CHRG");
+
+        status.utility_failed = true;
+        status.battery_low = true;
+        status.charging = false;
+        assert_eq!(ups_status(&status), "OB LB");
+    }
+
+    #[test]
+    fn answers_upsc_and_upsmon() {
+        let snapshot = snapshot(status::UPSStatus {
+            model: Some("UPS2000".to_string()),
+            input_voltage: 230.0,
+            output_load: 12,
+            remaining_capacity: 85,
+            seconds_to_empty: 600,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            ask("LIST UPS", &snapshot),
+            "BEGIN LIST UPS\nUPS ups \"UPS2000\"\nEND LIST UPS\n"
+        );
+        assert_eq!(
+            ask("GET VAR ups battery.charge", &snapshot),
+            "VAR ups battery.charge \"85\"\n"
+        );
+        assert_eq!(
+            ask("GET VAR ups ups.status", &snapshot),
+            "VAR ups ups.status \"OL\"\n"
+        );
+        assert_eq!(
+            ask("GET VAR ups battery.temperature", &snapshot),
+            "ERR VAR-NOT-SUPPORTED\n"
+        );
+        assert_eq!(
+            ask("GET VAR other ups.status", &snapshot),
+            "ERR UNKNOWN-UPS\n"
+        );
+        assert_eq!(ask("FSD ups", &snapshot), "ERR ACCESS-DENIED\n");
+
+        let list = ask("LIST VAR ups", &snapshot);
+        assert!(list.starts_with("BEGIN LIST VAR ups\n"));
+        assert!(list.ends_with("END LIST VAR ups\n"));
+        for line in [
+            "VAR ups input.voltage \"230.0\"",
+            "VAR ups ups.load \"12\"",
+            "VAR ups battery.runtime \"600\"",
+            "VAR ups ups.model \"UPS2000\"",
+        ] {
+            assert!(list.lines().any(|l| l == line), "missing {}", line);
+        }
+
+        let stale = Snapshot {
+            stale: true,
+            ..snapshot
+        };
+        assert_eq!(ask("GET VAR ups ups.status", &stale), "ERR DATA-STALE\n");
+    }
+
+    #[test]
+    fn serves_several_clients_at_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let snapshot = Arc::new(Mutex::new(snapshot(status::UPSStatus::default())));
+        accept(listener, "ups".to_string(), Arc::clone(&snapshot));
+
+        // Both stay connected, as upsmon would, and see the status as it changes.
+        let clients: Vec<TcpStream> = (0..2)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let mut readers: Vec<BufReader<TcpStream>> = clients
+            .iter()
+            .map(|client| BufReader::new(client.try_clone().unwrap()))
+            .collect();
+        for on_battery in [false, true] {
+            snapshot.lock().unwrap().status.utility_failed = on_battery;
+            for (client, reader) in clients.iter().zip(&mut readers) {
+                let mut client = client;
+                client.write_all(b"GET VAR ups ups.status\n").unwrap();
+                let mut reply = String::new();
+                reader.read_line(&mut reply).unwrap();
+                let expected = if on_battery { "OB" } else { "OL" };
+                assert_eq!(reply, format!("VAR ups ups.status \"{}\"\n", expected));
+            }
+        }
+    }
+}
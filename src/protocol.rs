@@ -0,0 +1,81 @@
+use crate::status;
+use crate::ups::UPSError;
+
+use std::time;
+
+// The quick self-test runs for roughly ten seconds.
+const QUICK_TEST_SECONDS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestKind {
+    // The quick self-test.
+    Quick,
+    // A battery test for the given minutes, between 1 and 99.
+    Minutes(u8),
+    // A battery test that runs until the battery is low.
+    UntilLow,
+}
+
+impl TestKind {
+    pub fn duration(&self) -> time::Duration {
+        // How long the test should take, or zero if we can't know.
+        match self {
+            TestKind::Quick => time::Duration::from_secs(QUICK_TEST_SECONDS),
+            TestKind::Minutes(minutes) => time::Duration::from_secs(*minutes as u64 * 60),
+            TestKind::UntilLow => time::Duration::ZERO,
+        }
+    }
+}
+
+pub trait UpsProtocol {
+    // Everything needed to monitor and control one family of UPS.
+    // The readers fill in the parts of the status their protocol knows about, leaving the rest.
+
+    // Open (or reopen) the link and check the UPS speaks this protocol.
+    fn connect(&mut self) -> Result<(), UPSError>;
+
+    // Release the link to the UPS.
+    fn disconnect(&mut self);
+
+    // Read the manufacturer, model and firmware version.
+    fn read_info(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError>;
+
+    // Read the rated output and battery values.
+    fn read_ratings(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError>;
+
+    // Read the live measurements and status flags.
+    fn read_status(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError>;
+
+    // Read just the utility, mode and fault, for quick checks between full reads.
+    // Units without a lighter query do a full read.
+    fn read_mode(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        self.read_status(status)
+    }
+
+    // Read the battery pack count and group voltage, for units with an extended battery query.
+    // Units without one leave them unset.
+    fn read_battery_detail(&mut self, _status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        Ok(())
+    }
+
+    // Shut the UPS output off after `delay` minutes, restarting `restart` minutes later, with 0
+    // meaning no timed restart.
+    fn shutdown(&self, delay: f32, restart: i32) -> Result<(), UPSError>;
+
+    // Describe what `shutdown` would send the UPS, for dry runs.
+    fn describe_shutdown(&self, delay: f32, restart: i32) -> String {
+        format!("shut down in {}M, restarting {}M later", delay, restart)
+    }
+
+    // Cancel a pending shutdown.
+    fn cancel_shutdown(&self) -> Result<(), UPSError>;
+
+    // Start a self-test.
+    fn start_test(&self, kind: TestKind) -> Result<(), UPSError>;
+
+    // Stop a running self-test.
+    fn cancel_test(&self) -> Result<(), UPSError>;
+
+    // Toggle the beeper between enabled and disabled.
+    fn toggle_beep(&self) -> Result<(), UPSError>;
+}
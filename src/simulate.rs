@@ -0,0 +1,224 @@
+use crate::protocol::{TestKind, UpsProtocol};
+use crate::status;
+use crate::ups::UPSError;
+
+use figment::{
+    providers::{Format, Toml},
+    Figment,
+};
+use serde::Deserialize;
+use std::{cell::Cell, collections::VecDeque, path::Path, time};
+use tracing::info;
+
+#[derive(Deserialize, Debug)]
+pub struct Simulation {
+    // Outline for a `--simulate` file
+    //
+    // Parameters
+    // ----------
+    // - `poll_delay_scale` multiplies the poll delays and step durations, so a script needn't
+    //   run in real time
+    // - `states` are applied in turn, each only changing the values it sets, and held for its
+    //   `duration` seconds (or a single poll if unset)
+    #[serde(default = "default_poll_delay_scale")]
+    pub poll_delay_scale: f64,
+    pub states: Vec<SimulatedState>,
+}
+
+fn default_poll_delay_scale() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedState {
+    duration: Option<f64>,
+    capacity_per_poll: Option<i16>,
+    input_voltage: Option<f32>,
+    output_load: Option<u8>,
+    remaining_capacity: Option<u8>,
+    seconds_to_empty: Option<i32>,
+    utility_failed: Option<bool>,
+    battery_low: Option<bool>,
+    charging: Option<bool>,
+    ups_failed: Option<bool>,
+    overloaded: Option<bool>,
+    replace_battery: Option<bool>,
+    shutdown_active: Option<bool>,
+}
+
+impl Simulation {
+    pub fn load(path: &Path) -> Simulation {
+        Figment::new()
+            .merge(Toml::file(path))
+            .extract()
+            .expect("Failed to read simulation file")
+    }
+}
+
+pub struct SimulatedProtocol {
+    // Stands in for a UPS, replaying the states from a `--simulate` file.
+    states: VecDeque<SimulatedState>,
+    poll_delay_scale: f64,
+    status: status::UPSStatus,
+    cancelled: Cell<bool>,
+    // The current step, with when it ends and how it changes the capacity each poll.
+    step_ends: Option<time::Instant>,
+    capacity_per_poll: i16,
+}
+
+impl SimulatedProtocol {
+    pub fn new(simulation: Simulation) -> SimulatedProtocol {
+        // Start from a healthy UPS on line power, so scripts only need to describe changes.
+        let mut status = status::UPSStatus::default();
+        status.manufacturer = Some("Simulated".to_string());
+        status.model = Some("UPS".to_string());
+        status.input_voltage = 230.0;
+        status.input_frequency = 50.0;
+        status.output_voltage = 230.0;
+        status.output_frequency = 50.0;
+        status.rated_output_voltage = 230.0;
+        status.rated_output_frequency = 50.0;
+        status.remaining_capacity = 100;
+        status.charging = true;
+        status.ups_mode = status::UPSModes::Line;
+
+        SimulatedProtocol {
+            states: simulation.states.into(),
+            poll_delay_scale: simulation.poll_delay_scale,
+            status,
+            cancelled: Cell::new(false),
+            step_ends: None,
+            capacity_per_poll: 0,
+        }
+    }
+
+    fn apply(&mut self, state: SimulatedState) {
+        // Steps without a duration last a single poll.
+        self.step_ends = state.duration.map(|d| {
+            time::Instant::now() + time::Duration::from_secs_f64(d * self.poll_delay_scale)
+        });
+        self.capacity_per_poll = state.capacity_per_poll.unwrap_or(0);
+
+        let status = &mut self.status;
+        if let Some(v) = state.input_voltage {
+            status.input_voltage = v;
+        }
+        if let Some(v) = state.output_load {
+            status.output_load = v;
+        }
+        if let Some(v) = state.remaining_capacity {
+            status.remaining_capacity = v;
+        }
+        if let Some(v) = state.seconds_to_empty {
+            status.seconds_to_empty = v;
+        }
+        if let Some(v) = state.utility_failed {
+            status.utility_failed = v;
+        }
+        if let Some(v) = state.battery_low {
+            status.battery_low = v;
+        }
+        if let Some(v) = state.charging {
+            status.charging = v;
+        }
+        if let Some(v) = state.ups_failed {
+            status.ups_failed = v;
+        }
+        if let Some(v) = state.overloaded {
+            status.overloaded = v;
+        }
+        if let Some(v) = state.replace_battery {
+            status.replace_battery = v;
+        }
+        if let Some(v) = state.shutdown_active {
+            status.shutdown_active = v;
+        }
+
+        // Keep the derived values in line with the flags, as a real UPS would.
+        status.fault = status.ups_failed;
+        status.ups_mode = if status.fault {
+            status::UPSModes::Fault
+        } else if status.utility_failed {
+            status::UPSModes::Inverting
+        } else {
+            status::UPSModes::Line
+        };
+    }
+}
+
+impl UpsProtocol for SimulatedProtocol {
+    fn connect(&mut self) -> Result<(), UPSError> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {}
+
+    fn read_info(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        status.manufacturer = self.status.manufacturer.clone();
+        status.model = self.status.model.clone();
+        Ok(())
+    }
+
+    fn read_ratings(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        status.rated_output_voltage = self.status.rated_output_voltage;
+        status.rated_output_frequency = self.status.rated_output_frequency;
+        Ok(())
+    }
+
+    fn read_status(&mut self, status: &mut status::UPSStatus) -> Result<(), UPSError> {
+        // Step through the script, then hold on the final state.
+        let step_finished = match self.step_ends {
+            Some(ends) => time::Instant::now() >= ends,
+            None => true,
+        };
+        if step_finished && !self.states.is_empty() {
+            let state = self.states.pop_front().unwrap();
+            self.apply(state);
+            if self.states.is_empty() {
+                info!("Simulation reached its last state.");
+            }
+        } else {
+            // Drain (or charge) the battery while the step lasts.
+            let capacity = self.status.remaining_capacity as i16 + self.capacity_per_poll;
+            self.status.remaining_capacity = capacity.clamp(0, 100) as u8;
+        }
+
+        // Like a real UPS, a cancel clears the pending shutdown.
+        if self.cancelled.take() {
+            self.status.shutdown_active = false;
+        }
+
+        *status = self.status.clone();
+        Ok(())
+    }
+
+    fn shutdown(&self, delay: f32, restart: i32) -> Result<(), UPSError> {
+        info!(
+            "Simulated UPS would shut down in {}M, restarting {}M later.",
+            delay, restart
+        );
+        Ok(())
+    }
+
+    fn cancel_shutdown(&self) -> Result<(), UPSError> {
+        info!("Simulated UPS cancelled its shutdown.");
+        self.cancelled.set(true);
+        Ok(())
+    }
+
+    fn start_test(&self, kind: TestKind) -> Result<(), UPSError> {
+        info!("Simulated UPS would start a {:?} self-test.", kind);
+        Ok(())
+    }
+
+    fn cancel_test(&self) -> Result<(), UPSError> {
+        info!("Simulated UPS would cancel its self-test.");
+        Ok(())
+    }
+
+    fn toggle_beep(&self) -> Result<(), UPSError> {
+        info!("Simulated UPS would toggle its beeper.");
+        Ok(())
+    }
+}
@@ -1,5 +1,10 @@
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum UPSTestResults {
+    #[default]
     NoTest,
     Passed,
     Warning,
@@ -8,8 +13,10 @@ pub enum UPSTestResults {
     InProgress,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum UPSModes {
+    #[default]
     Idle,
     Standby,
     Line,
@@ -18,8 +25,22 @@ pub enum UPSModes {
     Fault,
 }
 
-#[derive(Debug)]
+pub fn name<T: Serialize>(value: T) -> String {
+    // The name an enum serializes to, e.g. `self_test`.
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+// Fields missing from older output are left at their defaults, so it still reads back.
+#[serde(default)]
 pub struct UPSStatus {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub firmware_version: Option<String>,
+
     pub input_voltage: f32,
     pub input_frequency: f32,
     pub input_fault_voltage: f32,
@@ -39,10 +60,20 @@ pub struct UPSStatus {
 
     pub rated_battery_voltage: f32,
 
+    // From the extended battery query, for units that answer it.
+    pub battery_count: Option<u8>,
+    pub battery_group_voltage: Option<f32>,
+
     pub utility_failed: bool,
+    pub battery_low: bool,
     pub charging: bool,
 
+    pub bypass_active: bool,
+    pub ups_failed: bool,
+    pub standby_type: bool,
+    pub test_in_progress: bool,
     pub shutdown_active: bool,
+    pub beeper_on: bool,
 
     pub fault: bool,
     pub overloaded: bool,
@@ -50,41 +81,230 @@ pub struct UPSStatus {
 
     pub test_result: UPSTestResults,
     pub ups_mode: UPSModes,
+
+    // The status bits as the UPS sent them, from `QS` and `QI`, for any vendor-specific bits
+    // beyond those decoded above. Empty for protocols without them.
+    pub raw_status_flags: String,
+    pub raw_info_flags: String,
 }
 
 impl UPSStatus {
-    pub fn new() -> UPSStatus {
-        return UPSStatus {
-            input_voltage: 0.,
-            input_frequency: 0.0,
-            input_fault_voltage: 0.0,
+    pub fn identity(&self) -> String {
+        // A short description of the unit, e.g. "ACME UPS2000 (firmware 1.0)".
+        let name = [&self.manufacturer, &self.model]
+            .iter()
+            .filter_map(|f| f.as_deref())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let name = if name.is_empty() {
+            "unknown UPS"
+        } else {
+            &name
+        };
 
-            output_voltage: 0.0,
-            output_current: 0.0,
-            output_frequency: 0.0,
-            output_load: 0,
+        match &self.firmware_version {
+            Some(version) => format!("{} (firmware {})", name, version),
+            None => name.to_string(),
+        }
+    }
 
-            rated_output_voltage: 0.0,
-            rated_output_current: 0,
-            rated_output_frequency: 0.0,
+    pub fn status_bit(&self, index: usize) -> Option<bool> {
+        // Bit `index` of the raw `QS` status bits, counting from the left as the spec does, or
+        // None if the UPS didn't send that many.
+        self.raw_status_flags
+            .as_bytes()
+            .get(index)
+            .map(|&bit| bit == b'1')
+    }
 
-            battery_voltage: 0.0,
-            remaining_capacity: 0,
-            seconds_to_empty: 0,
+    fn flags(&self) -> String {
+        // The status flags that are set, e.g. "utility failed, battery low", or "OK" for none.
+        let flags: Vec<&str> = [
+            (self.utility_failed, "utility failed"),
+            (self.battery_low, "battery low"),
+            (self.replace_battery, "replace battery"),
+            (self.ups_failed || self.fault, "UPS fault"),
+            (self.overloaded, "overloaded"),
+            (self.bypass_active, "bypass active"),
+            (self.test_in_progress, "self-test in progress"),
+            (self.shutdown_active, "shutdown pending"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect();
+        if flags.is_empty() {
+            "OK".to_string()
+        } else {
+            flags.join(", ")
+        }
+    }
 
-            rated_battery_voltage: 0.0,
+    pub fn rows(&self) -> Vec<(&'static str, String)> {
+        // Every reading with its units, named for people rather than scripts.
+        let runtime = if self.seconds_to_empty > 0 {
+            format!(
+                "{:02}:{:02}",
+                self.seconds_to_empty / 60,
+                self.seconds_to_empty % 60
+            )
+        } else {
+            "unknown".to_string()
+        };
+        let mut rows = vec![
+            ("UPS", self.identity()),
+            ("Mode", format!("{:?}", self.ups_mode)),
+            ("Status", self.flags()),
+            ("Input voltage", format!("{:.1} V", self.input_voltage)),
+            ("Input frequency", format!("{:.1} Hz", self.input_frequency)),
+            ("Output voltage", format!("{:.1} V", self.output_voltage)),
+            (
+                "Output frequency",
+                format!("{:.1} Hz", self.output_frequency),
+            ),
+            ("Output current", format!("{:.1} A", self.output_current)),
+            ("Load", format!("{} %", self.output_load)),
+            ("Battery", format!("{} %", self.remaining_capacity)),
+            ("Runtime", runtime),
+            ("Battery voltage", format!("{:.1} V", self.battery_voltage)),
+        ];
+        if let (Some(count), Some(voltage)) = (self.battery_count, self.battery_group_voltage) {
+            rows.push((
+                "Battery packs",
+                format!("{}, {:.1} V per group", count, voltage),
+            ));
+        }
+        rows.extend([
+            (
+                "Charging",
+                if self.charging { "yes" } else { "no" }.to_string(),
+            ),
+            (
+                "Beeper",
+                if self.beeper_on { "on" } else { "off" }.to_string(),
+            ),
+            (
+                "Rated output",
+                format!(
+                    "{:.1} V, {} A, {:.1} Hz",
+                    self.rated_output_voltage,
+                    self.rated_output_current,
+                    self.rated_output_frequency
+                ),
+            ),
+            (
+                "Rated battery",
+                format!("{:.1} V", self.rated_battery_voltage),
+            ),
+            ("Last self-test", format!("{:?}", self.test_result)),
+        ]);
+        rows
+    }
 
-            utility_failed: false,
-            charging: false,
+    pub fn table(&self) -> String {
+        // Every reading on its own line, for looking over at a terminal.
+        let rows = self.rows();
+        let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        rows.iter()
+            .map(|(name, value)| format!("{:<width$}  {}", name, value, width = width))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+impl fmt::Display for UPSStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A readable report for alerts, e.g. "Battery: 13.2 V, 87%, ~18 min remaining".
+        writeln!(f, "UPS: {}", self.identity())?;
+        writeln!(f, "Mode: {:?}", self.ups_mode)?;
+        writeln!(
+            f,
+            "Input: {:.1} V / {:.1} Hz",
+            self.input_voltage, self.input_frequency
+        )?;
+        writeln!(
+            f,
+            "Output: {:.1} V / {:.1} Hz",
+            self.output_voltage, self.output_frequency
+        )?;
+        writeln!(f, "Load: {}%", self.output_load)?;
+
+        // Not every UPS reports its battery voltage or a runtime estimate, so leave them out
+        // rather than claim zero.
+        write!(f, "Battery: ")?;
+        if self.battery_voltage > 0.0 {
+            write!(f, "{:.1} V, ", self.battery_voltage)?;
+        }
+        write!(f, "{}%", self.remaining_capacity)?;
+        if self.seconds_to_empty > 0 {
+            write!(f, ", ~{} min remaining", self.seconds_to_empty / 60)?;
+        }
+        if self.charging {
+            write!(f, " (charging)")?;
+        }
+        writeln!(f)?;
+        if let (Some(count), Some(voltage)) = (self.battery_count, self.battery_group_voltage) {
+            writeln!(f, "Battery packs: {}, {:.1} V per group", count, voltage)?;
+        }
+
+        writeln!(f, "Status: {}", self.flags())?;
+        write!(f, "Last self-test: {:?}", self.test_result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serde_round_trip() {
+        let mut status = UPSStatus {
+            model: Some("UPS2000".to_string()),
+            input_voltage: 236.5,
+            remaining_capacity: 85,
+            battery_count: Some(2),
+            utility_failed: true,
+            test_result: UPSTestResults::InProgress,
+            ups_mode: UPSModes::SelfTest,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&status).unwrap();
 
-            shutdown_active: false,
+        // The enums are snake case on the wire.
+        assert_eq!(json["test_result"], "in_progress");
+        assert_eq!(json["ups_mode"], "self_test");
+        assert_eq!(serde_json::from_value::<UPSStatus>(json).unwrap(), status);
 
-            fault: false,
-            overloaded: false,
-            replace_battery: false,
+        // Output from before a field existed still reads, leaving it at its default.
+        status = serde_json::from_str(r#"{"input_voltage": 230.0, "ups_mode": "line"}"#).unwrap();
+        assert_eq!(status.input_voltage, 230.0);
+        assert_eq!(status.ups_mode, UPSModes::Line);
+        assert_eq!(status.battery_count, None);
+        assert_eq!(status.test_result, UPSTestResults::NoTest);
+    }
 
-            test_result: UPSTestResults::NoTest,
-            ups_mode: UPSModes::Idle,
+    #[test]
+    fn reports_readings_with_their_units() {
+        let status = UPSStatus {
+            model: Some("UPS2000".to_string()),
+            input_voltage: 229.4,
+            input_frequency: 50.0,
+            output_load: 34,
+            battery_voltage: 13.2,
+            remaining_capacity: 87,
+            seconds_to_empty: 18 * 60,
+            utility_failed: true,
+            battery_low: true,
+            ..Default::default()
         };
+        let report = status.to_string();
+        assert!(report.contains("Input: 229.4 V / 50.0 Hz\n"));
+        assert!(report.contains("Load: 34%\n"));
+        assert!(report.contains("Battery: 13.2 V, 87%, ~18 min remaining\n"));
+        assert!(report.contains("Status: utility failed, battery low\n"));
+
+        // Readings the UPS doesn't give are left out.
+        let report = UPSStatus::default().to_string();
+        assert!(report.contains("Battery: 0%\n"));
     }
 }
@@ -0,0 +1,229 @@
+use crate::status;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::SystemTime,
+};
+use tracing::{info, warn};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusLogFormat {
+    // One JSON object per line.
+    Json,
+    // A header row, then one row per poll.
+    Csv,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StatusLogSettings {
+    // Outline for the optional `[status_log]` section of the ups settings
+    //
+    // Parameters
+    // ----------
+    // - `path` of the file to append each poll to, unset to not log
+    // - `format` of each line, either `json` or `csv`, by default `csv` for a `.csv` path and
+    //   otherwise `json`
+    // - `max_size_mb` to rotate the file at, moving it to `<path>.1`. 0 means never
+    // - `keep_files` rotated out, as `<path>.1` (the newest) to `<path>.<keep_files>`
+    pub path: Option<String>,
+    pub format: Option<StatusLogFormat>,
+    pub max_size_mb: u64,
+    pub keep_files: u32,
+}
+
+impl Default for StatusLogSettings {
+    fn default() -> Self {
+        StatusLogSettings {
+            path: None,
+            format: None,
+            max_size_mb: 10,
+            keep_files: 1,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    // A poll as logged, with when it was taken ahead of the status itself.
+    time: String,
+    #[serde(flatten)]
+    status: &'a status::UPSStatus,
+}
+
+pub struct StatusLog {
+    // Appends each poll to a file, for looking back over a power event.
+    path: String,
+    format: StatusLogFormat,
+    max_size: u64,
+    keep_files: u32,
+    failing: bool,
+}
+
+impl StatusLog {
+    pub fn new(settings: &StatusLogSettings) -> Option<StatusLog> {
+        let path = settings.path.clone()?;
+        info!("Logging UPS status to {}", path);
+
+        // Go by the extension, unless told otherwise.
+        let format = settings.format.unwrap_or_else(|| {
+            match Path::new(&path).extension().and_then(|e| e.to_str()) {
+                Some(extension) if extension.eq_ignore_ascii_case("csv") => StatusLogFormat::Csv,
+                _ => StatusLogFormat::Json,
+            }
+        });
+
+        Some(StatusLog {
+            path,
+            format,
+            max_size: settings.max_size_mb * 1024 * 1024,
+            keep_files: settings.keep_files.max(1),
+            failing: false,
+        })
+    }
+
+    pub fn record(&mut self, status: &status::UPSStatus) {
+        // Losing the log shouldn't affect monitoring, so just report it, once until it recovers.
+        match self.append(status) {
+            Ok(_) if self.failing => {
+                self.failing = false;
+                info!("Logging UPS status to {} again.", self.path)
+            }
+            Ok(_) => {}
+            Err(e) if !self.failing => {
+                self.failing = true;
+                warn!("Failed to log UPS status to {}: {}", self.path, e)
+            }
+            Err(_) => {}
+        }
+    }
+
+    fn append(&self, status: &status::UPSStatus) -> io::Result<()> {
+        // Rotate before writing, so the file never grows much past the limit.
+        if self.max_size > 0 {
+            if let Ok(metadata) = fs::metadata(&self.path) {
+                if metadata.len() >= self.max_size {
+                    self.rotate()?;
+                }
+            }
+        }
+
+        // Reopen each poll, so a file rotated or deleted by something else is simply recreated,
+        // along with its directory.
+        if let Some(parent) = Path::new(&self.path).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        let entry = Entry {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            status,
+        };
+        let line = match self.format {
+            StatusLogFormat::Json => serde_json::to_string(&entry)?,
+            StatusLogFormat::Csv => {
+                // Columns follow the status fields, which only change between versions, so a
+                // header on each new file is enough.
+                let fields = match serde_json::to_value(status)? {
+                    Value::Object(fields) => fields,
+                    _ => Default::default(),
+                };
+                let row = csv_row(
+                    [entry.time]
+                        .into_iter()
+                        .chain(fields.values().map(csv_value)),
+                );
+                if file.metadata()?.len() == 0 {
+                    let header = ["time".to_string()]
+                        .into_iter()
+                        .chain(fields.keys().cloned());
+                    format!("{}\n{}", csv_row(header), row)
+                } else {
+                    row
+                }
+            }
+        };
+        writeln!(file, "{}", line)
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        // Shift each rotated file along one, the oldest falling off the end, then move the log
+        // itself to `.1`.
+        for index in (1..self.keep_files).rev() {
+            let from = format!("{}.{}", self.path, index);
+            if Path::new(&from).exists() {
+                fs::rename(&from, format!("{}.{}", self.path, index + 1))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))
+    }
+}
+
+fn csv_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn csv_row(values: impl Iterator<Item = String>) -> String {
+    // Quote anything that would otherwise split the row, doubling any quotes inside.
+    values
+        .map(|value| {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_keeping_a_header_on_each_file() {
+        let dir = std::env::temp_dir().join(format!("ups-status-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("history").join("status.csv");
+        let mut log = StatusLog::new(&StatusLogSettings {
+            path: Some(path.to_str().unwrap().to_string()),
+            keep_files: 2,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(log.format, StatusLogFormat::Csv);
+        // Rotate on every poll after the first.
+        log.max_size = 1;
+
+        let status = status::UPSStatus::default();
+        for _ in 0..4 {
+            log.record(&status);
+        }
+        assert!(!log.failing);
+
+        let rotated = |index| dir.join("history").join(format!("status.csv.{}", index));
+        for file in [path.clone(), rotated(1), rotated(2)] {
+            let text = fs::read_to_string(&file).unwrap();
+            let lines: Vec<&str> = text.lines().collect();
+            assert_eq!(lines.len(), 2);
+            assert!(lines[0].starts_with("time,"));
+            assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+        }
+        assert!(!rotated(3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+#[cfg(unix)]
+use signal_hook::consts::SIGHUP;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+// How often a wait checks whether we've been asked to stop.
+const STOP_CHECK_INTERVAL_MS: u64 = 100;
+
+#[derive(Clone, Default)]
+pub struct Stop(Arc<AtomicBool>);
+
+impl Stop {
+    pub fn on_signals() -> Stop {
+        // Flag a stop on SIGTERM/SIGINT, so we can finish the current poll and exit cleanly.
+        let stop = Stop::default();
+        for signal in [SIGTERM, SIGINT] {
+            signal_hook::flag::register(signal, Arc::clone(&stop.0))
+                .expect("Failed to register signal handler");
+        }
+        stop
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    fn set(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn wait(&self, duration: time::Duration, wake: impl Fn() -> bool) -> bool {
+        // Sleep for `duration`, waking early if asked to stop or once `wake` says to, and return
+        // whether we were asked to stop.
+        let until = time::Instant::now() + duration;
+        loop {
+            if self.is_set() {
+                return true;
+            }
+            if wake() {
+                return false;
+            }
+            let left = until.saturating_duration_since(time::Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            thread::sleep(left.min(time::Duration::from_millis(STOP_CHECK_INTERVAL_MS)));
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Reload(Arc<AtomicBool>);
+
+impl Reload {
+    #[cfg(unix)]
+    pub fn on_signal() -> Reload {
+        // Flag a reload on SIGHUP, picked up before the next poll.
+        let reload = Reload::default();
+        signal_hook::flag::register(SIGHUP, Arc::clone(&reload.0))
+            .expect("Failed to register signal handler");
+        reload
+    }
+
+    #[cfg(not(unix))]
+    pub fn on_signal() -> Reload {
+        // There's no SIGHUP here, so a reload is never asked for.
+        Reload::default()
+    }
+
+    pub fn take(&self) -> bool {
+        // Whether a reload was asked for since we last looked, clearing it.
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_ends_early_once_stopped() {
+        let stop = Stop::default();
+        let started = time::Instant::now();
+        assert!(!stop.wait(time::Duration::from_millis(50), || false));
+        assert!(started.elapsed() >= time::Duration::from_millis(50));
+
+        let stopper = stop.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            stopper.set();
+        });
+        let started = time::Instant::now();
+        assert!(stop.wait(time::Duration::from_secs(10), || false));
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+        handle.join().unwrap();
+
+        // Already stopped, so there's no waiting at all.
+        assert!(stop.wait(time::Duration::from_secs(10), || false));
+
+        // Woken without a stop, it ends at once but says it wasn't stopped.
+        assert!(!Stop::default().wait(time::Duration::from_secs(10), || true));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::ups::UPSError;
+
+use hidapi::{HidApi, HidDevice};
+use serialport::SerialPort;
+use std::{
+    cell::RefCell,
+    io::{self, Read, Write},
+    time,
+};
+
+// Serial units speak the same protocol over RS232 at 2400 baud, 8N1.
+const SERIAL_BAUD_RATE: u32 = 2400;
+
+pub trait Transport {
+    // Write one chunk of a command to the UPS.
+    fn write(&self, data: &[u8]) -> Result<usize, UPSError>;
+
+    // Read one chunk of a response into `buf`, giving the number of bytes read (0 on timeout).
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, UPSError>;
+}
+
+pub trait Connector {
+    // Open (or reopen) the link to the UPS.
+    fn open(&mut self) -> Result<Box<dyn Transport>, UPSError>;
+}
+
+#[derive(Debug)]
+pub struct DeviceSelector {
+    // The vid:pid should narrow down to our UPS.
+    pub vendor_id: u16,
+    pub product_id: u16,
+    // The serial and path narrow it down further when several identical units are attached.
+    // Both are optional, and if both are given then both must match.
+    pub serial_number: Option<String>,
+    pub usb_path: Option<String>,
+}
+
+pub struct HidConnector {
+    api: HidApi,
+    selector: DeviceSelector,
+}
+
+impl HidConnector {
+    pub fn new(api: HidApi, selector: DeviceSelector) -> HidConnector {
+        HidConnector { api, selector }
+    }
+
+    pub fn selector(&self) -> &DeviceSelector {
+        &self.selector
+    }
+
+    pub fn open_device(&mut self) -> Result<HidDevice, UPSError> {
+        let vendor_id = self.selector.vendor_id;
+        let product_id = self.selector.product_id;
+
+        match (self.selector.serial_number.clone(), &self.selector.usb_path) {
+            (None, None) => {
+                self.api
+                    .open(vendor_id, product_id)
+                    .map_err(|error| UPSError::OpenFailed {
+                        vendor_id,
+                        product_id,
+                        error,
+                    })
+            }
+            (Some(serial), None) => match self.api.open_serial(vendor_id, product_id, &serial) {
+                Ok(device) => Ok(device),
+                Err(error) => {
+                    // Distinguish a missing serial from one we found but couldn't open.
+                    let serials = self.list_serials()?;
+                    if serials.contains(&serial) {
+                        Err(UPSError::OpenFailed {
+                            vendor_id,
+                            product_id,
+                            error,
+                        })
+                    } else {
+                        Err(UPSError::NoMatchingDevice {
+                            vendor_id,
+                            product_id,
+                            serials,
+                        })
+                    }
+                }
+            },
+            (_, Some(_)) => self.open_matching_device(),
+        }
+    }
+
+    fn list_serials(&mut self) -> Result<Vec<String>, UPSError> {
+        // Refresh the device list, as the UPS may have been re-enumerated since we last looked.
+        self.api.refresh_devices()?;
+
+        Ok(self
+            .api
+            .device_list()
+            .filter(|d| {
+                d.vendor_id() == self.selector.vendor_id
+                    && d.product_id() == self.selector.product_id
+            })
+            .map(|d| d.serial_number().unwrap_or("<none>").to_string())
+            .collect())
+    }
+
+    fn open_matching_device(&mut self) -> Result<HidDevice, UPSError> {
+        // Refresh the device list, as the UPS may have been re-enumerated since we last looked.
+        self.api.refresh_devices()?;
+
+        // Walk the attached UPSes, noting serials so we can report them if none match.
+        let mut serials: Vec<String> = Vec::new();
+        for info in self.api.device_list().filter(|d| {
+            d.vendor_id() == self.selector.vendor_id && d.product_id() == self.selector.product_id
+        }) {
+            let serial_matches = match &self.selector.serial_number {
+                Some(serial) => info.serial_number() == Some(serial.as_str()),
+                None => true,
+            };
+            let path_matches = match &self.selector.usb_path {
+                Some(path) => info.path().to_string_lossy() == path.as_str(),
+                None => true,
+            };
+
+            if serial_matches && path_matches {
+                return Ok(info.open_device(&self.api)?);
+            }
+
+            serials.push(info.serial_number().unwrap_or("<none>").to_string());
+        }
+
+        Err(UPSError::NoMatchingDevice {
+            vendor_id: self.selector.vendor_id,
+            product_id: self.selector.product_id,
+            serials,
+        })
+    }
+}
+
+impl Connector for HidConnector {
+    fn open(&mut self) -> Result<Box<dyn Transport>, UPSError> {
+        Ok(Box::new(HidTransport {
+            device: self.open_device()?,
+        }))
+    }
+}
+
+pub struct HidTransport {
+    device: HidDevice,
+}
+
+impl Transport for HidTransport {
+    fn write(&self, data: &[u8]) -> Result<usize, UPSError> {
+        // We need to prefix with a null byte to specify the USB interface to use.
+        let mut report: Vec<u8> = Vec::with_capacity(data.len() + 1);
+        report.push(0);
+        report.extend_from_slice(data);
+
+        Ok(self.device.write(&report)?)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, UPSError> {
+        Ok(self.device.read_timeout(buf, timeout)?)
+    }
+}
+
+pub struct SerialConnector {
+    path: String,
+}
+
+impl SerialConnector {
+    pub fn new(path: String) -> SerialConnector {
+        SerialConnector { path }
+    }
+}
+
+impl Connector for SerialConnector {
+    fn open(&mut self) -> Result<Box<dyn Transport>, UPSError> {
+        let port = serialport::new(&self.path, SERIAL_BAUD_RATE)
+            .data_bits(serialport::DataBits::Eight)
+            .parity(serialport::Parity::None)
+            .stop_bits(serialport::StopBits::One)
+            .flow_control(serialport::FlowControl::None)
+            .open()?;
+
+        Ok(Box::new(SerialTransport {
+            port: RefCell::new(port),
+        }))
+    }
+}
+
+pub struct SerialTransport {
+    // The port needs `&mut` for IO, but transports are shared like a `HidDevice`.
+    port: RefCell<Box<dyn SerialPort>>,
+}
+
+impl Transport for SerialTransport {
+    fn write(&self, data: &[u8]) -> Result<usize, UPSError> {
+        // Commands are zero-padded to the HID report size, which serial units don't expect.
+        let end = data.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+        let mut port = self.port.borrow_mut();
+        port.write_all(&data[..end])?;
+        port.flush()?;
+
+        Ok(end)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> Result<usize, UPSError> {
+        let mut port = self.port.borrow_mut();
+        port.set_timeout(time::Duration::from_millis(timeout.max(0) as u64))?;
+
+        // A quiet line is reported as an empty read, matching hidapi.
+        match port.read(buf) {
+            Ok(bytes_read) => Ok(bytes_read),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    // A fake UPS for tests, answering each command with canned responses.
+    use super::{Connector, Transport};
+    use crate::ups::UPSError;
+
+    use std::{
+        cell::RefCell,
+        collections::{HashMap, VecDeque},
+        rc::Rc,
+    };
+
+    const TERMINATOR: u8 = 13;
+
+    #[derive(Default)]
+    pub struct MockState {
+        // Responses to each command in turn, with `None` for no reply. The last one repeats.
+        pub responses: HashMap<String, VecDeque<Option<Vec<u8>>>>,
+        // The most bytes handed back per read, to mimic the 8-byte HID reports.
+        pub chunk: usize,
+        // Zero-pad short reads to the full chunk, as HID reports are.
+        pub pad: bool,
+        // Every complete command written, in order.
+        pub commands: Vec<String>,
+        command: Vec<u8>,
+        pending: VecDeque<u8>,
+    }
+
+    impl MockState {
+        pub fn new() -> Rc<RefCell<MockState>> {
+            let mut state = MockState {
+                chunk: 8,
+                ..Default::default()
+            };
+            state.respond("M", &[Some(b"H\r")]);
+            Rc::new(RefCell::new(state))
+        }
+
+        pub fn respond(&mut self, cmd: &str, responses: &[Option<&[u8]>]) {
+            self.responses.insert(
+                cmd.to_string(),
+                responses.iter().map(|r| r.map(|r| r.to_vec())).collect(),
+            );
+        }
+    }
+
+    pub struct MockConnector(pub Rc<RefCell<MockState>>);
+
+    impl Connector for MockConnector {
+        fn open(&mut self) -> Result<Box<dyn Transport>, UPSError> {
+            // Unanswered bytes don't survive a reconnect.
+            self.0.borrow_mut().pending.clear();
+            Ok(Box::new(MockTransport(Rc::clone(&self.0))))
+        }
+    }
+
+    pub struct MockTransport(Rc<RefCell<MockState>>);
+
+    impl Transport for MockTransport {
+        fn write(&self, data: &[u8]) -> Result<usize, UPSError> {
+            let mut state = self.0.borrow_mut();
+            for &c in data.iter().filter(|&&c| c != 0) {
+                if c != TERMINATOR {
+                    state.command.push(c);
+                    continue;
+                }
+
+                let cmd = String::from_utf8_lossy(&state.command).to_string();
+                state.command.clear();
+                let response = state.responses.get_mut(&cmd).and_then(|responses| {
+                    if responses.len() > 1 {
+                        responses.pop_front().flatten()
+                    } else {
+                        responses.front().cloned().flatten()
+                    }
+                });
+                if let Some(response) = response {
+                    state.pending.extend(response);
+                }
+                state.commands.push(cmd);
+            }
+
+            Ok(data.len())
+        }
+
+        fn read_timeout(&self, buf: &mut [u8], _timeout: i32) -> Result<usize, UPSError> {
+            let mut state = self.0.borrow_mut();
+            let length = buf.len().min(state.chunk).min(state.pending.len());
+            for b in buf.iter_mut().take(length) {
+                *b = state.pending.pop_front().unwrap();
+            }
+
+            if state.pad && length > 0 {
+                let padded = buf.len().min(state.chunk);
+                buf[length..padded].fill(0);
+                return Ok(padded);
+            }
+            Ok(length)
+        }
+    }
+}
@@ -1,41 +1,205 @@
+use crate::health;
+use crate::protocol::{TestKind, UpsProtocol};
 use crate::status;
 
-use hidapi::{HidApi, HidDevice, HidError};
+use hidapi::HidError;
 use std::{
-    fmt,
+    error::Error,
+    fmt, io,
     num::{ParseFloatError, ParseIntError},
     str::Utf8Error,
     thread, time,
 };
-
-// The UPS uses ASCII characters for communication.
-const TERMINATOR: u8 = 13; // Carriage return
-const SEPARATOR: u8 = 32; // Space
-const PROTOCOL_ID: u8 = 72; // 'H'
-
-// Messages received are at most 8 values.
-// Longer messages are hence split with the above terminator.
-const MAX_DATA_LENGTH: usize = 8;
-
-// We use an arbitrary max number of messages to try receive.
-const MAX_DATA_LOOP: usize = 20;
-
-const TIMEOUT: i32 = 500;
-const RETRIES: usize = 3;
+use tracing::{info, warn};
 
 #[derive(Debug)]
 pub enum UPSError {
     ProtocolMismatch,
+    WrongProtocol {
+        got: u8,
+    },
     NoDevice,
+    OpenFailed {
+        vendor_id: u16,
+        product_id: u16,
+        error: HidError,
+    },
+    NoMatchingDevice {
+        vendor_id: u16,
+        product_id: u16,
+        serials: Vec<String>,
+    },
+    CancelIgnored,
+    TestRefused,
+    Descriptor(String),
+    Unsupported(u32),
     EmptyResponse,
+    Rejected {
+        reply: Vec<u8>,
+    },
+    Malformed {
+        command: String,
+        raw: String,
+        reason: String,
+    },
+    Command {
+        command: String,
+        error: Box<UPSError>,
+    },
     Hid(HidError),
-    ParseInt(ParseIntError),
-    ParseFloat(ParseFloatError),
+    Serial(serialport::Error),
+    Io(io::Error),
+    ParseInt {
+        text: String,
+        error: ParseIntError,
+    },
+    ParseFloat {
+        text: String,
+        error: ParseFloatError,
+    },
     Utf8(Utf8Error),
+    Unresponsive {
+        seconds: u64,
+    },
+    Stopped,
+    Settings(String),
+}
+impl UPSError {
+    pub fn during(self, command: &str) -> UPSError {
+        // Note which command was in flight when this went wrong.
+        UPSError::Command {
+            command: command.to_string(),
+            error: Box::new(self),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        // The error and everything behind it, for alerts, e.g. "A: B: C".
+        let mut report = self.to_string();
+        let mut source = self.source();
+        while let Some(error) = source {
+            report.push_str(&format!(": {}", error));
+            source = error.source();
+        }
+        report
+    }
+
+    pub fn is_open_failure(&self) -> bool {
+        // Whether the UPS couldn't be found or opened at all, rather than failing once talking.
+        matches!(
+            self,
+            UPSError::NoDevice
+                | UPSError::OpenFailed { .. }
+                | UPSError::NoMatchingDevice { .. }
+                | UPSError::Serial(_)
+        )
+    }
+
+    pub fn is_link_failure(&self) -> bool {
+        // Whether the UPS went quiet or dropped off, which reconnecting may fix.
+        self.is_open_failure()
+            || matches!(
+                self,
+                UPSError::EmptyResponse | UPSError::Hid(_) | UPSError::Io(_)
+            )
+    }
 }
 impl fmt::Display for UPSError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Issue with UPS communication")
+        // Wrapped errors are left to `source`, so they aren't repeated in a report.
+        match self {
+            UPSError::ProtocolMismatch => write!(f, "UPS does not speak the configured protocol"),
+            UPSError::WrongProtocol { got } => {
+                write!(
+                    f,
+                    "UPS reported protocol {:?}, expected 'H' (set protocol_override if it's compatible)",
+                    *got as char
+                )
+            }
+            UPSError::NoDevice => write!(f, "UPS is not connected"),
+            UPSError::OpenFailed {
+                vendor_id,
+                product_id,
+                ..
+            } => {
+                write!(f, "Failed to open UPS {:04x}:{:04x}", vendor_id, product_id)?;
+                // macOS only lets processes with Input Monitoring open HID devices, and its own
+                // power management may already be holding the UPS.
+                if cfg!(target_os = "macos") {
+                    write!(
+                        f,
+                        " (grant Input Monitoring to whatever runs ups, in System Settings > \
+                         Privacy & Security, and check no other UPS software is using it)"
+                    )?;
+                }
+                Ok(())
+            }
+            UPSError::NoMatchingDevice {
+                vendor_id,
+                product_id,
+                serials,
+            } => write!(
+                f,
+                "No UPS {:04x}:{:04x} matched the configured serial/path, found serials: [{}]",
+                vendor_id,
+                product_id,
+                serials.join(", ")
+            ),
+            UPSError::CancelIgnored => write!(f, "UPS still has a shutdown pending after cancel"),
+            UPSError::TestRefused => write!(f, "Refusing to start a self-test on battery power"),
+            UPSError::Descriptor(reason) => {
+                write!(f, "Failed to read UPS report descriptor: {}", reason)
+            }
+            UPSError::Unsupported(usage) => {
+                write!(f, "UPS does not support HID usage {:#010x}", usage)
+            }
+            UPSError::EmptyResponse => write!(f, "No response from UPS"),
+            UPSError::Rejected { reply } => write!(
+                f,
+                "UPS rejected the command, replying {:?}",
+                String::from_utf8_lossy(reply)
+            ),
+            UPSError::Malformed {
+                command,
+                raw,
+                reason,
+            } => write!(
+                f,
+                "Malformed UPS response to {:?}, {}: {:?}",
+                command, reason, raw
+            ),
+            UPSError::Command { command, .. } => write!(f, "UPS command {:?} failed", command),
+            UPSError::Hid(_) => write!(f, "UPS HID communication failed"),
+            UPSError::Serial(_) => write!(f, "Failed to open UPS serial port"),
+            UPSError::Io(_) => write!(f, "UPS serial communication failed"),
+            UPSError::ParseInt { text, .. } => {
+                write!(f, "Failed to parse {:?} from the UPS as an integer", text)
+            }
+            UPSError::ParseFloat { text, .. } => {
+                write!(f, "Failed to parse {:?} from the UPS as a number", text)
+            }
+            UPSError::Utf8(_) => write!(f, "UPS response is not valid text"),
+            UPSError::Unresponsive { seconds } => {
+                write!(f, "UPS I/O didn't finish within {}s", seconds)
+            }
+            UPSError::Stopped => write!(f, "UPS I/O thread has stopped"),
+            UPSError::Settings(reason) => write!(f, "Invalid UPS settings: {}", reason),
+        }
+    }
+}
+impl Error for UPSError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UPSError::OpenFailed { error, .. } => Some(error),
+            UPSError::Command { error, .. } => Some(error.as_ref()),
+            UPSError::Hid(error) => Some(error),
+            UPSError::Serial(error) => Some(error),
+            UPSError::Io(error) => Some(error),
+            UPSError::ParseInt { error, .. } => Some(error),
+            UPSError::ParseFloat { error, .. } => Some(error),
+            UPSError::Utf8(error) => Some(error),
+            _ => None,
+        }
     }
 }
 impl From<HidError> for UPSError {
@@ -43,14 +207,14 @@ impl From<HidError> for UPSError {
         UPSError::Hid(err)
     }
 }
-impl From<ParseIntError> for UPSError {
-    fn from(err: ParseIntError) -> UPSError {
-        UPSError::ParseInt(err)
+impl From<serialport::Error> for UPSError {
+    fn from(err: serialport::Error) -> UPSError {
+        UPSError::Serial(err)
     }
 }
-impl From<ParseFloatError> for UPSError {
-    fn from(err: ParseFloatError) -> UPSError {
-        UPSError::ParseFloat(err)
+impl From<io::Error> for UPSError {
+    fn from(err: io::Error) -> UPSError {
+        UPSError::Io(err)
     }
 }
 impl From<Utf8Error> for UPSError {
@@ -59,267 +223,167 @@ impl From<Utf8Error> for UPSError {
     }
 }
 
+// Named as `UPSStatus` and `UPSError` are.
+#[allow(clippy::upper_case_acronyms)]
 pub struct UPS {
-    api: HidApi,
-    device: Option<HidDevice>,
+    protocol: Box<dyn UpsProtocol>,
     pub status: status::UPSStatus,
+    // Cleared once the UPS refuses the extended battery query, so we stop asking.
+    battery_detail: bool,
+    // What we've seen of the battery over time, for the health report.
+    pub health: health::History,
 }
 
 impl UPS {
-    pub fn new(api: hidapi::HidApi) -> UPS {
+    pub fn new(protocol: Box<dyn UpsProtocol>) -> Result<UPS, UPSError> {
         // Create our UPS structure.
         let mut ups: UPS = UPS {
-            api,
-            device: None,
-            status: status::UPSStatus::new(),
+            protocol,
+            status: status::UPSStatus::default(),
+            battery_detail: true,
+            health: health::History::default(),
         };
 
-        ups.connect().expect("Failed to connect to UPS");
+        ups.connect()?;
+
+        // The identity is a nice-to-have, so don't fail startup over units that refuse it.
+        if let Err(e) = ups.get_ups_info() {
+            warn!("Failed to read UPS info: {}", e.report())
+        }
 
         // Update with the rated values and current status.
-        ups.get_ups_ratings().expect("Failed to read UPS ratings");
-        ups.get_ups_status().expect("Failed to update UPS status");
+        ups.get_ups_ratings()?;
+        ups.get_ups_status()?;
 
-        return ups;
+        Ok(ups)
     }
 
     pub fn connect(&mut self) -> Result<(), UPSError> {
-        if self.device.is_some() {
-            self.device = None;
-        }
-
-        // This vid:pid should narrow down to our UPS
-        self.device = Some(self.api.open(0x0665, 0x5161)?);
+        self.protocol.connect()
+    }
 
-        // Check the protocol is right.
-        self.send_command("M")?;
-        let mut res: Vec<u8> = Vec::new();
-        self.get_response(&mut res, None)?;
+    pub fn disconnect(&mut self) {
+        self.protocol.disconnect()
+    }
 
-        if res[0] != PROTOCOL_ID {
-            return Err(UPSError::ProtocolMismatch);
-        }
+    pub fn get_ups_info(&mut self) -> Result<(), UPSError> {
+        self.protocol.read_info(&mut self.status)
+    }
 
-        Ok(())
+    pub fn get_ups_ratings(&mut self) -> Result<(), UPSError> {
+        self.protocol.read_ratings(&mut self.status)
     }
 
-    fn send_command(&self, cmd: &str) -> Result<(), UPSError> {
-        if let Some(device) = &self.device {
-            // We first read a few times to make sure there's no partial messages waiting.
-            for i in 0..MAX_DATA_LOOP {
-                if cfg!(debug_assertions) {
-                    println!("CLEAR LOOP {}", i);
-                }
-                // Read one message.
-                let bytes_read = device.read_timeout(&mut [0; MAX_DATA_LENGTH], TIMEOUT)?;
-                if bytes_read == 0 {
-                    break;
-                }
-                if i == (MAX_DATA_LOOP - 1) {
-                    eprintln!("Appears messages may still be waiting on device - may crash.")
+    pub fn get_ups_status(&mut self) -> Result<(), UPSError> {
+        self.protocol.read_status(&mut self.status)?;
+        self.health.observe(&self.status, time::SystemTime::now());
+
+        // The battery detail is extra, so never fail a poll over it.
+        if self.battery_detail {
+            if let Err(e) = self.get_battery_detail() {
+                let refused = match &e {
+                    UPSError::Command { error, .. } => {
+                        matches!(**error, UPSError::Rejected { .. } | UPSError::EmptyResponse)
+                    }
+                    _ => false,
+                };
+                if refused {
+                    info!("UPS doesn't support the extended battery query.");
+                    self.battery_detail = false;
+                } else {
+                    warn!("Failed to read battery detail: {}", e.report())
                 }
             }
+        }
 
-            if cfg!(debug_assertions) {
-                println!("=====================");
-            }
-            for chunk in cmd.as_bytes().chunks(MAX_DATA_LENGTH) {
-                // We need to prefix with a null byte to specify the USB interface to use.
-                // Hence our message is `MAX_DATA_LENGTH` + 1.
-                let mut message: [u8; MAX_DATA_LENGTH + 1] = [0; MAX_DATA_LENGTH + 1];
-
-                // Now we convert our command to bytes
-                for i in 0..chunk.len() {
-                    message[i + 1] = chunk[i]
-                }
-
-                if cfg!(debug_assertions) {
-                    println!(
-                        "SEND {:?} {}",
-                        message,
-                        std::str::from_utf8(&message).unwrap()
-                    );
-                }
-
-                // And send it off to the UPS.
-                device.write(&message)?;
-            }
+        Ok(())
+    }
 
-            if cfg!(debug_assertions) {
-                println!(
-                    "SEND {:?} {}",
-                    [0, TERMINATOR],
-                    std::str::from_utf8(&[0, TERMINATOR]).unwrap()
-                );
-            }
-            device.write(&[0, TERMINATOR])?;
-            Ok(())
-        } else {
-            return Err(UPSError::NoDevice);
-        }
+    pub fn health_report(&self) -> health::HealthReport {
+        health::HealthReport::new(&self.status, &self.health)
     }
 
-    fn get_response(&self, res: &mut Vec<u8>, length: Option<usize>) -> Result<(), UPSError> {
-        if let Some(device) = &self.device {
-            // We at most `MAX_DATA_LOOP` times (till we read a terminator).
-            for i in 0..MAX_DATA_LOOP {
-                if cfg!(debug_assertions) {
-                    println!("READ LOOP {}", i);
-                }
+    pub fn get_ups_mode_quick(&mut self) -> Result<(), UPSError> {
+        // A lighter read of just the utility, mode and fault, for checks between full polls.
+        self.protocol.read_mode(&mut self.status)
+    }
 
-                // Temporary array for data.
-                let mut data: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
+    pub fn get_battery_detail(&mut self) -> Result<(), UPSError> {
+        // Clear the old values first, so a failed read doesn't leave them stale.
+        self.status.battery_count = None;
+        self.status.battery_group_voltage = None;
+        self.protocol.read_battery_detail(&mut self.status)
+    }
 
-                // Read one message.
-                let bytes_read = device.read_timeout(&mut data, TIMEOUT)?;
-                if bytes_read == 0 {
-                    return Err(UPSError::EmptyResponse);
-                }
+    pub fn shutdown(&self, delay: f32, restart: i32) -> Result<(), UPSError> {
+        self.protocol.shutdown(delay, restart)
+    }
 
-                if cfg!(debug_assertions) {
-                    println!("READ {:?} {}", data, std::str::from_utf8(&data).unwrap());
-                }
+    pub fn describe_shutdown(&self, delay: f32, restart: i32) -> String {
+        self.protocol.describe_shutdown(delay, restart)
+    }
 
-                // Add character by character to the output, and return on the terminator.
-                // Alternately return when message is the right length.
-                for c in data {
-                    if c == TERMINATOR {
-                        return Ok(());
-                    }
-                    res.push(c);
-                    if let Some(l) = length {
-                        if res.len() == l {
-                            return Ok(());
-                        }
-                    }
-                }
-            }
+    pub fn start_test(&self, kind: TestKind) -> Result<(), UPSError> {
+        // Testing drains the battery, which we can't spare if the utility has failed.
+        if self.status.utility_failed {
+            return Err(UPSError::TestRefused);
         }
 
-        Err(UPSError::NoDevice)
+        self.protocol.start_test(kind)
+    }
+
+    pub fn cancel_test(&self) -> Result<(), UPSError> {
+        self.protocol.cancel_test()
     }
 
-    fn send_and_split(
+    pub fn run_test(
         &mut self,
-        cmd: &str,
-        out: &mut Vec<Vec<u8>>,
-        length: Option<usize>,
-    ) -> Result<(), UPSError> {
-        // Set up an array for our data, then send and receive from the UPS.
-        let mut data: Vec<u8> = Vec::new();
-
-        for attempt in 0..RETRIES {
-            self.send_command(cmd)?;
-            match self.get_response(&mut data, length) {
-                Ok(_) => break,
-                Err(e) => {
-                    if matches!(e, UPSError::EmptyResponse) {
-                        self.connect()?;
-                        thread::sleep(time::Duration::from_millis(200));
-                        if attempt == (RETRIES - 1) {
-                            return Err(e);
-                        }
-                    } else {
-                        return Err(e);
-                    }
-                }
+        kind: TestKind,
+        timeout: time::Duration,
+    ) -> Result<status::UPSTestResults, UPSError> {
+        self.start_test(kind)?;
+
+        // Give the test its expected run time as a head start, then allow `timeout` beyond that.
+        let deadline = time::Instant::now() + kind.duration() + timeout;
+        thread::sleep(kind.duration());
+
+        // Now poll until the UPS reports something other than `InProgress`, or we run out of time.
+        loop {
+            self.get_ups_status()?;
+            if self.status.test_result != status::UPSTestResults::InProgress
+                || time::Instant::now() >= deadline
+            {
+                return Ok(self.status.test_result);
             }
+            thread::sleep(time::Duration::from_secs(1));
         }
+    }
 
-        // Strip the first character (a '#' or '(').
-        data.remove(0);
+    pub fn cancel_shutdown(&mut self) -> Result<(), UPSError> {
+        self.protocol.cancel_shutdown()?;
 
-        // Loop through the full message and split at `SEPARATOR`, pushing vectors to the output.
-        out.push(Vec::new());
-        for c in data {
-            if c == SEPARATOR {
-                out.push(Vec::new());
-            } else {
-                out.last_mut().unwrap().push(c);
-            }
+        // Check the next status response to confirm the UPS actually dropped the shutdown.
+        self.get_ups_status()?;
+        if self.status.shutdown_active {
+            return Err(UPSError::CancelIgnored);
         }
 
         Ok(())
     }
 
-    pub fn get_ups_ratings(&mut self) -> Result<(), UPSError> {
-        let mut res: Vec<Vec<u8>> = Vec::new();
-        self.send_and_split("F", &mut res, None)?;
-        self.status.rated_output_voltage = std::str::from_utf8(&(res[0]))?.parse()?;
-        self.status.rated_output_current = std::str::from_utf8(&res[1])?.parse()?;
-        self.status.rated_battery_voltage = std::str::from_utf8(&res[2])?.parse()?;
-        self.status.rated_output_frequency = std::str::from_utf8(&res[3])?.parse()?;
-
-        Ok(())
-    }
-
-    pub fn get_ups_status(&mut self) -> Result<(), UPSError> {
-        let mut res: Vec<Vec<u8>> = Vec::new();
-        self.send_and_split("QS", &mut res, None)?;
-        self.status.input_voltage = std::str::from_utf8(&res[0])?.parse()?;
-        self.status.input_fault_voltage = std::str::from_utf8(&res[1])?.parse()?;
-        self.status.output_voltage = std::str::from_utf8(&res[2])?.parse()?;
-        self.status.output_load = std::str::from_utf8(&res[3])?.parse()?;
-        self.status.output_frequency = std::str::from_utf8(&res[4])?.parse()?;
-        self.status.battery_voltage = std::str::from_utf8(&res[5])?.parse()?;
-
-        self.status.utility_failed = res[7][0] == b'1';
-        self.status.shutdown_active = res[7][6] == b'1';
-
-        let mut res: Vec<Vec<u8>> = Vec::new();
-        self.send_and_split("QI", &mut res, Some(48))?;
-        self.status.remaining_capacity = std::str::from_utf8(&res[0])?.parse()?;
-        self.status.seconds_to_empty = std::str::from_utf8(&res[1])?.parse()?;
-        self.status.input_frequency = std::str::from_utf8(&res[2])?.parse()?;
-        self.status.output_current = std::str::from_utf8(&res[3])?.parse()?;
-
-        self.status.test_result = match res[7][7] {
-            b'1' => status::UPSTestResults::Passed,
-            b'2' => status::UPSTestResults::Warning,
-            b'3' => status::UPSTestResults::Error,
-            b'4' => status::UPSTestResults::Aborted,
-            b'5' => status::UPSTestResults::InProgress,
-            _ => status::UPSTestResults::NoTest,
-        };
-        self.status.overloaded = res[7][8] == b'1';
-        self.status.replace_battery = res[7][9] == b'1';
-        self.status.charging = res[7][10] == b'1';
-        self.status.ups_mode = match res[7][12] {
-            b'1' => status::UPSModes::Standby,
-            b'2' => status::UPSModes::Line,
-            b'3' => status::UPSModes::Inverting,
-            b'4' => status::UPSModes::SelfTest,
-            b'5' => status::UPSModes::Fault,
-            _ => status::UPSModes::Idle,
-        };
-
-        Ok(())
+    pub fn toggle_beep(&self) -> Result<(), UPSError> {
+        self.protocol.toggle_beep()
     }
 
-    pub fn shutdown(&self, delay: f32, restart: i32) -> Result<(), UPSError> {
-        let cmd: String;
-        if delay < 1.0 {
-            cmd = format!("S.{:01}R{:04}", delay * 10.0, restart);
-        } else {
-            cmd = format!("S{:02}R{:04}", delay, restart);
+    pub fn set_beep(&mut self, enabled: bool) -> Result<(), UPSError> {
+        // The beeper command is a toggle, so check where it is first and only toggle if needed,
+        // reading it back after.
+        self.get_ups_status()?;
+        if self.status.beeper_on != enabled {
+            self.protocol.toggle_beep()?;
+            self.get_ups_status()?;
         }
-        self.send_command(cmd.as_str())?;
+
         Ok(())
     }
-
-    // fn run_test(&self) -> Result<(), UPSError> {
-    //     self.send_command("T")?;
-    //     thread::sleep(time::Duration::from_secs(10));
-    //     Ok(())
-    // }
-
-    // fn cancel_shutdown(&self) -> Result<(), UPSError> {
-    //     self.send_command("C")?;
-    //     Ok(())
-    // }
-
-    // fn toggle_beep(&self) -> Result<(), UPSError> {
-    //     self.send_command("Q")?;
-    //     Ok(())
-    // }
 }
@@ -0,0 +1,78 @@
+use crate::notifier::{EventKind, MultiNotifier, Notifier, Severity};
+
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+use tracing::{error, warn};
+
+pub struct Watchdog {
+    // The main loop pets this each poll, and a separate thread alerts if it stops.
+    // Pets are stored as milliseconds since `started`, so they fit in an atomic.
+    started: time::Instant,
+    last_pet: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+    pub fn spawn(timeout: time::Duration, abort: bool, notifiers: MultiNotifier) -> Watchdog {
+        // The thread has its own notifiers, so it doesn't wait on anything the main loop holds.
+        let started = time::Instant::now();
+        let last_pet = Arc::new(AtomicU64::new(0));
+
+        let watched = Arc::clone(&last_pet);
+        thread::spawn(move || {
+            let mut stalled = false;
+            loop {
+                thread::sleep((timeout / 4).max(time::Duration::from_secs(1)));
+
+                let since_pet = started
+                    .elapsed()
+                    .saturating_sub(time::Duration::from_millis(watched.load(Ordering::Relaxed)));
+                if since_pet > timeout && !stalled {
+                    stalled = true;
+                    warn!(
+                        "No poll for {}s - monitor appears stalled.",
+                        since_pet.as_secs()
+                    );
+                    notifiers.send(
+                        EventKind::Stalled,
+                        Severity::Critical,
+                        "UPS monitor appears stalled.",
+                        &format!(
+                            "The monitor hasn't polled the UPS for {}s (timeout {}s), so it won't \
+                             notice an outage.",
+                            since_pet.as_secs(),
+                            timeout.as_secs()
+                        ),
+                    );
+                    notifiers.flush(true);
+
+                    if abort {
+                        // Let the service manager restart us.
+                        error!("Aborting stalled monitor.");
+                        process::abort();
+                    }
+                } else if since_pet <= timeout && stalled {
+                    stalled = false;
+                    notifiers.send(
+                        EventKind::Unstalled,
+                        Severity::Info,
+                        "UPS monitor recovered.",
+                        "The monitor is polling the UPS again.",
+                    );
+                }
+            }
+        });
+
+        Watchdog { started, last_pet }
+    }
+
+    pub fn pet(&self) {
+        self.last_pet
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
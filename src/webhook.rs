@@ -0,0 +1,237 @@
+use crate::notifier::{Event, EventKind, Notifier, NotifyError, Severity, Worker};
+use crate::status;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::HashMap, thread, time};
+use tracing::{debug, error, warn};
+
+const SEND_RETRY_DELAY: u64 = 1; // Seconds before the first retry, doubling after each.
+const CONNECT_TIMEOUT: u64 = 5; // Seconds to wait on connecting to the endpoint.
+const READ_TIMEOUT: u64 = 10; // Seconds to wait on the endpoint's response.
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WebhookSettings {
+    // Outline for a webhook, either the `[webhook]` section of the mailer settings or an entry in
+    // `[[notifiers]]` with `type = "webhook"`
+    //
+    // Parameters
+    // ----------
+    // - `url` is where alerts are POSTed as JSON
+    // - `headers` are added to each request, e.g. for auth tokens
+    // - `template` optionally replaces the body, with `{machine_id}`, `{event}`, `{severity}`,
+    //   `{subject}`, `{message}`, `{timestamp}` and `{status}` filled in as JSON values
+    // - `retries` of a failed call, backing off between each
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub template: Option<String>,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl WebhookSettings {
+    pub fn validate(&self) -> Vec<String> {
+        // Fill the template with sample values, so a typo shows up now rather than on an alert.
+        let mut problems = Vec::new();
+        if let Some(template) = &self.template {
+            let sample = render(
+                Some(template),
+                &body(
+                    "machine",
+                    EventKind::UtilityFailed,
+                    Severity::Warning,
+                    "subject",
+                    "message",
+                    None,
+                ),
+            );
+            if let Err(e) = serde_json::from_str::<Value>(&sample) {
+                problems.push(format!(
+                    "webhook template for {} isn't valid JSON once filled in: {}",
+                    self.url, e
+                ));
+            }
+        }
+        problems
+    }
+}
+
+fn body(
+    machine_id: &str,
+    event: EventKind,
+    severity: Severity,
+    subject: &str,
+    message: &str,
+    status: Option<&status::UPSStatus>,
+) -> Value {
+    json!({
+        "machine_id": machine_id,
+        "event": event,
+        "severity": severity,
+        "subject": subject,
+        "message": message,
+        "timestamp": humantime::format_rfc3339_seconds(time::SystemTime::now()).to_string(),
+        "status": status,
+    })
+}
+
+fn render(template: Option<&String>, body: &Value) -> String {
+    // The body as is, or filled into the template. Braces that aren't a known placeholder are
+    // left alone, as the template is JSON itself.
+    let mut rest = match template {
+        Some(template) => template.as_str(),
+        None => return body.to_string(),
+    };
+    let mut rendered = String::new();
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let placeholder = after
+            .find('}')
+            .and_then(|end| body.get(&after[..end]).map(|value| (value, end)));
+        match placeholder {
+            Some((value, end)) => {
+                rendered.push_str(&value.to_string());
+                rest = &after[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+pub struct WebhookNotifier {
+    // POSTs rendered bodies to the webhook, retrying failures on the worker.
+    worker: Worker<String>,
+    template: Option<String>,
+    machine_id: String,
+    dry_run: bool,
+}
+
+impl WebhookNotifier {
+    pub fn new(settings: WebhookSettings, machine_id: String, dry_run: bool) -> WebhookNotifier {
+        let url = settings.url;
+        let headers = settings.headers;
+        let retries = settings.retries;
+        // One agent for every attempt, so a hung endpoint can't stall the queue forever.
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(time::Duration::from_secs(CONNECT_TIMEOUT))
+            .timeout_read(time::Duration::from_secs(READ_TIMEOUT))
+            .build();
+        let worker = Worker::spawn("the webhook", move |body: String| {
+            post(&agent, &url, &headers, retries, &body)
+        });
+
+        WebhookNotifier {
+            worker,
+            template: settings.template,
+            machine_id,
+            dry_run,
+        }
+    }
+}
+
+fn post(
+    agent: &ureq::Agent,
+    url: &str,
+    headers: &HashMap<String, String>,
+    retries: u32,
+    body: &str,
+) {
+    // Retry with backoff, as the endpoint may just be restarting.
+    let mut retry_delay = SEND_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        let mut request = agent.post(url).set("Content-Type", "application/json");
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        match request.send_string(body) {
+            Ok(_) => return,
+            Err(e) if attempt < retries => {
+                warn!(
+                    "Failed to call webhook (attempt {}/{}), retrying in {}s: {}",
+                    attempt + 1,
+                    retries + 1,
+                    retry_delay,
+                    e
+                );
+                thread::sleep(time::Duration::from_secs(retry_delay));
+                retry_delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                error!("Failed to call webhook: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        // Send a UPS alert to the webhook
+        if cfg!(debug_assertions) {
+            // In debug builds, don't spam anyone!
+            debug!("In debug build, not calling webhook.");
+            return Ok(());
+        }
+
+        let body = body(
+            &self.machine_id,
+            event.kind,
+            event.severity,
+            &event.subject(self.dry_run),
+            &event.message,
+            event.status.as_ref(),
+        );
+        self.worker.send(render(self.template.as_ref(), &body))
+    }
+
+    fn flush(&self, force: bool) {
+        self.worker.flush(force)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_fills_in_placeholders_once() {
+        let body = body(
+            "rack",
+            EventKind::BatteryLow,
+            Severity::Warning,
+            "Battery low capacity.",
+            "Left as {status}",
+            Some(&status::UPSStatus {
+                remaining_capacity: 42,
+                ..Default::default()
+            }),
+        );
+        let template =
+            r#"{"text": {subject}, "event": {event}, "note": {message}, "ups": {status}}"#
+                .to_string();
+        let rendered: Value = serde_json::from_str(&render(Some(&template), &body)).unwrap();
+
+        assert_eq!(rendered["text"], "Battery low capacity.");
+        assert_eq!(rendered["event"], "battery_low");
+        assert_eq!(rendered["note"], "Left as {status}");
+        assert_eq!(rendered["ups"]["remaining_capacity"], 42);
+        assert_eq!(render(None, &body), body.to_string());
+    }
+}